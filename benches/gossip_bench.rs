@@ -5,20 +5,25 @@
 //! - Message creation overhead
 //! - Different payload type performance
 //! - End-to-end dissemination latency versus network size and versus fanout
+//! - Rate limiter contention, and inbound throughput from many busy peers
 
 use std::hint::black_box;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
 use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use futures::SinkExt;
 use grapevine::{
-    AntiEntropyConfig, EpidemicConfig, Message, MessageCodec, Node, NodeConfigBuilder, Payload,
+    AntiEntropyConfig, Budget, EpidemicConfig, Identity, Message, MessageCodec, Node,
+    NodeConfigBuilder, Payload, RateLimitConfig, RateLimiter, TrafficClass, WireEncoding,
 };
+use tokio::net::TcpStream;
 use tokio::runtime::Runtime;
-use tokio_util::codec::{Decoder, Encoder};
+use tokio_util::codec::{Decoder, Encoder, Framed};
 
 /// Benchmark message codec encoding performance.
 fn message_encoding(c: &mut Criterion) {
@@ -161,6 +166,25 @@ fn send_path_serialization(c: &mut Criterion) {
     group.finish();
 }
 
+fn wire_encodings(c: &mut Criterion) {
+    let mut group = c.benchmark_group("wire_encodings");
+
+    let addr: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+    let message = Message::new(addr, 0, Payload::Application(Bytes::from(vec![0u8; 1024])));
+
+    for wire in WireEncoding::ALL {
+        let encoding = wire.encoding();
+        group.bench_function(BenchmarkId::new("round_trip", format!("{wire:?}")), |b| {
+            b.iter(|| {
+                let bytes = encoding.encode(black_box(&message)).unwrap();
+                black_box(encoding.decode(&bytes).unwrap());
+            });
+        });
+    }
+
+    group.finish();
+}
+
 /// Convergence ceiling for the dissemination benchmarks. Reached only on
 /// failure; a healthy broadcast converges far sooner (the wait returns as soon
 /// as every leaf has delivered the new message).
@@ -186,6 +210,7 @@ fn brisk_anti_entropy() -> AntiEntropyConfig {
         interval: Duration::from_millis(200),
         fanout: 4,
         enabled: true,
+        ..AntiEntropyConfig::default()
     }
 }
 
@@ -329,6 +354,180 @@ fn propagation_latency_vs_fanout(c: &mut Criterion) {
     group.finish();
 }
 
+/// Busy peers sending to one node in the rate limiting benchmarks.
+const BUSY_PEERS: usize = 64;
+
+/// Messages each busy peer sends per iteration.
+const MESSAGES_PER_PEER: u64 = 50;
+
+/// Rate limits generous enough never to pace the benchmarks, so they measure
+/// the cost of the accounting: a per-connection budget and a global one that
+/// every connection charges.
+fn generous_rate_limit() -> RateLimitConfig {
+    RateLimitConfig {
+        capacity: 1_000_000,
+        refill_rate: 1_000_000,
+        byte_capacity: 1 << 30,
+        byte_refill_rate: 1 << 30,
+        global: Some(Budget {
+            capacity: 1_000_000,
+            refill_rate: 1_000_000,
+            byte_capacity: 1 << 30,
+            byte_refill_rate: 1 << 30,
+        }),
+        ..RateLimitConfig::default()
+    }
+}
+
+/// Charges from one thread per busy peer, each on its own connection's
+/// buckets and all on the shared global budget.
+fn rate_limiter_contention(c: &mut Criterion) {
+    const CHARGES: u64 = 1_000;
+
+    let mut group = c.benchmark_group("rate_limiter_contention");
+    group.throughput(Throughput::Elements(CHARGES * BUSY_PEERS as u64));
+    group.bench_function(BenchmarkId::from_parameter(BUSY_PEERS), |b| {
+        b.iter_custom(|iters| {
+            let limiter = Arc::new(RateLimiter::try_new(generous_rate_limit()).expect("limiter"));
+            let mut total = Duration::ZERO;
+            for _ in 0..iters {
+                let connections: Vec<_> = (0..BUSY_PEERS).map(|_| limiter.connection()).collect();
+                let start = Instant::now();
+                thread::scope(|scope| {
+                    for connection in &connections {
+                        scope.spawn(move || {
+                            for _ in 0..CHARGES {
+                                let _ = black_box(connection.allow(TrafficClass::Application, 256));
+                            }
+                        });
+                    }
+                });
+                total += start.elapsed();
+            }
+            total
+        });
+    });
+    group.finish();
+}
+
+/// A connection to the sink, and the broadcasts it will send.
+type BusyPeer = (Framed<TcpStream, MessageCodec>, Vec<Message>);
+
+/// Start a node limited by `rate_limit`, counting the messages it delivers.
+async fn start_sink(rate_limit: RateLimitConfig) -> (Node, SocketAddr, Arc<AtomicU32>) {
+    let node = Node::new(
+        NodeConfigBuilder::new()
+            .max_peers(4 * BUSY_PEERS)
+            .rate_limit(rate_limit)
+            .build()
+            .expect("sink config"),
+    )
+    .await
+    .expect("create sink");
+    let delivered = Arc::new(AtomicU32::new(0));
+    let counter = Arc::clone(&delivered);
+    node.on_message(move |_origin, _data| {
+        counter.fetch_add(1, Ordering::Relaxed);
+    })
+    .await;
+    node.start().await.expect("start sink");
+    let addr = node.local_addr().await.expect("sink address");
+    (node, addr, delivered)
+}
+
+/// Connect the busy peers to `sink`, each with its broadcasts signed in
+/// advance so that signing is not measured.
+async fn connect_busy_peers(sink: SocketAddr) -> Vec<BusyPeer> {
+    let mut peers = Vec::with_capacity(BUSY_PEERS);
+    for _ in 0..BUSY_PEERS {
+        let stream = TcpStream::connect(sink).await.expect("connect to sink");
+        let origin = stream.local_addr().expect("peer address");
+        let identity = Identity::generate();
+        let messages = (1..=MESSAGES_PER_PEER)
+            .map(|sequence| {
+                let payload = Payload::Application(Bytes::from_static(&[0x42; 256]));
+                identity
+                    .author(origin, sequence, payload)
+                    .expect("author message")
+            })
+            .collect();
+        peers.push((Framed::new(stream, MessageCodec::new()), messages));
+    }
+    peers
+}
+
+/// Send every busy peer's broadcasts at once and return the time until the
+/// sink has delivered them all.
+async fn flood_and_await(delivered: &AtomicU32, peers: Vec<BusyPeer>) -> Duration {
+    let expected = delivered.load(Ordering::Relaxed)
+        + u32::try_from(BUSY_PEERS as u64 * MESSAGES_PER_PEER).expect("message count");
+
+    let start = Instant::now();
+    let senders: Vec<_> = peers
+        .into_iter()
+        .map(|(mut framed, messages)| {
+            tokio::spawn(async move {
+                for message in messages {
+                    framed.feed(message).await.expect("send to sink");
+                }
+                framed.flush().await.expect("flush to sink");
+                framed
+            })
+        })
+        .collect();
+
+    let deadline = start + CONVERGE_DEADLINE;
+    while delivered.load(Ordering::Relaxed) < expected {
+        assert!(Instant::now() < deadline, "sink never delivered the flood");
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    let elapsed = start.elapsed();
+
+    for sender in senders {
+        drop(sender.await);
+    }
+    elapsed
+}
+
+/// Messages a node takes in per second from many busy peers at once, with
+/// and without rate limiting.
+fn inbound_throughput_many_peers(c: &mut Criterion) {
+    let rt = bench_runtime();
+    let mut group = c.benchmark_group("inbound_throughput_many_peers");
+    group.throughput(Throughput::Elements(BUSY_PEERS as u64 * MESSAGES_PER_PEER));
+
+    let cases = [
+        (
+            "unlimited",
+            RateLimitConfig {
+                enabled: false,
+                ..RateLimitConfig::default()
+            },
+        ),
+        ("rate_limited", generous_rate_limit()),
+    ];
+    for (name, rate_limit) in cases {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(name),
+            &rate_limit,
+            |b, rate_limit| {
+                b.to_async(&rt).iter_custom(|iters| async move {
+                    let (sink, sink_addr, delivered) = start_sink(rate_limit.clone()).await;
+                    let mut total = Duration::ZERO;
+                    for _ in 0..iters {
+                        let peers = connect_busy_peers(sink_addr).await;
+                        total += flood_and_await(&delivered, peers).await;
+                    }
+                    sink.shutdown().await.ok();
+                    total
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
+
 criterion_group! {
     name = codec_benches;
     config = Criterion::default()
@@ -339,7 +538,8 @@ criterion_group! {
         message_decoding,
         message_creation,
         payload_types,
-        send_path_serialization
+        send_path_serialization,
+        wire_encodings
 }
 
 criterion_group! {
@@ -352,4 +552,14 @@ criterion_group! {
         propagation_latency_vs_fanout
 }
 
-criterion_main!(codec_benches, dissemination_benches);
+criterion_group! {
+    name = rate_limit_benches;
+    config = Criterion::default()
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(10));
+    targets =
+        rate_limiter_contention,
+        inbound_throughput_many_peers
+}
+
+criterion_main!(codec_benches, dissemination_benches, rate_limit_benches);
@@ -0,0 +1,506 @@
+//! Canonical encoding of the signing preimage.
+//!
+//! Signatures commit to bytes, so the bytes a message is signed over must not
+//! move when the wire encoding does. This module pins them: it is a hand-written
+//! encoder for exactly the types a preimage contains, independent of any
+//! serialization library, and its output is frozen. It reproduces the layout
+//! `1.1.0` signed with (bincode 2's standard configuration), so signatures stay
+//! valid between `1.1.0` nodes and later ones.
+//!
+//! # Format
+//!
+//! - **Integers** (`u16`, `u32`, `u64`, lengths, enum tags) are variable-length:
+//!   a value below 251 is one byte; otherwise a marker byte (251, 252 or 253)
+//!   is followed by the value as a little-endian `u16`, `u32` or `u64`.
+//! - **`u8`** is one raw byte.
+//! - **`f64`** is its eight IEEE 754 bytes, little-endian.
+//! - **Byte strings** and **strings** are a length followed by the raw bytes.
+//! - **Sequences** are a length followed by each element.
+//! - **Options** are a `u8` tag (`0` none, `1` some), then the value if any.
+//! - **Tuples** are their elements in order.
+//! - **Fixed arrays** (public keys) are their raw bytes, with no length.
+//! - **Socket addresses** are a tag (`0` IPv4, `1` IPv6), the 4 or 16 address
+//!   octets, and the port. IPv6 flow info and scope id are not included.
+//! - **Payloads** are their [`Payload::kind`] as the tag, then their fields in
+//!   declaration order.
+//! - **Messages** (nested in a repair response or snapshot chunk) are the origin, sequence,
+//!   timestamp, TTL, payload, public key, and signature.
+//!
+//! A preimage is the domain tag (as a byte string), the origin, the sequence,
+//! and the payload. A stamped preimage, signed by messages that carry a hybrid
+//! logical clock stamp, adds the timestamp after the sequence. A peer
+//! advertisement's preimage is the domain tag, the advertised address, the
+//! public key, and the issue time.
+
+use std::net::SocketAddr;
+
+use crate::{Message, Payload, PeerId};
+
+/// Largest integer encoded in a single byte.
+const SINGLE_BYTE_MAX: u64 = 250;
+
+/// Marker for a `u16` follow-up.
+const U16_MARKER: u8 = 251;
+
+/// Marker for a `u32` follow-up.
+const U32_MARKER: u8 = 252;
+
+/// Marker for a `u64` follow-up.
+const U64_MARKER: u8 = 253;
+
+/// The canonical preimage a message signature commits to.
+pub(crate) fn preimage(
+    domain: &[u8],
+    origin: SocketAddr,
+    sequence: u64,
+    payload: &Payload,
+) -> Vec<u8> {
+    let mut out = Canonical::default();
+    out.bytes(domain);
+    out.addr(origin);
+    out.varint(sequence);
+    out.payload(payload);
+    out.0
+}
+
+/// The canonical preimage a stamped message's signature commits to: the
+/// [`preimage`] with the timestamp after the sequence.
+pub(crate) fn stamped_preimage(
+    domain: &[u8],
+    origin: SocketAddr,
+    sequence: u64,
+    timestamp: u64,
+    payload: &Payload,
+) -> Vec<u8> {
+    let mut out = Canonical::default();
+    out.bytes(domain);
+    out.addr(origin);
+    out.varint(sequence);
+    out.varint(timestamp);
+    out.payload(payload);
+    out.0
+}
+
+/// The canonical preimage a peer advertisement's signature commits to.
+pub(crate) fn advertisement_preimage(
+    domain: &[u8],
+    addr: SocketAddr,
+    peer_id: &PeerId,
+    issued_at: u64,
+) -> Vec<u8> {
+    let mut out = Canonical::default();
+    out.bytes(domain);
+    out.addr(addr);
+    out.0.extend_from_slice(peer_id.as_bytes());
+    out.varint(issued_at);
+    out.0
+}
+
+/// The canonical preimage a key rotation's signature commits to.
+pub(crate) fn rotation_preimage(
+    domain: &[u8],
+    old: &PeerId,
+    new: &PeerId,
+    issued_at: u64,
+) -> Vec<u8> {
+    let mut out = Canonical::default();
+    out.bytes(domain);
+    out.0.extend_from_slice(old.as_bytes());
+    out.0.extend_from_slice(new.as_bytes());
+    out.varint(issued_at);
+    out.0
+}
+
+/// The canonical preimage a key revocation's signature commits to.
+pub(crate) fn revocation_preimage(domain: &[u8], key: &PeerId, issued_at: u64) -> Vec<u8> {
+    let mut out = Canonical::default();
+    out.bytes(domain);
+    out.0.extend_from_slice(key.as_bytes());
+    out.varint(issued_at);
+    out.0
+}
+
+/// Canonical encoder state: the bytes written so far.
+#[derive(Default)]
+struct Canonical(Vec<u8>);
+
+impl Canonical {
+    fn varint(&mut self, value: u64) {
+        if value <= SINGLE_BYTE_MAX
+            && let Ok(byte) = u8::try_from(value)
+        {
+            self.0.push(byte);
+        } else if let Ok(value) = u16::try_from(value) {
+            self.0.push(U16_MARKER);
+            self.0.extend_from_slice(&value.to_le_bytes());
+        } else if let Ok(value) = u32::try_from(value) {
+            self.0.push(U32_MARKER);
+            self.0.extend_from_slice(&value.to_le_bytes());
+        } else {
+            self.0.push(U64_MARKER);
+            self.0.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn len(&mut self, len: usize) {
+        self.varint(u64::try_from(len).unwrap_or(u64::MAX));
+    }
+
+    fn float(&mut self, value: f64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.len(bytes.len());
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn addr(&mut self, addr: SocketAddr) {
+        match addr {
+            SocketAddr::V4(v4) => {
+                self.varint(0);
+                self.0.extend_from_slice(&v4.ip().octets());
+            }
+            SocketAddr::V6(v6) => {
+                self.varint(1);
+                self.0.extend_from_slice(&v6.ip().octets());
+            }
+        }
+        self.varint(u64::from(addr.port()));
+    }
+
+    fn version_vector(&mut self, entries: &[(SocketAddr, u64)]) {
+        self.len(entries.len());
+        for &(origin, sequence) in entries {
+            self.addr(origin);
+            self.varint(sequence);
+        }
+    }
+
+    fn payload(&mut self, payload: &Payload) {
+        self.varint(u64::from(payload.kind()));
+        match payload {
+            Payload::Application(data) => self.bytes(data),
+            Payload::Heartbeat { from } => self.addr(*from),
+            Payload::PeerListRequest => {}
+            Payload::PeerListResponse { peers } => {
+                self.len(peers.len());
+                for &peer in peers {
+                    self.addr(peer);
+                }
+            }
+            Payload::AntiEntropyDigest { version_vector }
+            | Payload::MessageRequest { version_vector } => self.version_vector(version_vector),
+            Payload::MessageResponse { messages } => {
+                self.len(messages.len());
+                for message in messages {
+                    self.message(message);
+                }
+            }
+            Payload::Goodbye { reason } => self.bytes(reason.as_bytes()),
+            Payload::DirectMessage { recipient, data } => {
+                self.addr(*recipient);
+                self.bytes(data);
+            }
+            Payload::CausalApplication { data, clock } => {
+                self.bytes(data);
+                self.version_vector(clock);
+            }
+            Payload::ExpiringApplication {
+                data,
+                clock,
+                expires_at,
+            } => {
+                self.bytes(data);
+                self.version_vector(clock);
+                self.varint(*expires_at);
+            }
+            Payload::Retraction { sequence } => self.varint(*sequence),
+            Payload::KvDelta { delta } => self.bytes(delta),
+            Payload::KvDigest { digest } => self.varint(*digest),
+            Payload::KvState { state, reply } => {
+                self.bytes(state);
+                self.0.push(u8::from(*reply));
+            }
+            Payload::Aggregate { epoch, shares } => {
+                self.varint(*epoch);
+                self.len(shares.len());
+                for share in shares {
+                    self.bytes(share.metric.as_bytes());
+                    for value in [share.sum, share.weight, share.min, share.max] {
+                        self.float(value);
+                    }
+                    self.len(share.count_sketch.len());
+                    for &sample in &share.count_sketch {
+                        self.float(sample);
+                    }
+                }
+            }
+            Payload::SnapshotRequest {
+                session,
+                after,
+                window,
+            } => {
+                self.varint(*session);
+                match after {
+                    Some((origin, sequence)) => {
+                        self.0.push(1);
+                        self.addr(*origin);
+                        self.varint(*sequence);
+                    }
+                    None => self.0.push(0),
+                }
+                self.varint(u64::from(*window));
+            }
+            Payload::SnapshotChunk {
+                session,
+                messages,
+                done,
+            } => {
+                self.varint(*session);
+                self.len(messages.len());
+                for message in messages {
+                    self.message(message);
+                }
+                self.0.push(u8::from(*done));
+            }
+            Payload::SnapshotAck { session } => self.varint(*session),
+            Payload::Ping { nonce } | Payload::Pong { nonce } => self.varint(*nonce),
+            Payload::ConnectionProof { challenge } => self.varint(*challenge),
+            Payload::PeerZones { zones } => {
+                self.len(zones.len());
+                for (addr, zone) in zones {
+                    self.addr(*addr);
+                    self.bytes(zone.as_bytes());
+                }
+            }
+            Payload::PeerAdvertisements { ads } => {
+                self.len(ads.len());
+                for ad in ads {
+                    self.addr(ad.addr);
+                    self.0.extend_from_slice(ad.peer_id.as_bytes());
+                    self.varint(ad.issued_at);
+                    self.bytes(ad.signature.as_bytes());
+                }
+            }
+            Payload::KeyStatements {
+                rotations,
+                revocations,
+            } => {
+                self.len(rotations.len());
+                for rotation in rotations {
+                    self.0.extend_from_slice(rotation.old.as_bytes());
+                    self.0.extend_from_slice(rotation.new.as_bytes());
+                    self.varint(rotation.issued_at);
+                    self.bytes(rotation.signature.as_bytes());
+                }
+                self.len(revocations.len());
+                for revocation in revocations {
+                    self.0.extend_from_slice(revocation.key.as_bytes());
+                    self.varint(revocation.issued_at);
+                    self.bytes(revocation.signature.as_bytes());
+                }
+            }
+            Payload::RangeDigest { held } | Payload::RangeRequest { held } => {
+                self.len(held.len());
+                for (origin, ranges) in held {
+                    self.addr(*origin);
+                    self.len(ranges.len());
+                    for &(start, end) in ranges {
+                        self.varint(start);
+                        self.varint(end);
+                    }
+                }
+            }
+        }
+    }
+
+    fn message(&mut self, message: &Message) {
+        self.addr(message.id.origin);
+        self.varint(message.id.sequence);
+        self.varint(message.id.timestamp);
+        self.0.push(message.ttl);
+        self.payload(&message.payload);
+        self.0.extend_from_slice(message.origin_key.as_bytes());
+        self.bytes(message.signature.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{AggregateShare, Identity};
+
+    const DOMAIN: &[u8] = b"grapevine.message.v1";
+
+    /// The preimage as `1.1.0` computed it.
+    fn bincode_preimage(origin: SocketAddr, sequence: u64, payload: &Payload) -> Vec<u8> {
+        #[derive(serde::Serialize)]
+        struct Preimage<'a> {
+            domain: &'static [u8],
+            origin: SocketAddr,
+            sequence: u64,
+            payload: &'a Payload,
+        }
+
+        bincode::serde::encode_to_vec(
+            Preimage {
+                domain: DOMAIN,
+                origin,
+                sequence,
+                payload,
+            },
+            bincode::config::standard(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn matches_the_layout_1_1_0_signed() {
+        let v4: SocketAddr = "10.1.2.3:8000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::7]:65535".parse().unwrap();
+        let nested = Identity::generate()
+            .author(v6, 300, Payload::Application(Bytes::from_static(b"inner")))
+            .unwrap();
+
+        let payloads = [
+            Payload::Application(Bytes::from(vec![7u8; 300])),
+            Payload::Heartbeat { from: v6 },
+            Payload::PeerListRequest,
+            Payload::PeerListResponse {
+                peers: vec![v4, v6],
+            },
+            Payload::AntiEntropyDigest {
+                version_vector: vec![(v4, 0), (v6, u64::from(u32::MAX) + 1)],
+            },
+            Payload::MessageRequest {
+                version_vector: vec![(v4, 70_000)],
+            },
+            Payload::MessageResponse {
+                messages: vec![nested.clone()],
+            },
+            Payload::Goodbye {
+                reason: "Normal shutdown".to_string(),
+            },
+            Payload::DirectMessage {
+                recipient: v4,
+                data: Bytes::from_static(b"hi"),
+            },
+            Payload::RangeDigest {
+                held: vec![(v4, vec![(0, 3), (260, 70_000)]), (v6, Vec::new())],
+            },
+            Payload::RangeRequest {
+                held: vec![(v6, vec![(5, u64::MAX)])],
+            },
+            Payload::CausalApplication {
+                data: Bytes::from_static(b"after"),
+                clock: vec![(v6, 3), (v4, 70_000)],
+            },
+            Payload::ExpiringApplication {
+                data: Bytes::from_static(b"soon"),
+                clock: vec![(v6, 1)],
+                expires_at: 1_750_000_000_000,
+            },
+            Payload::Retraction { sequence: 70_000 },
+            Payload::KvDelta {
+                delta: Bytes::from_static(b"delta"),
+            },
+            Payload::KvDigest { digest: u64::MAX },
+            Payload::KvState {
+                state: Bytes::from_static(b"state"),
+                reply: true,
+            },
+            Payload::Aggregate {
+                epoch: 70_000,
+                shares: vec![AggregateShare {
+                    metric: "load".to_string(),
+                    sum: 1.5,
+                    weight: 0.25,
+                    min: -3.0,
+                    max: f64::INFINITY,
+                    count_sketch: vec![0.125, 7.0],
+                }],
+            },
+            Payload::SnapshotRequest {
+                session: u64::MAX,
+                after: Some((v6, 70_000)),
+                window: 4,
+            },
+            Payload::SnapshotRequest {
+                session: 1,
+                after: None,
+                window: u32::MAX,
+            },
+            Payload::SnapshotChunk {
+                session: 300,
+                messages: vec![nested],
+                done: true,
+            },
+            Payload::SnapshotAck { session: 300 },
+            Payload::Ping { nonce: u64::MAX },
+            Payload::Pong { nonce: 251 },
+            Payload::PeerZones {
+                zones: vec![(v4, "eu-west-1a".to_string()), (v6, String::new())],
+            },
+            Payload::PeerAdvertisements {
+                ads: vec![
+                    Identity::generate().advertise(v4, 1_750_000_000_000),
+                    Identity::generate().advertise(v6, 0),
+                ],
+            },
+            Payload::KeyStatements {
+                rotations: vec![Identity::generate().rotate(1_750_000_000_000)],
+                revocations: vec![Identity::generate().revocation(0)],
+            },
+            Payload::ConnectionProof {
+                challenge: u64::MAX,
+            },
+        ];
+
+        for sequence in [0, 250, 251, u64::from(u16::MAX) + 1, u64::MAX] {
+            for payload in &payloads {
+                assert_eq!(
+                    preimage(DOMAIN, v4, sequence, payload),
+                    bincode_preimage(v4, sequence, payload),
+                    "sequence {sequence}, {payload:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn preimage_bytes_are_pinned() {
+        let origin: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let payload = Payload::Application(Bytes::from_static(b"hi"));
+
+        let mut expected = vec![20];
+        expected.extend_from_slice(DOMAIN);
+        expected.extend_from_slice(&[0, 127, 0, 0, 1, 251, 0x40, 0x1f]);
+        expected.extend_from_slice(&[251, 0x2c, 0x01]);
+        expected.extend_from_slice(&[0, 2, b'h', b'i']);
+
+        assert_eq!(preimage(DOMAIN, origin, 300, &payload), expected);
+    }
+
+    #[test]
+    fn stamped_preimage_bytes_are_pinned() {
+        let origin: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let payload = Payload::Application(Bytes::from_static(b"hi"));
+        let timestamp = (1 << 63) | 5;
+
+        let mut expected = vec![20];
+        expected.extend_from_slice(DOMAIN);
+        expected.extend_from_slice(&[0, 127, 0, 0, 1, 251, 0x40, 0x1f]);
+        expected.push(7);
+        expected.push(253);
+        expected.extend_from_slice(&u64::to_le_bytes(timestamp));
+        expected.extend_from_slice(&[0, 2, b'h', b'i']);
+
+        assert_eq!(
+            stamped_preimage(DOMAIN, origin, 7, timestamp, &payload),
+            expected
+        );
+    }
+}
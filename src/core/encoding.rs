@@ -0,0 +1,173 @@
+//! Wire encodings for message bodies.
+//!
+//! An [`Encoding`] turns a [`Message`] into the body of a frame and back. Two
+//! ship with the crate:
+//!
+//! - [`Bincode`]: bincode 2 with its standard configuration, the `1.1.0`
+//!   format. Every node speaks it, and it is the only encoding used towards a
+//!   peer that has not negotiated another.
+//! - [`Postcard`]: the [postcard](https://docs.rs/postcard) format, whose wire
+//!   layout is documented and stable across its 1.x releases.
+//!
+//! A node prefers one of them for outbound frames ([`WireEncoding`], set
+//! through `NodeConfig::encoding`) and decodes both. The preferred encoding is
+//! used only towards peers that advertised support for it during capability
+//! negotiation; other peers are sent bincode. Each versioned frame names its
+//! body's encoding, so a connection may carry a mix.
+//!
+//! Signatures do not depend on the encoding: they cover a canonical preimage
+//! (see [`core::identity`](crate::core::identity)), so switching encodings
+//! never invalidates one.
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::wire::Capabilities;
+use crate::{Error, Message, Result};
+
+/// A message body encoding.
+pub trait Encoding: Send + Sync {
+    /// Encode `message` as a frame body.
+    ///
+    /// # Errors
+    /// Returns an error if the message cannot be represented.
+    fn encode(&self, message: &Message) -> Result<Vec<u8>>;
+
+    /// Decode a message from the front of `bytes`, returning it and the number
+    /// of bytes it occupied. Trailing bytes are left to the caller.
+    ///
+    /// # Errors
+    /// Returns [`Error::Deserialization`] if `bytes` do not start with a valid
+    /// message.
+    fn decode(&self, bytes: &[u8]) -> Result<(Message, usize)>;
+}
+
+/// bincode 2 with its standard configuration: the `1.1.0` wire format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl Encoding for Bincode {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>> {
+        Ok(bincode::serde::encode_to_vec(
+            message,
+            bincode::config::standard(),
+        )?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<(Message, usize)> {
+        bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+            .map_err(|e| Error::Deserialization(format!("Failed to deserialize message: {e}")))
+    }
+}
+
+/// The postcard format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Postcard;
+
+impl Encoding for Postcard {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>> {
+        postcard::to_stdvec(message).map_err(|e| Error::Encoding(e.to_string()))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<(Message, usize)> {
+        let (message, rest) = postcard::take_from_bytes(bytes)
+            .map_err(|e| Error::Deserialization(format!("Failed to deserialize message: {e}")))?;
+        Ok((message, bytes.len() - rest.len()))
+    }
+}
+
+/// Selects an [`Encoding`] by name, for configuration and frame headers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireEncoding {
+    /// [`Bincode`]
+    #[default]
+    Bincode,
+    /// [`Postcard`]
+    Postcard,
+}
+
+impl WireEncoding {
+    /// Every encoding this build decodes.
+    pub const ALL: [Self; 2] = [Self::Bincode, Self::Postcard];
+
+    /// The implementation.
+    pub fn encoding(self) -> &'static dyn Encoding {
+        match self {
+            Self::Bincode => &Bincode,
+            Self::Postcard => &Postcard,
+        }
+    }
+
+    /// The code naming this encoding in a versioned frame's flags.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Self::Bincode => 0,
+            Self::Postcard => 1,
+        }
+    }
+
+    /// The encoding named by `id`, if this build knows it.
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|encoding| encoding.id() == id)
+    }
+
+    /// The capability a peer advertises to receive this encoding. Bincode
+    /// needs none: every node decodes it.
+    pub(crate) fn capability(self) -> Capabilities {
+        match self {
+            Self::Bincode => Capabilities::NONE,
+            Self::Postcard => Capabilities::POSTCARD,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{Identity, Payload, verify_message};
+
+    #[test]
+    fn every_encoding_round_trips_a_signed_message() {
+        let addr = "[2001:db8::1]:9000".parse().unwrap();
+        let message = Identity::generate()
+            .author(
+                addr,
+                300,
+                Payload::Application(Bytes::from_static(b"payload")),
+            )
+            .unwrap();
+
+        for wire in WireEncoding::ALL {
+            let mut bytes = wire.encoding().encode(&message).unwrap();
+            bytes.extend_from_slice(b"trailer");
+
+            let (decoded, consumed) = wire.encoding().decode(&bytes).unwrap();
+            assert_eq!(consumed + b"trailer".len(), bytes.len(), "{wire:?}");
+            assert_eq!(decoded.id, message.id);
+            assert!(
+                verify_message(&decoded).is_ok(),
+                "{wire:?} preserves the signature"
+            );
+        }
+    }
+
+    #[test]
+    fn encodings_differ_on_the_wire() {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        let message = Message::new(addr, 1_000, Payload::PeerListRequest);
+        assert_ne!(
+            Bincode.encode(&message).unwrap(),
+            Postcard.encode(&message).unwrap()
+        );
+    }
+
+    #[test]
+    fn ids_round_trip() {
+        for wire in WireEncoding::ALL {
+            assert_eq!(WireEncoding::from_id(wire.id()), Some(wire));
+        }
+        assert_eq!(WireEncoding::from_id(3), None);
+    }
+}
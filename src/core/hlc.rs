@@ -0,0 +1,326 @@
+//! Hybrid logical clock timestamps.
+//!
+//! A node's wall clock cannot order messages across hosts: clocks skew, and a
+//! message can appear to be received before it was sent. A hybrid logical
+//! clock (HLC) pairs the wall-clock time with a logical counter so that:
+//!
+//! - timestamps a node issues strictly increase, even if its wall clock steps
+//!   backwards;
+//! - a message is always stamped later than every message its author had
+//!   received when sending it, whatever the two hosts' clocks say; and
+//! - the physical part stays close to real time, so timestamps remain
+//!   meaningful on a timeline.
+//!
+//! With [`ClockConfig::signed`] set, every message a node authors is stamped
+//! from its [`HybridClock`]. The clock advances past the stamp of every
+//! authentic message received. With it off, messages carry raw wall-clock
+//! milliseconds outside the signature, which nothing can vouch for, so the
+//! clock observes nothing from them: the causal guarantee above then holds
+//! only for key-value register writes, whose stamps are merged whatever the
+//! setting (see [`kv`](crate::kv)). The stamp travels in [`MessageId::timestamp`](crate::MessageId::timestamp) and
+//! is covered by the message signature (see [`Identity`](crate::Identity)), so
+//! receivers can rely on it for last-writer-wins decisions.
+//!
+//! # Wire form
+//!
+//! A stamp is packed into the `u64` timestamp field: the top bit marks it as a
+//! stamp, the next 47 bits hold the physical time in milliseconds since the
+//! Unix epoch, and the low 16 bits hold the logical counter. Messages from
+//! `1.1.0` nodes carry raw wall-clock milliseconds there, which never have the
+//! top bit set, so they read as unstamped.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Top bit of the wire form: the timestamp is a hybrid logical clock stamp.
+const STAMP_MARKER: u64 = 1 << 63;
+
+/// Width of the logical counter in the wire form.
+const LOGICAL_BITS: u32 = 16;
+
+/// Largest physical time the wire form holds.
+const PHYSICAL_MAX: u64 = (1 << (63 - LOGICAL_BITS)) - 1;
+
+/// Hybrid logical clock configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClockConfig {
+    /// Stamp authored messages with a signed hybrid logical clock timestamp.
+    ///
+    /// Off by default: `1.1.0` nodes cannot verify a signature that covers
+    /// the timestamp, so enable this once none remain in the cluster.
+    /// Unstamped messages carry raw wall-clock milliseconds, as in `1.1.0`.
+    /// These are not signed, so the clock never advances on receiving one:
+    /// across hosts it orders nothing but key-value register writes until
+    /// this is set cluster-wide.
+    pub signed: bool,
+
+    /// How far ahead of the local wall clock a received stamp may be and still
+    /// advance the clock. A stamp further ahead is still delivered, but does
+    /// not drag this node's clock into the future.
+    pub max_drift: Duration,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            signed: false,
+            max_drift: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ClockConfig {
+    /// Validate configuration.
+    ///
+    /// # Errors
+    /// Returns an error message if configuration is invalid.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_drift.is_zero() {
+            return Err("clock max_drift must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A hybrid logical clock timestamp.
+///
+/// Timestamps order by physical time, then by logical counter. Two messages
+/// from different origins can carry equal timestamps; break ties by origin
+/// when a total order is needed.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct HlcTimestamp {
+    /// Milliseconds since the Unix epoch.
+    pub physical: u64,
+
+    /// Counter ordering timestamps that share a physical time.
+    pub logical: u16,
+}
+
+impl HlcTimestamp {
+    /// Read a stamp from a message's timestamp field, or `None` if the field
+    /// holds raw wall-clock milliseconds.
+    pub fn from_wire(timestamp: u64) -> Option<Self> {
+        if timestamp & STAMP_MARKER == 0 {
+            return None;
+        }
+        let timestamp = timestamp & !STAMP_MARKER;
+        Some(Self {
+            physical: timestamp >> LOGICAL_BITS,
+            logical: u16::try_from(timestamp & u64::from(u16::MAX)).unwrap_or(u16::MAX),
+        })
+    }
+
+    /// The stamp's form in a message's timestamp field.
+    pub fn to_wire(self) -> u64 {
+        STAMP_MARKER | (self.physical.min(PHYSICAL_MAX) << LOGICAL_BITS) | u64::from(self.logical)
+    }
+
+    /// The timestamp immediately after this one.
+    fn successor(self) -> Self {
+        match self.logical.checked_add(1) {
+            Some(logical) => Self {
+                physical: self.physical,
+                logical,
+            },
+            None => Self {
+                physical: self.physical + 1,
+                logical: 0,
+            },
+        }
+    }
+}
+
+impl fmt::Display for HlcTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.physical, self.logical)
+    }
+}
+
+/// A node's hybrid logical clock.
+///
+/// Lock-free: the latest issued timestamp lives in one atomic word.
+#[derive(Debug)]
+pub struct HybridClock {
+    /// The latest timestamp issued or observed, in wire form.
+    latest: AtomicU64,
+
+    /// See [`ClockConfig::max_drift`].
+    max_drift: Duration,
+}
+
+impl HybridClock {
+    /// Create a clock that ignores received stamps more than `max_drift` ahead
+    /// of the local wall clock.
+    pub fn new(max_drift: Duration) -> Self {
+        Self {
+            latest: AtomicU64::new(HlcTimestamp::default().to_wire()),
+            max_drift,
+        }
+    }
+
+    /// Issue a timestamp for a local event, later than every timestamp this
+    /// clock has issued or observed.
+    pub fn now(&self) -> HlcTimestamp {
+        let wall = wall_clock_ms();
+        self.advance(|latest| {
+            if wall > latest.physical {
+                HlcTimestamp {
+                    physical: wall,
+                    logical: 0,
+                }
+            } else {
+                latest.successor()
+            }
+        })
+    }
+
+    /// Advance past a timestamp received from a peer, returning the clock's
+    /// new reading, or `None` if `remote` is too far ahead of the local wall
+    /// clock to be trusted.
+    pub fn observe(&self, remote: HlcTimestamp) -> Option<HlcTimestamp> {
+        let wall = wall_clock_ms();
+        let max_drift = u64::try_from(self.max_drift.as_millis()).unwrap_or(u64::MAX);
+        if remote.physical > wall.saturating_add(max_drift) {
+            return None;
+        }
+        Some(self.advance(|latest| {
+            let later = latest.max(remote);
+            if wall > later.physical {
+                HlcTimestamp {
+                    physical: wall,
+                    logical: 0,
+                }
+            } else {
+                later.successor()
+            }
+        }))
+    }
+
+    /// The latest timestamp issued or observed, without advancing the clock.
+    pub fn latest(&self) -> HlcTimestamp {
+        HlcTimestamp::from_wire(self.latest.load(Ordering::Acquire)).unwrap_or_default()
+    }
+
+    fn advance(&self, next: impl Fn(HlcTimestamp) -> HlcTimestamp) -> HlcTimestamp {
+        let mut issued = HlcTimestamp::default();
+        // The closure always returns `Some`, so the update cannot fail.
+        let _ = self
+            .latest
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |latest| {
+                issued = next(HlcTimestamp::from_wire(latest).unwrap_or_default());
+                Some(issued.to_wire())
+            });
+        issued
+    }
+}
+
+/// Milliseconds since the Unix epoch by the local wall clock.
+pub(crate) fn wall_clock_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamping_is_off_by_default() {
+        // `1.1.0` nodes reject signatures covering a stamp.
+        assert!(!ClockConfig::default().signed);
+    }
+
+    #[test]
+    fn wire_form_round_trips_and_marks_stamps() {
+        let stamp = HlcTimestamp {
+            physical: 1_750_000_000_123,
+            logical: 7,
+        };
+        assert_eq!(HlcTimestamp::from_wire(stamp.to_wire()), Some(stamp));
+
+        // Raw wall-clock milliseconds, as `1.1.0` sends, are not a stamp.
+        assert_eq!(HlcTimestamp::from_wire(1_750_000_000_123), None);
+    }
+
+    #[test]
+    fn wire_form_orders_like_the_timestamps() {
+        let earlier = HlcTimestamp {
+            physical: 100,
+            logical: u16::MAX,
+        };
+        let later = HlcTimestamp {
+            physical: 101,
+            logical: 0,
+        };
+        assert!(earlier < later);
+        assert!(earlier.to_wire() < later.to_wire());
+    }
+
+    #[test]
+    fn issued_timestamps_strictly_increase() {
+        let clock = HybridClock::new(Duration::from_secs(60));
+        let mut previous = clock.now();
+        for _ in 0..10_000 {
+            let next = clock.now();
+            assert!(next > previous);
+            previous = next;
+        }
+        assert!(previous.physical >= wall_clock_ms() - 1_000);
+    }
+
+    #[test]
+    fn observing_a_later_stamp_moves_past_it() {
+        let clock = HybridClock::new(Duration::from_secs(60));
+        let remote = HlcTimestamp {
+            physical: wall_clock_ms() + 5_000,
+            logical: 3,
+        };
+
+        let observed = clock.observe(remote).unwrap();
+        assert!(observed > remote);
+        assert!(clock.now() > observed);
+    }
+
+    #[test]
+    fn observing_an_earlier_stamp_keeps_the_clock_ahead() {
+        let clock = HybridClock::new(Duration::from_secs(60));
+        let before = clock.now();
+
+        let observed = clock.observe(HlcTimestamp::default()).unwrap();
+        assert!(observed > before);
+    }
+
+    #[test]
+    fn stamps_beyond_max_drift_are_ignored() {
+        let clock = HybridClock::new(Duration::from_secs(1));
+        let remote = HlcTimestamp {
+            physical: wall_clock_ms() + 60_000,
+            logical: 0,
+        };
+
+        assert_eq!(clock.observe(remote), None);
+        assert!(clock.now() < remote);
+    }
+
+    #[test]
+    fn logical_overflow_carries_into_physical_time() {
+        let stamp = HlcTimestamp {
+            physical: 5,
+            logical: u16::MAX,
+        };
+        assert_eq!(
+            stamp.successor(),
+            HlcTimestamp {
+                physical: 6,
+                logical: 0
+            }
+        );
+    }
+}
@@ -7,15 +7,20 @@
 //! the public half. A node signs every message it authors over a
 //! domain-separated encoding of the message's *immutable* fields---the origin
 //! address, the per-origin sequence, and the payload---and embeds its public
-//! key alongside the signature. The mutable [`Message::ttl`] and the
-//! metadata-only `MessageId::timestamp` are deliberately excluded, so a
-//! signature survives the TTL decrements that forwarding applies.
+//! key alongside the signature. The mutable [`Message::ttl`] is deliberately
+//! excluded, so a signature survives the TTL decrements that forwarding
+//! applies. When the message carries a hybrid logical clock stamp (see
+//! [`MessageId::hlc`]) the signature also covers `MessageId::timestamp`, under
+//! a separate domain tag; otherwise the timestamp is unsigned metadata, as in
+//! `1.1.0`.
 //!
 //! On receipt every message is authenticated by [`authenticate`], which
 //! provides:
 //!
-//! - **Integrity.** A single bit flipped in the origin, sequence, or payload
-//!   invalidates the signature, so tampered messages are dropped.
+//! - **Integrity.** A single bit flipped in the origin, sequence, payload, or
+//!   clock stamp invalidates the signature, so tampered messages are dropped.
+//!   Stripping the stamp marker changes the preimage too, so a stamp cannot be
+//!   downgraded to unsigned metadata.
 //! - **Proof of possession.** A valid signature proves the sender holds the
 //!   private key for the embedded public key.
 //! - **Origin authenticity (trust-on-first-use).** The first time a message is
@@ -37,23 +42,49 @@
 //!   membership overlay.
 //! - **No Sybil resistance.** Identities are self-minted keypairs; nothing
 //!   binds a key to a real-world principal or limits how many a peer creates.
+//!
+//! A node also signs a [`PeerAdvertisement`] of its own listening address, so
+//! peer exchange can relay addresses that a relay cannot forge: an
+//! advertisement proves the holder of its key claimed the address at its issue
+//! time, and a node rejects one whose key differs from the key it pinned for
+//! that address.
+//!
+//! A key can be retired. [`Identity::rotate`] replaces a node's keypair and
+//! returns a [`KeyRotation`], signed by the old key, that moves the node's
+//! pins to the new one; a [`KeyRevocation`], signed by the key it revokes,
+//! makes every node reject that key. The [`PinStore`] holds both, and
+//! [`authenticate`] honors them.
 
 use std::fmt;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
-use dashmap::DashMap;
-use dashmap::mapref::entry::Entry;
 use ed25519_dalek::{Signature as Ed25519Signature, Signer, SigningKey, VerifyingKey};
 use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::{Error, Message, MessageId, Payload, Result};
+use crate::core::canonical;
+use crate::core::hlc::HybridClock;
+use crate::{Error, Message, MessageId, Payload, PinStore, Result};
 
 /// Domain-separation tag mixed into every signature preimage so a
 /// Grapevine signature can never be confused with one produced for a
 /// different protocol or future wire version.
 const SIGNING_DOMAIN: &[u8] = b"grapevine.message.v1";
 
+/// Domain-separation tag for the preimage of a message carrying a hybrid
+/// logical clock stamp, which also commits to the timestamp.
+const STAMPED_SIGNING_DOMAIN: &[u8] = b"grapevine.message.stamped.v1";
+
+/// Domain-separation tag for the preimage of a [`PeerAdvertisement`].
+const ADVERTISEMENT_DOMAIN: &[u8] = b"grapevine.advertisement.v1";
+
+/// Domain-separation tag for the preimage of a [`KeyRotation`].
+const ROTATION_DOMAIN: &[u8] = b"grapevine.rotation.v1";
+
+/// Domain-separation tag for the preimage of a [`KeyRevocation`].
+const REVOCATION_DOMAIN: &[u8] = b"grapevine.revocation.v1";
+
 /// A node's cryptographic identity: the Ed25519 public key, in compressed form.
 ///
 /// Identity is the key, not the socket address, so two nodes are the same peer iff
@@ -155,15 +186,15 @@ impl<'de> Deserialize<'de> for Signature {
 /// [`Identity::author`], which signs them, and read the node's public identity
 /// through [`Identity::peer_id`].
 pub struct Identity {
-    signing_key: SigningKey,
-    peer_id: PeerId,
+    signing_key: Mutex<SigningKey>,
+    clock: Option<Arc<HybridClock>>,
 }
 
 impl fmt::Debug for Identity {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Never render the private key.
         f.debug_struct("Identity")
-            .field("peer_id", &self.peer_id)
+            .field("peer_id", &self.peer_id())
             .finish_non_exhaustive()
     }
 }
@@ -171,28 +202,66 @@ impl fmt::Debug for Identity {
 impl Identity {
     /// Generate a fresh keypair from operating-system randomness.
     ///
-    /// The identity is stable for the lifetime of the process; persisting it
-    /// across restarts is deferred for now.
+    /// The identity lasts until [`Identity::rotate`] replaces it or the
+    /// process exits; persisting it across restarts is deferred for now.
     pub fn generate() -> Self {
-        let seed: [u8; 32] = rand::random();
-        let signing_key = SigningKey::from_bytes(&seed);
-        let peer_id = PeerId(signing_key.verifying_key().to_bytes());
         Self {
-            signing_key,
-            peer_id,
+            signing_key: Mutex::new(random_key()),
+            clock: None,
         }
     }
 
+    /// Stamp every message this identity authors from `clock`, signing the
+    /// stamp along with the message.
+    #[must_use]
+    pub fn with_clock(mut self, clock: Arc<HybridClock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
     /// This node's public identity.
     pub fn peer_id(&self) -> PeerId {
-        self.peer_id
+        peer_id(&lock(&self.signing_key))
+    }
+
+    /// Replace the keypair with a fresh one, returning a rotation statement
+    /// signed by the old key and issued at `issued_at` (milliseconds since the
+    /// Unix epoch). Messages authored from now on are signed with the new key.
+    pub fn rotate(&self, issued_at: u64) -> KeyRotation {
+        let mut signing_key = lock(&self.signing_key);
+        let replacement = random_key();
+        let old = peer_id(&signing_key);
+        let new = peer_id(&replacement);
+        let preimage = canonical::rotation_preimage(ROTATION_DOMAIN, &old, &new, issued_at);
+        let signature = Signature(signing_key.sign(&preimage).to_bytes());
+        *signing_key = replacement;
+        KeyRotation {
+            old,
+            new,
+            issued_at,
+            signature,
+        }
+    }
+
+    /// Sign a statement revoking the current key, issued at `issued_at`
+    /// (milliseconds since the Unix epoch). Keep it somewhere safe: once
+    /// published, every node rejects the key.
+    pub fn revocation(&self, issued_at: u64) -> KeyRevocation {
+        let signing_key = lock(&self.signing_key);
+        let key = peer_id(&signing_key);
+        let preimage = canonical::revocation_preimage(REVOCATION_DOMAIN, &key, issued_at);
+        KeyRevocation {
+            key,
+            issued_at,
+            signature: Signature(signing_key.sign(&preimage).to_bytes()),
+        }
     }
 
     /// Author and sign a message originated by this node, with the default TTL.
     ///
     /// # Errors
-    /// Returns [`Error::Serialization`] if the signing preimage cannot be
-    /// encoded (in practice this does not occur for well-formed payloads).
+    /// Currently infallible: the canonical signing preimage cannot fail to
+    /// encode. The `Result` is kept for API stability.
     pub fn author(&self, origin: SocketAddr, sequence: u64, payload: Payload) -> Result<Message> {
         self.author_with_ttl(origin, sequence, payload, Message::DEFAULT_TTL)
     }
@@ -200,8 +269,7 @@ impl Identity {
     /// Author and sign a message originated by this node, with an explicit TTL.
     ///
     /// # Errors
-    /// Returns [`Error::Serialization`] if the signing preimage cannot be
-    /// encoded.
+    /// Currently infallible, as for [`Identity::author`].
     pub fn author_with_ttl(
         &self,
         origin: SocketAddr,
@@ -209,42 +277,169 @@ impl Identity {
         payload: Payload,
         ttl: u8,
     ) -> Result<Message> {
-        let preimage = preimage_bytes(origin, sequence, &payload)?;
-        let signature = Signature(self.signing_key.sign(&preimage).to_bytes());
+        let id = match &self.clock {
+            Some(clock) => MessageId::stamped(origin, sequence, clock.now()),
+            None => MessageId::new(origin, sequence),
+        };
+        let preimage = preimage_bytes(&id, &payload);
+        let signing_key = lock(&self.signing_key);
+        let signature = Signature(signing_key.sign(&preimage).to_bytes());
         Ok(Message {
-            id: MessageId::new(origin, sequence),
+            id,
             ttl,
             payload,
-            origin_key: self.peer_id,
+            origin_key: peer_id(&signing_key),
             signature,
         })
     }
+
+    /// Sign an advertisement that this node listens on `addr`, issued at
+    /// `issued_at` (milliseconds since the Unix epoch).
+    pub fn advertise(&self, addr: SocketAddr, issued_at: u64) -> PeerAdvertisement {
+        let signing_key = lock(&self.signing_key);
+        let peer_id = peer_id(&signing_key);
+        let preimage =
+            canonical::advertisement_preimage(ADVERTISEMENT_DOMAIN, addr, &peer_id, issued_at);
+        PeerAdvertisement {
+            addr,
+            peer_id,
+            issued_at,
+            signature: Signature(signing_key.sign(&preimage).to_bytes()),
+        }
+    }
+}
+
+/// A node's signed claim that it listens on an address.
+///
+/// Relayed in [`Payload::PeerAdvertisements`]; see
+/// [`peer_exchange`](crate::protocol::peer_exchange) for how they are used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerAdvertisement {
+    /// The canonical (listening) address advertised.
+    pub addr: SocketAddr,
+
+    /// The advertising node's key.
+    pub peer_id: PeerId,
+
+    /// When the advertisement was signed, in milliseconds since the Unix epoch.
+    pub issued_at: u64,
+
+    /// Signature by `peer_id` over the address, key and issue time.
+    pub signature: Signature,
+}
+
+impl PeerAdvertisement {
+    /// Verify the advertisement's signature against the key it carries.
+    ///
+    /// # Errors
+    /// Returns [`Error::InvalidSignature`] if the key is invalid or the
+    /// signature does not verify.
+    pub fn verify(&self) -> Result<()> {
+        let verifying_key = VerifyingKey::from_bytes(&self.peer_id.0)
+            .map_err(|_| Error::InvalidSignature(self.addr))?;
+        let preimage = canonical::advertisement_preimage(
+            ADVERTISEMENT_DOMAIN,
+            self.addr,
+            &self.peer_id,
+            self.issued_at,
+        );
+        verifying_key
+            .verify_strict(&preimage, &Ed25519Signature::from_bytes(&self.signature.0))
+            .map_err(|_| Error::InvalidSignature(self.addr))
+    }
+}
+
+/// A statement, signed by a retiring key, that its holder now signs with
+/// another.
+///
+/// Relayed in [`Payload::KeyStatements`]; see [`PinStore`] for how nodes
+/// apply it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRotation {
+    /// The retiring key.
+    pub old: PeerId,
+
+    /// The key replacing it.
+    pub new: PeerId,
+
+    /// When the rotation was signed, in milliseconds since the Unix epoch.
+    pub issued_at: u64,
+
+    /// Signature by `old` over both keys and the issue time.
+    pub signature: Signature,
+}
+
+impl KeyRotation {
+    /// Verify the rotation's signature against the retiring key.
+    ///
+    /// # Errors
+    /// Returns [`Error::Crypto`] if the key is invalid or the signature does
+    /// not verify.
+    pub fn verify(&self) -> Result<()> {
+        let preimage =
+            canonical::rotation_preimage(ROTATION_DOMAIN, &self.old, &self.new, self.issued_at);
+        if verifies(&self.old, &preimage, &self.signature) {
+            Ok(())
+        } else {
+            Err(Error::Crypto(format!(
+                "invalid rotation of key {}",
+                self.old
+            )))
+        }
+    }
+}
+
+/// A statement, signed by a key, that the key must no longer be trusted.
+///
+/// Relayed in [`Payload::KeyStatements`]; see [`PinStore`] for how nodes
+/// apply it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRevocation {
+    /// The revoked key.
+    pub key: PeerId,
+
+    /// When the revocation was signed, in milliseconds since the Unix epoch.
+    pub issued_at: u64,
+
+    /// Signature by `key` over itself and the issue time.
+    pub signature: Signature,
+}
+
+impl KeyRevocation {
+    /// Verify the revocation's signature against the revoked key.
+    ///
+    /// # Errors
+    /// Returns [`Error::Crypto`] if the key is invalid or the signature does
+    /// not verify.
+    pub fn verify(&self) -> Result<()> {
+        let preimage = canonical::revocation_preimage(REVOCATION_DOMAIN, &self.key, self.issued_at);
+        if verifies(&self.key, &preimage, &self.signature) {
+            Ok(())
+        } else {
+            Err(Error::Crypto(format!(
+                "invalid revocation of key {}",
+                self.key
+            )))
+        }
+    }
 }
 
 /// Authenticate a received message: verify its signature, then enforce the
 /// trust-on-first-use binding between its origin address and its key.
 ///
 /// The first authentic message seen for an origin pins that origin to its key;
-/// a later message claiming the same origin under a different key is rejected.
+/// a later message claiming the same origin under a different key is rejected,
+/// unless `pins` holds a rotation from the pinned key to it. Messages signed
+/// with a revoked key are rejected whatever their origin.
 ///
 /// # Errors
-/// Returns [`Error::InvalidSignature`] if verification fails, or
+/// Returns [`Error::InvalidSignature`] if verification fails,
+/// [`Error::KeyRevoked`] if the key has been revoked, or
 /// [`Error::OriginKeyMismatch`] if the origin is already pinned to a different
 /// key.
-pub fn authenticate(message: &Message, pins: &DashMap<SocketAddr, PeerId>) -> Result<()> {
+pub fn authenticate(message: &Message, pins: &PinStore) -> Result<()> {
     verify_message(message)?;
-
-    let origin = message.id.origin;
-    match pins.entry(origin) {
-        Entry::Occupied(pinned) if *pinned.get() != message.origin_key => {
-            Err(Error::OriginKeyMismatch(origin))
-        }
-        Entry::Occupied(_) => Ok(()),
-        Entry::Vacant(slot) => {
-            slot.insert(message.origin_key);
-            Ok(())
-        }
-    }
+    pins.pin(message.id.origin, message.origin_key)
 }
 
 /// Verify a message's signature against the public key it carries.
@@ -265,7 +460,7 @@ pub fn verify_message(message: &Message) -> Result<()> {
 
     let verifying_key = VerifyingKey::from_bytes(&message.origin_key.0)
         .map_err(|_| Error::InvalidSignature(origin))?;
-    let preimage = preimage_bytes(origin, message.id.sequence, &message.payload)?;
+    let preimage = preimage_bytes(&message.id, &message.payload);
     let signature = Ed25519Signature::from_bytes(&message.signature.0);
 
     verifying_key
@@ -273,27 +468,44 @@ pub fn verify_message(message: &Message) -> Result<()> {
         .map_err(|_| Error::InvalidSignature(origin))
 }
 
+/// Whether `signature` over `preimage` verifies against `key`.
+fn verifies(key: &PeerId, preimage: &[u8], signature: &Signature) -> bool {
+    VerifyingKey::from_bytes(&key.0).is_ok_and(|verifying_key| {
+        verifying_key
+            .verify_strict(preimage, &Ed25519Signature::from_bytes(&signature.0))
+            .is_ok()
+    })
+}
+
+fn random_key() -> SigningKey {
+    let seed: [u8; 32] = rand::random();
+    SigningKey::from_bytes(&seed)
+}
+
+fn peer_id(signing_key: &SigningKey) -> PeerId {
+    PeerId(signing_key.verifying_key().to_bytes())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 /// The bytes a signature commits to: the domain tag, the origin, the sequence,
-/// and the payload.
-fn preimage_bytes(origin: SocketAddr, sequence: u64, payload: &Payload) -> Result<Vec<u8>> {
-    #[derive(Serialize)]
-    struct Preimage<'a> {
-        domain: &'static [u8],
-        origin: SocketAddr,
-        sequence: u64,
-        payload: &'a Payload,
-    }
-
-    let preimage = Preimage {
-        domain: SIGNING_DOMAIN,
-        origin,
-        sequence,
-        payload,
-    };
-    Ok(bincode::serde::encode_to_vec(
-        &preimage,
-        bincode::config::standard(),
-    )?)
+/// the timestamp if it is a clock stamp, and the payload, in the
+/// [canonical encoding](super::canonical). The preimage never depends on the
+/// wire encoding, so changing that cannot invalidate a signature.
+fn preimage_bytes(id: &MessageId, payload: &Payload) -> Vec<u8> {
+    if id.hlc().is_some() {
+        canonical::stamped_preimage(
+            STAMPED_SIGNING_DOMAIN,
+            id.origin,
+            id.sequence,
+            id.timestamp,
+            payload,
+        )
+    } else {
+        canonical::preimage(SIGNING_DOMAIN, id.origin, id.sequence, payload)
+    }
 }
 
 #[cfg(test)]
@@ -395,6 +607,46 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn clock_stamp_is_signed() {
+        let clock = Arc::new(HybridClock::new(std::time::Duration::from_secs(60)));
+        let identity = Identity::generate().with_clock(Arc::clone(&clock));
+        let message = identity
+            .author(
+                addr(8000),
+                1,
+                Payload::Application(Bytes::from_static(b"x")),
+            )
+            .unwrap();
+        assert_eq!(message.id.hlc(), Some(clock.latest()));
+        assert!(verify_message(&message).is_ok());
+
+        let mut later = message.clone();
+        later.id.timestamp += 1;
+        assert!(matches!(
+            verify_message(&later),
+            Err(Error::InvalidSignature(_))
+        ));
+
+        // Clearing the marker turns the stamp into unsigned metadata, which
+        // changes the preimage, so the stamp cannot be stripped either.
+        let mut stripped = message;
+        stripped.id.timestamp &= !(1 << 63);
+        assert_eq!(stripped.id.hlc(), None);
+        assert!(matches!(
+            verify_message(&stripped),
+            Err(Error::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn unstamped_timestamp_is_unsigned_metadata() {
+        let mut message = app(addr(8000), 1, "body");
+        assert_eq!(message.id.hlc(), None);
+        message.id.timestamp += 1;
+        assert!(verify_message(&message).is_ok());
+    }
+
     #[test]
     fn unsigned_message_is_rejected() {
         let message = Message::new(addr(8000), 0, Payload::PeerListRequest);
@@ -416,7 +668,7 @@ mod tests {
 
     #[test]
     fn authenticate_pins_first_key_and_rejects_later_changes() {
-        let pins: DashMap<SocketAddr, PeerId> = DashMap::new();
+        let pins = PinStore::new();
         let origin = addr(8000);
 
         let honest = Identity::generate();
@@ -424,7 +676,7 @@ mod tests {
             .author(origin, 0, Payload::Application(Bytes::from_static(b"one")))
             .unwrap();
         assert!(authenticate(&first, &pins).is_ok());
-        assert_eq!(pins.get(&origin).map(|k| *k), Some(honest.peer_id()));
+        assert_eq!(pins.pinned(origin), Some(honest.peer_id()));
 
         // A second authentic message from the same key is accepted.
         let second = honest
@@ -448,4 +700,70 @@ mod tests {
             Err(Error::OriginKeyMismatch(o)) if o == origin
         ));
     }
+
+    #[test]
+    fn rotation_signs_with_the_old_key_and_switches_to_the_new() {
+        let identity = Identity::generate();
+        let old = identity.peer_id();
+        let rotation = identity.rotate(1_750_000_000_000);
+
+        assert_eq!(rotation.old, old);
+        assert_eq!(rotation.new, identity.peer_id());
+        assert_ne!(rotation.old, rotation.new);
+        assert!(rotation.verify().is_ok());
+        let message = identity
+            .author(addr(8000), 0, Payload::PeerListRequest)
+            .unwrap();
+        assert_eq!(message.origin_key, rotation.new);
+
+        let redirected = KeyRotation {
+            new: Identity::generate().peer_id(),
+            ..rotation
+        };
+        let backdated = KeyRotation {
+            issued_at: 0,
+            ..rotation
+        };
+        for forged in [redirected, backdated] {
+            assert!(forged.verify().is_err(), "{forged:?}");
+        }
+    }
+
+    #[test]
+    fn revocations_cannot_be_altered() {
+        let identity = Identity::generate();
+        let revocation = identity.revocation(1_750_000_000_000);
+        assert_eq!(revocation.key, identity.peer_id());
+        assert!(revocation.verify().is_ok());
+
+        let reassigned = KeyRevocation {
+            key: Identity::generate().peer_id(),
+            ..revocation
+        };
+        assert!(reassigned.verify().is_err());
+    }
+
+    #[test]
+    fn advertisements_cannot_be_altered() {
+        let identity = Identity::generate();
+        let ad = identity.advertise(addr(8000), 1_750_000_000_000);
+        assert_eq!(ad.peer_id, identity.peer_id());
+        assert!(ad.verify().is_ok());
+
+        let moved = PeerAdvertisement {
+            addr: addr(8001),
+            ..ad
+        };
+        let refreshed = PeerAdvertisement {
+            issued_at: ad.issued_at + 1,
+            ..ad
+        };
+        let claimed = PeerAdvertisement {
+            peer_id: Identity::generate().peer_id(),
+            ..ad
+        };
+        for forged in [moved, refreshed, claimed] {
+            assert!(forged.verify().is_err(), "{forged:?}");
+        }
+    }
 }
@@ -3,12 +3,13 @@
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 
-use crate::core::identity::{PeerId, Signature};
+use crate::core::hlc::{HlcTimestamp, wall_clock_ms};
+use crate::core::identity::{KeyRevocation, KeyRotation, PeerAdvertisement, PeerId, Signature};
 
 /// Unique identifier for a message: the originating node plus that node's
 /// monotonic per-origin sequence number.
@@ -20,29 +21,44 @@ pub struct MessageId {
     /// Per-origin monotonic sequence number assigned by the originating node.
     pub sequence: u64,
 
-    /// Creation time in milliseconds since the Unix epoch.
+    /// Creation time: a signed hybrid logical clock stamp in wire form (see
+    /// [`HlcTimestamp::from_wire`]), or raw wall-clock milliseconds since the
+    /// Unix epoch for a message authored without one.
     ///
-    /// Metadata only. Identity is `(origin, sequence)`: `timestamp` is excluded
-    /// from [`PartialEq`], [`Eq`], and [`Hash`] so a node's wall clock can
+    /// Not part of identity. Identity is `(origin, sequence)`: `timestamp` is
+    /// excluded from [`PartialEq`], [`Eq`], and [`Hash`] so a node's clock can
     /// neither split one logical message into two keys nor collapse two distinct
     /// messages into one.
     pub timestamp: u64,
 }
 
 impl MessageId {
-    /// Create an identifier for a message originated by `origin` at `sequence`.
+    /// Create an identifier for a message originated by `origin` at `sequence`,
+    /// timestamped with the wall clock.
     pub fn new(origin: SocketAddr, sequence: u64) -> Self {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map(|elapsed| u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX))
-            .unwrap_or(0);
+        Self {
+            origin,
+            sequence,
+            timestamp: wall_clock_ms(),
+        }
+    }
 
+    /// Create an identifier carrying a hybrid logical clock stamp.
+    pub fn stamped(origin: SocketAddr, sequence: u64, stamp: HlcTimestamp) -> Self {
         Self {
             origin,
             sequence,
-            timestamp,
+            timestamp: stamp.to_wire(),
         }
     }
+
+    /// The hybrid logical clock stamp, if the message carries one.
+    ///
+    /// The stamp is covered by the message signature, so on an authenticated
+    /// message it is the time its origin stamped it with.
+    pub fn hlc(&self) -> Option<HlcTimestamp> {
+        HlcTimestamp::from_wire(self.timestamp)
+    }
 }
 
 impl PartialEq for MessageId {
@@ -106,6 +122,25 @@ impl Message {
         }
     }
 
+    /// The deadline after which the message is no longer delivered, forwarded,
+    /// or repaired, if it has one.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        match self.payload {
+            Payload::ExpiringApplication { expires_at, .. } => {
+                Some(UNIX_EPOCH + Duration::from_millis(expires_at))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the message's deadline has passed.
+    pub fn is_expired(&self) -> bool {
+        match self.payload {
+            Payload::ExpiringApplication { expires_at, .. } => expires_at <= wall_clock_ms(),
+            _ => false,
+        }
+    }
+
     /// Decrement TTL and check if message should be propagated.
     pub fn decrement_ttl(&mut self) -> bool {
         if self.ttl > 0 {
@@ -174,12 +209,257 @@ pub enum Payload {
         /// Message data
         data: Bytes,
     },
+
+    /// Precise anti-entropy digest: exactly which broadcasts the sender holds.
+    /// The recipient pushes back every message it holds outside these ranges.
+    RangeDigest {
+        /// For each origin, the sorted, disjoint, half-open `[start, end)`
+        /// sequence ranges the sender holds. An origin that is absent is one
+        /// the sender holds nothing from.
+        held: Vec<(SocketAddr, Vec<(u64, u64)>)>,
+    },
+
+    /// Precise pull request, answering a `RangeDigest`: asks the recipient to
+    /// push every message it holds outside the sender's ranges.
+    RangeRequest {
+        /// Same shape and meaning as `RangeDigest`'s `held`.
+        held: Vec<(SocketAddr, Vec<(u64, u64)>)>,
+    },
+
+    /// Application broadcast carrying the origin's vector clock, for causal
+    /// delivery.
+    CausalApplication {
+        /// Message data
+        data: Bytes,
+        /// For each other origin, how many of its broadcasts the origin had
+        /// delivered when it sent this one. Origins with none are omitted.
+        clock: Vec<(SocketAddr, u64)>,
+    },
+
+    /// Application broadcast with an absolute deadline, after which nodes stop
+    /// delivering, forwarding, and repairing it.
+    ExpiringApplication {
+        /// Message data
+        data: Bytes,
+        /// As for `CausalApplication`; empty unless the origin delivers
+        /// causally.
+        clock: Vec<(SocketAddr, u64)>,
+        /// Deadline, in milliseconds since the Unix epoch.
+        expires_at: u64,
+    },
+
+    /// Tombstone retracting one of the origin's earlier broadcasts.
+    Retraction {
+        /// Sequence of the retracted broadcast; its origin is this message's.
+        sequence: u64,
+    },
+
+    /// Update to the replicated key-value store ([`crate::kv`]), gossiped
+    /// like a broadcast.
+    KvDelta {
+        /// The encoded state of the updated key, as the origin changed it.
+        delta: Bytes,
+    },
+
+    /// Digest of the sender's key-value store, offered for anti-entropy.
+    KvDigest {
+        /// Hash of the sender's encoded store state.
+        digest: u64,
+    },
+
+    /// The sender's whole key-value store, for the receiver to merge.
+    KvState {
+        /// The encoded store state.
+        state: Bytes,
+        /// Whether the receiver should answer with its own state if the
+        /// sender is missing anything.
+        reply: bool,
+    },
+
+    /// Push-sum aggregation shares handed to one peer in a gossip round (see
+    /// [`crate::protocol::aggregation`]).
+    Aggregate {
+        /// Aggregation epoch the shares belong to.
+        epoch: u64,
+        /// One share per metric.
+        shares: Vec<AggregateShare>,
+    },
+
+    /// A joining node asks for a snapshot of the recipient's retained
+    /// messages (see [`crate::protocol::snapshot`]), starting a new transfer
+    /// or resuming an interrupted one.
+    SnapshotRequest {
+        /// Transfer session chosen by the joiner; chunks echo it.
+        session: u64,
+        /// Checkpoint: the `(origin, sequence)` of the last message already
+        /// received, or `None` to start from the beginning. The snapshot holds
+        /// only messages ordered after it.
+        after: Option<(SocketAddr, u64)>,
+        /// Chunks the recipient may send before waiting for an acknowledgement.
+        window: u32,
+    },
+
+    /// One chunk of a snapshot, in `(origin, sequence)` order.
+    SnapshotChunk {
+        /// Session of the request being answered.
+        session: u64,
+        /// The chunk's messages.
+        messages: Vec<Message>,
+        /// Whether this is the snapshot's last chunk.
+        done: bool,
+    },
+
+    /// The joiner has applied a snapshot chunk, letting the sender send one more.
+    SnapshotAck {
+        /// Session of the acknowledged chunk.
+        session: u64,
+    },
+
+    /// Round-trip probe, sent in place of a heartbeat to peers that
+    /// understand it; answered with a [`Payload::Pong`] echoing its nonce.
+    Ping {
+        /// Random value identifying the probe.
+        nonce: u64,
+    },
+
+    /// Answer to a [`Payload::Ping`].
+    Pong {
+        /// The nonce of the ping being answered.
+        nonce: u64,
+    },
+
+    /// Zone labels the sender knows (see [`crate::protocol::zone`]): its own,
+    /// and those of peers it has heard from.
+    PeerZones {
+        /// `(canonical address, zone label)` pairs.
+        zones: Vec<(SocketAddr, String)>,
+    },
+
+    /// Signed listening addresses (see [`crate::protocol::peer_exchange`]): the
+    /// sender's own, and those of peers it has verified.
+    PeerAdvertisements {
+        /// The advertisements, each signed by the node it names.
+        ads: Vec<PeerAdvertisement>,
+    },
+
+    /// Key rotations and revocations (see [`crate::PinStore`]), relayed to
+    /// every peer until the whole cluster knows them.
+    KeyStatements {
+        /// Rotations, each signed by the key it retires.
+        rotations: Vec<KeyRotation>,
+        /// Revocations, each signed by the key it revokes.
+        revocations: Vec<KeyRevocation>,
+    },
+
+    /// Proof that the sender holds the key it signs with, answering the
+    /// challenge in the recipient's hello on this connection (see
+    /// [`Capabilities::HANDSHAKE`](crate::Capabilities::HANDSHAKE)). Handled
+    /// by the transport; never forwarded.
+    ConnectionProof {
+        /// The challenge the recipient issued.
+        challenge: u64,
+    },
+}
+
+/// The part of one metric's aggregation state a node hands to a peer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AggregateShare {
+    /// Metric name.
+    pub metric: String,
+
+    /// Push-sum value mass.
+    pub sum: f64,
+
+    /// Push-sum weight mass.
+    pub weight: f64,
+
+    /// Smallest contribution seen this epoch.
+    pub min: f64,
+
+    /// Largest contribution seen this epoch.
+    pub max: f64,
+
+    /// Per-slot minimum of the contributors' exponential samples, from which
+    /// the number of contributors is estimated.
+    pub count_sketch: Vec<f64>,
 }
 
 impl Payload {
+    /// Number of payload kinds this build knows: [`Payload::kind`] returns a
+    /// code below it.
+    pub const KINDS: u8 = 27;
+
+    /// The payload's wire kind code, carried in versioned frame headers.
+    ///
+    /// Codes are append-only and equal the variant's declaration index (its
+    /// bincode tag), so a new variant must be declared last.
+    pub fn kind(&self) -> u8 {
+        match self {
+            Self::Application(_) => 0,
+            Self::Heartbeat { .. } => 1,
+            Self::PeerListRequest => 2,
+            Self::PeerListResponse { .. } => 3,
+            Self::AntiEntropyDigest { .. } => 4,
+            Self::MessageRequest { .. } => 5,
+            Self::MessageResponse { .. } => 6,
+            Self::Goodbye { .. } => 7,
+            Self::DirectMessage { .. } => 8,
+            Self::RangeDigest { .. } => 9,
+            Self::RangeRequest { .. } => 10,
+            Self::CausalApplication { .. } => 11,
+            Self::ExpiringApplication { .. } => 12,
+            Self::Retraction { .. } => 13,
+            Self::KvDelta { .. } => 14,
+            Self::KvDigest { .. } => 15,
+            Self::KvState { .. } => 16,
+            Self::Aggregate { .. } => 17,
+            Self::SnapshotRequest { .. } => 18,
+            Self::SnapshotChunk { .. } => 19,
+            Self::SnapshotAck { .. } => 20,
+            Self::Ping { .. } => 21,
+            Self::Pong { .. } => 22,
+            Self::PeerZones { .. } => 23,
+            Self::PeerAdvertisements { .. } => 24,
+            Self::KeyStatements { .. } => 25,
+            Self::ConnectionProof { .. } => 26,
+        }
+    }
+
     /// Check if this is a protocol message (vs application message).
     pub fn is_protocol_message(&self) -> bool {
-        !matches!(self, Self::Application(_) | Self::DirectMessage { .. })
+        !matches!(
+            self,
+            Self::Application(_)
+                | Self::CausalApplication { .. }
+                | Self::ExpiringApplication { .. }
+                | Self::Retraction { .. }
+                | Self::KvDelta { .. }
+                | Self::DirectMessage { .. }
+        )
+    }
+
+    /// Whether this payload is an application broadcast, plain, causal or
+    /// expiring.
+    pub fn is_application(&self) -> bool {
+        matches!(
+            self,
+            Self::Application(_)
+                | Self::CausalApplication { .. }
+                | Self::ExpiringApplication { .. }
+        )
+    }
+
+    /// Whether this payload is gossiped to every node: an application
+    /// broadcast, a retraction, or a key-value store update.
+    pub fn is_broadcast(&self) -> bool {
+        matches!(
+            self,
+            Self::Application(_)
+                | Self::CausalApplication { .. }
+                | Self::ExpiringApplication { .. }
+                | Self::Retraction { .. }
+                | Self::KvDelta { .. }
+        )
     }
 }
 
@@ -284,6 +564,84 @@ mod tests {
         }
     }
 
+    #[test]
+    fn kind_matches_the_bincode_variant_tag() {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        let payloads = [
+            Payload::Application(Bytes::new()),
+            Payload::Heartbeat { from: addr },
+            Payload::PeerListRequest,
+            Payload::PeerListResponse { peers: Vec::new() },
+            Payload::AntiEntropyDigest {
+                version_vector: Vec::new(),
+            },
+            Payload::MessageRequest {
+                version_vector: Vec::new(),
+            },
+            Payload::MessageResponse {
+                messages: Vec::new(),
+            },
+            Payload::Goodbye {
+                reason: String::new(),
+            },
+            Payload::DirectMessage {
+                recipient: addr,
+                data: Bytes::new(),
+            },
+            Payload::RangeDigest { held: Vec::new() },
+            Payload::RangeRequest { held: Vec::new() },
+            Payload::CausalApplication {
+                data: Bytes::new(),
+                clock: Vec::new(),
+            },
+            Payload::ExpiringApplication {
+                data: Bytes::new(),
+                clock: Vec::new(),
+                expires_at: 0,
+            },
+            Payload::Retraction { sequence: 0 },
+            Payload::KvDelta {
+                delta: Bytes::new(),
+            },
+            Payload::KvDigest { digest: 0 },
+            Payload::KvState {
+                state: Bytes::new(),
+                reply: false,
+            },
+            Payload::Aggregate {
+                epoch: 0,
+                shares: Vec::new(),
+            },
+            Payload::SnapshotRequest {
+                session: 0,
+                after: None,
+                window: 0,
+            },
+            Payload::SnapshotChunk {
+                session: 0,
+                messages: Vec::new(),
+                done: false,
+            },
+            Payload::SnapshotAck { session: 0 },
+            Payload::Ping { nonce: 0 },
+            Payload::Pong { nonce: 0 },
+            Payload::PeerZones { zones: Vec::new() },
+            Payload::PeerAdvertisements { ads: Vec::new() },
+            Payload::KeyStatements {
+                rotations: Vec::new(),
+                revocations: Vec::new(),
+            },
+            Payload::ConnectionProof { challenge: 0 },
+        ];
+        assert_eq!(payloads.len(), usize::from(Payload::KINDS));
+
+        for payload in payloads {
+            let encoded =
+                bincode::serde::encode_to_vec(&payload, bincode::config::standard()).unwrap();
+            assert_eq!(encoded[0], payload.kind(), "{payload:?}");
+        }
+    }
+
     #[test]
     fn message_carries_its_explicit_sequence() {
         let addr = "127.0.0.1:8000".parse().unwrap();
@@ -1,9 +1,61 @@
 //! Codec for framing and encoding/decoding gossip messages.
+//!
+//! # Frame layout
+//!
+//! Every frame starts with a big-endian `u32` length prefix counting the bytes
+//! after it. Its high bit selects one of two layouts:
+//!
+//! - **Plain** (high bit clear): `[length][body]`, the v1.1.0 layout and the
+//!   only one a v1.1.0 node understands.
+//! - **Versioned** (high bit set): `[length | 1 << 31][version][flags][kind]`,
+//!   followed by the sender's capabilities (big-endian `u32`) when the hello
+//!   flag is set, then the body. `version` is the protocol version the frame is
+//!   written in, `kind` is the body's [`Payload::kind`], and `flags` say
+//!   whether the body is LZ4-compressed, whether the frame carries a hello,
+//!   and which [`WireEncoding`] the body uses.
+//!
+//! The body is the encoded [`Message`]: always bincode in a plain frame, and
+//! the encoding the flags name in a versioned one.
+//!
+//! # Negotiation
+//!
+//! The first frame a codec writes on a connection carries a hello: this
+//! build's protocol version and [`Capabilities`]. In a plain frame the hello is
+//! a nine-byte trailer after the encoded message (`GVhi`, the version, the
+//! capabilities), which a v1.1.0 decoder ignores because it reads the message
+//! and discards trailing bytes. A codec writes versioned frames only once its
+//! peer has advertised version 2 or later, and compressed bodies only once the
+//! peer has advertised [`Capabilities::LZ4`]; until then it writes plain frames
+//! and withholds payloads a v1.1.0 node cannot decode. Likewise a non-bincode
+//! preferred encoding is used only once the peer has advertised support for it.
+//!
+//! A codec built [`with_handshake`](MessageCodec::with_handshake) also
+//! advertises [`Capabilities::HANDSHAKE`] and puts a random challenge (a
+//! big-endian `u64`) in its hello: after the capabilities in a plain frame's
+//! trailer, and after the capabilities in a versioned header written in version
+//! 4 or later. The peer proves its key by signing the challenge (see
+//! [`Payload::ConnectionProof`]); the transport checks the proof.
+//!
+//! A versioned frame whose kind this build does not know is skipped rather
+//! than failing the connection: its length and kind are in the header, so the
+//! decoder can step over it without parsing the body.
+//!
+//! [`Payload::kind`]: crate::Payload::kind
+
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 
 use bytes::{Buf, BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
+use tracing::debug;
 
-use crate::{Error, Message, Result};
+use crate::core::wire::{
+    Capabilities, HANDSHAKE_VERSION, LEGACY_PROTOCOL_VERSION, LOCAL_CAPABILITIES, PROTOCOL_VERSION,
+    PeerProtocol,
+};
+use crate::{Bincode, Encoding, Error, Message, Payload, Result, WireEncoding};
 
 /// Maximum message size (10 MB).
 ///
@@ -15,26 +67,377 @@ use crate::{Error, Message, Result};
 /// but this represents the absolute maximum enforced by the codec.
 pub const MAX_FRAME_SIZE: usize = 10 * 1024 * 1024; // 10 MB
 
+/// Length-prefix bit marking a versioned frame: a header follows.
+const VERSIONED_FRAME: u32 = 1 << 31;
+
+/// Versioned header length: version, flags and kind.
+const HEADER_LEN: usize = 3;
+
+/// Frame flag: the body is an LZ4 block preceded by its decompressed length
+/// (big-endian `u32`).
+const FLAG_LZ4: u8 = 0b0000_0001;
+
+/// Frame flag: the sender's capabilities follow the header.
+const FLAG_HELLO: u8 = 0b0000_0010;
+
+/// Position of the body's [`WireEncoding`] id within the frame flags.
+const ENCODING_SHIFT: u8 = 2;
+
+/// Frame flag bits holding the body's [`WireEncoding`] id.
+const ENCODING_MASK: u8 = 0b0000_1100;
+
+/// Frame flags this build understands.
+const KNOWN_FLAGS: u8 = FLAG_LZ4 | FLAG_HELLO | ENCODING_MASK;
+
+/// Width of the capabilities field.
+const CAPABILITIES_LEN: usize = 4;
+
+/// Leading bytes of the hello trailer on a plain frame.
+const HELLO_MAGIC: [u8; 4] = *b"GVhi";
+
+/// Hello trailer length: the magic, the version and the capabilities.
+const HELLO_LEN: usize = HELLO_MAGIC.len() + 1 + CAPABILITIES_LEN;
+
+/// Width of the connection challenge in a hello.
+const CHALLENGE_LEN: usize = 8;
+
+/// Width of the decompressed-length field ahead of an LZ4 block.
+const LZ4_SIZE_FIELD: usize = 4;
+
+/// The most bytes framing adds to an encoded message beyond the length prefix:
+/// the hello trailer of a plain frame with a challenge, which outweighs a
+/// versioned header with both. Callers sizing messages to fit a frame reserve
+/// this much.
+pub(crate) const FRAME_OVERHEAD: usize = HELLO_LEN + CHALLENGE_LEN;
+
+/// Outbound frame compression.
+///
+/// Compression is negotiated per connection: frames are compressed only
+/// towards peers that advertised they can decompress them, so enabling it never
+/// breaks interoperability with older nodes. Every node can *read* compressed
+/// frames; this only controls whether it *sends* them.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CompressionConfig {
+    /// Compress outbound frames to peers that support it
+    pub enabled: bool,
+
+    /// Encoded messages smaller than this many bytes are sent uncompressed
+    pub min_size: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_size: 1024,
+        }
+    }
+}
+
+/// Per-connection negotiation state, shared by the reader and writer halves.
+#[derive(Debug, Default)]
+struct Negotiation {
+    /// Whether this side has sent its hello.
+    advertised: AtomicBool,
+    /// Protocol version the peer advertised; `0` until (and unless) it does.
+    peer_version: AtomicU8,
+    /// Capabilities the peer advertised.
+    peer_capabilities: AtomicU32,
+    /// The challenge this side's hello carries, if it asks for a proof.
+    challenge: Option<u64>,
+    /// The challenge the peer's hello carried, if any.
+    peer_challenge: OnceLock<u64>,
+    /// Frames skipped because their payload kind is unknown.
+    skipped_frames: AtomicU64,
+}
+
 /// Codec for gossip messages.
 ///
-/// Uses length-prefixed framing: [4 bytes length][message bytes]
+/// Uses length-prefixed framing (see the [module docs](self) for the layout).
+///
+/// Clones share one connection's negotiation state: build a connection's
+/// reader and writer from clones of one codec, and each connection from a
+/// fresh codec.
 #[derive(Debug, Clone)]
 pub struct MessageCodec {
     max_frame_size: usize,
+    compression: CompressionConfig,
+    encoding: WireEncoding,
+    negotiation: Arc<Negotiation>,
+    last_frame_size: usize,
+}
+
+/// A parsed versioned frame.
+struct Versioned<'a> {
+    kind: u8,
+    compressed: bool,
+    encoding: WireEncoding,
+    body: &'a [u8],
 }
 
 impl MessageCodec {
     /// Create a new codec with default max frame size.
     pub fn new() -> Self {
-        Self {
-            max_frame_size: MAX_FRAME_SIZE,
-        }
+        Self::with_max_frame_size(MAX_FRAME_SIZE)
     }
 
     /// Create a new codec with custom max frame size.
+    ///
+    /// The limit bounds a message's *decoded* size, so a compressed frame
+    /// cannot expand past it.
     pub fn with_max_frame_size(max_frame_size: usize) -> Self {
-        Self { max_frame_size }
+        Self {
+            max_frame_size,
+            compression: CompressionConfig::default(),
+            encoding: WireEncoding::default(),
+            negotiation: Arc::default(),
+            last_frame_size: 0,
+        }
+    }
+
+    /// Set the outbound compression policy.
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Set the preferred encoding for outbound frames. It is used towards peers
+    /// that advertised support for it; others are sent bincode.
+    pub fn with_encoding(mut self, encoding: WireEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Advertise [`Capabilities::HANDSHAKE`] and issue a fresh random
+    /// challenge in the hello, for the peer to prove its key with.
+    ///
+    /// Call it before cloning the codec for a connection's two halves.
+    pub fn with_handshake(mut self) -> Self {
+        self.negotiation = Arc::new(Negotiation {
+            challenge: Some(rand::random()),
+            ..Negotiation::default()
+        });
+        self
+    }
+
+    /// The challenge this side's hello carries, if it was built
+    /// [`with_handshake`](Self::with_handshake).
+    pub fn challenge(&self) -> Option<u64> {
+        self.negotiation.challenge
+    }
+
+    /// The challenge the peer's hello carried, once it has arrived: the value
+    /// a [`Payload::ConnectionProof`] to the peer must answer. `None` if the
+    /// peer does not prove its key.
+    pub fn peer_challenge(&self) -> Option<u64> {
+        self.negotiation.peer_challenge.get().copied()
+    }
+
+    /// What the peer on this connection advertised, or `None` if it has not
+    /// (yet) sent a hello. A peer that never does is a v1.1.0 node.
+    pub fn peer_protocol(&self) -> Option<PeerProtocol> {
+        let version = self.negotiation.peer_version.load(Ordering::Acquire);
+        (version != 0).then(|| PeerProtocol {
+            version,
+            capabilities: Capabilities::from_bits(
+                self.negotiation.peer_capabilities.load(Ordering::Relaxed),
+            ),
+        })
+    }
+
+    /// Size on the wire, length prefix included, of the last message this
+    /// codec decoded.
+    pub fn last_frame_size(&self) -> usize {
+        self.last_frame_size
+    }
+
+    /// Number of frames skipped on this connection because their payload kind
+    /// is unknown to this build.
+    pub fn skipped_frames(&self) -> u64 {
+        self.negotiation.skipped_frames.load(Ordering::Relaxed)
+    }
+
+    fn too_large(&self, size: usize) -> Error {
+        Error::MessageTooLarge {
+            size,
+            max: self.max_frame_size,
+        }
+    }
+
+    /// The capabilities this side advertises.
+    fn capabilities(&self) -> Capabilities {
+        if self.negotiation.challenge.is_some() {
+            LOCAL_CAPABILITIES.union(Capabilities::HANDSHAKE)
+        } else {
+            LOCAL_CAPABILITIES
+        }
     }
+
+    /// Record the peer's advertised version, capabilities and challenge.
+    fn record_hello(
+        &self,
+        version: u8,
+        capabilities: [u8; CAPABILITIES_LEN],
+        challenge: Option<[u8; CHALLENGE_LEN]>,
+    ) {
+        if version == 0 {
+            return;
+        }
+        if let Some(challenge) = challenge {
+            let _ = self
+                .negotiation
+                .peer_challenge
+                .set(u64::from_be_bytes(challenge));
+        }
+        self.negotiation
+            .peer_capabilities
+            .store(u32::from_be_bytes(capabilities), Ordering::Relaxed);
+        self.negotiation
+            .peer_version
+            .store(version, Ordering::Release);
+    }
+
+    /// Record the peer's hello if `trailer` (the bytes after a plain frame's
+    /// message) is one. Longer trailers are accepted so a later version can
+    /// extend it.
+    fn record_trailer(&self, trailer: &[u8]) {
+        if trailer.len() >= HELLO_LEN && trailer[..HELLO_MAGIC.len()] == HELLO_MAGIC {
+            let version = trailer[HELLO_MAGIC.len()];
+            let mut capabilities = [0u8; CAPABILITIES_LEN];
+            capabilities.copy_from_slice(&trailer[HELLO_MAGIC.len() + 1..HELLO_LEN]);
+            let challenge = if carries_challenge(version, capabilities) {
+                trailer
+                    .get(HELLO_LEN..HELLO_LEN + CHALLENGE_LEN)
+                    .and_then(|challenge| challenge.try_into().ok())
+            } else {
+                None
+            };
+            self.record_hello(version, capabilities, challenge);
+        }
+    }
+
+    /// Parse a versioned frame's header, recording any hello it carries.
+    fn read_header<'a>(&self, frame: &'a [u8]) -> Result<Versioned<'a>> {
+        let truncated = || Error::Deserialization("truncated frame header".to_string());
+        let (header, rest) = frame.split_at_checked(HEADER_LEN).ok_or_else(truncated)?;
+        let [version, flags, kind] = [header[0], header[1], header[2]];
+
+        if !(LEGACY_PROTOCOL_VERSION + 1..=PROTOCOL_VERSION).contains(&version) {
+            return Err(Error::Deserialization(format!(
+                "unsupported protocol version {version}"
+            )));
+        }
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(Error::Deserialization(format!(
+                "unknown frame flags {flags:#04x}"
+            )));
+        }
+
+        let encoding_id = (flags & ENCODING_MASK) >> ENCODING_SHIFT;
+        let encoding = WireEncoding::from_id(encoding_id).ok_or_else(|| {
+            Error::Deserialization(format!("unknown frame encoding {encoding_id}"))
+        })?;
+
+        let mut body = rest;
+        if flags & FLAG_HELLO != 0 {
+            let (capabilities, rest) = body
+                .split_at_checked(CAPABILITIES_LEN)
+                .ok_or_else(truncated)?;
+            let mut bits = [0u8; CAPABILITIES_LEN];
+            bits.copy_from_slice(capabilities);
+            body = rest;
+            let challenge = if carries_challenge(version, bits) {
+                let (challenge, rest) =
+                    body.split_at_checked(CHALLENGE_LEN).ok_or_else(truncated)?;
+                body = rest;
+                challenge.try_into().ok()
+            } else {
+                None
+            };
+            self.record_hello(version, bits, challenge);
+        }
+        Ok(Versioned {
+            kind,
+            compressed: flags & FLAG_LZ4 != 0,
+            encoding,
+            body,
+        })
+    }
+
+    /// Decompress an LZ4 body (decompressed length, then the block).
+    fn decompress(&self, packed: &[u8]) -> Result<Vec<u8>> {
+        let (size_field, block) = packed
+            .split_at_checked(LZ4_SIZE_FIELD)
+            .ok_or_else(|| Error::Deserialization("truncated compressed frame".to_string()))?;
+        let mut size_bytes = [0u8; LZ4_SIZE_FIELD];
+        size_bytes.copy_from_slice(size_field);
+        let size = usize::try_from(u32::from_be_bytes(size_bytes))
+            .map_err(|_| self.too_large(usize::MAX))?;
+
+        // Checked before allocating: a small frame that claims a large
+        // expansion is a decompression bomb.
+        if size > self.max_frame_size {
+            return Err(self.too_large(size));
+        }
+
+        let mut body = vec![0u8; size];
+        let written = lz4_flex::block::decompress_into(block, &mut body)
+            .map_err(|e| Error::Deserialization(format!("Failed to decompress frame: {e}")))?;
+        if written != size {
+            return Err(Error::Deserialization(format!(
+                "compressed frame declared {size} B but held {written} B"
+            )));
+        }
+        Ok(body)
+    }
+
+    /// Append a frame to `dst`: the length prefix, the versioned `header` (if
+    /// any), and `parts` in order.
+    fn put_frame(&self, dst: &mut BytesMut, header: Option<&[u8]>, parts: &[&[u8]]) -> Result<()> {
+        let length = header.map_or(0, <[u8]>::len) + parts.iter().map(|p| p.len()).sum::<usize>();
+        if length > self.max_frame_size {
+            return Err(self.too_large(length));
+        }
+        let prefix = u32::try_from(length)
+            .ok()
+            .filter(|prefix| prefix & VERSIONED_FRAME == 0)
+            .ok_or_else(|| self.too_large(length))?;
+
+        dst.reserve(4 + length);
+        match header {
+            Some(header) => {
+                dst.put_u32(prefix | VERSIONED_FRAME);
+                dst.put_slice(header);
+            }
+            None => dst.put_u32(prefix),
+        }
+        for part in parts {
+            dst.put_slice(part);
+        }
+        Ok(())
+    }
+
+    /// Compress `body` if the policy and the peer allow it and it helps.
+    fn compress(&self, body: &[u8], peer: PeerProtocol) -> Option<Vec<u8>> {
+        (self.compression.enabled
+            && body.len() >= self.compression.min_size
+            && peer.capabilities.contains(Capabilities::LZ4))
+        .then(|| {
+            let mut packed = Vec::with_capacity(LZ4_SIZE_FIELD + body.len() / 2);
+            packed.extend_from_slice(&u32::try_from(body.len()).unwrap_or(u32::MAX).to_be_bytes());
+            packed.extend_from_slice(&lz4_flex::block::compress(body));
+            packed
+        })
+        .filter(|packed| packed.len() < body.len())
+    }
+}
+
+/// Whether a hello in `version` advertising `capabilities` carries a
+/// challenge.
+fn carries_challenge(version: u8, capabilities: [u8; CAPABILITIES_LEN]) -> bool {
+    version >= HANDSHAKE_VERSION
+        && Capabilities::from_bits(u32::from_be_bytes(capabilities))
+            .contains(Capabilities::HANDSHAKE)
 }
 
 impl Default for MessageCodec {
@@ -48,38 +451,63 @@ impl Decoder for MessageCodec {
     type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
-        if src.len() < 4 {
-            return Ok(None);
-        }
+        loop {
+            if src.len() < 4 {
+                return Ok(None);
+            }
+
+            let mut length_bytes = [0u8; 4];
+            length_bytes.copy_from_slice(&src[..4]);
+            let prefix = u32::from_be_bytes(length_bytes);
+            let versioned = prefix & VERSIONED_FRAME != 0;
+            let length = usize::try_from(prefix & !VERSIONED_FRAME)
+                .map_err(|_| self.too_large(usize::MAX))?;
 
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[..4]);
-        let length = usize::try_from(u32::from_be_bytes(length_bytes)).map_err(|_| {
-            Error::MessageTooLarge {
-                size: usize::MAX,
-                max: self.max_frame_size,
+            if length > self.max_frame_size {
+                return Err(self.too_large(length));
             }
-        })?;
 
-        if length > self.max_frame_size {
-            return Err(Error::MessageTooLarge {
-                size: length,
-                max: self.max_frame_size,
-            });
-        }
+            if src.len() < 4 + length {
+                src.reserve(4 + length - src.len());
+                return Ok(None);
+            }
 
-        if src.len() < 4 + length {
-            src.reserve(4 + length - src.len());
-            return Ok(None);
-        }
+            // Skip the length marker
+            src.advance(4);
 
-        // Skip the length marker
-        src.advance(4);
+            let data = src.split_to(length);
+            if !versioned {
+                let (message, consumed) = Bincode.decode(&data)?;
+                self.record_trailer(&data[consumed..]);
+                self.last_frame_size = 4 + length;
+                return Ok(Some(message));
+            }
 
-        let data = src.split_to(length);
-        bincode::serde::decode_from_slice(&data, bincode::config::standard())
-            .map(|(msg, _)| Some(msg))
-            .map_err(|e| Error::Deserialization(format!("Failed to deserialize message: {e}")))
+            let frame = self.read_header(&data)?;
+            let kind = frame.kind;
+            if kind >= Payload::KINDS {
+                self.negotiation
+                    .skipped_frames
+                    .fetch_add(1, Ordering::Relaxed);
+                debug!(kind, length, "Skipping frame with unknown payload kind");
+                continue;
+            }
+
+            let body: Cow<'_, [u8]> = if frame.compressed {
+                Cow::Owned(self.decompress(frame.body)?)
+            } else {
+                Cow::Borrowed(frame.body)
+            };
+            let (message, _) = frame.encoding.encoding().decode(&body)?;
+            if message.payload.kind() != kind {
+                return Err(Error::Deserialization(format!(
+                    "frame header names payload kind {kind} but the body holds kind {}",
+                    message.payload.kind()
+                )));
+            }
+            self.last_frame_size = 4 + length;
+            return Ok(Some(message));
+        }
     }
 }
 
@@ -87,24 +515,67 @@ impl Encoder<Message> for MessageCodec {
     type Error = Error;
 
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<()> {
-        let data = bincode::serde::encode_to_vec(&item, bincode::config::standard())?;
-
-        let length = data.len();
-        if length > self.max_frame_size {
-            return Err(Error::MessageTooLarge {
-                size: length,
-                max: self.max_frame_size,
-            });
+        let peer = self
+            .peer_protocol()
+            .filter(|peer| peer.version > LEGACY_PROTOCOL_VERSION);
+        if peer.is_none() && !PeerProtocol::LEGACY.understands(&item.payload) {
+            debug!(
+                kind = item.payload.kind(),
+                "Withholding payload the peer cannot decode"
+            );
+            return Ok(());
         }
-        let length_prefix = u32::try_from(length).map_err(|_| Error::MessageTooLarge {
-            size: length,
-            max: self.max_frame_size,
-        })?;
 
-        dst.reserve(4 + length);
-        dst.put_u32(length_prefix);
-        dst.put_slice(&data);
+        let advertise = !self.negotiation.advertised.load(Ordering::Acquire);
+        let capabilities = self.capabilities().bits().to_be_bytes();
+        let challenge = self.negotiation.challenge.map(u64::to_be_bytes);
+        let challenge = challenge
+            .as_ref()
+            .map_or(&[][..], |challenge| &challenge[..]);
 
+        match peer {
+            Some(peer) => {
+                let encoding = if peer.capabilities.contains(self.encoding.capability()) {
+                    self.encoding
+                } else {
+                    WireEncoding::Bincode
+                };
+                let body = encoding.encoding().encode(&item)?;
+                let packed = self.compress(&body, peer);
+                let mut flags = encoding.id() << ENCODING_SHIFT;
+                if packed.is_some() {
+                    flags |= FLAG_LZ4;
+                }
+                if advertise {
+                    flags |= FLAG_HELLO;
+                }
+                let version = peer.negotiated_version();
+                let mut header = vec![version, flags, item.payload.kind()];
+                if advertise {
+                    header.extend_from_slice(&capabilities);
+                    if version >= HANDSHAKE_VERSION {
+                        header.extend_from_slice(challenge);
+                    }
+                }
+                self.put_frame(dst, Some(&header), &[packed.as_deref().unwrap_or(&body)])?;
+            }
+            None if advertise => self.put_frame(
+                dst,
+                None,
+                &[
+                    &Bincode.encode(&item)?,
+                    &HELLO_MAGIC,
+                    &[PROTOCOL_VERSION],
+                    &capabilities,
+                    challenge,
+                ],
+            )?,
+            None => self.put_frame(dst, None, &[&Bincode.encode(&item)?])?,
+        }
+
+        if advertise {
+            self.negotiation.advertised.store(true, Ordering::Release);
+        }
         Ok(())
     }
 }
@@ -279,4 +750,339 @@ mod tests {
         let result = codec.decode(&mut buffer);
         assert!(matches!(result, Err(Error::MessageTooLarge { .. })));
     }
+
+    fn compressible_message(len: usize) -> Message {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        let json = "{\"key\":\"value\"},".repeat(len / 16);
+        Message::new(addr, 0, Payload::Application(Bytes::from(json)))
+    }
+
+    fn eager_compression() -> CompressionConfig {
+        CompressionConfig {
+            enabled: true,
+            min_size: 64,
+        }
+    }
+
+    /// A codec whose peer has advertised `capabilities` at our version.
+    fn negotiated(codec: MessageCodec, capabilities: Capabilities) -> MessageCodec {
+        codec.record_hello(PROTOCOL_VERSION, capabilities.bits().to_be_bytes(), None);
+        codec
+    }
+
+    /// A versioned frame built by hand.
+    fn versioned_frame(buffer: &mut BytesMut, version: u8, flags: u8, kind: u8, body: &[u8]) {
+        let length = u32::try_from(HEADER_LEN + body.len()).unwrap();
+        buffer.put_u32(length | VERSIONED_FRAME);
+        buffer.put_slice(&[version, flags, kind]);
+        buffer.put_slice(body);
+    }
+
+    #[test]
+    fn hello_trailer_is_invisible_to_legacy_decoders() {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        let message = Message::new(addr, 3, Payload::Heartbeat { from: addr });
+
+        let mut buffer = BytesMut::new();
+        MessageCodec::new()
+            .encode(message.clone(), &mut buffer)
+            .unwrap();
+
+        // A v1.1.0 decoder: strip the plain length prefix and bincode-decode,
+        // ignoring whatever follows the message.
+        let length = usize::try_from(buffer.get_u32()).unwrap();
+        assert_eq!(length, buffer.len());
+        let (decoded, consumed): (Message, _) =
+            bincode::serde::decode_from_slice(&buffer, bincode::config::standard()).unwrap();
+        assert_eq!(decoded.id, message.id);
+        assert_eq!(consumed + HELLO_LEN, length, "only the trailer follows");
+    }
+
+    #[test]
+    fn handshake_challenges_travel_in_both_hello_forms() {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        let mut ours = MessageCodec::new().with_handshake();
+        let mut theirs = MessageCodec::new().with_handshake();
+        assert!(ours.challenge().is_some());
+        assert_ne!(ours.challenge(), theirs.challenge());
+
+        // Our hello rides a plain frame's trailer...
+        let mut wire = BytesMut::new();
+        ours.encode(
+            Message::new(addr, 0, Payload::Heartbeat { from: addr }),
+            &mut wire,
+        )
+        .unwrap();
+        assert_eq!(wire[0] & 0x80, 0);
+        theirs.decode(&mut wire).unwrap().unwrap();
+        assert_eq!(theirs.peer_challenge(), ours.challenge());
+
+        // ...and theirs a versioned header.
+        theirs
+            .encode(Message::new(addr, 0, Payload::PeerListRequest), &mut wire)
+            .unwrap();
+        assert_ne!(wire[0] & 0x80, 0);
+        assert!(matches!(
+            ours.decode(&mut wire).unwrap().unwrap().payload,
+            Payload::PeerListRequest
+        ));
+        assert_eq!(ours.peer_challenge(), theirs.challenge());
+        let protocol = ours.peer_protocol().unwrap();
+        assert!(protocol.capabilities.contains(Capabilities::HANDSHAKE));
+    }
+
+    #[test]
+    fn peers_without_a_handshake_issue_no_challenge() {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        let mut ours = MessageCodec::new().with_handshake();
+        let mut theirs = MessageCodec::new();
+        assert_eq!(theirs.challenge(), None);
+
+        let mut wire = BytesMut::new();
+        theirs
+            .encode(Message::new(addr, 0, Payload::PeerListRequest), &mut wire)
+            .unwrap();
+        ours.decode(&mut wire).unwrap().unwrap();
+        assert_eq!(ours.peer_challenge(), None);
+        let protocol = ours.peer_protocol().unwrap();
+        assert!(!protocol.capabilities.contains(Capabilities::HANDSHAKE));
+    }
+
+    #[test]
+    fn compression_waits_for_the_peer_to_advertise_support() {
+        let message = compressible_message(8 * 1024);
+        let mut ours = MessageCodec::new().with_compression(eager_compression());
+        let mut theirs = MessageCodec::new();
+
+        // The peer has not spoken yet: the first frame goes out plain.
+        let mut wire = BytesMut::new();
+        ours.encode(message.clone(), &mut wire).unwrap();
+        assert_eq!(wire[0] & 0x80, 0, "no flagged frame before negotiation");
+        theirs.decode(&mut wire).unwrap().unwrap();
+
+        // The peer's first frame carries its capabilities.
+        theirs
+            .encode(
+                Message::new(message.id.origin, 0, Payload::PeerListRequest),
+                &mut wire,
+            )
+            .unwrap();
+        ours.decode(&mut wire).unwrap().unwrap();
+        assert_eq!(ours.peer_protocol(), Some(PeerProtocol::LOCAL));
+
+        let raw_len = bincode::serde::encode_to_vec(&message, bincode::config::standard())
+            .unwrap()
+            .len();
+        ours.encode(message.clone(), &mut wire).unwrap();
+        assert_ne!(wire[0] & 0x80, 0, "a negotiated frame is versioned");
+        assert_eq!(wire[5], FLAG_LZ4, "and compressed");
+        assert!(
+            wire.len() < raw_len / 4,
+            "a verbose payload compresses well"
+        );
+
+        let decoded = theirs.decode(&mut wire).unwrap().unwrap();
+        match (&decoded.payload, &message.payload) {
+            (Payload::Application(got), Payload::Application(sent)) => assert_eq!(got, sent),
+            other => panic!("expected Application payloads, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn small_frames_are_not_compressed() {
+        let mut ours = negotiated(
+            MessageCodec::new().with_compression(CompressionConfig {
+                enabled: true,
+                min_size: 4096,
+            }),
+            Capabilities::LZ4,
+        );
+
+        let mut wire = BytesMut::new();
+        ours.encode(compressible_message(1024), &mut wire).unwrap();
+        assert_eq!(
+            wire[5] & FLAG_LZ4,
+            0,
+            "below min_size the body stays uncompressed"
+        );
+    }
+
+    #[test]
+    fn decompressed_size_is_bounded_by_the_frame_limit() {
+        let mut codec = MessageCodec::with_max_frame_size(1000);
+
+        // A tiny flagged frame claiming a 1 MB expansion.
+        let block = lz4_flex::block::compress(&[0u8; 64]);
+        let mut body = (1024u32 * 1024).to_be_bytes().to_vec();
+        body.extend_from_slice(&block);
+        let mut buffer = BytesMut::new();
+        versioned_frame(&mut buffer, PROTOCOL_VERSION, FLAG_LZ4, 0, &body);
+
+        let result = codec.decode(&mut buffer);
+        assert!(matches!(
+            result,
+            Err(Error::MessageTooLarge { size, max: 1000 }) if size == 1024 * 1024
+        ));
+    }
+
+    #[test]
+    fn unknown_frame_flags_are_rejected() {
+        let mut codec = MessageCodec::new();
+        let mut buffer = BytesMut::new();
+        versioned_frame(&mut buffer, PROTOCOL_VERSION, 0x80, 0, &[0]);
+
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(Error::Deserialization(_))
+        ));
+    }
+
+    #[test]
+    fn unsupported_versions_are_rejected() {
+        for version in [0, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION + 1] {
+            let mut buffer = BytesMut::new();
+            versioned_frame(&mut buffer, version, 0, 0, &[0]);
+            assert!(
+                matches!(
+                    MessageCodec::new().decode(&mut buffer),
+                    Err(Error::Deserialization(_))
+                ),
+                "version {version}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_payload_kinds_are_skipped() {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        let known = Message::new(addr, 1, Payload::PeerListRequest);
+        let mut codec = MessageCodec::new();
+
+        // A newer peer's first frame: an unknown kind carrying its hello.
+        let mut buffer = BytesMut::new();
+        let mut hello = LOCAL_CAPABILITIES.bits().to_be_bytes().to_vec();
+        hello.extend_from_slice(b"opaque body of a future payload");
+        versioned_frame(
+            &mut buffer,
+            PROTOCOL_VERSION,
+            FLAG_HELLO,
+            Payload::KINDS,
+            &hello,
+        );
+        negotiated(MessageCodec::new(), Capabilities::NONE)
+            .encode(known.clone(), &mut buffer)
+            .unwrap();
+
+        let decoded = codec.decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(decoded.id, known.id);
+        assert_eq!(codec.skipped_frames(), 1);
+        assert_eq!(
+            codec.peer_protocol(),
+            Some(PeerProtocol::LOCAL),
+            "the hello on a skipped frame still counts"
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn mismatched_kind_is_rejected() {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        let mut buffer = BytesMut::new();
+        negotiated(MessageCodec::new(), Capabilities::NONE)
+            .encode(Message::new(addr, 0, Payload::PeerListRequest), &mut buffer)
+            .unwrap();
+        buffer[6] = Payload::Application(Bytes::new()).kind();
+
+        assert!(matches!(
+            MessageCodec::new().decode(&mut buffer),
+            Err(Error::Deserialization(_))
+        ));
+    }
+
+    #[test]
+    fn preferred_encoding_needs_the_peer_to_support_it() {
+        let message = compressible_message(512);
+        let encoding_of = |wire: &BytesMut| (wire[5] & ENCODING_MASK) >> ENCODING_SHIFT;
+
+        let mut wire = BytesMut::new();
+        negotiated(
+            MessageCodec::new().with_encoding(WireEncoding::Postcard),
+            Capabilities::NONE,
+        )
+        .encode(message.clone(), &mut wire)
+        .unwrap();
+        assert_eq!(encoding_of(&wire), WireEncoding::Bincode.id());
+
+        let mut wire = BytesMut::new();
+        negotiated(
+            MessageCodec::new().with_encoding(WireEncoding::Postcard),
+            Capabilities::POSTCARD,
+        )
+        .encode(message.clone(), &mut wire)
+        .unwrap();
+        assert_eq!(encoding_of(&wire), WireEncoding::Postcard.id());
+
+        let decoded = MessageCodec::new().decode(&mut wire).unwrap().unwrap();
+        assert_eq!(decoded.id, message.id);
+        assert!(wire.is_empty());
+    }
+
+    #[test]
+    fn postcard_frames_can_be_compressed() {
+        let message = compressible_message(8 * 1024);
+        let mut ours = negotiated(
+            MessageCodec::new()
+                .with_encoding(WireEncoding::Postcard)
+                .with_compression(eager_compression()),
+            LOCAL_CAPABILITIES,
+        );
+
+        let mut wire = BytesMut::new();
+        ours.encode(message.clone(), &mut wire).unwrap();
+        assert_ne!(wire[5] & FLAG_LZ4, 0);
+
+        let decoded = MessageCodec::new().decode(&mut wire).unwrap().unwrap();
+        assert_eq!(decoded.id, message.id);
+    }
+
+    #[test]
+    fn unknown_encodings_are_rejected() {
+        let mut buffer = BytesMut::new();
+        versioned_frame(
+            &mut buffer,
+            PROTOCOL_VERSION,
+            ENCODING_MASK,
+            Payload::PeerListRequest.kind(),
+            &[0],
+        );
+        assert!(matches!(
+            MessageCodec::new().decode(&mut buffer),
+            Err(Error::Deserialization(_))
+        ));
+    }
+
+    #[test]
+    fn legacy_peers_keep_receiving_plain_frames() {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        let mut ours = MessageCodec::new()
+            .with_compression(eager_compression())
+            .with_encoding(WireEncoding::Postcard);
+
+        let mut wire = BytesMut::new();
+        for _ in 0..2 {
+            ours.encode(compressible_message(8 * 1024), &mut wire)
+                .unwrap();
+            ours.encode(Message::new(addr, 0, Payload::PeerListRequest), &mut wire)
+                .unwrap();
+        }
+        assert_eq!(ours.peer_protocol(), None);
+
+        // Every frame parses with the v1.1.0 layout.
+        while wire.has_remaining() {
+            let length = usize::try_from(wire.get_u32()).unwrap();
+            let frame = wire.split_to(length);
+            let _: (Message, _) =
+                bincode::serde::decode_from_slice(&frame, bincode::config::standard()).unwrap();
+        }
+    }
 }
@@ -1,13 +1,28 @@
 //! Core types for Grapevine protocol.
 
+mod canonical;
+pub mod encoding;
+pub mod hlc;
 pub mod identity;
 pub mod message;
 pub mod message_codec;
 pub mod peer;
+pub mod pins;
 pub mod rate_limiter;
+pub mod wire;
 
-pub use identity::{Identity, PeerId, Signature, authenticate, verify_message};
-pub use message::{Message, MessageId, Payload};
-pub use message_codec::MessageCodec;
+pub use encoding::{Bincode, Encoding, Postcard, WireEncoding};
+pub use hlc::{ClockConfig, HlcTimestamp, HybridClock};
+pub use identity::{
+    Identity, KeyRevocation, KeyRotation, PeerAdvertisement, PeerId, Signature, authenticate,
+    verify_message,
+};
+pub use message::{AggregateShare, Message, MessageId, Payload};
+pub use message_codec::{CompressionConfig, MessageCodec};
 pub use peer::{Peer, PeerInfo, PeerState};
-pub use rate_limiter::{RateLimitConfig, RateLimiter};
+pub use pins::PinStore;
+pub use rate_limiter::{
+    Budget, BudgetExceeded, ClassBudgets, ConnectionLimiter, RateLimitConfig, RateLimiter,
+    TrafficClass,
+};
+pub use wire::{Capabilities, PeerProtocol};
@@ -1,13 +1,11 @@
 //! Peer management types.
 
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use tokio::sync::mpsc::Sender;
-use tokio::sync::mpsc::error::TrySendError;
-use tracing::debug;
-
-use crate::{Error, Message, Result};
+use crate::transport::OutboundQueue;
+use crate::{PeerId, PeerProtocol};
 
 /// Age bonus divisor for health score calculation (seconds).
 const AGE_BONUS_DIVISOR: f64 = 300.0;
@@ -24,6 +22,10 @@ const MAX_CONSECUTIVE_PENALTY: f64 = 0.5;
 /// Maximum consecutive failures before peer disconnection.
 const MAX_CONSECUTIVE_FAILURES: u64 = 5;
 
+/// Weight, in eighths, the smoothed round-trip time keeps on each new sample
+/// (the RFC 6298 gain of 1/8).
+const RTT_SMOOTHING_EIGHTHS: u32 = 7;
+
 /// State of a peer connection.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PeerState {
@@ -63,6 +65,25 @@ pub struct PeerInfo {
 
     /// Consecutive failures (reset on success)
     pub consecutive_failures: u64,
+
+    /// Protocol the peer advertised; `None` until its hello arrives, and for
+    /// good if it is a v1.1.0 node
+    pub protocol: Option<PeerProtocol>,
+
+    /// Smoothed round-trip time measured by ping and pong; `None` until the
+    /// first sample
+    pub rtt: Option<Duration>,
+
+    /// Zone label the peer announced, or that peer exchange reported for it;
+    /// `None` until one is known
+    pub zone: Option<String>,
+
+    /// Whether the peer connected to this node, rather than this node to it
+    pub inbound: bool,
+
+    /// The key the peer proved it holds at handshake; `None` until its proof
+    /// arrives, or if it proves none
+    pub peer_id: Option<PeerId>,
 }
 
 impl PeerInfo {
@@ -78,6 +99,11 @@ impl PeerInfo {
             messages_sent: 0,
             message_failures: 0,
             consecutive_failures: 0,
+            protocol: None,
+            rtt: None,
+            zone: None,
+            inbound: false,
+            peer_id: None,
         }
     }
 
@@ -127,6 +153,18 @@ impl PeerInfo {
         self.consecutive_failures = self.consecutive_failures.saturating_add(1);
     }
 
+    /// Fold a round-trip time sample into the smoothed RTT.
+    pub fn record_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => {
+                rtt.saturating_mul(RTT_SMOOTHING_EIGHTHS)
+                    .saturating_add(sample)
+                    / 8
+            }
+            None => sample,
+        });
+    }
+
     /// Calculate health score (0.0 = poor, 1.0 = excellent).
     pub fn health_score(&self) -> f64 {
         let total_attempts = self.messages_sent.saturating_add(self.message_failures);
@@ -161,38 +199,24 @@ impl PeerInfo {
 pub struct Peer {
     /// Peer information
     pub info: PeerInfo,
-    /// Bounded channel to this peer's writer task.
-    sender: Sender<Message>,
+    /// Bounded queue to this peer's writer task, closed when the peer is
+    /// dropped.
+    queue: Arc<OutboundQueue>,
 }
 
 impl Peer {
-    /// Create a new peer for the connection at `addr`.
-    pub fn new(addr: SocketAddr, sender: Sender<Message>) -> Self {
+    /// Create a new peer for the connection at `addr`, whose writer task
+    /// drains `queue`.
+    pub fn new(addr: SocketAddr, queue: Arc<OutboundQueue>) -> Self {
         Self {
             info: PeerInfo::new(addr),
-            sender,
+            queue,
         }
     }
 
-    /// Queue a message for this peer's writer task.
-    pub fn send(&mut self, message: Message) -> Result<()> {
-        match self.sender.try_send(message) {
-            Ok(()) => {
-                self.info.increment_sent();
-                Ok(())
-            }
-            Err(TrySendError::Full(_)) => {
-                debug!(
-                    "Write channel full for {}, dropping message",
-                    self.info.addr
-                );
-                Ok(())
-            }
-            Err(TrySendError::Closed(_)) => {
-                self.info.record_failure();
-                Err(Error::Channel("peer write channel closed".to_string()))
-            }
-        }
+    /// The queue of messages for this peer's writer task.
+    pub fn queue(&self) -> &Arc<OutboundQueue> {
+        &self.queue
     }
 
     /// Get the peer's connection address.
@@ -206,12 +230,18 @@ impl Peer {
     }
 }
 
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use bytes::Bytes;
 
     use super::*;
-    use crate::Payload;
+    use crate::{Message, OutboundConfig, Payload};
 
     fn message(seq: u64) -> Message {
         let addr = "127.0.0.1:8000".parse().unwrap();
@@ -256,72 +286,32 @@ mod tests {
     }
 
     #[test]
-    fn peer_send_success() {
-        let addr = "127.0.0.1:8000".parse().unwrap();
-        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
-
-        let mut peer = Peer::new(addr, tx);
-        let msg = message(1);
-
-        assert_eq!(peer.info.messages_sent, 0);
-        assert!(peer.send(msg.clone()).is_ok());
-        assert_eq!(peer.info.messages_sent, 1);
-
-        let received = rx.try_recv().expect("message was queued");
-        assert_eq!(received.id, msg.id);
-    }
-
-    #[test]
-    fn peer_send_failure_channel_closed() {
+    fn rtt_is_smoothed_from_the_first_sample() {
         let addr = "127.0.0.1:8000".parse().unwrap();
-        let (tx, rx) = tokio::sync::mpsc::channel::<Message>(16);
+        let mut info = PeerInfo::new(addr);
+        assert_eq!(info.rtt, None);
 
-        // Drop receiver to close channel
-        drop(rx);
+        info.record_rtt(Duration::from_millis(80));
+        assert_eq!(info.rtt, Some(Duration::from_millis(80)));
 
-        let mut peer = Peer::new(addr, tx);
+        info.record_rtt(Duration::from_millis(160));
+        assert_eq!(info.rtt, Some(Duration::from_millis(90)));
 
-        let result = peer.send(message(1));
-        assert!(result.is_err());
-        assert!(matches!(result.unwrap_err(), Error::Channel(_)));
+        info.record_rtt(Duration::MAX);
+        assert!(info.rtt.is_some());
     }
 
-    #[test]
-    fn peer_multiple_sends() {
+    #[tokio::test]
+    async fn dropping_a_peer_closes_its_queue() {
         let addr = "127.0.0.1:8000".parse().unwrap();
-        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
-
-        let mut peer = Peer::new(addr, tx);
-
-        for i in 1..=5 {
-            assert!(peer.send(message(i)).is_ok());
-            assert_eq!(peer.info.messages_sent, i);
+        let queue = Arc::new(OutboundQueue::new(&OutboundConfig::default()));
+        let peer = Peer::new(addr, Arc::clone(&queue));
 
-            let received = rx.try_recv().unwrap();
-            assert_eq!(received.id.sequence, i);
-        }
-    }
+        queue.push(message(1)).await.expect("queue is open");
+        drop(peer);
 
-    #[test]
-    fn send_drops_newest_when_channel_full() {
-        let addr = "127.0.0.1:8000".parse().unwrap();
-        // Capacity 1, receiver never drains: the second send overflows.
-        let (tx, _rx) = tokio::sync::mpsc::channel::<Message>(1);
-
-        let mut peer = Peer::new(addr, tx);
-
-        assert!(peer.send(message(1)).is_ok());
-        assert_eq!(peer.info.messages_sent, 1);
-
-        // Full channel: dropped, still Ok, and not counted as a send.
-        assert!(peer.send(message(2)).is_ok());
-        assert_eq!(
-            peer.info.messages_sent, 1,
-            "a dropped frame is not counted as sent"
-        );
-        assert_eq!(
-            peer.info.consecutive_failures, 0,
-            "backpressure is not a peer failure"
-        );
+        assert!(queue.push(message(2)).await.is_err());
+        assert_eq!(queue.pop().await.map(|m| m.id.sequence), Some(1));
+        assert!(queue.pop().await.is_none());
     }
 }
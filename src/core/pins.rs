@@ -0,0 +1,460 @@
+//! Origin key pins, and the rotations and revocations that amend them.
+//!
+//! [`authenticate`](crate::authenticate) pins each origin address to the key
+//! of the first authentic message seen from it. A pin would otherwise hold for
+//! as long as the node runs, so a node that replaced its key could no longer
+//! be heard, and a stolen key could be used for as long as its thief liked.
+//! The pin store therefore also holds the signed statements that retire keys:
+//!
+//! - A [`KeyRotation`], signed by the old key, moves every pin on the old key
+//!   to the new one. The old key is retired: messages signed with it are
+//!   rejected from then on, so send no more once it is rotated. A key rotates
+//!   once: a second rotation of the same key, say by a thief racing its owner,
+//!   is ignored.
+//! - A [`KeyRevocation`], signed by the key it revokes, makes every message
+//!   signed with that key fail, whatever its origin. It also drops the key's
+//!   rotation, since whoever signed that may have stolen the key, and the pins
+//!   on the key and on the keys it was rotated to, so the origins are pinned
+//!   afresh on first use.
+//!
+//! Both kinds of statement verify on their own, so any node may relay them;
+//! the gossip engine floods them in [`Payload::KeyStatements`].
+//!
+//! Each statement is signed by the key it retires, so a key can make at most
+//! one of each kind, and anyone can make any number by generating keys. The
+//! store therefore keeps for as long as the node runs only the statements
+//! that matter to it: those about keys its pins lead to, and those it was
+//! handed through [`PinStore::hold_rotation`] and [`PinStore::hold_revocation`]
+//! rather than received. Statements about other keys may still matter later,
+//! say a stolen key revoked before its thief reaches this node, so the latest
+//! [`MAX_STATEMENTS`] of each kind are kept too, the oldest making room for
+//! newer ones. No statement is refused for want of room.
+//!
+//! [`Payload::KeyStatements`]: crate::Payload::KeyStatements
+
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+
+use crate::{Error, KeyRevocation, KeyRotation, PeerId, Result};
+
+/// Rotations, and revocations, held at most about keys no pin leads to;
+/// beyond this, the oldest are dropped.
+pub const MAX_STATEMENTS: usize = 4_096;
+
+/// Longest chain of rotations followed from a pinned key.
+const MAX_ROTATION_CHAIN: usize = 16;
+
+/// Origin key pins, with the key rotations and revocations that amend them.
+#[derive(Debug, Default)]
+pub struct PinStore {
+    pins: DashMap<SocketAddr, PeerId>,
+    rotations: DashMap<PeerId, KeyRotation>,
+    revocations: DashMap<PeerId, KeyRevocation>,
+    unpinned: Mutex<Unpinned>,
+}
+
+/// The keys of the statements that may be dropped, oldest first.
+#[derive(Debug, Default)]
+struct Unpinned {
+    rotations: VecDeque<PeerId>,
+    revocations: VecDeque<PeerId>,
+}
+
+impl PinStore {
+    /// An empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The key `origin` is pinned to, if any.
+    pub fn pinned(&self, origin: SocketAddr) -> Option<PeerId> {
+        self.pins.get(&origin).map(|pinned| *pinned)
+    }
+
+    /// Whether `old` was rotated to `new`, directly or through later
+    /// rotations.
+    pub fn is_rotated_to(&self, old: PeerId, new: PeerId) -> bool {
+        self.successors(old).contains(&new)
+    }
+
+    /// Whether `key` has been revoked.
+    pub fn is_revoked(&self, key: PeerId) -> bool {
+        self.revocations.contains_key(&key)
+    }
+
+    /// Accept `key` for `origin`: pin the origin to it if it is not pinned, or
+    /// move its pin to it if the pinned key was rotated to it.
+    ///
+    /// # Errors
+    /// Returns [`Error::KeyRevoked`] if `key` has been revoked, or
+    /// [`Error::OriginKeyMismatch`] if `key` has been rotated, or `origin` is
+    /// pinned to another key that was not rotated to `key`.
+    pub fn pin(&self, origin: SocketAddr, key: PeerId) -> Result<()> {
+        if self.is_revoked(key) {
+            return Err(Error::KeyRevoked(origin));
+        }
+        if self.rotations.contains_key(&key) {
+            return Err(Error::OriginKeyMismatch(origin));
+        }
+        match self.pins.entry(origin) {
+            Entry::Occupied(mut pinned) => {
+                let current = *pinned.get();
+                if current == key {
+                    return Ok(());
+                }
+                if !self.successors(current).contains(&key) {
+                    return Err(Error::OriginKeyMismatch(origin));
+                }
+                pinned.insert(key);
+                Ok(())
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(key);
+                Ok(())
+            }
+        }
+    }
+
+    /// Record a received `rotation`, moving the pins on its old key to its
+    /// new one. Returns whether it was new; a rotation of a key that is
+    /// revoked or already rotated is ignored.
+    ///
+    /// # Errors
+    /// Returns [`Error::Crypto`] if its signature does not verify.
+    pub fn rotate(&self, rotation: &KeyRotation) -> Result<bool> {
+        self.record_rotation(rotation, false)
+    }
+
+    /// Like [`PinStore::rotate`], for a rotation this node issued: it is
+    /// kept whether or not a pin leads to its key.
+    ///
+    /// # Errors
+    /// Returns [`Error::Crypto`] if its signature does not verify.
+    pub fn hold_rotation(&self, rotation: &KeyRotation) -> Result<bool> {
+        self.record_rotation(rotation, true)
+    }
+
+    /// Record a received `revocation`, dropping the revoked key's rotation and
+    /// the pins it led to. Returns whether it was new.
+    ///
+    /// # Errors
+    /// Returns [`Error::Crypto`] if its signature does not verify.
+    pub fn revoke(&self, revocation: &KeyRevocation) -> Result<bool> {
+        self.record_revocation(revocation, false)
+    }
+
+    /// Like [`PinStore::revoke`], for a revocation this node issued or was
+    /// handed by its operator: it is kept whether or not a pin leads to its
+    /// key, even if it was already held.
+    ///
+    /// # Errors
+    /// Returns [`Error::Crypto`] if its signature does not verify.
+    pub fn hold_revocation(&self, revocation: &KeyRevocation) -> Result<bool> {
+        self.record_revocation(revocation, true)
+    }
+
+    fn record_rotation(&self, rotation: &KeyRotation, hold: bool) -> Result<bool> {
+        rotation.verify()?;
+        if rotation.old == rotation.new
+            || self.is_revoked(rotation.old)
+            || self.is_revoked(rotation.new)
+        {
+            return Ok(false);
+        }
+        match self.rotations.entry(rotation.old) {
+            Entry::Occupied(held) => {
+                let same = held.get() == rotation;
+                drop(held);
+                if hold && same {
+                    lock(&self.unpinned)
+                        .rotations
+                        .retain(|key| *key != rotation.old);
+                }
+                return Ok(false);
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(*rotation);
+            }
+        }
+        let latest = self.latest(rotation.new);
+        for mut pinned in self.pins.iter_mut() {
+            if *pinned == rotation.old {
+                *pinned = latest;
+            }
+        }
+        if !hold && !self.leads_to_pin(rotation.old) {
+            let mut unpinned = lock(&self.unpinned);
+            unpinned.rotations.push_back(rotation.old);
+            self.evict_oldest(&mut unpinned.rotations, &self.rotations);
+        }
+        Ok(true)
+    }
+
+    fn record_revocation(&self, revocation: &KeyRevocation, hold: bool) -> Result<bool> {
+        revocation.verify()?;
+        if self.revocations.contains_key(&revocation.key) {
+            if hold {
+                lock(&self.unpinned)
+                    .revocations
+                    .retain(|key| *key != revocation.key);
+            }
+            return Ok(false);
+        }
+        // Whether to keep it goes by the pins it is about to drop.
+        let pinned = hold || self.leads_to_pin(revocation.key);
+        match self.revocations.entry(revocation.key) {
+            Entry::Occupied(_) => return Ok(false),
+            Entry::Vacant(slot) => {
+                slot.insert(*revocation);
+            }
+        }
+        let mut retired: HashSet<PeerId> = self.successors(revocation.key).into_iter().collect();
+        retired.insert(revocation.key);
+        self.rotations.remove(&revocation.key);
+        self.pins.retain(|_, pinned| !retired.contains(pinned));
+        let mut unpinned = lock(&self.unpinned);
+        unpinned.rotations.retain(|key| *key != revocation.key);
+        if !pinned {
+            unpinned.revocations.push_back(revocation.key);
+            self.evict_oldest(&mut unpinned.revocations, &self.revocations);
+        }
+        Ok(true)
+    }
+
+    /// Drop the oldest of the `statements` in `order` until at most
+    /// [`MAX_STATEMENTS`] remain. One whose key a pin has come to lead to
+    /// since it arrived is kept, and no longer counted.
+    fn evict_oldest<T>(&self, order: &mut VecDeque<PeerId>, statements: &DashMap<PeerId, T>) {
+        while order.len() > MAX_STATEMENTS {
+            let Some(oldest) = order.pop_front() else {
+                break;
+            };
+            if !self.leads_to_pin(oldest) {
+                statements.remove(&oldest);
+            }
+        }
+    }
+
+    /// Whether some origin is pinned to `key` or a key it was rotated to.
+    fn leads_to_pin(&self, key: PeerId) -> bool {
+        let mut keys = self.successors(key);
+        keys.push(key);
+        self.pins.iter().any(|pinned| keys.contains(pinned.value()))
+    }
+
+    /// The rotations held.
+    pub fn rotations(&self) -> Vec<KeyRotation> {
+        self.rotations.iter().map(|entry| *entry.value()).collect()
+    }
+
+    /// The revocations held.
+    pub fn revocations(&self) -> Vec<KeyRevocation> {
+        self.revocations
+            .iter()
+            .map(|entry| *entry.value())
+            .collect()
+    }
+
+    /// The keys `key` was rotated to, in order, up to the chain limit.
+    fn successors(&self, key: PeerId) -> Vec<PeerId> {
+        let mut chain = Vec::new();
+        let mut current = key;
+        while chain.len() < MAX_ROTATION_CHAIN {
+            let Some(next) = self.rotations.get(&current).map(|rotation| rotation.new) else {
+                break;
+            };
+            if next == key || chain.contains(&next) {
+                break;
+            }
+            chain.push(next);
+            current = next;
+        }
+        chain
+    }
+
+    /// The last key `key` was rotated to, or `key` itself.
+    fn latest(&self, key: PeerId) -> PeerId {
+        self.successors(key).last().copied().unwrap_or(key)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Identity;
+
+    const NOW: u64 = 1_750_000_000_000;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn rotation_moves_the_pin_to_the_new_key() {
+        let pins = PinStore::new();
+        let identity = Identity::generate();
+        let old = identity.peer_id();
+        pins.pin(addr(1), old).unwrap();
+
+        let rotation = identity.rotate(NOW);
+        assert!(pins.rotate(&rotation).unwrap());
+        assert!(!pins.rotate(&rotation).unwrap());
+        assert_eq!(pins.pinned(addr(1)), Some(rotation.new));
+        assert!(matches!(
+            pins.pin(addr(1), old),
+            Err(Error::OriginKeyMismatch(_))
+        ));
+        assert!(pins.pin(addr(1), rotation.new).is_ok());
+    }
+
+    #[test]
+    fn pins_follow_chains_of_rotations_in_any_order() {
+        let identity = Identity::generate();
+        let old = identity.peer_id();
+        let first = identity.rotate(NOW);
+        let second = identity.rotate(NOW + 1);
+
+        let pins = PinStore::new();
+        pins.pin(addr(1), old).unwrap();
+        pins.rotate(&second).unwrap();
+        pins.rotate(&first).unwrap();
+        assert_eq!(pins.pinned(addr(1)), Some(second.new));
+
+        // A pin on the middle key, made before either rotation was known,
+        // follows the chain when the latest key arrives.
+        let late = PinStore::new();
+        late.pin(addr(1), first.new).unwrap();
+        late.rotate(&first).unwrap();
+        late.rotate(&second).unwrap();
+        late.pins.insert(addr(2), first.new);
+        assert!(late.pin(addr(2), second.new).is_ok());
+        assert!(matches!(
+            late.pin(addr(3), old),
+            Err(Error::OriginKeyMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn revoked_keys_are_rejected_and_their_pins_dropped() {
+        let pins = PinStore::new();
+        let identity = Identity::generate();
+        let old = identity.peer_id();
+        let revocation = identity.revocation(NOW);
+        let rotation = identity.rotate(NOW);
+        pins.pin(addr(1), old).unwrap();
+        pins.rotate(&rotation).unwrap();
+        pins.pin(addr(2), Identity::generate().peer_id()).unwrap();
+
+        assert!(pins.revoke(&revocation).unwrap());
+        assert!(!pins.revoke(&revocation).unwrap());
+        assert!(matches!(pins.pin(addr(3), old), Err(Error::KeyRevoked(_))));
+        // The rotation is dropped with the key, and the origin it led to is
+        // unpinned; others keep their pins.
+        assert!(pins.rotations().is_empty());
+        assert_eq!(pins.pinned(addr(1)), None);
+        assert!(pins.pinned(addr(2)).is_some());
+        assert!(!pins.rotate(&rotation).unwrap());
+    }
+
+    /// Fill the room for statements about unpinned keys, as a flood of them
+    /// would, without signing thousands.
+    fn flood(pins: &PinStore) {
+        let identity = Identity::generate();
+        let rotation = identity.rotate(NOW);
+        let revocation = identity.revocation(NOW);
+        let mut unpinned = lock(&pins.unpinned);
+        for i in 0..MAX_STATEMENTS {
+            let mut key = [0xff; 32];
+            key[..8].copy_from_slice(&i.to_le_bytes());
+            let key = PeerId(key);
+            pins.rotations.insert(
+                key,
+                KeyRotation {
+                    old: key,
+                    ..rotation
+                },
+            );
+            pins.revocations
+                .insert(key, KeyRevocation { key, ..revocation });
+            unpinned.rotations.push_back(key);
+            unpinned.revocations.push_back(key);
+        }
+    }
+
+    #[test]
+    fn unpinned_statements_make_room_for_newer_ones() {
+        let pins = PinStore::new();
+        let pinned = Identity::generate();
+        pins.pin(addr(1), pinned.peer_id()).unwrap();
+        let pinned = pinned.revocation(NOW);
+        let held = Identity::generate().revocation(NOW);
+        let oldest = Identity::generate().revocation(NOW);
+        let rotation = Identity::generate().rotate(NOW);
+        assert!(pins.revoke(&pinned).unwrap());
+        assert!(pins.hold_revocation(&held).unwrap());
+        assert!(pins.revoke(&oldest).unwrap());
+        assert!(pins.rotate(&rotation).unwrap());
+
+        // Statements about fresh keys are still taken once there is no room,
+        // pushing out the oldest about unpinned keys.
+        flood(&pins);
+        let newest = Identity::generate();
+        assert!(pins.rotate(&newest.rotate(NOW)).unwrap());
+        assert!(pins.revoke(&newest.revocation(NOW)).unwrap());
+
+        assert!(pins.is_revoked(newest.peer_id()));
+        assert!(pins.is_revoked(pinned.key));
+        assert!(pins.is_revoked(held.key));
+        assert!(!pins.is_revoked(oldest.key));
+        assert_eq!(pins.revocations().len(), MAX_STATEMENTS + 2);
+        assert!(!pins.rotations().contains(&rotation));
+        assert_eq!(pins.rotations().len(), MAX_STATEMENTS);
+    }
+
+    #[test]
+    fn statements_about_keys_pinned_since_are_kept() {
+        let pins = PinStore::new();
+        let rotation = Identity::generate().rotate(NOW);
+        pins.rotate(&rotation).unwrap();
+        pins.pin(addr(1), rotation.new).unwrap();
+
+        flood(&pins);
+        pins.rotate(&Identity::generate().rotate(NOW)).unwrap();
+        assert!(pins.rotations().contains(&rotation));
+
+        // Handing over a revocation already received keeps it too.
+        let revocation = Identity::generate().revocation(NOW);
+        pins.revoke(&revocation).unwrap();
+        assert!(!pins.hold_revocation(&revocation).unwrap());
+        flood(&pins);
+        pins.revoke(&Identity::generate().revocation(NOW)).unwrap();
+        assert!(pins.is_revoked(revocation.key));
+    }
+
+    #[test]
+    fn forged_statements_are_refused() {
+        let pins = PinStore::new();
+        let identity = Identity::generate();
+        let rotation = KeyRotation {
+            new: Identity::generate().peer_id(),
+            ..identity.rotate(NOW)
+        };
+        let revocation = KeyRevocation {
+            issued_at: 0,
+            ..identity.revocation(NOW)
+        };
+
+        assert!(pins.rotate(&rotation).is_err());
+        assert!(pins.revoke(&revocation).is_err());
+        assert!(pins.rotations().is_empty());
+        assert!(pins.revocations().is_empty());
+    }
+}
@@ -1,18 +1,147 @@
 //! Implements rate limiting.
-
-use std::collections::HashMap;
-use std::net::SocketAddr;
+//!
+//! Each connection has a token bucket per [`TrafficClass`], so a flood of
+//! broadcasts cannot starve the heartbeats and repairs a cluster needs to stay
+//! healthy. A bucket meters messages and, if configured, bytes, so a 10 MB
+//! repair costs more than a heartbeat. An optional global budget meters
+//! everything received, from every peer together.
+//!
+//! Buckets are charged with atomic updates, and each connection owns its own,
+//! so reading from one peer never waits on another. A reader over budget can
+//! either drop the message ([`ConnectionLimiter::allow`]) or take it anyway and
+//! pause until the budget refills ([`ConnectionLimiter::reserve`]), which
+//! pushes back on the sender through TCP flow control.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use std::time::{Duration, Instant};
 
 use serde::{Deserialize, Serialize};
 
-use crate::Error;
+use crate::{Error, Payload};
+
+/// The class of traffic a message is metered as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrafficClass {
+    /// Membership and liveness: heartbeats, pings, peer lists, goodbyes and
+    /// the like
+    Control,
+
+    /// Broadcasts, retractions, key-value writes and direct messages
+    Application,
+
+    /// Anti-entropy, key-value and snapshot repair
+    Repair,
+}
+
+impl TrafficClass {
+    /// Every class.
+    pub const ALL: [Self; 3] = [Self::Control, Self::Application, Self::Repair];
+
+    /// The class `payload` is metered as.
+    pub fn of(payload: &Payload) -> Self {
+        match payload {
+            Payload::Application(_)
+            | Payload::CausalApplication { .. }
+            | Payload::ExpiringApplication { .. }
+            | Payload::Retraction { .. }
+            | Payload::KvDelta { .. }
+            | Payload::DirectMessage { .. } => Self::Application,
+            Payload::AntiEntropyDigest { .. }
+            | Payload::MessageRequest { .. }
+            | Payload::MessageResponse { .. }
+            | Payload::RangeDigest { .. }
+            | Payload::RangeRequest { .. }
+            | Payload::KvDigest { .. }
+            | Payload::KvState { .. }
+            | Payload::SnapshotRequest { .. }
+            | Payload::SnapshotChunk { .. }
+            | Payload::SnapshotAck { .. } => Self::Repair,
+            Payload::Heartbeat { .. }
+            | Payload::PeerListRequest
+            | Payload::PeerListResponse { .. }
+            | Payload::Goodbye { .. }
+            | Payload::Aggregate { .. }
+            | Payload::Ping { .. }
+            | Payload::Pong { .. }
+            | Payload::PeerZones { .. }
+            | Payload::PeerAdvertisements { .. }
+            | Payload::KeyStatements { .. }
+            | Payload::ConnectionProof { .. } => Self::Control,
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Self::Control => 0,
+            Self::Application => 1,
+            Self::Repair => 2,
+        }
+    }
+}
+
+impl fmt::Display for TrafficClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Control => "control",
+            Self::Application => "application",
+            Self::Repair => "repair",
+        })
+    }
+}
+
+/// A token bucket's size and refill, in messages and optionally bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Budget {
+    /// Maximum burst size (messages)
+    pub capacity: u32,
 
-/// Cleanup interval for removing stale peer buckets (5 minutes).
-const CLEANUP_INTERVAL: Duration = Duration::from_secs(300);
+    /// Message refill rate (messages per second)
+    pub refill_rate: u32,
 
-/// Maximum age for a peer bucket before cleanup (10 minutes).
-const BUCKET_MAX_AGE: Duration = Duration::from_secs(600);
+    /// Maximum burst size in bytes; 0 leaves bytes unmetered
+    #[serde(default)]
+    pub byte_capacity: u32,
+
+    /// Byte refill rate (bytes per second)
+    #[serde(default)]
+    pub byte_refill_rate: u32,
+}
+
+impl Budget {
+    /// Validate the budget, naming it `name` in errors.
+    ///
+    /// # Errors
+    /// Returns a description of the first invalid field.
+    pub fn validate(&self, name: &str) -> Result<(), String> {
+        if self.capacity == 0 {
+            return Err(format!("{name} capacity must be greater than 0"));
+        }
+        if self.refill_rate == 0 {
+            return Err(format!("{name} refill_rate must be greater than 0"));
+        }
+        if self.byte_capacity > 0 && self.byte_refill_rate == 0 {
+            return Err(format!(
+                "{name} byte_refill_rate must be greater than 0 when byte_capacity is set"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Budgets for particular traffic classes, in place of the default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClassBudgets {
+    /// Budget for control traffic
+    pub control: Option<Budget>,
+
+    /// Budget for application traffic
+    pub application: Option<Budget>,
+
+    /// Budget for repair traffic
+    pub repair: Option<Budget>,
+}
 
 /// Rate limiting configuration.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,11 +149,33 @@ pub struct RateLimitConfig {
     /// Enable rate limiting
     pub enabled: bool,
 
-    /// Maximum burst size (tokens)
+    /// Maximum burst size (tokens), per peer and traffic class
     pub capacity: u32,
 
     /// Token refill rate (tokens per second)
     pub refill_rate: u32,
+
+    /// Maximum burst size in bytes, per peer and traffic class; 0 leaves
+    /// bytes unmetered
+    #[serde(default)]
+    pub byte_capacity: u32,
+
+    /// Byte refill rate (bytes per second)
+    #[serde(default)]
+    pub byte_refill_rate: u32,
+
+    /// Budgets for particular traffic classes, in place of the above
+    #[serde(default)]
+    pub classes: ClassBudgets,
+
+    /// Budget for everything received, from all peers together
+    #[serde(default)]
+    pub global: Option<Budget>,
+
+    /// Drop messages over budget, counting them as misbehaviour; if unset,
+    /// reads from the peer pause until its budget refills instead
+    #[serde(default = "drop_excess_by_default")]
+    pub drop_excess: bool,
 }
 
 impl Default for RateLimitConfig {
@@ -33,10 +184,19 @@ impl Default for RateLimitConfig {
             enabled: true,
             capacity: 100,
             refill_rate: 50,
+            byte_capacity: 0,
+            byte_refill_rate: 0,
+            classes: ClassBudgets::default(),
+            global: None,
+            drop_excess: true,
         }
     }
 }
 
+fn drop_excess_by_default() -> bool {
+    true
+}
+
 impl RateLimitConfig {
     /// Validate the configuration.
     pub fn validate(&self) -> Result<(), String> {
@@ -46,25 +206,205 @@ impl RateLimitConfig {
         if self.refill_rate == 0 {
             return Err("Rate limit refill_rate must be greater than 0".to_string());
         }
+        self.default_budget().validate("Rate limit")?;
+        for class in TrafficClass::ALL {
+            if let Some(budget) = self.class_budget(class) {
+                budget.validate(&format!("Rate limit {class}"))?;
+            }
+        }
+        if let Some(global) = &self.global {
+            global.validate("Global rate limit")?;
+        }
         Ok(())
     }
+
+    /// The budget each peer has for `class`.
+    pub fn budget(&self, class: TrafficClass) -> Budget {
+        self.class_budget(class)
+            .copied()
+            .unwrap_or_else(|| self.default_budget())
+    }
+
+    fn default_budget(&self) -> Budget {
+        Budget {
+            capacity: self.capacity,
+            refill_rate: self.refill_rate,
+            byte_capacity: self.byte_capacity,
+            byte_refill_rate: self.byte_refill_rate,
+        }
+    }
+
+    fn class_budget(&self, class: TrafficClass) -> Option<&Budget> {
+        match class {
+            TrafficClass::Control => self.classes.control.as_ref(),
+            TrafficClass::Application => self.classes.application.as_ref(),
+            TrafficClass::Repair => self.classes.repair.as_ref(),
+        }
+    }
+}
+
+/// The budget a message was refused by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetExceeded {
+    /// The sending peer's budget for the class
+    Peer(TrafficClass),
+
+    /// The global budget
+    Global,
 }
 
-/// Simple token bucket rate limiter.
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Peer(class) => write!(f, "peer {class} budget"),
+            Self::Global => f.write_str("global budget"),
+        }
+    }
+}
+
+/// Rate limiter shared by a transport's connections.
+///
+/// It holds the configuration and the global budget; each connection takes a
+/// [`ConnectionLimiter`] of its own buckets from it. Buckets are charged with
+/// atomic operations, so connections never wait on each other.
 #[derive(Debug)]
 pub struct RateLimiter {
     /// Rate limiting configuration
     config: RateLimitConfig,
-    /// Per-peer token buckets
-    buckets: HashMap<SocketAddr, TokenBucket>,
-    /// Last cleanup time
-    last_cleanup: Instant,
+    /// Instant the buckets' clocks count from
+    epoch: Instant,
+    /// Bucket shared by all connections, if there is a global budget
+    global: Option<TokenBucket>,
 }
 
-#[derive(Debug, Clone)]
+/// One connection's buckets, one per traffic class.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    limiter: Arc<RateLimiter>,
+    classes: [TokenBucket; 3],
+}
+
+/// A token bucket, kept as the time at which it will next be full so that it
+/// can be charged with one atomic update (the generic cell rate algorithm).
+#[derive(Debug)]
 struct TokenBucket {
-    tokens: f64,
-    last_refill: Instant,
+    messages: Cell,
+    bytes: Option<Cell>,
+}
+
+/// One metered quantity of a bucket.
+#[derive(Debug)]
+struct Cell {
+    capacity: u32,
+    refill_rate: u32,
+    /// Nanoseconds after the epoch at which the cell is full again
+    full_at: AtomicU64,
+}
+
+impl Cell {
+    fn new(capacity: u32, refill_rate: u32) -> Self {
+        Self {
+            capacity,
+            refill_rate,
+            full_at: AtomicU64::new(0),
+        }
+    }
+
+    /// Nanoseconds `tokens` take to refill.
+    fn nanos(&self, tokens: u32) -> u64 {
+        let nanos = u128::from(tokens) * 1_000_000_000 / u128::from(self.refill_rate);
+        u64::try_from(nanos).unwrap_or(u64::MAX)
+    }
+
+    /// How long after `now` a charge of `cost` fits, if `full_at` is as given.
+    /// A charge larger than the capacity fits a full cell, and leaves it in
+    /// debt.
+    fn wait(&self, full_at: u64, cost: u32, now: u64) -> u64 {
+        let backlog = full_at.saturating_sub(now);
+        let slack = self.nanos(self.capacity - cost.min(self.capacity));
+        backlog.saturating_sub(slack)
+    }
+
+    /// `full_at` after charging `cost` at `now`.
+    fn charged(&self, full_at: u64, cost: u32, now: u64) -> u64 {
+        full_at.max(now).saturating_add(self.nanos(cost))
+    }
+
+    /// Charge `cost` if it fits at `now`, or return how long until it does.
+    fn try_take(&self, cost: u32, now: u64) -> Result<(), u64> {
+        let mut wait = 0;
+        self.full_at
+            .fetch_update(AtomicOrdering::AcqRel, AtomicOrdering::Acquire, |full_at| {
+                wait = self.wait(full_at, cost, now);
+                (wait == 0).then(|| self.charged(full_at, cost, now))
+            })
+            .map(drop)
+            .map_err(|_| wait)
+    }
+
+    /// Charge `cost` whether or not it fits, returning how long after `now`
+    /// it does.
+    fn reserve(&self, cost: u32, now: u64) -> u64 {
+        let mut wait = 0;
+        let _ =
+            self.full_at
+                .fetch_update(AtomicOrdering::AcqRel, AtomicOrdering::Acquire, |full_at| {
+                    wait = self.wait(full_at, cost, now);
+                    Some(self.charged(full_at, cost, now))
+                });
+        wait
+    }
+
+    /// Give back a charge of `cost`.
+    fn refund(&self, cost: u32) {
+        let nanos = self.nanos(cost);
+        let _ =
+            self.full_at
+                .fetch_update(AtomicOrdering::AcqRel, AtomicOrdering::Acquire, |full_at| {
+                    Some(full_at.saturating_sub(nanos))
+                });
+    }
+}
+
+impl TokenBucket {
+    fn new(budget: &Budget) -> Self {
+        Self {
+            messages: Cell::new(budget.capacity, budget.refill_rate),
+            bytes: (budget.byte_capacity > 0)
+                .then(|| Cell::new(budget.byte_capacity, budget.byte_refill_rate)),
+        }
+    }
+
+    /// Charge a message of `size` bytes if it fits at `now`, or return how
+    /// long until it does.
+    fn try_take(&self, size: u32, now: u64) -> Result<(), u64> {
+        self.messages.try_take(1, now)?;
+        if let Some(bytes) = &self.bytes
+            && let Err(wait) = bytes.try_take(size, now)
+        {
+            self.messages.refund(1);
+            return Err(wait);
+        }
+        Ok(())
+    }
+
+    /// Charge a message of `size` bytes, returning how long after `now` it
+    /// fits.
+    fn reserve(&self, size: u32, now: u64) -> u64 {
+        let messages = self.messages.reserve(1, now);
+        let bytes = self
+            .bytes
+            .as_ref()
+            .map_or(0, |bytes| bytes.reserve(size, now));
+        messages.max(bytes)
+    }
+
+    fn refund(&self, size: u32) {
+        self.messages.refund(1);
+        if let Some(bytes) = &self.bytes {
+            bytes.refund(size);
+        }
+    }
 }
 
 impl RateLimiter {
@@ -80,9 +420,9 @@ impl RateLimiter {
         config.validate().map_err(Error::Config)?;
 
         Ok(Self {
+            global: config.global.as_ref().map(TokenBucket::new),
             config,
-            buckets: HashMap::new(),
-            last_cleanup: Instant::now(),
+            epoch: Instant::now(),
         })
     }
 
@@ -99,6 +439,7 @@ impl RateLimiter {
             enabled: true,
             capacity,
             refill_rate,
+            ..RateLimitConfig::default()
         })
     }
 
@@ -107,66 +448,72 @@ impl RateLimiter {
         &self.config
     }
 
-    /// Check if a request from the given peer should be allowed.
-    ///
-    /// Returns `true` if the request is within the rate limit, `false` otherwise.
-    pub fn allow_request(&mut self, peer: SocketAddr) -> bool {
-        self.maybe_cleanup();
-
-        let capacity = self.config.capacity;
-        let refill_rate = self.config.refill_rate;
-
-        let bucket = self.buckets.entry(peer).or_insert_with(|| TokenBucket {
-            tokens: f64::from(capacity),
-            last_refill: Instant::now(),
-        });
-
-        Self::refill_bucket(bucket, capacity, refill_rate);
-
-        if bucket.tokens >= 1.0 {
-            bucket.tokens -= 1.0;
-            true
-        } else {
-            false
+    /// Full buckets for a new connection, sharing this limiter's global
+    /// budget.
+    pub fn connection(self: &Arc<Self>) -> ConnectionLimiter {
+        ConnectionLimiter {
+            classes: TrafficClass::ALL.map(|class| TokenBucket::new(&self.config.budget(class))),
+            limiter: Arc::clone(self),
         }
     }
 
-    /// Refill tokens in a bucket based on elapsed time.
-    fn refill_bucket(bucket: &mut TokenBucket, capacity: u32, refill_rate: u32) {
-        let now = Instant::now();
-        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+    /// Nanoseconds since the epoch.
+    fn now(&self) -> u64 {
+        u64::try_from(self.epoch.elapsed().as_nanos()).unwrap_or(u64::MAX)
+    }
+}
 
-        bucket.tokens += elapsed * f64::from(refill_rate);
-        bucket.tokens = bucket.tokens.min(f64::from(capacity));
-        bucket.last_refill = now;
+impl ConnectionLimiter {
+    /// The configuration of the limiter the buckets came from.
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.limiter.config
     }
 
-    /// Periodic cleanup of stale peer buckets.
+    /// Check if a request should be allowed, counting it as an application
+    /// message of unmetered size.
     ///
-    /// Removes buckets that haven't been used for more than `BUCKET_MAX_AGE`.
-    /// This prevents unbounded memory growth for peers that disconnect.
-    fn maybe_cleanup(&mut self) {
-        if self.last_cleanup.elapsed() > CLEANUP_INTERVAL {
-            let now = Instant::now();
-            self.buckets
-                .retain(|_, bucket| bucket.last_refill.elapsed() < BUCKET_MAX_AGE);
-            self.last_cleanup = now;
-        }
+    /// Returns `true` if the request is within the rate limit, `false` otherwise.
+    pub fn allow_request(&self) -> bool {
+        self.allow(TrafficClass::Application, 0).is_ok()
     }
 
-    /// Get the number of active peer buckets.
-    #[cfg(test)]
-    fn bucket_count(&self) -> usize {
-        self.buckets.len()
+    /// Check whether a message of `class` and `size` bytes fits the
+    /// connection's budget and the global one, and take it from both if so.
+    ///
+    /// # Errors
+    /// Returns the [`BudgetExceeded`] the message exceeds; nothing is taken.
+    pub fn allow(&self, class: TrafficClass, size: usize) -> Result<(), BudgetExceeded> {
+        let now = self.limiter.now();
+        let size = u32::try_from(size).unwrap_or(u32::MAX);
+        let bucket = &self.classes[class.index()];
+        bucket
+            .try_take(size, now)
+            .map_err(|_| BudgetExceeded::Peer(class))?;
+        if let Some(global) = &self.limiter.global
+            && global.try_take(size, now).is_err()
+        {
+            bucket.refund(size);
+            return Err(BudgetExceeded::Global);
+        }
+        Ok(())
     }
 
-    /// Force cleanup for testing purposes with a custom max age.
-    #[cfg(test)]
-    fn cleanup_with_max_age(&mut self, max_age: Duration) {
-        let now = Instant::now();
-        self.buckets
-            .retain(|_, bucket| bucket.last_refill.elapsed() < max_age);
-        self.last_cleanup = now;
+    /// Take a message of `class` and `size` bytes from the connection's
+    /// budget and the global one, going into debt if need be, and return how
+    /// long to wait before the message fits.
+    ///
+    /// Pausing for that long before reading more paces the peer at its
+    /// budget without dropping anything.
+    pub fn reserve(&self, class: TrafficClass, size: usize) -> Duration {
+        let now = self.limiter.now();
+        let size = u32::try_from(size).unwrap_or(u32::MAX);
+        let own = self.classes[class.index()].reserve(size, now);
+        let global = self
+            .limiter
+            .global
+            .as_ref()
+            .map_or(0, |global| global.reserve(size, now));
+        Duration::from_nanos(own.max(global))
     }
 }
 
@@ -176,61 +523,59 @@ mod tests {
 
     use super::*;
 
+    fn connection(limiter: RateLimiter) -> ConnectionLimiter {
+        Arc::new(limiter).connection()
+    }
+
     #[test]
     fn allows_within_limit() {
-        let mut limiter = RateLimiter::try_with_params(10, 10).unwrap();
-        let peer = "127.0.0.1:8000".parse().unwrap();
+        let limiter = connection(RateLimiter::try_with_params(10, 10).unwrap());
 
         for _ in 0..10 {
-            assert!(limiter.allow_request(peer), "Should allow within capacity");
+            assert!(limiter.allow_request(), "Should allow within capacity");
         }
     }
 
     #[test]
     fn blocks_over_limit() {
-        let mut limiter = RateLimiter::try_with_params(5, 5).unwrap();
-        let peer = "127.0.0.1:8000".parse().unwrap();
+        let limiter = connection(RateLimiter::try_with_params(5, 5).unwrap());
 
         for _ in 0..5 {
-            assert!(limiter.allow_request(peer));
+            assert!(limiter.allow_request());
         }
 
-        assert!(
-            !limiter.allow_request(peer),
-            "Should block when over capacity"
-        );
+        assert!(!limiter.allow_request(), "Should block when over capacity");
     }
 
     #[test]
     fn refills() {
-        let mut limiter = RateLimiter::try_with_params(2, 10).unwrap();
-        let peer = "127.0.0.1:8000".parse().unwrap();
+        let limiter = connection(RateLimiter::try_with_params(2, 10).unwrap());
 
-        assert!(limiter.allow_request(peer));
-        assert!(limiter.allow_request(peer));
-        assert!(!limiter.allow_request(peer));
+        assert!(limiter.allow_request());
+        assert!(limiter.allow_request());
+        assert!(!limiter.allow_request());
 
         thread::sleep(Duration::from_millis(200));
 
-        assert!(limiter.allow_request(peer), "Should refill after delay");
+        assert!(limiter.allow_request(), "Should refill after delay");
     }
 
     #[test]
-    fn per_peer() {
-        let mut limiter = RateLimiter::try_with_params(2, 2).unwrap();
-        let peer1 = "127.0.0.1:8000".parse().unwrap();
-        let peer2 = "127.0.0.1:8001".parse().unwrap();
+    fn per_connection() {
+        let limiter = Arc::new(RateLimiter::try_with_params(2, 2).unwrap());
+        let first = limiter.connection();
+        let second = limiter.connection();
 
-        assert!(limiter.allow_request(peer1));
-        assert!(limiter.allow_request(peer1));
-        assert!(!limiter.allow_request(peer1));
+        assert!(first.allow_request());
+        assert!(first.allow_request());
+        assert!(!first.allow_request());
 
         assert!(
-            limiter.allow_request(peer2),
-            "Peer2 should have separate bucket"
+            second.allow_request(),
+            "Second connection should have separate bucket"
         );
-        assert!(limiter.allow_request(peer2));
-        assert!(!limiter.allow_request(peer2));
+        assert!(second.allow_request());
+        assert!(!second.allow_request());
     }
 
     #[test]
@@ -239,17 +584,26 @@ mod tests {
             enabled: true,
             capacity: 5,
             refill_rate: 10,
+            ..RateLimitConfig::default()
         };
 
-        let mut limiter = RateLimiter::try_new(config.clone()).unwrap();
+        let limiter = RateLimiter::try_new(config.clone()).unwrap();
         assert_eq!(limiter.config().capacity, 5);
         assert_eq!(limiter.config().refill_rate, 10);
 
-        let peer = "127.0.0.1:8000".parse().unwrap();
+        let limiter = connection(limiter);
         for _ in 0..5 {
-            assert!(limiter.allow_request(peer));
+            assert!(limiter.allow_request());
         }
-        assert!(!limiter.allow_request(peer));
+        assert!(!limiter.allow_request());
+    }
+
+    #[test]
+    fn older_configs_keep_dropping_excess() {
+        let json = r#"{"enabled": true, "capacity": 5, "refill_rate": 10}"#;
+        let config: RateLimitConfig = serde_json::from_str(json).unwrap();
+        assert!(config.drop_excess);
+        assert!(RateLimitConfig::default().drop_excess);
     }
 
     #[test]
@@ -258,6 +612,7 @@ mod tests {
             enabled: true,
             capacity: 0,
             refill_rate: 10,
+            ..RateLimitConfig::default()
         };
         assert!(invalid_capacity.validate().is_err());
 
@@ -265,13 +620,35 @@ mod tests {
             enabled: true,
             capacity: 10,
             refill_rate: 0,
+            ..RateLimitConfig::default()
         };
         assert!(invalid_refill.validate().is_err());
 
+        let unmetered_bytes = RateLimitConfig {
+            byte_capacity: 1_000,
+            ..RateLimitConfig::default()
+        };
+        assert!(unmetered_bytes.validate().is_err());
+
+        let invalid_class = RateLimitConfig {
+            classes: ClassBudgets {
+                repair: Some(Budget {
+                    capacity: 0,
+                    refill_rate: 1,
+                    byte_capacity: 0,
+                    byte_refill_rate: 0,
+                }),
+                ..ClassBudgets::default()
+            },
+            ..RateLimitConfig::default()
+        };
+        assert!(invalid_class.validate().is_err());
+
         let valid = RateLimitConfig {
             enabled: true,
             capacity: 10,
             refill_rate: 5,
+            ..RateLimitConfig::default()
         };
         assert!(valid.validate().is_ok());
     }
@@ -282,42 +659,151 @@ mod tests {
             enabled: true,
             capacity: 0,
             refill_rate: 10,
+            ..RateLimitConfig::default()
         };
         assert!(RateLimiter::try_new(invalid_config).is_err());
     }
 
     #[test]
-    fn bucket_cleanup() {
-        const TEST_BUCKET_MAX_AGE: Duration = Duration::from_millis(100);
+    fn meters_bytes() {
+        let limiter = connection(
+            RateLimiter::try_new(RateLimitConfig {
+                byte_capacity: 1_000,
+                byte_refill_rate: 1,
+                ..RateLimitConfig::default()
+            })
+            .unwrap(),
+        );
+        let class = TrafficClass::Repair;
 
-        let mut limiter = RateLimiter::try_with_params(10, 10).unwrap();
-        let peer1 = "127.0.0.1:8000".parse().unwrap();
-        let peer2 = "127.0.0.1:8001".parse().unwrap();
+        assert_eq!(limiter.allow(class, 600), Ok(()));
+        assert_eq!(limiter.allow(class, 600), Err(BudgetExceeded::Peer(class)));
+        assert_eq!(limiter.allow(class, 400), Ok(()));
+    }
 
-        // Create bucket for peer1
-        limiter.allow_request(peer1);
-        assert_eq!(limiter.bucket_count(), 1);
+    #[test]
+    fn oversized_messages_pass_a_full_bucket() {
+        let limiter = connection(
+            RateLimiter::try_new(RateLimitConfig {
+                byte_capacity: 1_000,
+                byte_refill_rate: 1,
+                ..RateLimitConfig::default()
+            })
+            .unwrap(),
+        );
+        let class = TrafficClass::Repair;
 
-        // Wait for peer1's bucket to age beyond MAX_AGE
-        thread::sleep(Duration::from_millis(120));
+        assert_eq!(limiter.allow(class, 5_000), Ok(()));
+        // The bucket is in debt until it refills past zero.
+        assert!(limiter.allow(class, 1).is_err());
+    }
 
-        // Create bucket for peer2 (fresh)
-        limiter.allow_request(peer2);
-        assert_eq!(limiter.bucket_count(), 2);
+    #[test]
+    fn classes_have_separate_budgets() {
+        let limiter = connection(
+            RateLimiter::try_new(RateLimitConfig {
+                capacity: 2,
+                refill_rate: 1,
+                classes: ClassBudgets {
+                    control: Some(Budget {
+                        capacity: 5,
+                        refill_rate: 1,
+                        byte_capacity: 0,
+                        byte_refill_rate: 0,
+                    }),
+                    ..ClassBudgets::default()
+                },
+                ..RateLimitConfig::default()
+            })
+            .unwrap(),
+        );
 
-        // Trigger cleanup with test-specific max age
-        // This tests the cleanup logic using a shorter timeout than production
-        limiter.cleanup_with_max_age(TEST_BUCKET_MAX_AGE);
+        for _ in 0..2 {
+            assert!(limiter.allow(TrafficClass::Application, 0).is_ok());
+        }
+        assert!(limiter.allow(TrafficClass::Application, 0).is_err());
+        // A flood of application messages leaves control and repair alone.
+        for _ in 0..5 {
+            assert!(limiter.allow(TrafficClass::Control, 0).is_ok());
+        }
+        assert!(limiter.allow(TrafficClass::Control, 0).is_err());
+        assert!(limiter.allow(TrafficClass::Repair, 0).is_ok());
+    }
 
-        // Only peer2 should remain (peer1's bucket is > 100ms old, peer2 is fresh)
-        assert_eq!(
-            limiter.bucket_count(),
-            1,
-            "Stale bucket should be cleaned up"
+    #[test]
+    fn global_budget_spans_connections() {
+        let limiter = Arc::new(
+            RateLimiter::try_new(RateLimitConfig {
+                global: Some(Budget {
+                    capacity: 3,
+                    refill_rate: 1,
+                    byte_capacity: 0,
+                    byte_refill_rate: 0,
+                }),
+                ..RateLimitConfig::default()
+            })
+            .unwrap(),
         );
+        let class = TrafficClass::Control;
+
+        for _ in 0..3 {
+            assert_eq!(limiter.connection().allow(class, 0), Ok(()));
+        }
+        let last = limiter.connection();
+        assert_eq!(last.allow(class, 0), Err(BudgetExceeded::Global));
+        // The refused message was not charged to the connection.
+        for _ in 0..100 {
+            assert_ne!(last.allow(class, 0), Err(BudgetExceeded::Peer(class)));
+        }
+    }
 
-        // Verify peer2 is the one that remains
-        assert!(limiter.buckets.contains_key(&peer2));
-        assert!(!limiter.buckets.contains_key(&peer1));
+    #[test]
+    fn reserving_paces_instead_of_refusing() {
+        let limiter = connection(RateLimiter::try_with_params(2, 10).unwrap());
+        let class = TrafficClass::Application;
+
+        assert_eq!(limiter.reserve(class, 0), Duration::ZERO);
+        assert_eq!(limiter.reserve(class, 0), Duration::ZERO);
+        // Each message beyond the burst waits a tenth of a second more.
+        let third = limiter.reserve(class, 0);
+        let fourth = limiter.reserve(class, 0);
+        assert!(third > Duration::from_millis(90) && third <= Duration::from_millis(100));
+        assert!(fourth > Duration::from_millis(190) && fourth <= Duration::from_millis(200));
+        assert!(limiter.allow(class, 0).is_err());
+    }
+
+    #[test]
+    fn charges_from_many_threads_add_up() {
+        let limiter = Arc::new(RateLimiter::try_with_params(1_000, 1).unwrap());
+        let connection = Arc::new(limiter.connection());
+        let allowed: usize = (0..8)
+            .map(|_| {
+                let connection = Arc::clone(&connection);
+                thread::spawn(move || (0..500).filter(|_| connection.allow_request()).count())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .sum();
+        assert!((1_000..=1_001).contains(&allowed), "allowed {allowed}");
+    }
+
+    #[test]
+    fn classifies_payloads() {
+        let data = bytes::Bytes::from_static(b"x");
+        assert_eq!(
+            TrafficClass::of(&Payload::Application(data)),
+            TrafficClass::Application
+        );
+        assert_eq!(
+            TrafficClass::of(&Payload::Ping { nonce: 1 }),
+            TrafficClass::Control
+        );
+        assert_eq!(
+            TrafficClass::of(&Payload::MessageResponse {
+                messages: Vec::new()
+            }),
+            TrafficClass::Repair
+        );
     }
 }
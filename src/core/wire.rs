@@ -0,0 +1,297 @@
+//! Wire protocol versioning and capability negotiation.
+//!
+//! A connection starts out speaking the v1.1.0 wire format (protocol version
+//! 1). The first frame each side writes advertises its protocol version and
+//! [`Capabilities`] in a form a v1.1.0 node ignores (see
+//! [`MessageCodec`](crate::MessageCodec) for the frame layouts). Once a peer has
+//! advertised version 2 or later, frames to it carry a versioned header naming
+//! the payload kind, so a decoder can skip a kind it does not know instead of
+//! failing the connection. A peer that never advertises is treated as a v1.1.0
+//! node and is only ever sent payloads that version decodes.
+//!
+//! Payload kinds are append-only: a version's kinds are a prefix of every later
+//! version's, which is what lets a node decide, from the version alone, whether
+//! a peer can decode a payload. Version 2 added the versioned frame and no
+//! kinds; version 3 added every kind from [`Payload::RangeDigest`] on; version
+//! 4 added [`Payload::ConnectionProof`] and the connection challenge a hello
+//! carries from peers advertising [`Capabilities::HANDSHAKE`].
+
+use std::fmt;
+
+use crate::Payload;
+
+/// The protocol version this build speaks.
+pub const PROTOCOL_VERSION: u8 = 4;
+
+/// The version assumed for a peer that never advertises one: a v1.1.0 node.
+pub const LEGACY_PROTOCOL_VERSION: u8 = 1;
+
+/// Number of payload kinds (codes `0..n`) each protocol version decodes, from
+/// version 1 on. A change that adds a kind bumps [`PROTOCOL_VERSION`] and
+/// appends the new count.
+const KINDS_BY_VERSION: [u8; 4] = [9, 9, 26, 27];
+
+/// The first protocol version whose hello carries a connection challenge.
+pub(crate) const HANDSHAKE_VERSION: u8 = 4;
+
+/// Optional features a peer supports, advertised during negotiation.
+///
+/// Bits this build does not know are preserved, so a newer peer's
+/// advertisement survives a round trip.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// No optional features.
+    pub const NONE: Self = Self(0);
+
+    /// Decodes LZ4-compressed frames.
+    pub const LZ4: Self = Self(1 << 0);
+
+    /// Decodes postcard-encoded frames (see [`WireEncoding`](crate::WireEncoding)).
+    pub const POSTCARD: Self = Self(1 << 1);
+
+    /// Proves its key on each connection: its hello carries a challenge, and
+    /// it answers the peer's with a [`Payload::ConnectionProof`].
+    pub const HANDSHAKE: Self = Self(1 << 2);
+
+    /// Construct from raw advertised bits.
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// The raw advertised bits.
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Whether every feature in `other` is present.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The features present in either set.
+    #[must_use]
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Capabilities this build advertises. Decoding support is advertised whether
+/// or not the matching feature is enabled for sending.
+pub(crate) const LOCAL_CAPABILITIES: Capabilities = Capabilities::LZ4.union(Capabilities::POSTCARD);
+
+/// What a peer advertised about the protocol it speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerProtocol {
+    /// The highest protocol version the peer speaks.
+    pub version: u8,
+
+    /// The optional features the peer supports.
+    pub capabilities: Capabilities,
+}
+
+impl PeerProtocol {
+    /// A v1.1.0 peer, which advertises nothing.
+    pub const LEGACY: Self = Self {
+        version: LEGACY_PROTOCOL_VERSION,
+        capabilities: Capabilities::NONE,
+    };
+
+    /// What this build advertises.
+    pub const LOCAL: Self = Self {
+        version: PROTOCOL_VERSION,
+        capabilities: LOCAL_CAPABILITIES,
+    };
+
+    /// The version both sides speak: the lower of the peer's and ours.
+    pub fn negotiated_version(&self) -> u8 {
+        self.version.min(PROTOCOL_VERSION)
+    }
+
+    /// Whether a peer speaking this protocol decodes `payload`, including every
+    /// message nested inside it.
+    pub fn understands(&self, payload: &Payload) -> bool {
+        if payload.kind() >= known_kinds(self.version) {
+            return false;
+        }
+        match payload {
+            Payload::MessageResponse { messages } | Payload::SnapshotChunk { messages, .. } => {
+                messages
+                    .iter()
+                    .all(|message| self.understands(&message.payload))
+            }
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for PeerProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "v{} (capabilities {:#x})",
+            self.version,
+            self.capabilities.bits()
+        )
+    }
+}
+
+/// Number of payload kinds (codes `0..n`) a node speaking `version` decodes.
+/// A peer at our version or later decodes every kind this build knows.
+fn known_kinds(version: u8) -> u8 {
+    let index = usize::from(version.max(LEGACY_PROTOCOL_VERSION) - LEGACY_PROTOCOL_VERSION);
+    KINDS_BY_VERSION
+        .get(index)
+        .copied()
+        .unwrap_or(Payload::KINDS)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::Message;
+
+    #[test]
+    fn legacy_peers_understand_every_v1_payload() {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        let payloads = [
+            Payload::Application(Bytes::from_static(b"x")),
+            Payload::Heartbeat { from: addr },
+            Payload::PeerListRequest,
+            Payload::Goodbye {
+                reason: String::new(),
+            },
+        ];
+        for payload in &payloads {
+            assert!(PeerProtocol::LEGACY.understands(payload), "{payload:?}");
+        }
+    }
+
+    #[test]
+    fn legacy_peers_do_not_understand_later_payloads() {
+        let payloads = [
+            Payload::RangeDigest { held: Vec::new() },
+            Payload::CausalApplication {
+                data: Bytes::new(),
+                clock: Vec::new(),
+            },
+            Payload::ExpiringApplication {
+                data: Bytes::new(),
+                clock: Vec::new(),
+                expires_at: 0,
+            },
+            Payload::Retraction { sequence: 0 },
+            Payload::KvDelta {
+                delta: Bytes::new(),
+            },
+            Payload::KvDigest { digest: 0 },
+            Payload::KvState {
+                state: Bytes::new(),
+                reply: false,
+            },
+            Payload::Aggregate {
+                epoch: 0,
+                shares: Vec::new(),
+            },
+            Payload::SnapshotRequest {
+                session: 0,
+                after: None,
+                window: 1,
+            },
+            Payload::SnapshotChunk {
+                session: 0,
+                messages: Vec::new(),
+                done: true,
+            },
+            Payload::SnapshotAck { session: 0 },
+            Payload::Ping { nonce: 0 },
+            Payload::Pong { nonce: 0 },
+            Payload::PeerZones { zones: Vec::new() },
+            Payload::PeerAdvertisements { ads: Vec::new() },
+            Payload::KeyStatements {
+                rotations: Vec::new(),
+                revocations: Vec::new(),
+            },
+        ];
+        // Version 2 peers speak versioned frames, but predate these kinds.
+        let versioned = PeerProtocol {
+            version: 2,
+            capabilities: LOCAL_CAPABILITIES,
+        };
+        for payload in &payloads {
+            assert!(!PeerProtocol::LEGACY.understands(payload), "{payload:?}");
+            assert!(!versioned.understands(payload), "{payload:?}");
+            assert!(PeerProtocol::LOCAL.understands(payload), "{payload:?}");
+        }
+    }
+
+    #[test]
+    fn only_version_4_peers_understand_connection_proofs() {
+        let proof = Payload::ConnectionProof { challenge: 0 };
+        let before = PeerProtocol {
+            version: HANDSHAKE_VERSION - 1,
+            capabilities: LOCAL_CAPABILITIES,
+        };
+        assert!(!before.understands(&proof));
+        assert!(PeerProtocol::LOCAL.understands(&proof));
+    }
+
+    #[test]
+    fn new_kinds_bump_the_protocol_version() {
+        // A kind added without a new version would be sent to peers that
+        // cannot decode it.
+        assert_eq!(
+            KINDS_BY_VERSION.len(),
+            usize::from(PROTOCOL_VERSION - LEGACY_PROTOCOL_VERSION + 1)
+        );
+        assert_eq!(known_kinds(PROTOCOL_VERSION), Payload::KINDS);
+        assert_eq!(known_kinds(PROTOCOL_VERSION + 1), Payload::KINDS);
+        assert_eq!(known_kinds(0), known_kinds(LEGACY_PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn nested_messages_are_checked() {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        let response = Payload::MessageResponse {
+            messages: vec![Message::new(addr, 0, Payload::PeerListRequest)],
+        };
+        assert!(PeerProtocol::LEGACY.understands(&response));
+        assert!(PeerProtocol::LOCAL.understands(&response));
+
+        let response = Payload::MessageResponse {
+            messages: vec![Message::new(
+                addr,
+                0,
+                Payload::RangeRequest { held: Vec::new() },
+            )],
+        };
+        assert!(!PeerProtocol::LEGACY.understands(&response));
+    }
+
+    #[test]
+    fn capabilities_preserve_unknown_bits() {
+        let advertised = Capabilities::from_bits(0x8000_0001);
+        assert!(advertised.contains(Capabilities::LZ4));
+        assert_eq!(advertised.bits(), 0x8000_0001);
+        assert_eq!(
+            Capabilities::NONE.union(Capabilities::LZ4),
+            Capabilities::LZ4
+        );
+    }
+
+    #[test]
+    fn negotiated_version_is_the_lower_of_the_two() {
+        let newer = PeerProtocol {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: Capabilities::NONE,
+        };
+        assert_eq!(newer.negotiated_version(), PROTOCOL_VERSION);
+        assert_eq!(
+            PeerProtocol::LEGACY.negotiated_version(),
+            LEGACY_PROTOCOL_VERSION
+        );
+    }
+}
@@ -0,0 +1,566 @@
+//! Peers listed in DNS.
+//!
+//! A [`DnsSeed`] asks a nameserver for the SRV records of its name (such as
+//! `_grapevine._tcp.example.com`), and resolves each record's target to
+//! addresses with the record's port. Addresses the nameserver sends along in
+//! the additional section are used as they are; other targets are looked up
+//! with A and AAAA queries. A name without SRV records is looked up with A and
+//! AAAA queries itself, and its addresses take [`DnsConfig::port`].
+//!
+//! Queries go over UDP, one at a time, to [`DnsConfig::nameserver`] or else
+//! the first nameserver in `/etc/resolv.conf`. Without either, only the A and
+//! AAAA lookup is made, through the system resolver. The client is a small one:
+//! it does not retry, fall back to TCP on truncation, or validate DNSSEC, so
+//! SRV record sets should fit in a 512-byte answer.
+
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::time;
+
+use super::Discovery;
+use crate::{Error, Result};
+
+/// How long a nameserver has to answer one query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest DNS name, in bytes of its wire form.
+const MAX_NAME_LEN: usize = 255;
+
+/// Longest label in a DNS name.
+const MAX_LABEL_LEN: usize = 63;
+
+/// Compression pointers followed in one name before it is rejected as a loop.
+const MAX_POINTERS: usize = 16;
+
+/// Largest response read.
+const MAX_RESPONSE_LEN: usize = 4_096;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+/// Header flags of a query: recursion desired.
+const FLAGS_QUERY: u16 = 0x0100;
+/// Header flag marking a response.
+const FLAG_RESPONSE: u16 = 0x8000;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// DNS discovery configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsConfig {
+    /// Name to look up, such as `_grapevine._tcp.example.com`
+    pub name: String,
+
+    /// Port of the addresses found when the name has no SRV records
+    pub port: u16,
+
+    /// Nameserver to ask; `None` uses the first in `/etc/resolv.conf`
+    #[serde(default)]
+    pub nameserver: Option<SocketAddr>,
+}
+
+impl DnsConfig {
+    /// Look up `name`, giving addresses from A and AAAA records `port`.
+    pub fn new(name: impl Into<String>, port: u16) -> Self {
+        Self {
+            name: name.into(),
+            port,
+            nameserver: None,
+        }
+    }
+
+    /// Ask `nameserver` instead of the system's.
+    pub fn with_nameserver(mut self, nameserver: SocketAddr) -> Self {
+        self.nameserver = Some(nameserver);
+        self
+    }
+
+    /// Validate the configuration.
+    ///
+    /// # Errors
+    /// Returns a description of the first invalid field.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if encode_name(&self.name).is_err() {
+            return Err(format!("discovery dns name {:?} is not valid", self.name));
+        }
+        if self.port == 0 {
+            return Err("discovery dns port must be > 0".into());
+        }
+        Ok(())
+    }
+}
+
+/// Peers listed in DNS SRV, A or AAAA records.
+#[derive(Debug, Clone)]
+pub struct DnsSeed {
+    config: DnsConfig,
+}
+
+impl DnsSeed {
+    /// Look up peers as `config` describes.
+    pub fn new(config: DnsConfig) -> Self {
+        Self { config }
+    }
+
+    async fn lookup(&self) -> Result<Vec<SocketAddr>> {
+        let nameserver = match self.config.nameserver {
+            Some(nameserver) => nameserver,
+            None => match system_nameserver().await {
+                Some(nameserver) => nameserver,
+                None => {
+                    let addrs =
+                        tokio::net::lookup_host((self.config.name.as_str(), self.config.port))
+                            .await?;
+                    return Ok(addrs.collect());
+                }
+            },
+        };
+
+        let name = normalize(&self.config.name);
+        let response = query(nameserver, &name, TYPE_SRV).await?;
+        let mut services: Vec<(u16, String, u16)> = response
+            .iter()
+            .filter_map(|record| match record {
+                Record::Srv {
+                    owner,
+                    priority,
+                    target,
+                    port,
+                } if *owner == name && target != "." => Some((*priority, target.clone(), *port)),
+                _ => None,
+            })
+            .collect();
+        services.sort();
+
+        let mut found = Vec::new();
+        if services.is_empty() {
+            for ip in resolve(nameserver, &name, &[]).await? {
+                found.push(SocketAddr::new(ip, self.config.port));
+            }
+        }
+        for (_, target, port) in services {
+            for ip in resolve(nameserver, &target, &response).await? {
+                found.push(SocketAddr::new(ip, port));
+            }
+        }
+        let mut seen = HashSet::new();
+        found.retain(|addr| seen.insert(*addr));
+        Ok(found)
+    }
+}
+
+impl Discovery for DnsSeed {
+    fn name(&self) -> &'static str {
+        "DNS"
+    }
+
+    fn discover(&self, _local_addr: SocketAddr) -> BoxFuture<'_, Result<Vec<SocketAddr>>> {
+        Box::pin(self.lookup())
+    }
+}
+
+/// A resource record of a type discovery uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Record {
+    Address {
+        owner: String,
+        ip: IpAddr,
+    },
+    Srv {
+        owner: String,
+        priority: u16,
+        target: String,
+        port: u16,
+    },
+}
+
+/// The addresses of `name`: those among `known` records, or else those
+/// returned by A and AAAA queries.
+async fn resolve(nameserver: SocketAddr, name: &str, known: &[Record]) -> Result<Vec<IpAddr>> {
+    let mut ips = addresses_of(name, known);
+    if ips.is_empty() {
+        for qtype in [TYPE_A, TYPE_AAAA] {
+            ips.extend(addresses_of(name, &query(nameserver, name, qtype).await?));
+        }
+    }
+    Ok(ips)
+}
+
+fn addresses_of(name: &str, records: &[Record]) -> Vec<IpAddr> {
+    records
+        .iter()
+        .filter_map(|record| match record {
+            Record::Address { owner, ip } if owner == name => Some(*ip),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Ask `nameserver` for the records of type `qtype` for `name`. A name that
+/// does not exist has none.
+async fn query(nameserver: SocketAddr, name: &str, qtype: u16) -> Result<Vec<Record>> {
+    let bind = if nameserver.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(nameserver).await?;
+    let id: u16 = rand::random();
+    socket.send(&encode_query(id, name, qtype)?).await?;
+
+    let mut buf = vec![0; MAX_RESPONSE_LEN];
+    time::timeout(QUERY_TIMEOUT, async {
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            // A datagram answering another query is a late or forged reply.
+            if let Some(records) = parse_response(&buf[..len], id)? {
+                return Ok(records);
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::network(format!("DNS query for {name} to {nameserver} timed out")))?
+}
+
+/// The first nameserver in `/etc/resolv.conf`.
+async fn system_nameserver() -> Option<SocketAddr> {
+    let contents = tokio::fs::read_to_string("/etc/resolv.conf").await.ok()?;
+    contents.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        (words.next() == Some("nameserver"))
+            .then(|| words.next())
+            .flatten()
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .map(|ip| SocketAddr::new(ip, 53))
+    })
+}
+
+/// `name` in lower case without its trailing dot, as records are compared.
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn encode_name(name: &str) -> Result<Vec<u8>> {
+    let name = name.trim_end_matches('.');
+    let invalid = || Error::network(format!("invalid DNS name {name:?}"));
+    if name.is_empty() {
+        return Err(invalid());
+    }
+    let mut wire = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(invalid());
+        }
+        wire.push(u8::try_from(label.len()).map_err(|_| invalid())?);
+        wire.extend_from_slice(label.as_bytes());
+    }
+    wire.push(0);
+    if wire.len() > MAX_NAME_LEN {
+        return Err(invalid());
+    }
+    Ok(wire)
+}
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(12 + name.len() + 6);
+    for field in [id, FLAGS_QUERY, 1, 0, 0, 0] {
+        query.extend_from_slice(&field.to_be_bytes());
+    }
+    query.extend(encode_name(name)?);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// The A, AAAA and SRV records in the answer and additional sections of a
+/// response to query `id`; `None` if `response` answers another query.
+fn parse_response(response: &[u8], id: u16) -> Result<Option<Vec<Record>>> {
+    let mut reader = Reader::new(response);
+    if reader.u16()? != id {
+        return Ok(None);
+    }
+    let flags = reader.u16()?;
+    if flags & FLAG_RESPONSE == 0 {
+        return Ok(None);
+    }
+    match flags & RCODE_MASK {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok(Some(Vec::new())),
+        rcode => return Err(Error::network(format!("DNS server answered rcode {rcode}"))),
+    }
+    let questions = reader.u16()?;
+    let answers = reader.u16()?;
+    let authorities = reader.u16()?;
+    let additionals = reader.u16()?;
+
+    for _ in 0..questions {
+        reader.name()?;
+        reader.skip(4)?;
+    }
+    let mut records = Vec::new();
+    for section in 0..3 {
+        let count = [answers, authorities, additionals][section];
+        for _ in 0..count {
+            let owner = reader.name()?;
+            let rtype = reader.u16()?;
+            let class = reader.u16()?;
+            reader.skip(4)?;
+            let len = usize::from(reader.u16()?);
+            let end = reader.pos + len;
+            if end > response.len() {
+                return Err(malformed());
+            }
+            // Authority records (section 1) name nameservers, not peers.
+            if class == CLASS_IN && section != 1 {
+                match (rtype, len) {
+                    (TYPE_A, 4) => {
+                        let octets: [u8; 4] =
+                            reader.bytes(4)?.try_into().map_err(|_| malformed())?;
+                        records.push(Record::Address {
+                            owner,
+                            ip: IpAddr::from(octets),
+                        });
+                    }
+                    (TYPE_AAAA, 16) => {
+                        let octets: [u8; 16] =
+                            reader.bytes(16)?.try_into().map_err(|_| malformed())?;
+                        records.push(Record::Address {
+                            owner,
+                            ip: IpAddr::from(octets),
+                        });
+                    }
+                    (TYPE_SRV, 7..) => {
+                        let priority = reader.u16()?;
+                        let _weight = reader.u16()?;
+                        let port = reader.u16()?;
+                        let target = reader.name()?;
+                        records.push(Record::Srv {
+                            owner,
+                            priority,
+                            target,
+                            port,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            reader.pos = end;
+        }
+    }
+    Ok(Some(records))
+}
+
+fn malformed() -> Error {
+    Error::network("malformed DNS response")
+}
+
+/// A cursor over a DNS message.
+struct Reader<'a> {
+    message: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(message: &'a [u8]) -> Self {
+        Self { message, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .message
+            .get(self.pos..self.pos + len)
+            .ok_or_else(malformed)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<()> {
+        self.bytes(len).map(drop)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A name, following compression pointers, normalized.
+    fn name(&mut self) -> Result<String> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut at = self.pos;
+        let mut resume = None;
+        let mut pointers = 0;
+        let mut len = 0;
+        loop {
+            let size = *self.message.get(at).ok_or_else(malformed)?;
+            match size {
+                0 => {
+                    at += 1;
+                    break;
+                }
+                1..=63 => {
+                    let start = at + 1;
+                    let label = self
+                        .message
+                        .get(start..start + usize::from(size))
+                        .ok_or_else(malformed)?;
+                    len += 1 + label.len();
+                    if len > MAX_NAME_LEN {
+                        return Err(malformed());
+                    }
+                    labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                    at = start + usize::from(size);
+                }
+                0xc0.. => {
+                    let low = *self.message.get(at + 1).ok_or_else(malformed)?;
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return Err(malformed());
+                    }
+                    resume.get_or_insert(at + 2);
+                    at = usize::from(u16::from_be_bytes([size & 0x3f, low]));
+                }
+                _ => return Err(malformed()),
+            }
+        }
+        self.pos = resume.unwrap_or(at);
+        Ok(if labels.is_empty() {
+            ".".into()
+        } else {
+            labels.join(".")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response to query `id` for `name` with the given answer and
+    /// additional records, each as (owner offset or name, type, rdata).
+    fn response(id: u16, rcode: u16, name: &str, records: &[(Vec<u8>, u16, Vec<u8>)]) -> Vec<u8> {
+        let mut message = Vec::new();
+        let count = u16::try_from(records.len()).unwrap();
+        for field in [id, FLAG_RESPONSE | FLAGS_QUERY | rcode, 1, count, 0, 0] {
+            message.extend_from_slice(&field.to_be_bytes());
+        }
+        message.extend(encode_name(name).unwrap());
+        message.extend_from_slice(&TYPE_SRV.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        for (owner, rtype, rdata) in records {
+            message.extend(owner);
+            message.extend_from_slice(&rtype.to_be_bytes());
+            message.extend_from_slice(&CLASS_IN.to_be_bytes());
+            message.extend_from_slice(&300u32.to_be_bytes());
+            message.extend_from_slice(&u16::try_from(rdata.len()).unwrap().to_be_bytes());
+            message.extend(rdata);
+        }
+        message
+    }
+
+    /// A pointer to the question's name, at offset 12.
+    const QUESTION_NAME: [u8; 2] = [0xc0, 12];
+
+    fn srv(priority: u16, port: u16, target: &str) -> Vec<u8> {
+        let mut rdata = Vec::new();
+        for field in [priority, 0, port] {
+            rdata.extend_from_slice(&field.to_be_bytes());
+        }
+        rdata.extend(encode_name(target).unwrap());
+        rdata
+    }
+
+    #[test]
+    fn encodes_a_query() {
+        let query = encode_query(0xbeef, "seed.Example.com.", TYPE_A).unwrap();
+        assert_eq!(&query[..4], &[0xbe, 0xef, 0x01, 0x00]);
+        assert_eq!(
+            &query[12..],
+            b"\x04seed\x07Example\x03com\x00\x00\x01\x00\x01"
+        );
+
+        for bad in ["", "a..b", &"a".repeat(64), &["a"; 128].join(".")] {
+            assert!(encode_query(1, bad, TYPE_A).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn parses_srv_and_address_records_with_compression() {
+        let name = "_grapevine._tcp.example.com";
+        let mut target = encode_name("seed").unwrap();
+        target.pop();
+        // "seed" followed by a pointer into the question's name at "example".
+        target.extend([0xc0, 12 + 16]);
+        let mut rdata = srv(10, 7946, "x");
+        rdata.truncate(6);
+        rdata.extend(&target);
+        let message = response(
+            7,
+            0,
+            name,
+            &[
+                (QUESTION_NAME.to_vec(), TYPE_SRV, rdata),
+                (target.clone(), TYPE_A, vec![10, 0, 0, 1]),
+                (target, TYPE_AAAA, Ipv6Addr::LOCALHOST.octets().to_vec()),
+            ],
+        );
+
+        let records = parse_response(&message, 7).unwrap().unwrap();
+        assert_eq!(
+            records,
+            vec![
+                Record::Srv {
+                    owner: name.into(),
+                    priority: 10,
+                    target: "seed.example.com".into(),
+                    port: 7946,
+                },
+                Record::Address {
+                    owner: "seed.example.com".into(),
+                    ip: IpAddr::from([10, 0, 0, 1]),
+                },
+                Record::Address {
+                    owner: "seed.example.com".into(),
+                    ip: IpAddr::from(Ipv6Addr::LOCALHOST),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn other_queries_and_missing_names_are_not_errors() {
+        let message = response(7, 0, "a.example", &[]);
+        assert_eq!(parse_response(&message, 8).unwrap(), None);
+
+        let message = response(7, RCODE_NXDOMAIN, "a.example", &[]);
+        assert_eq!(parse_response(&message, 7).unwrap(), Some(Vec::new()));
+
+        let message = response(7, 2, "a.example", &[]);
+        assert!(parse_response(&message, 7).is_err(), "server failure");
+    }
+
+    #[test]
+    fn rejects_truncated_and_looping_messages() {
+        let message = response(
+            7,
+            0,
+            "a.example",
+            &[(QUESTION_NAME.to_vec(), TYPE_A, vec![1, 2, 3, 4])],
+        );
+        for len in [5, 20, message.len() - 1] {
+            assert!(parse_response(&message[..len], 7).is_err(), "{len}");
+        }
+
+        let mut looping = response(7, 0, "a.example", &[]);
+        looping.truncate(12);
+        looping.extend([0xc0, 12]);
+        assert!(parse_response(&looping, 7).is_err());
+    }
+}
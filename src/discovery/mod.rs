@@ -0,0 +1,136 @@
+//! Peer discovery providers.
+//!
+//! Besides its static bootstrap peers and the peer lists its peers send it, a
+//! node can find peers through [`Discovery`] providers. The gossip engine
+//! polls each provider every [`DiscoveryConfig::interval`] and hands the
+//! addresses it returns to the connection manager, which dials them while the
+//! node is below its target peer count, with the same backoff as any other
+//! candidate (see [`ReconnectConfig`](crate::ReconnectConfig)). Three ship with
+//! the crate, each enabled by its part of `NodeConfig::discovery`:
+//!
+//! - [`SeedFile`]: a file listing peer addresses, one per line, reloaded when
+//!   it changes. Operators or orchestration can rewrite it while nodes run.
+//! - [`DnsSeed`]: the SRV records of a seed name, or its A and AAAA records
+//!   when it has no SRV records, asked of a nameserver over UDP.
+//! - [`Multicast`]: announcements sent to, and heard from, a UDP multicast
+//!   group (or broadcast address) on the local network.
+//!
+//! Applications add their own providers with `Node::add_discovery`. A provider
+//! returns canonical (listening) addresses; addresses that turn out to be
+//! unreachable are dropped after `reconnect.max_attempts` failures, and come
+//! back if the provider returns them again.
+
+pub mod dns;
+pub mod multicast;
+pub mod seed_file;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub use dns::{DnsConfig, DnsSeed};
+use futures::future::BoxFuture;
+pub use multicast::{Multicast, MulticastConfig};
+pub use seed_file::SeedFile;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// A source of peer addresses, polled by the gossip engine.
+pub trait Discovery: Send + Sync {
+    /// A short name for logs.
+    fn name(&self) -> &'static str;
+
+    /// The canonical addresses of peers found since the last poll, or all
+    /// currently known; duplicates and this node's own address are ignored.
+    /// `local_addr` is the address this node listens on.
+    fn discover(&self, local_addr: SocketAddr) -> BoxFuture<'_, Result<Vec<SocketAddr>>>;
+}
+
+/// Peer discovery configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveryConfig {
+    /// How often each provider is polled
+    pub interval: Duration,
+
+    /// File listing peer addresses, reloaded when it changes
+    pub seed_file: Option<PathBuf>,
+
+    /// DNS name whose SRV, A or AAAA records list peers
+    pub dns: Option<DnsConfig>,
+
+    /// Multicast group, or broadcast address, peers announce themselves on
+    pub multicast: Option<MulticastConfig>,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            seed_file: None,
+            dns: None,
+            multicast: None,
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    /// Validate the configuration.
+    ///
+    /// # Errors
+    /// Returns a description of the first invalid field.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.interval.is_zero() {
+            return Err("discovery interval must be > 0".into());
+        }
+        if self
+            .seed_file
+            .as_ref()
+            .is_some_and(|path| path.as_os_str().is_empty())
+        {
+            return Err("discovery seed_file must not be empty".into());
+        }
+        if let Some(dns) = &self.dns {
+            dns.validate()?;
+        }
+        if let Some(multicast) = &self.multicast {
+            multicast.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Whether any provider is configured.
+    pub fn is_enabled(&self) -> bool {
+        self.seed_file.is_some() || self.dns.is_some() || self.multicast.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_rejects_bad_values() {
+        let config = DiscoveryConfig::default();
+        assert!(config.validate().is_ok());
+        assert!(!config.is_enabled());
+
+        let bad = [
+            DiscoveryConfig {
+                interval: Duration::ZERO,
+                ..DiscoveryConfig::default()
+            },
+            DiscoveryConfig {
+                seed_file: Some(PathBuf::new()),
+                ..DiscoveryConfig::default()
+            },
+            DiscoveryConfig {
+                dns: Some(DnsConfig::new("", 7946)),
+                ..DiscoveryConfig::default()
+            },
+        ];
+        for config in bad {
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Peers announcing themselves on the local network.
+//!
+//! Every poll, a [`Multicast`] provider sends one announcement to its group
+//! and returns the peers whose announcements arrived since the last poll, so
+//! two nodes on one network find each other within one discovery interval.
+//! An announcement carries the sender's listening address; one listening on
+//! an unspecified address (`0.0.0.0` or `::`) is reached at the address the
+//! datagram came from. Each provider tags its announcements with a random
+//! instance id, so it ignores its own when the group loops them back.
+//!
+//! The group is usually an IPv4 or IPv6 multicast address, joined on
+//! [`MulticastConfig::interface`]; an IPv4 address that is not multicast is
+//! treated as a broadcast address. The socket is bound with `SO_REUSEADDR`, so
+//! several nodes on one host share the port. Announcements are not signed: a
+//! forged one only makes the node dial an address, which the connection
+//! manager stops dialing after `reconnect.max_attempts` failures.
+
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+use super::Discovery;
+use crate::{Error, Result};
+
+/// First bytes of every announcement.
+const MAGIC: &[u8; 4] = b"GRPV";
+
+/// Announcement format version.
+const VERSION: u8 = 1;
+
+/// Largest announcement: magic, version, instance id, family, IPv6, port.
+const MAX_ANNOUNCEMENT_LEN: usize = 4 + 1 + 8 + 1 + 16 + 2;
+
+/// Multicast discovery configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MulticastConfig {
+    /// Group (or IPv4 broadcast address) and port to announce on
+    pub group: SocketAddr,
+
+    /// IPv4 interface to join the group on and send from; unspecified lets
+    /// the system choose
+    pub interface: Ipv4Addr,
+
+    /// Hops announcements may travel; 1 keeps them on the local network
+    pub ttl: u32,
+}
+
+impl Default for MulticastConfig {
+    fn default() -> Self {
+        Self {
+            group: SocketAddr::from(([239, 255, 42, 99], 7947)),
+            interface: Ipv4Addr::UNSPECIFIED,
+            ttl: 1,
+        }
+    }
+}
+
+impl MulticastConfig {
+    /// Validate the configuration.
+    ///
+    /// # Errors
+    /// Returns a description of the first invalid field.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.group.port() == 0 {
+            return Err("discovery multicast group port must be > 0".into());
+        }
+        match self.group.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => {
+                return Err("discovery multicast group must not be unspecified".into());
+            }
+            IpAddr::V6(ip) if !ip.is_multicast() => {
+                return Err("discovery multicast IPv6 group must be a multicast address".into());
+            }
+            _ => {}
+        }
+        if self.ttl == 0 {
+            return Err("discovery multicast ttl must be > 0".into());
+        }
+        Ok(())
+    }
+}
+
+/// Peers announcing themselves to a multicast group.
+#[derive(Debug)]
+pub struct Multicast {
+    socket: UdpSocket,
+    group: SocketAddr,
+
+    /// Tags this provider's own announcements
+    instance: u64,
+}
+
+impl Multicast {
+    /// Join the group `config` names. Must be called within a Tokio runtime.
+    ///
+    /// # Errors
+    /// Returns an error if the socket cannot be bound or the group joined.
+    pub fn bind(config: &MulticastConfig) -> Result<Self> {
+        let group = config.group;
+        let socket = Self::socket(config)
+            .map_err(|e| Error::network_with_source(format!("cannot join {group}"), e))?;
+        Ok(Self {
+            socket: UdpSocket::from_std(socket.into())?,
+            group,
+            instance: rand::random(),
+        })
+    }
+
+    fn socket(config: &MulticastConfig) -> io::Result<Socket> {
+        let group = config.group;
+        let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        let bind = match group.ip() {
+            IpAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())),
+            IpAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, group.port())),
+        };
+        socket.bind(&bind.into())?;
+        match group.ip() {
+            IpAddr::V4(ip) if ip.is_multicast() => {
+                socket.join_multicast_v4(&ip, &config.interface)?;
+                if !config.interface.is_unspecified() {
+                    socket.set_multicast_if_v4(&config.interface)?;
+                }
+                socket.set_multicast_loop_v4(true)?;
+                socket.set_multicast_ttl_v4(config.ttl)?;
+            }
+            IpAddr::V4(_) => {
+                socket.set_broadcast(true)?;
+                socket.set_ttl_v4(config.ttl)?;
+            }
+            IpAddr::V6(ip) => {
+                socket.join_multicast_v6(&ip, 0)?;
+                socket.set_multicast_loop_v6(true)?;
+                socket.set_multicast_hops_v6(config.ttl)?;
+            }
+        }
+        Ok(socket)
+    }
+
+    /// The peers whose announcements are waiting on the socket.
+    fn heard(&self, local_addr: SocketAddr) -> Vec<SocketAddr> {
+        let mut found = HashSet::new();
+        // One byte spare, so a longer datagram cannot pass for an announcement.
+        let mut buf = [0; MAX_ANNOUNCEMENT_LEN + 1];
+        loop {
+            match self.socket.try_recv_from(&mut buf) {
+                Ok((len, source)) => {
+                    if let Some((instance, addr)) = decode(&buf[..len])
+                        && instance != self.instance
+                    {
+                        let addr = if addr.ip().is_unspecified() {
+                            SocketAddr::new(source.ip(), addr.port())
+                        } else {
+                            addr
+                        };
+                        if addr != local_addr {
+                            found.insert(addr);
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("Multicast discovery receive failed: {e}");
+                    break;
+                }
+            }
+        }
+        found.into_iter().collect()
+    }
+}
+
+impl Discovery for Multicast {
+    fn name(&self) -> &'static str {
+        "multicast"
+    }
+
+    fn discover(&self, local_addr: SocketAddr) -> BoxFuture<'_, Result<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            let found = self.heard(local_addr);
+            self.socket
+                .send_to(&encode(self.instance, local_addr), self.group)
+                .await?;
+            Ok(found)
+        })
+    }
+}
+
+fn encode(instance: u64, addr: SocketAddr) -> Vec<u8> {
+    let mut announcement = Vec::with_capacity(MAX_ANNOUNCEMENT_LEN);
+    announcement.extend_from_slice(MAGIC);
+    announcement.push(VERSION);
+    announcement.extend_from_slice(&instance.to_be_bytes());
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            announcement.push(4);
+            announcement.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            announcement.push(6);
+            announcement.extend_from_slice(&ip.octets());
+        }
+    }
+    announcement.extend_from_slice(&addr.port().to_be_bytes());
+    announcement
+}
+
+/// The instance id and listening address in an announcement; `None` if it
+/// is not one.
+fn decode(announcement: &[u8]) -> Option<(u64, SocketAddr)> {
+    let rest = announcement.strip_prefix(MAGIC)?;
+    let (&version, rest) = rest.split_first()?;
+    if version != VERSION {
+        return None;
+    }
+    let (instance, rest) = rest.split_first_chunk::<8>()?;
+    let (&family, rest) = rest.split_first()?;
+    let (ip, rest) = match family {
+        4 => {
+            let (octets, rest) = rest.split_first_chunk::<4>()?;
+            (IpAddr::from(*octets), rest)
+        }
+        6 => {
+            let (octets, rest) = rest.split_first_chunk::<16>()?;
+            (IpAddr::from(*octets), rest)
+        }
+        _ => return None,
+    };
+    let port: [u8; 2] = rest.try_into().ok()?;
+    Some((
+        u64::from_be_bytes(*instance),
+        SocketAddr::new(ip, u16::from_be_bytes(port)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announcements_round_trip() {
+        for addr in [
+            SocketAddr::from(([10, 0, 0, 1], 7946)),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, 7946)),
+        ] {
+            let announcement = encode(42, addr);
+            assert!(announcement.len() <= MAX_ANNOUNCEMENT_LEN);
+            assert_eq!(decode(&announcement), Some((42, addr)));
+        }
+    }
+
+    #[test]
+    fn rejects_other_datagrams() {
+        let announcement = encode(42, SocketAddr::from(([10, 0, 0, 1], 7946)));
+        assert_eq!(decode(&announcement[..announcement.len() - 1]), None);
+        assert_eq!(decode(&[announcement.as_slice(), &[0]].concat()), None);
+        assert_eq!(decode(b"hello"), None);
+
+        let mut other_version = announcement.clone();
+        other_version[4] = VERSION + 1;
+        assert_eq!(decode(&other_version), None);
+    }
+
+    #[test]
+    fn validate_rejects_bad_groups() {
+        assert!(MulticastConfig::default().validate().is_ok());
+        for group in [
+            SocketAddr::from(([239, 255, 42, 99], 0)),
+            SocketAddr::from(([0, 0, 0, 0], 7947)),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, 7947)),
+        ] {
+            let config = MulticastConfig {
+                group,
+                ..MulticastConfig::default()
+            };
+            assert!(config.validate().is_err(), "{group}");
+        }
+    }
+}
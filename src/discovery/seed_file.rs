@@ -0,0 +1,130 @@
+//! Peers listed in a file.
+//!
+//! The file holds one address per line, as `ip:port` or `host:port`; blank
+//! lines and text after a `#` are ignored. The file is read again only when
+//! its modification time or length changes, so it can be rewritten while the
+//! node runs, but host names are resolved on every poll, so a name that moves
+//! is followed. A line that does not parse or resolve is skipped with a
+//! warning rather than failing the whole file.
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::SystemTime;
+
+use futures::future::BoxFuture;
+use tracing::{debug, warn};
+
+use super::Discovery;
+use crate::{Error, Result};
+
+/// A file's modification time and length, standing in for its contents.
+type Version = (Option<SystemTime>, u64);
+
+/// Peers listed in a file, reloaded when it changes.
+#[derive(Debug)]
+pub struct SeedFile {
+    path: PathBuf,
+
+    /// The entries last read, and the version of the file they came from
+    cached: Mutex<Option<(Version, Vec<String>)>>,
+}
+
+impl SeedFile {
+    /// Read peers from the file at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// The file's path.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The file's entries, read again if it changed since the last call.
+    async fn entries(&self) -> Result<Vec<String>> {
+        let metadata = tokio::fs::metadata(&self.path).await.map_err(|e| {
+            Error::network_with_source(format!("cannot read {}", self.path.display()), e)
+        })?;
+        let version = (metadata.modified().ok(), metadata.len());
+        if let Some((cached, entries)) = lock(&self.cached).as_ref()
+            && *cached == version
+        {
+            return Ok(entries.clone());
+        }
+
+        let contents = tokio::fs::read_to_string(&self.path).await.map_err(|e| {
+            Error::network_with_source(format!("cannot read {}", self.path.display()), e)
+        })?;
+        let entries = parse(&contents);
+        debug!(
+            "Loaded {} seed(s) from {}",
+            entries.len(),
+            self.path.display()
+        );
+        *lock(&self.cached) = Some((version, entries.clone()));
+        Ok(entries)
+    }
+}
+
+impl Discovery for SeedFile {
+    fn name(&self) -> &'static str {
+        "seed file"
+    }
+
+    fn discover(&self, _local_addr: SocketAddr) -> BoxFuture<'_, Result<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            let mut found = HashSet::new();
+            for entry in self.entries().await? {
+                if let Ok(addr) = entry.parse::<SocketAddr>() {
+                    found.insert(addr);
+                    continue;
+                }
+                match tokio::net::lookup_host(entry.as_str()).await {
+                    Ok(addrs) => found.extend(addrs),
+                    Err(e) => warn!("Skipping seed {entry:?} in {}: {e}", self.path.display()),
+                }
+            }
+            Ok(found.into_iter().collect())
+        })
+    }
+}
+
+/// The entries in a seed file's `contents`.
+fn parse(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_one_entry_per_line_skipping_comments() {
+        let contents = "\
+# seeds
+10.0.0.1:7946
+  seed.example.com:7946   # the DNS seed
+
+[::1]:7946
+";
+        assert_eq!(
+            parse(contents),
+            vec!["10.0.0.1:7946", "seed.example.com:7946", "[::1]:7946"]
+        );
+        assert!(parse("").is_empty());
+    }
+}
@@ -35,6 +35,11 @@ pub enum Error {
     #[error("Serialization error: {0}")]
     Serialization(#[from] bincode::error::EncodeError),
 
+    /// Encoding a message with a non-bincode [`WireEncoding`](crate::WireEncoding)
+    /// failed.
+    #[error("Encoding error: {0}")]
+    Encoding(String),
+
     /// Deserialization error.
     #[error("Deserialization failed: {0}")]
     Deserialization(String),
@@ -74,6 +79,24 @@ pub enum Error {
     #[error("Origin {0} is pinned to a different key (possible spoofing)")]
     OriginKeyMismatch(SocketAddr),
 
+    /// A message claiming the origin was signed with a key that has been
+    /// revoked.
+    #[error("Message claiming origin {0} is signed with a revoked key")]
+    KeyRevoked(SocketAddr),
+
+    /// A retraction named a message this node did not broadcast.
+    #[error("Cannot retract {0}: it is not a broadcast from this node")]
+    NotOwnBroadcast(crate::MessageId),
+
+    /// A key-value store write targeted a key holding a different type of
+    /// CRDT.
+    #[error("Key {0:?} holds a different type of value")]
+    KvTypeMismatch(String),
+
+    /// An aggregation contribution was NaN or infinite.
+    #[error("Contribution to metric {0:?} must be finite")]
+    NonFiniteContribution(String),
+
     /// Internal error.
     #[error("Internal error: {0}")]
     Internal(String),
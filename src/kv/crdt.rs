@@ -0,0 +1,393 @@
+//! State-based CRDTs held under the replicated map's keys.
+//!
+//! Each type is a join-semilattice: `merge` is commutative, associative, and
+//! idempotent. Replicas that have merged the same updates therefore hold the
+//! same state, whatever order the updates arrived in and however often. An
+//! update is expressed as a delta: a small state of the same type that merges
+//! into the full one.
+//!
+//! Replicas are identified by their node's canonical address.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::HlcTimestamp;
+
+/// A last-writer-wins register.
+///
+/// The write with the latest hybrid logical clock stamp wins; equal stamps are
+/// broken by writer address, then by value, so every replica picks the same
+/// winner.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwRegister {
+    value: Bytes,
+    stamp: HlcTimestamp,
+    writer: SocketAddr,
+}
+
+impl LwwRegister {
+    /// A register written with `value` by `writer` at `stamp`.
+    pub fn new(value: Bytes, stamp: HlcTimestamp, writer: SocketAddr) -> Self {
+        Self {
+            value,
+            stamp,
+            writer,
+        }
+    }
+
+    /// The winning value.
+    pub fn value(&self) -> &Bytes {
+        &self.value
+    }
+
+    /// When the winning value was written.
+    pub fn stamp(&self) -> HlcTimestamp {
+        self.stamp
+    }
+
+    /// The replica that wrote the winning value.
+    pub fn writer(&self) -> SocketAddr {
+        self.writer
+    }
+
+    /// Keep whichever of the two writes wins.
+    pub fn merge(&mut self, other: &Self) {
+        let rank = |register: &Self| (register.stamp, register.writer, register.value.clone());
+        if rank(other) > rank(self) {
+            *self = other.clone();
+        }
+    }
+}
+
+/// Uniquely identifies one addition to an [`OrSet`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Tag {
+    /// The replica that made the addition.
+    pub replica: SocketAddr,
+
+    /// When it was made. A replica's clock never repeats a stamp, so the pair
+    /// is unique.
+    pub stamp: HlcTimestamp,
+}
+
+/// An observed-remove set: additions win over concurrent removals.
+///
+/// Every addition is tagged. A removal deletes only the tags its replica had
+/// observed, so an addition made concurrently elsewhere survives it. Removed
+/// tags are remembered so that a delayed copy of the addition cannot revive
+/// the element.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrSet {
+    /// Each present element's live tags.
+    added: BTreeMap<Bytes, BTreeSet<Tag>>,
+
+    /// Tags removed, from any element.
+    removed: BTreeSet<Tag>,
+}
+
+impl OrSet {
+    /// An empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `element` under the fresh `tag`, returning the delta.
+    pub fn add(&mut self, element: Bytes, tag: Tag) -> Self {
+        let delta = Self {
+            added: BTreeMap::from([(element, BTreeSet::from([tag]))]),
+            removed: BTreeSet::new(),
+        };
+        self.merge(&delta);
+        delta
+    }
+
+    /// Remove `element`, returning the delta, or `None` if it is not present.
+    pub fn remove(&mut self, element: &[u8]) -> Option<Self> {
+        let tags = self.added.get(element)?.clone();
+        let delta = Self {
+            added: BTreeMap::new(),
+            removed: tags,
+        };
+        self.merge(&delta);
+        Some(delta)
+    }
+
+    /// Whether `element` is present.
+    pub fn contains(&self, element: &[u8]) -> bool {
+        self.added.contains_key(element)
+    }
+
+    /// The elements present.
+    pub fn elements(&self) -> BTreeSet<Bytes> {
+        self.added.keys().cloned().collect()
+    }
+
+    /// Take the union of both sets' additions and removals.
+    pub fn merge(&mut self, other: &Self) {
+        self.removed.extend(other.removed.iter().copied());
+        for (element, tags) in &other.added {
+            self.added
+                .entry(element.clone())
+                .or_default()
+                .extend(tags.iter().copied());
+        }
+        let removed = &self.removed;
+        self.added.retain(|_, tags| {
+            tags.retain(|tag| !removed.contains(tag));
+            !tags.is_empty()
+        });
+    }
+}
+
+/// A counter that can be incremented and decremented.
+///
+/// Each replica counts its own increments and decrements; merging keeps the
+/// larger count per replica, and the value is the difference of the totals.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PnCounter {
+    increments: BTreeMap<SocketAddr, u64>,
+    decrements: BTreeMap<SocketAddr, u64>,
+}
+
+impl PnCounter {
+    /// A counter at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `by`, which may be negative, on behalf of `replica`, returning the
+    /// delta.
+    ///
+    /// A replica's counts must only grow. A node that restarts at the same
+    /// address must merge the cluster's state before counting again, or its
+    /// new counts are hidden behind its old ones.
+    pub fn add(&mut self, replica: SocketAddr, by: i64) -> Self {
+        let counts = if by < 0 {
+            &mut self.decrements
+        } else {
+            &mut self.increments
+        };
+        let count = counts.entry(replica).or_default();
+        *count = count.saturating_add(by.unsigned_abs());
+
+        let mut delta = Self::new();
+        let counts = if by < 0 {
+            &mut delta.decrements
+        } else {
+            &mut delta.increments
+        };
+        counts.insert(replica, *count);
+        delta
+    }
+
+    /// The counter's value, saturating at the bounds of `i64`.
+    pub fn value(&self) -> i64 {
+        let total = |counts: &BTreeMap<SocketAddr, u64>| -> i128 {
+            counts.values().map(|&count| i128::from(count)).sum()
+        };
+        let value = total(&self.increments) - total(&self.decrements);
+        i64::try_from(value).unwrap_or(if value < 0 { i64::MIN } else { i64::MAX })
+    }
+
+    /// Keep the larger count per replica.
+    pub fn merge(&mut self, other: &Self) {
+        for (ours, theirs) in [
+            (&mut self.increments, &other.increments),
+            (&mut self.decrements, &other.decrements),
+        ] {
+            for (&replica, &count) in theirs {
+                let ours = ours.entry(replica).or_default();
+                *ours = (*ours).max(count);
+            }
+        }
+    }
+}
+
+/// The CRDT held under one key.
+///
+/// A key's type is set by its first write. Should replicas nonetheless write
+/// different types to one key concurrently, merging keeps the type declared
+/// later here, so they still converge.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Crdt {
+    /// A last-writer-wins register.
+    Register(LwwRegister),
+    /// An observed-remove set.
+    Set(OrSet),
+    /// A positive-negative counter.
+    Counter(PnCounter),
+}
+
+impl Crdt {
+    /// The current value.
+    pub fn value(&self) -> Value {
+        match self {
+            Self::Register(register) => Value::Register(register.value().clone()),
+            Self::Set(set) => Value::Set(set.elements()),
+            Self::Counter(counter) => Value::Counter(counter.value()),
+        }
+    }
+
+    /// Merge `other` into this state.
+    pub fn merge(&mut self, other: &Self) {
+        match (&mut *self, other) {
+            (Self::Register(ours), Self::Register(theirs)) => ours.merge(theirs),
+            (Self::Set(ours), Self::Set(theirs)) => ours.merge(theirs),
+            (Self::Counter(ours), Self::Counter(theirs)) => ours.merge(theirs),
+            _ => {
+                if other.rank() > self.rank() {
+                    *self = other.clone();
+                }
+            }
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Register(_) => 0,
+            Self::Set(_) => 1,
+            Self::Counter(_) => 2,
+        }
+    }
+}
+
+/// The value held under a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// A register's winning value.
+    Register(Bytes),
+    /// A set's elements.
+    Set(BTreeSet<Bytes>),
+    /// A counter's value.
+    Counter(i64),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn stamp(physical: u64) -> HlcTimestamp {
+        HlcTimestamp {
+            physical,
+            logical: 0,
+        }
+    }
+
+    fn tag(port: u16, physical: u64) -> Tag {
+        Tag {
+            replica: addr(port),
+            stamp: stamp(physical),
+        }
+    }
+
+    /// Merging every ordering of `updates` into `initial` gives one state.
+    fn converges<T: Clone + PartialEq + std::fmt::Debug>(
+        initial: &T,
+        updates: &[T],
+        merge: impl Fn(&mut T, &T),
+    ) {
+        let mut forward = initial.clone();
+        let mut backward = initial.clone();
+        for update in updates {
+            merge(&mut forward, update);
+        }
+        for update in updates.iter().rev() {
+            merge(&mut backward, update);
+            merge(&mut backward, update);
+        }
+        assert_eq!(forward, backward);
+    }
+
+    #[test]
+    fn register_keeps_the_latest_write() {
+        let mut register = LwwRegister::new(Bytes::from("old"), stamp(1), addr(2));
+        register.merge(&LwwRegister::new(Bytes::from("new"), stamp(2), addr(1)));
+        assert_eq!(register.value(), "new");
+
+        register.merge(&LwwRegister::new(Bytes::from("stale"), stamp(1), addr(3)));
+        assert_eq!(register.value(), "new");
+    }
+
+    #[test]
+    fn register_breaks_ties_by_writer() {
+        let writes = [
+            LwwRegister::new(Bytes::from("a"), stamp(5), addr(1)),
+            LwwRegister::new(Bytes::from("b"), stamp(5), addr(2)),
+        ];
+        converges(&writes[0], &writes, LwwRegister::merge);
+
+        let mut register = writes[1].clone();
+        register.merge(&writes[0]);
+        assert_eq!(register.value(), "b");
+    }
+
+    #[test]
+    fn set_addition_survives_a_concurrent_removal() {
+        let mut a = OrSet::new();
+        a.add(Bytes::from("x"), tag(1, 1));
+        let mut b = a.clone();
+
+        let removal = a.remove(b"x").unwrap();
+        let readd = b.add(Bytes::from("x"), tag(2, 2));
+
+        a.merge(&readd);
+        b.merge(&removal);
+        assert_eq!(a, b);
+        assert!(a.contains(b"x"));
+    }
+
+    #[test]
+    fn set_removal_is_not_undone_by_a_late_addition() {
+        let mut set = OrSet::new();
+        let addition = set.add(Bytes::from("x"), tag(1, 1));
+        set.remove(b"x").unwrap();
+
+        set.merge(&addition);
+        assert!(!set.contains(b"x"));
+        assert!(set.remove(b"x").is_none());
+    }
+
+    #[test]
+    fn set_merge_is_order_independent() {
+        let mut source = OrSet::new();
+        let updates = [
+            source.add(Bytes::from("x"), tag(1, 1)),
+            source.add(Bytes::from("y"), tag(2, 1)),
+            source.remove(b"x").unwrap(),
+            source.add(Bytes::from("x"), tag(1, 2)),
+        ];
+        converges(&OrSet::new(), &updates, OrSet::merge);
+    }
+
+    #[test]
+    fn counter_sums_every_replicas_counts() {
+        let mut a = PnCounter::new();
+        let mut b = PnCounter::new();
+        let deltas = [a.add(addr(1), 5), a.add(addr(1), -2), b.add(addr(2), 10)];
+        for delta in &deltas {
+            a.merge(delta);
+            b.merge(delta);
+        }
+        assert_eq!(a.value(), 13);
+        assert_eq!(a, b);
+        converges(&PnCounter::new(), &deltas, PnCounter::merge);
+    }
+
+    #[test]
+    fn mismatched_types_converge() {
+        let register = Crdt::Register(LwwRegister::new(Bytes::from("r"), stamp(1), addr(1)));
+        let counter = Crdt::Counter(PnCounter::new().add(addr(2), 1));
+        converges(&register, &[counter.clone(), register.clone()], Crdt::merge);
+
+        let mut merged = register;
+        merged.merge(&counter);
+        assert_eq!(merged.value(), Value::Counter(1));
+    }
+}
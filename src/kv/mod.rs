@@ -0,0 +1,475 @@
+//! Replicated key-value store built on the gossip layer.
+//!
+//! Every node holds a replica of one map from string keys to state-based
+//! CRDTs (see [`crdt`]): last-writer-wins registers, observed-remove sets, and
+//! positive-negative counters. Writes apply to the local replica at once and
+//! never block on other nodes; replicas converge once they have seen the same
+//! updates, in any order.
+//!
+//! Updates travel two ways:
+//!
+//! - **Deltas.** A write is gossiped as a
+//!   [`Payload::KvDelta`](crate::Payload::KvDelta) carrying only the updated
+//!   key. It is a broadcast like any other: deduplicated, forwarded, retained,
+//!   and repaired by the message anti-entropy.
+//! - **Full-state sync.** Every [`KvConfig::sync_interval`], a node with a
+//!   non-empty store offers a digest of it
+//!   ([`Payload::KvDigest`](crate::Payload::KvDigest)) to
+//!   [`KvConfig::sync_fanout`] random peers. A peer whose store differs sends
+//!   its whole state back ([`Payload::KvState`](crate::Payload::KvState)), and
+//!   the node answers with its own if the peer is missing anything. This heals
+//!   what delta gossip cannot: updates expired from every message store, and
+//!   partitions that outlasted retention.
+//!
+//! Register writes are stamped by the node's hybrid logical clock, and a
+//! replica advances the clock past the stamp of every register it merges, so
+//! a write made after seeing another's wins over it even if the writer's wall
+//! clock lags. This holds for writers whose clocks lag by up to
+//! [`ClockConfig::max_drift`](crate::ClockConfig::max_drift): a stamp further
+//! ahead still merges, but does not move the clock. Full states are sent in a
+//! single frame, so the store must fit within
+//! [`NodeConfig::max_message_size`](crate::NodeConfig::max_message_size).
+//!
+//! The store is held in memory only. `1.1.0` peers are never sent its traffic.
+
+pub mod crdt;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use bytes::Bytes;
+pub use crdt::{Crdt, LwwRegister, OrSet, PnCounter, Tag, Value};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+use crate::{Error, Gossip, HybridClock, Result};
+
+/// The store's state: each key's CRDT, in key order so that equal states
+/// encode to equal bytes.
+type State = BTreeMap<String, Crdt>;
+
+/// FNV-1a offset basis.
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// FNV-1a prime.
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Replicated key-value store configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KvConfig {
+    /// How often a node offers its store digest to peers.
+    pub sync_interval: Duration,
+
+    /// Peers offered the digest each round.
+    pub sync_fanout: usize,
+
+    /// Changes buffered for each subscriber. A subscriber that falls further
+    /// behind skips the oldest (see [`broadcast::error::RecvError::Lagged`]).
+    pub subscriber_capacity: usize,
+}
+
+impl Default for KvConfig {
+    fn default() -> Self {
+        Self {
+            sync_interval: Duration::from_secs(10),
+            sync_fanout: 1,
+            subscriber_capacity: 256,
+        }
+    }
+}
+
+impl KvConfig {
+    /// Validate configuration.
+    ///
+    /// # Errors
+    /// Returns an error message if configuration is invalid.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.sync_interval.is_zero() {
+            return Err("kv sync_interval must be greater than 0".to_string());
+        }
+        if self.sync_fanout == 0 {
+            return Err("kv sync_fanout must be greater than 0".to_string());
+        }
+        if self.subscriber_capacity == 0 {
+            return Err("kv subscriber_capacity must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A key whose value changed, by a local write or an update from a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// The key.
+    pub key: String,
+
+    /// Its new value.
+    pub value: Value,
+}
+
+/// A node's handle to the replicated key-value store.
+///
+/// Obtained from [`Node::kv`](crate::Node::kv). Writes need the node to be
+/// started, since they are gossiped from its address.
+#[derive(Clone)]
+pub struct Kv {
+    gossip: Arc<Gossip>,
+}
+
+impl Kv {
+    pub(crate) fn new(gossip: Arc<Gossip>) -> Self {
+        Self { gossip }
+    }
+
+    /// The value under `key`, if any.
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.gossip.kv().get(key)
+    }
+
+    /// The CRDT under `key`, if any: the value with its merge metadata, such
+    /// as a register's stamp and writer.
+    pub fn entry(&self, key: &str) -> Option<Crdt> {
+        self.gossip.kv().entry(key)
+    }
+
+    /// Every key, in order.
+    pub fn keys(&self) -> Vec<String> {
+        self.gossip.kv().keys()
+    }
+
+    /// Receive every subsequent change to any key.
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.gossip.kv().subscribe()
+    }
+
+    /// Write `value` to the register under `key`, creating it if absent.
+    ///
+    /// # Errors
+    /// Returns [`Error::KvTypeMismatch`] if `key` holds a set or counter.
+    pub async fn put(&self, key: impl Into<String>, value: Bytes) -> Result<()> {
+        let writer = self.writer().await?;
+        let stamp = self.gossip.now();
+        self.update(key.into(), |entry| match entry {
+            None | Some(Crdt::Register(_)) => {
+                Ok(Some(Crdt::Register(LwwRegister::new(value, stamp, writer))))
+            }
+            Some(_) => Err(()),
+        })
+        .await
+    }
+
+    /// Add `element` to the set under `key`, creating it if absent.
+    ///
+    /// # Errors
+    /// Returns [`Error::KvTypeMismatch`] if `key` holds a register or counter.
+    pub async fn add(&self, key: impl Into<String>, element: Bytes) -> Result<()> {
+        let tag = Tag {
+            replica: self.writer().await?,
+            stamp: self.gossip.now(),
+        };
+        self.update(key.into(), |entry| match entry {
+            None => Ok(Some(Crdt::Set(OrSet::new().add(element, tag)))),
+            Some(Crdt::Set(set)) => Ok(Some(Crdt::Set(set.add(element, tag)))),
+            Some(_) => Err(()),
+        })
+        .await
+    }
+
+    /// Remove `element` from the set under `key`. Does nothing if it is not
+    /// present.
+    ///
+    /// # Errors
+    /// Returns [`Error::KvTypeMismatch`] if `key` holds a register or counter.
+    pub async fn remove(&self, key: impl Into<String>, element: &[u8]) -> Result<()> {
+        self.writer().await?;
+        self.update(key.into(), |entry| match entry {
+            None => Ok(None),
+            Some(Crdt::Set(set)) => Ok(set.remove(element).map(Crdt::Set)),
+            Some(_) => Err(()),
+        })
+        .await
+    }
+
+    /// Add `by`, which may be negative, to the counter under `key`, creating
+    /// it at zero if absent.
+    ///
+    /// # Errors
+    /// Returns [`Error::KvTypeMismatch`] if `key` holds a register or set.
+    pub async fn increment(&self, key: impl Into<String>, by: i64) -> Result<()> {
+        let writer = self.writer().await?;
+        self.update(key.into(), |entry| match entry {
+            None => Ok(Some(Crdt::Counter(PnCounter::new().add(writer, by)))),
+            Some(Crdt::Counter(counter)) => Ok(Some(Crdt::Counter(counter.add(writer, by)))),
+            Some(_) => Err(()),
+        })
+        .await
+    }
+
+    /// The address local writes are attributed to.
+    async fn writer(&self) -> Result<SocketAddr> {
+        self.gossip
+            .local_addr()
+            .await
+            .ok_or_else(|| Error::internal("No local address"))
+    }
+
+    /// Apply `write` to the local replica and gossip the delta it returns.
+    async fn update(
+        &self,
+        key: String,
+        write: impl FnOnce(Option<&mut Crdt>) -> std::result::Result<Option<Crdt>, ()>,
+    ) -> Result<()> {
+        if let Some(delta) = self.gossip.kv().update(key, write)? {
+            self.gossip.publish_delta(delta).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A node's replica of the store.
+#[derive(Debug)]
+pub(crate) struct Replica {
+    state: Mutex<State>,
+    changes: broadcast::Sender<Change>,
+
+    /// The clock local writes are stamped with, advanced past merged stamps
+    clock: Arc<HybridClock>,
+}
+
+impl Replica {
+    pub(crate) fn new(config: &KvConfig, clock: Arc<HybridClock>) -> Self {
+        let (changes, _) = broadcast::channel(config.subscriber_capacity);
+        Self {
+            state: Mutex::new(State::new()),
+            changes,
+            clock,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Value> {
+        self.lock().get(key).map(Crdt::value)
+    }
+
+    fn entry(&self, key: &str) -> Option<Crdt> {
+        self.lock().get(key).cloned()
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.lock().keys().cloned().collect()
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Apply a local write to `key`, returning the encoded delta to gossip, or
+    /// `None` if the write changed nothing. `write` receives the key's current
+    /// CRDT, may update it in place, and returns the delta, or `Err` if the
+    /// key holds the wrong type.
+    fn update(
+        &self,
+        key: String,
+        write: impl FnOnce(Option<&mut Crdt>) -> std::result::Result<Option<Crdt>, ()>,
+    ) -> Result<Option<Bytes>> {
+        let mut state = self.lock();
+        let before = state.get(&key).map(Crdt::value);
+        let delta = match write(state.get_mut(&key)) {
+            Ok(Some(delta)) => delta,
+            Ok(None) => return Ok(None),
+            Err(()) => return Err(Error::KvTypeMismatch(key)),
+        };
+        let encoded = encode(&State::from([(key.clone(), delta.clone())]))?;
+        let ours = state.entry(key.clone()).or_insert_with(|| delta.clone());
+        ours.merge(&delta);
+        self.notify(key, before, ours);
+        Ok(Some(encoded))
+    }
+
+    /// Merge a delta gossiped by a peer.
+    pub(crate) fn apply_delta(&self, delta: &[u8]) {
+        match decode(delta) {
+            Ok(delta) => self.merge(&mut self.lock(), delta),
+            Err(e) => warn!("Dropping undecodable key-value delta: {e}"),
+        }
+    }
+
+    /// Merge a peer's full state, returning whether the peer lacks anything
+    /// this replica holds.
+    pub(crate) fn merge_state(&self, state: &[u8]) -> Result<bool> {
+        let theirs = decode(state)?;
+        let mut ours = self.lock();
+        self.merge(&mut ours, theirs);
+        Ok(fnv1a(&encode(&ours)?) != fnv1a(state))
+    }
+
+    /// The encoded state.
+    pub(crate) fn state(&self) -> Result<Bytes> {
+        encode(&self.lock())
+    }
+
+    /// A digest of the encoded state: equal states have equal digests.
+    pub(crate) fn digest(&self) -> Result<u64> {
+        Ok(fnv1a(&self.state()?))
+    }
+
+    /// Merge `other` into `state`, telling subscribers of each key whose
+    /// value changed.
+    fn merge(&self, state: &mut State, other: State) {
+        for (key, theirs) in other {
+            // A later local write must outrank the one merged, whatever the
+            // two hosts' wall clocks say.
+            if let Crdt::Register(register) = &theirs
+                && self.clock.observe(register.stamp()).is_none()
+            {
+                debug!(
+                    "Not advancing the clock to {} from key {key:?}: beyond max drift",
+                    register.stamp()
+                );
+            }
+            let before = state.get(&key).map(Crdt::value);
+            let ours = state.entry(key.clone()).or_insert_with(|| theirs.clone());
+            ours.merge(&theirs);
+            self.notify(key, before, ours);
+        }
+    }
+
+    /// Tell subscribers of `key`'s new value if it differs from `before`.
+    fn notify(&self, key: String, before: Option<Value>, after: &Crdt) {
+        let value = after.value();
+        if before.as_ref() != Some(&value) {
+            debug!("Key {key:?} changed");
+            let _ = self.changes.send(Change { key, value });
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn encode(state: &State) -> Result<Bytes> {
+    Ok(bincode::serde::encode_to_vec(state, bincode::config::standard())?.into())
+}
+
+fn decode(bytes: &[u8]) -> Result<State> {
+    bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+        .map(|(state, _)| state)
+        .map_err(|e| Error::Deserialization(format!("key-value state: {e}")))
+}
+
+/// 64-bit FNV-1a: stable across builds, so nodes running different versions
+/// agree on digests.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HlcTimestamp;
+
+    fn register(value: &'static str, physical: u64) -> Crdt {
+        Crdt::Register(LwwRegister::new(
+            Bytes::from_static(value.as_bytes()),
+            HlcTimestamp {
+                physical,
+                logical: 0,
+            },
+            SocketAddr::from(([127, 0, 0, 1], 1)),
+        ))
+    }
+
+    fn replica() -> Replica {
+        Replica::new(
+            &KvConfig::default(),
+            Arc::new(HybridClock::new(Duration::from_secs(60))),
+        )
+    }
+
+    fn delta(key: &str, crdt: Crdt) -> Bytes {
+        encode(&State::from([(key.to_string(), crdt)])).unwrap()
+    }
+
+    #[test]
+    fn deltas_notify_subscribers_of_changed_values_only() {
+        let replica = replica();
+        let mut changes = replica.subscribe();
+
+        replica.apply_delta(&delta("k", register("new", 2)));
+        replica.apply_delta(&delta("k", register("old", 1)));
+        replica.apply_delta(&delta("k", register("new", 2)));
+
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            Change {
+                key: "k".to_string(),
+                value: Value::Register(Bytes::from_static(b"new")),
+            }
+        );
+        assert!(changes.try_recv().is_err());
+        assert_eq!(replica.get("k"), Some(register("new", 2).value()));
+    }
+
+    #[test]
+    fn merging_full_state_reports_what_the_sender_lacks() {
+        let a = replica();
+        let b = replica();
+        a.apply_delta(&delta("a", register("x", 1)));
+        b.apply_delta(&delta("b", register("y", 1)));
+
+        assert!(a.merge_state(&b.state().unwrap()).unwrap());
+        assert!(!b.merge_state(&a.state().unwrap()).unwrap());
+        assert_eq!(a.digest().unwrap(), b.digest().unwrap());
+        assert_eq!(a.keys(), ["a", "b"]);
+    }
+
+    #[test]
+    fn writes_after_a_merge_win_over_a_writer_whose_clock_runs_ahead() {
+        let replica = replica();
+        // The other writer's clock runs half a minute ahead of this host's.
+        let ahead = crate::core::hlc::wall_clock_ms() + 30_000;
+        replica.apply_delta(&delta("k", register("theirs", ahead)));
+
+        let stamp = replica.clock.now();
+        assert!(stamp.physical >= ahead);
+        let ours = Crdt::Register(LwwRegister::new(
+            Bytes::from_static(b"ours"),
+            stamp,
+            SocketAddr::from(([127, 0, 0, 1], 2)),
+        ));
+        replica.update("k".to_string(), |_| Ok(Some(ours))).unwrap();
+        assert_eq!(
+            replica.get("k"),
+            Some(Value::Register(Bytes::from_static(b"ours")))
+        );
+    }
+
+    #[test]
+    fn writes_to_a_key_of_another_type_are_refused() {
+        let replica = replica();
+        replica.apply_delta(&delta("k", register("x", 1)));
+
+        let result = replica.update("k".to_string(), |entry| match entry {
+            Some(Crdt::Counter(_)) => Ok(None),
+            _ => Err(()),
+        });
+        assert!(matches!(result, Err(Error::KvTypeMismatch(key)) if key == "k"));
+    }
+
+    #[test]
+    fn undecodable_state_is_rejected() {
+        let replica = replica();
+        assert!(replica.merge_state(&[0xff, 0xff]).is_err());
+        replica.apply_delta(&[0xff, 0xff]);
+        assert!(replica.is_empty());
+    }
+}
@@ -39,20 +39,41 @@
 #![warn(clippy::all)]
 
 pub mod core;
+pub mod discovery;
 pub mod error;
+pub mod kv;
 pub mod node;
 pub mod protocol;
 pub mod transport;
 
 pub use core::{
-    Identity, Message, MessageCodec, MessageId, Payload, Peer, PeerId, PeerInfo, PeerState,
-    RateLimitConfig, RateLimiter, Signature, authenticate, verify_message,
+    AggregateShare, Bincode, Budget, BudgetExceeded, Capabilities, ClassBudgets, ClockConfig,
+    CompressionConfig, ConnectionLimiter, Encoding, HlcTimestamp, HybridClock, Identity,
+    KeyRevocation, KeyRotation, Message, MessageCodec, MessageId, Payload, Peer, PeerAdvertisement,
+    PeerId, PeerInfo, PeerProtocol, PeerState, PinStore, Postcard, RateLimitConfig, RateLimiter,
+    Signature, TrafficClass, WireEncoding, authenticate, verify_message,
 };
 
+pub use discovery::{
+    Discovery, DiscoveryConfig, DnsConfig, DnsSeed, Multicast, MulticastConfig, SeedFile,
+};
 pub use error::Error;
+pub use kv::{Kv, KvConfig};
 pub use node::{Node, NodeConfig, NodeConfigBuilder};
-pub use protocol::{AntiEntropy, AntiEntropyConfig, EpidemicConfig, Gossip, MessageEntry};
-pub use transport::{Tcp, TransportConfig};
+pub use protocol::{
+    AclConfig, AclGrant, Action, AdaptiveConfig, AdaptiveStats, AggregationConfig, AntiEntropy,
+    AntiEntropyConfig, BroadcastReport, ConnectionStatus, Delivered, DeliveryConfig, DeliveryOrder,
+    DeliveryStats, EpidemicConfig, Estimate, EvictionPolicy, GapPolicy, Gossip, HealthWeighted,
+    MessageEntry, MessageStore, MessageStoreConfig, MessageStoreStats, PeerExchangeConfig,
+    PeerExchangeStats, PeerSelection, PeerSelector, Permissions, Reconciliation,
+    ReconnectCandidate, ReconnectConfig, ReplayConfig, Retracted, RttWeighted, SnapshotConfig,
+    SnapshotProgress, SnapshotState, Uniform, ZoneConfig,
+};
+pub use transport::{
+    Ban, BanReason, BanTarget, ConnectionLimitsConfig, Misbehaviour, MisbehaviourConfig,
+    OutboundConfig, OutboundQueue, OverflowPolicy, PeerOverflow, SendOutcome, Tcp, TrafficStats,
+    TransportConfig,
+};
 
 /// Result type alias for all operations.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -4,12 +4,18 @@ pub mod node_config;
 
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use bytes::Bytes;
 pub use node_config::{NodeConfig, NodeConfigBuilder};
 use tracing::trace;
 
-use crate::{Gossip, PeerId, Result};
+use crate::{
+    AclConfig, AdaptiveStats, Ban, BanTarget, BroadcastReport, ConnectionStatus, Delivered,
+    DeliveryStats, Discovery, Estimate, Gossip, HlcTimestamp, KeyRevocation, KeyRotation, Kv,
+    MessageId, MessageStoreStats, OverflowPolicy, PeerExchangeStats, PeerId, PeerProtocol,
+    Permissions, Result, Retracted, SnapshotProgress, TrafficStats,
+};
 
 /// A Grapevine gossip node.
 ///
@@ -95,6 +101,53 @@ impl Node {
         self.protocol.broadcast(data.into()).await
     }
 
+    /// Broadcast a message as [`Node::broadcast`] does, returning its id,
+    /// which [`Node::retract`] accepts.
+    pub async fn broadcast_id(&self, data: impl Into<Bytes>) -> Result<MessageId> {
+        self.protocol.broadcast_id(data.into()).await
+    }
+
+    /// Broadcast a message, reporting which peers of the fanout it was queued
+    /// for, which dropped it or failed, and which were skipped because they
+    /// cannot decode it.
+    ///
+    /// A producer can slow down when reports show drops. With
+    /// [`OverflowPolicy::Block`] configured in
+    /// [`NodeConfig::outbound`](crate::NodeConfig::outbound), or for a peer
+    /// with [`Node::set_peer_overflow`], this waits for room in full peer
+    /// queues instead, up to the policy's timeout.
+    pub async fn broadcast_with_report(&self, data: impl Into<Bytes>) -> Result<BroadcastReport> {
+        self.protocol.broadcast_with_report(data.into()).await
+    }
+
+    /// Broadcast a message that expires at `deadline`: once it passes, nodes
+    /// stop delivering, forwarding, and repairing it.
+    ///
+    /// The deadline is signed with the message and checked against each
+    /// node's wall clock. `1.1.0` peers are not sent expiring messages.
+    pub async fn broadcast_until(
+        &self,
+        data: impl Into<Bytes>,
+        deadline: SystemTime,
+    ) -> Result<MessageId> {
+        self.protocol.broadcast_until(data.into(), deadline).await
+    }
+
+    /// Retract one of this node's broadcasts cluster-wide.
+    ///
+    /// A signed tombstone is gossiped in its place: nodes stop forwarding and
+    /// repairing the message, drop it if it has not arrived yet, and call the
+    /// handler set by [`Node::on_retracted`]. A node that already delivered
+    /// the message is told, but the delivery is not undone.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NotOwnBroadcast`](crate::Error::NotOwnBroadcast) if `id`
+    /// is not a broadcast from this node.
+    pub async fn retract(&self, id: MessageId) -> Result<MessageId> {
+        self.protocol.retract(id).await
+    }
+
     /// Send a direct message to a specific peer.
     ///
     /// Unlike broadcast, direct messages are only delivered to the specified
@@ -105,6 +158,9 @@ impl Node {
     /// * `peer` - The recipient's socket address
     /// * `data` - The message payload
     ///
+    /// Under [`OverflowPolicy::Block`] this waits for room in the peer's
+    /// queue, up to the policy's timeout.
+    ///
     /// # Errors
     ///
     /// Returns an error if the peer is not connected or if sending fails.
@@ -123,6 +179,65 @@ impl Node {
         self.protocol.set_message_handler(handler);
     }
 
+    /// Set a handler for received application messages that also receives
+    /// each message's id and signed clock stamp (see [`Delivered`]).
+    ///
+    /// An alternative to [`Node::on_message`]: whichever is set first takes
+    /// effect.
+    pub async fn on_delivered<F>(&self, handler: F)
+    where
+        F: Fn(Delivered) + Send + Sync + 'static,
+    {
+        self.protocol.set_delivered_handler(handler);
+    }
+
+    /// Set a handler told when an origin retracts one of its broadcasts.
+    pub async fn on_retracted<F>(&self, handler: F)
+    where
+        F: Fn(Retracted) + Send + Sync + 'static,
+    {
+        self.protocol.set_retraction_handler(handler);
+    }
+
+    /// Set this node's contribution to the cluster-wide aggregate `metric`.
+    ///
+    /// Every node's estimate of the metric's average, sum, count, minimum and
+    /// maximum converges over a few gossip rounds (see
+    /// [`crate::protocol::aggregation`]). Calling it again replaces the
+    /// contribution.
+    ///
+    /// # Errors
+    /// Returns [`Error::NonFiniteContribution`](crate::Error::NonFiniteContribution)
+    /// if `value` is NaN or infinite.
+    pub fn contribute(&self, metric: &str, value: f64) -> Result<()> {
+        self.protocol.contribute(metric, value)
+    }
+
+    /// Stop contributing to `metric`. Estimates include the last contribution
+    /// until the next aggregation epoch.
+    pub fn withdraw(&self, metric: &str) {
+        self.protocol.withdraw(metric);
+    }
+
+    /// This node's current estimate of `metric` across the cluster, or `None`
+    /// if no contribution to it has reached this node.
+    pub fn estimate(&self, metric: &str) -> Option<Estimate> {
+        self.protocol.estimate(metric)
+    }
+
+    /// A handle to this node's replica of the key-value store (see
+    /// [`crate::kv`]).
+    pub fn kv(&self) -> Kv {
+        Kv::new(Arc::clone(&self.protocol))
+    }
+
+    /// A timestamp from this node's hybrid logical clock, ordered after every
+    /// stamp it has issued or received. Use it to stamp local writes that are
+    /// compared with received messages' stamps.
+    pub fn now(&self) -> HlcTimestamp {
+        self.protocol.now()
+    }
+
     /// Get the node's local address.
     pub async fn local_addr(&self) -> Option<SocketAddr> {
         self.protocol.local_addr().await
@@ -138,6 +253,164 @@ impl Node {
         self.protocol.peer_list().await
     }
 
+    /// Get the wire protocol a connected peer speaks.
+    ///
+    /// Returns the version and capabilities the peer advertised when the
+    /// connection opened, [`PeerProtocol::LEGACY`] for a peer that advertised
+    /// nothing (a v1.1.0 node), or `None` if `peer` is not connected.
+    pub fn peer_protocol(&self, peer: SocketAddr) -> Option<PeerProtocol> {
+        self.protocol.peer_protocol(peer)
+    }
+
+    /// The smoothed round-trip time to a connected peer, measured by ping and
+    /// pong on the heartbeat; `None` if the peer is not connected or not yet
+    /// measured.
+    pub fn peer_rtt(&self, peer: SocketAddr) -> Option<Duration> {
+        self.protocol.peer_rtt(peer)
+    }
+
+    /// The zone label a connected peer announced, or that peer exchange
+    /// reported for it (see [`ZoneConfig`](crate::ZoneConfig)); `None` if the
+    /// peer is not connected or its zone is not known.
+    pub fn peer_zone(&self, peer: SocketAddr) -> Option<String> {
+        self.protocol.peer_zone(peer)
+    }
+
+    /// Handle a full outbound queue for the peer listening at `peer` as
+    /// `overflow` says, in place of the default, from now on: on its current
+    /// connection and on any it makes later. See
+    /// [`OutboundConfig::peers`](crate::OutboundConfig::peers) to set it from
+    /// the start.
+    pub fn set_peer_overflow(&self, peer: SocketAddr, overflow: OverflowPolicy) {
+        self.protocol.set_peer_overflow(peer, overflow);
+    }
+
+    /// How many broadcasts are held back waiting for their turn, and how many
+    /// were released past a gap (see [`DeliveryConfig`](crate::DeliveryConfig)).
+    pub fn delivery_stats(&self) -> DeliveryStats {
+        self.protocol.delivery_stats()
+    }
+
+    /// How many message ids this node remembers for deduplication, and how
+    /// many messages (and bytes) it retains for forwarding.
+    pub fn message_store_stats(&self) -> MessageStoreStats {
+        self.protocol.message_store_stats()
+    }
+
+    /// The estimated cluster size, and the fanout and anti-entropy interval
+    /// in use (see [`AdaptiveConfig`](crate::AdaptiveConfig)).
+    pub fn adaptive_stats(&self) -> AdaptiveStats {
+        self.protocol.adaptive_stats()
+    }
+
+    /// Add a peer discovery provider, polled every discovery interval
+    /// alongside those configured in [`DiscoveryConfig`](crate::DiscoveryConfig).
+    ///
+    /// Providers are captured when the node starts, so this must be called
+    /// before [`Node::start`].
+    pub fn add_discovery(&self, discovery: impl Discovery + 'static) {
+        self.protocol.add_discovery(Arc::new(discovery));
+    }
+
+    /// Connected peers, and the bootstrap and lost peers this node redials
+    /// with backoff (see [`ReconnectConfig`](crate::ReconnectConfig)).
+    pub fn connection_status(&self) -> ConnectionStatus {
+        self.protocol.connection_status()
+    }
+
+    /// The size of the address book this node fills from peer exchange (see
+    /// [`PeerExchangeConfig`](crate::PeerExchangeConfig)).
+    pub fn peer_exchange_stats(&self) -> PeerExchangeStats {
+        self.protocol.peer_exchange_stats()
+    }
+
+    /// How far this node's snapshot transfer from a bootstrap peer has got
+    /// (see [`SnapshotConfig`](crate::SnapshotConfig)).
+    pub fn snapshot_progress(&self) -> SnapshotProgress {
+        self.protocol.snapshot_progress()
+    }
+
+    /// Bytes this node has sent to and received from peers, framing included,
+    /// since it was created.
+    pub fn traffic(&self) -> TrafficStats {
+        self.protocol.traffic()
+    }
+
+    /// Ban `target` for `duration`: connections from the address, or from
+    /// the peer signing with the key, are closed, refused and not dialed until
+    /// the ban lifts or [`Node::unban`] lifts it.
+    pub fn ban(&self, target: BanTarget, duration: Duration) {
+        self.protocol.ban(target, duration);
+    }
+
+    /// Lift the ban on `target`, returning whether there was one.
+    pub fn unban(&self, target: BanTarget) -> bool {
+        self.protocol.unban(target)
+    }
+
+    /// The bans in force: those placed with [`Node::ban`], and those peers
+    /// earned by misbehaving.
+    pub fn bans(&self) -> Vec<Ban> {
+        self.protocol.bans()
+    }
+
+    /// The publish policy in force: the configured one, as amended since.
+    pub fn acl(&self) -> AclConfig {
+        self.protocol.acl()
+    }
+
+    /// Replace the publish policy. Messages already accepted stay delivered.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Config`](crate::Error::Config) if the policy lists a
+    /// key twice.
+    pub fn set_acl(&self, config: AclConfig) -> Result<()> {
+        self.protocol.set_acl(config)
+    }
+
+    /// Grant `peer` `permissions` in place of the default, replacing any grant
+    /// it had.
+    pub fn grant(&self, peer: PeerId, permissions: Permissions) {
+        self.protocol.grant(peer, permissions);
+    }
+
+    /// Drop `peer`'s grant, leaving it the default permissions. Returns
+    /// whether it had one.
+    pub fn remove_grant(&self, peer: PeerId) -> bool {
+        self.protocol.remove_grant(peer)
+    }
+
+    /// Switch this node to a fresh key, returning the rotation, signed with
+    /// the old key, that tells peers to accept the new one.
+    ///
+    /// The rotation is flooded to the cluster, and any grant held by the old
+    /// key passes to the new one. `1.1.0` peers are never told, and reject
+    /// the node's messages from then on.
+    pub async fn rotate_key(&self) -> KeyRotation {
+        self.protocol.rotate_key().await
+    }
+
+    /// A statement revoking this node's current key, to be kept somewhere safe
+    /// and passed to [`Node::revoke_key`] should the key be stolen.
+    pub fn revocation_certificate(&self) -> KeyRevocation {
+        self.protocol.revocation_certificate()
+    }
+
+    /// Revoke a key with a statement it signed, returning whether it was new.
+    ///
+    /// The revocation is flooded to the cluster, and every node rejects
+    /// messages signed with the key from then on. This node keeps it for as
+    /// long as it runs, however many other revocations arrive. If it revokes
+    /// this node's own key, the node switches to a fresh key.
+    ///
+    /// # Errors
+    /// Returns [`Error::Crypto`](crate::Error::Crypto) if the revocation's
+    /// signature does not verify.
+    pub async fn revoke_key(&self, revocation: KeyRevocation) -> Result<bool> {
+        self.protocol.revoke_key(revocation).await
+    }
+
     /// Shutdown the node gracefully.
     ///
     /// This sends goodbye messages to all connected peers, stops all background
@@ -6,7 +6,13 @@ use std::time::Duration;
 use serde::{Deserialize, Serialize};
 
 use crate::core::message_codec::MAX_FRAME_SIZE;
-use crate::{AntiEntropyConfig, EpidemicConfig, Error, RateLimitConfig, Result, TransportConfig};
+use crate::{
+    AclConfig, AdaptiveConfig, AggregationConfig, AntiEntropyConfig, ClockConfig,
+    CompressionConfig, ConnectionLimitsConfig, DeliveryConfig, DiscoveryConfig, EpidemicConfig,
+    Error, KvConfig, MessageStoreConfig, MisbehaviourConfig, OutboundConfig, PeerExchangeConfig,
+    PeerSelection, RateLimitConfig, ReconnectConfig, ReplayConfig, Result, SnapshotConfig,
+    TransportConfig, WireEncoding, ZoneConfig,
+};
 
 const DEFAULT_BIND_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
 
@@ -46,6 +52,60 @@ pub struct NodeConfig {
     /// Message deduplication TTL (how long to remember seen messages)
     pub message_dedup_ttl: Duration,
 
+    /// Caps on the messages retained for forwarding
+    pub message_store: MessageStoreConfig,
+
+    /// Order in which broadcasts are delivered to the application
+    pub delivery: DeliveryConfig,
+
+    /// Hybrid logical clock stamping authored messages
+    pub clock: ClockConfig,
+
+    /// Replicated key-value store synchronization
+    pub kv: KvConfig,
+
+    /// Gossip aggregation of cluster-wide metrics
+    pub aggregation: AggregationConfig,
+
+    /// Snapshot state transfer when joining through bootstrap peers
+    pub snapshot: SnapshotConfig,
+
+    /// Fanout and anti-entropy interval adapted to the cluster's size and
+    /// observed repairs, within bounds
+    pub adaptive: AdaptiveConfig,
+
+    /// How fanout and anti-entropy rounds choose the peers to send to
+    pub peer_selection: PeerSelection,
+
+    /// This node's zone, and how strongly fanout and anti-entropy prefer peers
+    /// in it
+    pub zone: ZoneConfig,
+
+    /// Redialing bootstrap and lost peers, with backoff
+    pub reconnect: ReconnectConfig,
+
+    /// Seed file, DNS and multicast peer discovery
+    pub discovery: DiscoveryConfig,
+
+    /// Signed advertisements and the address book peer lists fill
+    pub peer_exchange: PeerExchangeConfig,
+
+    /// Per-address and per-subnet inbound limits, reserved outbound slots and
+    /// inbound eviction
+    pub connection_limits: ConnectionLimitsConfig,
+
+    /// Per-peer outbound queue size, and what a full queue does
+    pub outbound: OutboundConfig,
+
+    /// Misbehaviour scoring and temporary bans
+    pub misbehaviour: MisbehaviourConfig,
+
+    /// Freshness checks rejecting replayed control messages
+    pub replay: ReplayConfig,
+
+    /// Which keys may broadcast, send direct messages and relay
+    pub acl: AclConfig,
+
     /// Anti-entropy protocol configuration
     pub anti_entropy: AntiEntropyConfig,
 
@@ -55,6 +115,12 @@ pub struct NodeConfig {
     /// Rate limiting configuration
     pub rate_limit: RateLimitConfig,
 
+    /// Frame compression, negotiated per connection
+    pub compression: CompressionConfig,
+
+    /// Preferred wire encoding, negotiated per connection
+    pub encoding: WireEncoding,
+
     /// Transport protocol
     pub transport: TransportConfig,
 }
@@ -71,9 +137,28 @@ impl Default for NodeConfig {
             max_peers: 50,
             connection_timeout: Duration::from_secs(10),
             message_dedup_ttl: Duration::from_secs(300), // 5 minutes
+            message_store: MessageStoreConfig::default(),
+            delivery: DeliveryConfig::default(),
+            clock: ClockConfig::default(),
+            kv: KvConfig::default(),
+            aggregation: AggregationConfig::default(),
+            snapshot: SnapshotConfig::default(),
+            adaptive: AdaptiveConfig::default(),
+            peer_selection: PeerSelection::default(),
+            zone: ZoneConfig::default(),
+            reconnect: ReconnectConfig::default(),
+            discovery: DiscoveryConfig::default(),
+            peer_exchange: PeerExchangeConfig::default(),
+            connection_limits: ConnectionLimitsConfig::default(),
+            outbound: OutboundConfig::default(),
+            misbehaviour: MisbehaviourConfig::default(),
+            replay: ReplayConfig::default(),
+            acl: AclConfig::default(),
             anti_entropy: AntiEntropyConfig::default(),
             epidemic: EpidemicConfig::default(),
             rate_limit: RateLimitConfig::default(),
+            compression: CompressionConfig::default(),
+            encoding: WireEncoding::default(),
             transport: TransportConfig::Tcp,
         }
     }
@@ -119,6 +204,32 @@ impl NodeConfig {
         if self.rate_limit.enabled {
             self.rate_limit.validate().map_err(Error::Config)?;
         }
+        self.message_store.validate().map_err(Error::Config)?;
+        self.delivery.validate().map_err(Error::Config)?;
+        self.clock.validate().map_err(Error::Config)?;
+        self.kv.validate().map_err(Error::Config)?;
+        self.aggregation.validate().map_err(Error::Config)?;
+        self.snapshot.validate().map_err(Error::Config)?;
+        self.adaptive.validate().map_err(Error::Config)?;
+        if self.adaptive.enabled && self.adaptive.max_fanout > self.max_peers {
+            return Err(Error::Config(
+                "adaptive max_fanout cannot exceed max_peers".into(),
+            ));
+        }
+        self.zone.validate().map_err(Error::Config)?;
+        self.reconnect.validate().map_err(Error::Config)?;
+        self.discovery.validate().map_err(Error::Config)?;
+        self.peer_exchange.validate().map_err(Error::Config)?;
+        self.connection_limits.validate().map_err(Error::Config)?;
+        self.outbound.validate().map_err(Error::Config)?;
+        self.misbehaviour.validate().map_err(Error::Config)?;
+        self.replay.validate().map_err(Error::Config)?;
+        self.acl.validate().map_err(Error::Config)?;
+        if self.discovery.is_enabled() && !self.reconnect.enabled {
+            return Err(Error::Config(
+                "discovery requires reconnect to be enabled".into(),
+            ));
+        }
         Ok(())
     }
 }
@@ -139,9 +250,47 @@ struct NodeConfigUnchecked {
     max_peers: usize,
     connection_timeout: Duration,
     message_dedup_ttl: Duration,
+    #[serde(default)]
+    message_store: MessageStoreConfig,
+    #[serde(default)]
+    delivery: DeliveryConfig,
+    #[serde(default)]
+    clock: ClockConfig,
+    #[serde(default)]
+    kv: KvConfig,
+    #[serde(default)]
+    aggregation: AggregationConfig,
+    #[serde(default)]
+    snapshot: SnapshotConfig,
+    #[serde(default)]
+    adaptive: AdaptiveConfig,
+    #[serde(default)]
+    peer_selection: PeerSelection,
+    #[serde(default)]
+    zone: ZoneConfig,
+    #[serde(default)]
+    reconnect: ReconnectConfig,
+    #[serde(default)]
+    discovery: DiscoveryConfig,
+    #[serde(default)]
+    peer_exchange: PeerExchangeConfig,
+    #[serde(default)]
+    connection_limits: ConnectionLimitsConfig,
+    #[serde(default)]
+    outbound: OutboundConfig,
+    #[serde(default)]
+    misbehaviour: MisbehaviourConfig,
+    #[serde(default)]
+    replay: ReplayConfig,
+    #[serde(default)]
+    acl: AclConfig,
     anti_entropy: AntiEntropyConfig,
     epidemic: EpidemicConfig,
     rate_limit: RateLimitConfig,
+    #[serde(default)]
+    compression: CompressionConfig,
+    #[serde(default)]
+    encoding: WireEncoding,
     transport: TransportConfig,
 }
 
@@ -159,9 +308,28 @@ impl TryFrom<NodeConfigUnchecked> for NodeConfig {
             max_peers: raw.max_peers,
             connection_timeout: raw.connection_timeout,
             message_dedup_ttl: raw.message_dedup_ttl,
+            message_store: raw.message_store,
+            delivery: raw.delivery,
+            clock: raw.clock,
+            kv: raw.kv,
+            aggregation: raw.aggregation,
+            snapshot: raw.snapshot,
+            adaptive: raw.adaptive,
+            peer_selection: raw.peer_selection,
+            zone: raw.zone,
+            reconnect: raw.reconnect,
+            discovery: raw.discovery,
+            peer_exchange: raw.peer_exchange,
+            connection_limits: raw.connection_limits,
+            outbound: raw.outbound,
+            misbehaviour: raw.misbehaviour,
+            replay: raw.replay,
+            acl: raw.acl,
             anti_entropy: raw.anti_entropy,
             epidemic: raw.epidemic,
             rate_limit: raw.rate_limit,
+            compression: raw.compression,
+            encoding: raw.encoding,
             transport: raw.transport,
         };
         config.validate()?;
@@ -241,6 +409,108 @@ impl NodeConfigBuilder {
         self
     }
 
+    /// Set the caps on retained messages.
+    pub fn message_store(mut self, config: MessageStoreConfig) -> Self {
+        self.config.message_store = config;
+        self
+    }
+
+    /// Set the order in which broadcasts are delivered.
+    pub fn delivery(mut self, config: DeliveryConfig) -> Self {
+        self.config.delivery = config;
+        self
+    }
+
+    /// Set hybrid logical clock configuration.
+    pub fn clock(mut self, config: ClockConfig) -> Self {
+        self.config.clock = config;
+        self
+    }
+
+    /// Set replicated key-value store configuration.
+    pub fn kv(mut self, config: KvConfig) -> Self {
+        self.config.kv = config;
+        self
+    }
+
+    /// Set gossip aggregation configuration.
+    pub fn aggregation(mut self, config: AggregationConfig) -> Self {
+        self.config.aggregation = config;
+        self
+    }
+
+    /// Set snapshot state transfer configuration.
+    pub fn snapshot(mut self, config: SnapshotConfig) -> Self {
+        self.config.snapshot = config;
+        self
+    }
+
+    /// Set adaptive fanout and anti-entropy pacing configuration.
+    pub fn adaptive(mut self, config: AdaptiveConfig) -> Self {
+        self.config.adaptive = config;
+        self
+    }
+
+    /// Set how fanout and anti-entropy rounds choose peers.
+    pub fn peer_selection(mut self, selection: PeerSelection) -> Self {
+        self.config.peer_selection = selection;
+        self
+    }
+
+    /// Set zone-aware topology configuration.
+    pub fn zone(mut self, config: ZoneConfig) -> Self {
+        self.config.zone = config;
+        self
+    }
+
+    /// Set reconnection configuration.
+    pub fn reconnect(mut self, config: ReconnectConfig) -> Self {
+        self.config.reconnect = config;
+        self
+    }
+
+    /// Set peer discovery configuration.
+    pub fn discovery(mut self, config: DiscoveryConfig) -> Self {
+        self.config.discovery = config;
+        self
+    }
+
+    /// Set peer exchange configuration.
+    pub fn peer_exchange(mut self, config: PeerExchangeConfig) -> Self {
+        self.config.peer_exchange = config;
+        self
+    }
+
+    /// Set inbound connection limits configuration.
+    pub fn connection_limits(mut self, config: ConnectionLimitsConfig) -> Self {
+        self.config.connection_limits = config;
+        self
+    }
+
+    /// Set outbound queue configuration.
+    pub fn outbound(mut self, config: OutboundConfig) -> Self {
+        self.config.outbound = config;
+        self
+    }
+
+    /// Set misbehaviour scoring configuration.
+    pub fn misbehaviour(mut self, config: MisbehaviourConfig) -> Self {
+        self.config.misbehaviour = config;
+        self
+    }
+
+    /// Set replay protection configuration.
+    pub fn replay(mut self, config: ReplayConfig) -> Self {
+        self.config.replay = config;
+        self
+    }
+
+    /// Set publish access control configuration.
+    pub fn acl(mut self, config: AclConfig) -> Self {
+        self.config.acl = config;
+        self
+    }
+
     /// Set anti-entropy configuration.
     pub fn anti_entropy(mut self, config: AntiEntropyConfig) -> Self {
         self.config.anti_entropy = config;
@@ -259,6 +529,18 @@ impl NodeConfigBuilder {
         self
     }
 
+    /// Set frame compression configuration.
+    pub fn compression(mut self, config: CompressionConfig) -> Self {
+        self.config.compression = config;
+        self
+    }
+
+    /// Set the preferred wire encoding.
+    pub fn encoding(mut self, encoding: WireEncoding) -> Self {
+        self.config.encoding = encoding;
+        self
+    }
+
     /// Set transport configuration.
     pub fn transport(mut self, transport: TransportConfig) -> Self {
         self.config.transport = transport;
@@ -359,6 +641,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn validate_adaptive_max_fanout_within_max_peers() {
+        let adaptive = AdaptiveConfig {
+            enabled: true,
+            max_fanout: 10,
+            ..AdaptiveConfig::default()
+        };
+        let result = NodeConfigBuilder::new()
+            .max_peers(8)
+            .fanout(3)
+            .adaptive(adaptive)
+            .build();
+        match result {
+            Err(Error::Config(msg)) => assert!(msg.contains("max_fanout")),
+            _ => panic!("Expected Config error"),
+        }
+    }
+
+    #[test]
+    fn validate_discovery_needs_reconnect() {
+        let discovery = DiscoveryConfig {
+            seed_file: Some("seeds.txt".into()),
+            ..DiscoveryConfig::default()
+        };
+        let reconnect = ReconnectConfig {
+            enabled: false,
+            ..ReconnectConfig::default()
+        };
+        let result = NodeConfigBuilder::new()
+            .discovery(discovery)
+            .reconnect(reconnect)
+            .build();
+        match result {
+            Err(Error::Config(msg)) => assert!(msg.contains("discovery")),
+            _ => panic!("Expected Config error"),
+        }
+    }
+
     #[test]
     fn validate_max_peers_zero() {
         let result = NodeConfigBuilder::new().max_peers(0).build();
@@ -409,6 +729,46 @@ mod tests {
         assert!(matches!(config.transport, TransportConfig::Tcp));
     }
 
+    #[test]
+    fn config_without_compression_still_deserializes() {
+        let mut value = serde_json::to_value(NodeConfig::default()).unwrap();
+        value.as_object_mut().unwrap().remove("compression");
+
+        let config: NodeConfig = serde_json::from_value(value).unwrap();
+        assert!(!config.compression.enabled);
+    }
+
+    #[test]
+    fn encoding_is_configured_by_name() {
+        let mut value = serde_json::to_value(NodeConfig::default()).unwrap();
+        assert_eq!(value["encoding"], "bincode");
+
+        value["encoding"] = "postcard".into();
+        let config: NodeConfig = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(config.encoding, WireEncoding::Postcard);
+
+        value.as_object_mut().unwrap().remove("encoding");
+        let config: NodeConfig = serde_json::from_value(value).unwrap();
+        assert_eq!(config.encoding, WireEncoding::Bincode);
+    }
+
+    #[test]
+    fn peer_selection_is_configured_by_name() {
+        let mut value = serde_json::to_value(NodeConfig::default()).unwrap();
+        assert_eq!(value["peer_selection"], "uniform");
+
+        value["peer_selection"] = "rtt_weighted".into();
+        let config: NodeConfig = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(config.peer_selection, PeerSelection::RttWeighted);
+
+        value["peer_selection"] = "fastest".into();
+        assert!(serde_json::from_value::<NodeConfig>(value.clone()).is_err());
+
+        value.as_object_mut().unwrap().remove("peer_selection");
+        let config: NodeConfig = serde_json::from_value(value).unwrap();
+        assert_eq!(config.peer_selection, PeerSelection::Uniform);
+    }
+
     #[test]
     fn invalid_config_rejected_on_deserialize() {
         let valid = serde_json::to_value(NodeConfig::default()).unwrap();
@@ -418,8 +778,76 @@ mod tests {
         bad_fanout["fanout"] = serde_json::json!(0);
         assert!(serde_json::from_value::<NodeConfig>(bad_fanout).is_err());
 
-        let mut bad_rate_limit = valid;
+        let mut bad_rate_limit = valid.clone();
         bad_rate_limit["rate_limit"]["capacity"] = serde_json::json!(0);
         assert!(serde_json::from_value::<NodeConfig>(bad_rate_limit).is_err());
+
+        let mut bad_store = valid.clone();
+        bad_store["message_store"]["max_messages"] = serde_json::json!(0);
+        assert!(serde_json::from_value::<NodeConfig>(bad_store).is_err());
+
+        let mut bad_delivery = valid.clone();
+        bad_delivery["delivery"]["max_held"] = serde_json::json!(0);
+        assert!(serde_json::from_value::<NodeConfig>(bad_delivery).is_err());
+
+        let mut bad_clock = valid.clone();
+        bad_clock["clock"]["max_drift"] = serde_json::json!({ "secs": 0, "nanos": 0 });
+        assert!(serde_json::from_value::<NodeConfig>(bad_clock).is_err());
+
+        let mut bad_kv = valid.clone();
+        bad_kv["kv"]["sync_fanout"] = serde_json::json!(0);
+        assert!(serde_json::from_value::<NodeConfig>(bad_kv).is_err());
+
+        let mut bad_aggregation = valid.clone();
+        bad_aggregation["aggregation"]["count_samples"] = serde_json::json!(1);
+        assert!(serde_json::from_value::<NodeConfig>(bad_aggregation).is_err());
+
+        let mut bad_snapshot = valid.clone();
+        bad_snapshot["snapshot"]["window"] = serde_json::json!(0);
+        assert!(serde_json::from_value::<NodeConfig>(bad_snapshot).is_err());
+
+        let mut bad_adaptive = valid.clone();
+        bad_adaptive["adaptive"]["min_fanout"] = serde_json::json!(0);
+        assert!(serde_json::from_value::<NodeConfig>(bad_adaptive).is_err());
+
+        let mut bad_zone = valid.clone();
+        bad_zone["zone"]["label"] = serde_json::json!("");
+        assert!(serde_json::from_value::<NodeConfig>(bad_zone).is_err());
+
+        let mut bad_reconnect = valid.clone();
+        bad_reconnect["reconnect"]["jitter"] = serde_json::json!(1.5);
+        assert!(serde_json::from_value::<NodeConfig>(bad_reconnect).is_err());
+
+        let mut bad_discovery = valid.clone();
+        bad_discovery["discovery"]["dns"] = serde_json::json!({"name": "", "port": 7946});
+        assert!(serde_json::from_value::<NodeConfig>(bad_discovery).is_err());
+
+        let mut bad_peer_exchange = valid.clone();
+        bad_peer_exchange["peer_exchange"]["max_per_group"] = serde_json::json!(0);
+        assert!(serde_json::from_value::<NodeConfig>(bad_peer_exchange).is_err());
+
+        let mut bad_connection_limits = valid.clone();
+        bad_connection_limits["connection_limits"]["max_inbound_per_ip"] = serde_json::json!(0);
+        assert!(serde_json::from_value::<NodeConfig>(bad_connection_limits).is_err());
+
+        let mut bad_outbound = valid.clone();
+        bad_outbound["outbound"]["queue_capacity"] = serde_json::json!(0);
+        assert!(serde_json::from_value::<NodeConfig>(bad_outbound).is_err());
+
+        let mut bad_misbehaviour = valid.clone();
+        bad_misbehaviour["misbehaviour"]["ban_threshold"] = serde_json::json!(0);
+        assert!(serde_json::from_value::<NodeConfig>(bad_misbehaviour).is_err());
+
+        let mut bad_replay = valid.clone();
+        bad_replay["replay"]["window"] = serde_json::json!({ "secs": 0, "nanos": 0 });
+        assert!(serde_json::from_value::<NodeConfig>(bad_replay).is_err());
+
+        let grant = crate::AclGrant {
+            peer: crate::PeerId([1; 32]),
+            permissions: crate::Permissions::NONE,
+        };
+        let mut bad_acl = valid;
+        bad_acl["acl"]["grants"] = serde_json::to_value([grant, grant]).unwrap();
+        assert!(serde_json::from_value::<NodeConfig>(bad_acl).is_err());
     }
 }
@@ -0,0 +1,480 @@
+//! Publish access control.
+//!
+//! Signatures prove who wrote a message, not that its author may write at
+//! all: by default any node holding any key can broadcast payloads that every
+//! other node delivers and forwards. An [`AclConfig`] maps [`PeerId`]s to the
+//! [`Permissions`] they hold, so a cluster can mix trusted producers with
+//! read-only consumers. Checks run after a message is authenticated, so the
+//! key they go by is one the sender has proved it holds:
+//!
+//! - a broadcast (application data, retractions, key-value writes) needs its
+//!   origin to hold [`Action::Broadcast`], whoever delivers it;
+//! - a direct message needs its sender to hold [`Action::Direct`];
+//! - passing on what others wrote (forwarding their broadcasts, answering
+//!   anti-entropy or snapshot requests) needs the sender to hold
+//!   [`Action::Relay`]; and
+//! - sending key-value state needs the sender to hold both, since the state
+//!   merges writes it cannot prove anyone else made.
+//!
+//! A message that fails is dropped before it is stored, so it is neither
+//! delivered nor forwarded. Keys without a grant of their own hold
+//! [`AclConfig::default`], which allows everything unless changed. The policy
+//! can be replaced or amended while the node runs.
+//!
+//! The sender is the key its connection proved at handshake. A peer that
+//! proved none (a node predating the handshake, or a client that skips it)
+//! could be passing on anyone's messages, so what it relays is held to
+//! [`AclConfig::default`] whatever key signed it: unless the default allows
+//! relaying, it may not.
+//!
+//! There are no per-topic grants: application payloads are opaque bytes with
+//! no topic the node could read, so a policy can only go by the kind of
+//! message. Topic grants can follow once payloads carry a topic.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Message, Payload, PeerId};
+
+/// Something a key may be permitted to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    /// Author broadcasts
+    Broadcast,
+
+    /// Send direct messages
+    Direct,
+
+    /// Pass on messages authored by others
+    Relay,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Broadcast => "broadcast",
+            Self::Direct => "direct",
+            Self::Relay => "relay",
+        })
+    }
+}
+
+/// The actions a key is permitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permissions {
+    /// May author broadcasts
+    pub broadcast: bool,
+
+    /// May send direct messages
+    pub direct: bool,
+
+    /// May pass on messages authored by others
+    pub relay: bool,
+}
+
+impl Permissions {
+    /// Every action.
+    pub const ALL: Self = Self {
+        broadcast: true,
+        direct: true,
+        relay: true,
+    };
+
+    /// No action: the key's messages are received but nothing it sends is
+    /// accepted, apart from control messages.
+    pub const NONE: Self = Self {
+        broadcast: false,
+        direct: false,
+        relay: false,
+    };
+
+    /// Whether `action` is permitted.
+    pub fn allows(self, action: Action) -> bool {
+        match action {
+            Action::Broadcast => self.broadcast,
+            Action::Direct => self.direct,
+            Action::Relay => self.relay,
+        }
+    }
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+/// The permissions granted to one key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AclGrant {
+    /// The key
+    pub peer: PeerId,
+
+    /// What it may do, in place of the default
+    pub permissions: Permissions,
+}
+
+/// Publish access control configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AclConfig {
+    /// Permissions of keys without a grant
+    pub default: Permissions,
+
+    /// Permissions of particular keys
+    pub grants: Vec<AclGrant>,
+}
+
+impl AclConfig {
+    /// Validate the configuration.
+    ///
+    /// # Errors
+    /// Returns a description of the first invalid field.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut seen = HashSet::new();
+        for grant in &self.grants {
+            if !seen.insert(grant.peer) {
+                return Err(format!("acl grants list peer {} twice", grant.peer));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The policy in force.
+#[derive(Debug)]
+struct Policy {
+    default: Permissions,
+    grants: HashMap<PeerId, Permissions>,
+}
+
+impl From<AclConfig> for Policy {
+    fn from(config: AclConfig) -> Self {
+        Self {
+            default: config.default,
+            grants: config
+                .grants
+                .into_iter()
+                .map(|grant| (grant.peer, grant.permissions))
+                .collect(),
+        }
+    }
+}
+
+/// The publish policy, shared by the receiver and the node's handle.
+#[derive(Debug)]
+pub(crate) struct Acl {
+    policy: Mutex<Policy>,
+}
+
+impl Acl {
+    pub(crate) fn new(config: AclConfig) -> Self {
+        Self {
+            policy: Mutex::new(config.into()),
+        }
+    }
+
+    /// The permissions of `peer`.
+    pub(crate) fn permissions(&self, peer: PeerId) -> Permissions {
+        let policy = lock(&self.policy);
+        policy.grants.get(&peer).copied().unwrap_or(policy.default)
+    }
+
+    /// Whether `peer` may take `action`.
+    pub(crate) fn allows(&self, peer: PeerId, action: Action) -> bool {
+        self.permissions(peer).allows(action)
+    }
+
+    /// Replace the whole policy.
+    pub(crate) fn set(&self, config: AclConfig) {
+        *lock(&self.policy) = config.into();
+    }
+
+    /// The policy in force, grants ordered by key.
+    pub(crate) fn config(&self) -> AclConfig {
+        let policy = lock(&self.policy);
+        let mut grants: Vec<_> = policy
+            .grants
+            .iter()
+            .map(|(&peer, &permissions)| AclGrant { peer, permissions })
+            .collect();
+        grants.sort_by_key(|grant| grant.peer.0);
+        AclConfig {
+            default: policy.default,
+            grants,
+        }
+    }
+
+    /// Grant `peer` `permissions`, replacing any grant it had.
+    pub(crate) fn grant(&self, peer: PeerId, permissions: Permissions) {
+        lock(&self.policy).grants.insert(peer, permissions);
+    }
+
+    /// Drop `peer`'s grant, returning whether it had one.
+    pub(crate) fn remove_grant(&self, peer: PeerId) -> bool {
+        lock(&self.policy).grants.remove(&peer).is_some()
+    }
+
+    /// Give `new` the grant of `old`, which was rotated to it, unless it has
+    /// one of its own.
+    pub(crate) fn carry_grant(&self, old: PeerId, new: PeerId) {
+        let mut policy = lock(&self.policy);
+        if let Some(&permissions) = policy.grants.get(&old) {
+            policy.grants.entry(new).or_insert(permissions);
+        }
+    }
+
+    /// Check an authenticated `message` that arrived over a connection bound
+    /// to `sender`, the key its peer proved at handshake, returning the action
+    /// it lacks permission for.
+    ///
+    /// A peer that proved no key cannot be told apart from any other, so what
+    /// it relays is held to [`AclConfig::default`], whoever signed it.
+    pub(crate) fn check(&self, message: &Message, sender: Option<PeerId>) -> Result<(), Action> {
+        let policy = lock(&self.policy);
+        let permissions =
+            |peer: PeerId| policy.grants.get(&peer).copied().unwrap_or(policy.default);
+        let origin = permissions(message.origin_key);
+        let relayer = sender.map_or(policy.default, permissions);
+        match &message.payload {
+            payload if payload.is_broadcast() => {
+                if !origin.broadcast {
+                    return Err(Action::Broadcast);
+                }
+                if sender != Some(message.origin_key) && !relayer.relay {
+                    return Err(Action::Relay);
+                }
+                Ok(())
+            }
+            Payload::KvState { .. } => {
+                // The state's entries carry no signatures of their own, so a
+                // sender that may not broadcast could slip writes in with it.
+                if !origin.broadcast {
+                    return Err(Action::Broadcast);
+                }
+                if !origin.relay || !relayer.relay {
+                    return Err(Action::Relay);
+                }
+                Ok(())
+            }
+            Payload::MessageResponse { .. } | Payload::SnapshotChunk { .. } => {
+                if origin.relay && relayer.relay {
+                    Ok(())
+                } else {
+                    Err(Action::Relay)
+                }
+            }
+            Payload::DirectMessage { .. } if !origin.direct => Err(Action::Direct),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drop the repaired broadcasts in `messages` whose origins may not
+    /// broadcast, returning how many were dropped.
+    pub(crate) fn retain_permitted(&self, messages: &mut Vec<Message>) -> usize {
+        let before = messages.len();
+        messages.retain(|message| self.allows(message.origin_key, Action::Broadcast));
+        before - messages.len()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    const PRODUCER: PeerId = PeerId([1; 32]);
+    const CONSUMER: PeerId = PeerId([2; 32]);
+
+    fn from(key: PeerId, payload: Payload) -> Message {
+        let mut message = Message::new("127.0.0.1:7946".parse().unwrap(), 1, payload);
+        message.origin_key = key;
+        message
+    }
+
+    fn broadcast(key: PeerId) -> Message {
+        from(key, Payload::Application(Bytes::from_static(b"x")))
+    }
+
+    /// Only the producer may publish; nobody else may relay.
+    fn producers_only() -> Acl {
+        Acl::new(AclConfig {
+            default: Permissions::NONE,
+            grants: vec![AclGrant {
+                peer: PRODUCER,
+                permissions: Permissions::ALL,
+            }],
+        })
+    }
+
+    #[test]
+    fn everything_is_allowed_by_default() {
+        let acl = Acl::new(AclConfig::default());
+        let direct = Payload::DirectMessage {
+            recipient: "127.0.0.1:7947".parse().unwrap(),
+            data: Bytes::new(),
+        };
+
+        assert_eq!(acl.check(&broadcast(CONSUMER), Some(PRODUCER)), Ok(()));
+        assert_eq!(acl.check(&broadcast(CONSUMER), None), Ok(()));
+        assert_eq!(acl.check(&from(CONSUMER, direct), Some(CONSUMER)), Ok(()));
+    }
+
+    #[test]
+    fn broadcasts_need_the_origin_to_broadcast_and_the_sender_to_relay() {
+        let acl = producers_only();
+
+        assert_eq!(acl.check(&broadcast(PRODUCER), Some(PRODUCER)), Ok(()));
+        assert_eq!(
+            acl.check(&broadcast(CONSUMER), Some(CONSUMER)),
+            Err(Action::Broadcast)
+        );
+        assert_eq!(
+            acl.check(&broadcast(CONSUMER), Some(PRODUCER)),
+            Err(Action::Broadcast)
+        );
+        // The producer's broadcast, passed on by a consumer or an unknown key.
+        assert_eq!(
+            acl.check(&broadcast(PRODUCER), Some(CONSUMER)),
+            Err(Action::Relay)
+        );
+        assert_eq!(acl.check(&broadcast(PRODUCER), None), Err(Action::Relay));
+    }
+
+    #[test]
+    fn direct_messages_and_repairs_are_checked_against_the_sender() {
+        let acl = producers_only();
+        let direct = Payload::DirectMessage {
+            recipient: "127.0.0.1:7947".parse().unwrap(),
+            data: Bytes::new(),
+        };
+        let repair = Payload::MessageResponse {
+            messages: Vec::new(),
+        };
+        let heartbeat = Payload::Heartbeat {
+            from: "127.0.0.1:7948".parse().unwrap(),
+        };
+
+        assert_eq!(
+            acl.check(&from(CONSUMER, direct.clone()), Some(CONSUMER)),
+            Err(Action::Direct)
+        );
+        assert_eq!(acl.check(&from(PRODUCER, direct), Some(PRODUCER)), Ok(()));
+        assert_eq!(
+            acl.check(&from(CONSUMER, repair), Some(CONSUMER)),
+            Err(Action::Relay)
+        );
+        assert_eq!(
+            acl.check(&from(CONSUMER, heartbeat), Some(CONSUMER)),
+            Ok(())
+        );
+
+        let mut repaired = vec![broadcast(PRODUCER), broadcast(CONSUMER)];
+        assert_eq!(acl.retain_permitted(&mut repaired), 1);
+        assert_eq!(repaired[0].origin_key, PRODUCER);
+    }
+
+    #[test]
+    fn senders_that_proved_no_key_hold_the_default() {
+        let acl = producers_only();
+        let repair = Payload::MessageResponse {
+            messages: Vec::new(),
+        };
+        let state = Payload::KvState {
+            state: Bytes::new(),
+            reply: false,
+        };
+
+        // Signed by the producer, but passed on by whoever is at the other end.
+        assert_eq!(acl.check(&broadcast(PRODUCER), None), Err(Action::Relay));
+        assert_eq!(
+            acl.check(&from(PRODUCER, repair.clone()), None),
+            Err(Action::Relay)
+        );
+        assert_eq!(
+            acl.check(&from(PRODUCER, state.clone()), None),
+            Err(Action::Relay)
+        );
+
+        acl.set(AclConfig {
+            default: Permissions {
+                relay: true,
+                ..Permissions::NONE
+            },
+            ..acl.config()
+        });
+        assert_eq!(acl.check(&broadcast(PRODUCER), None), Ok(()));
+        assert_eq!(acl.check(&from(PRODUCER, repair), None), Ok(()));
+        assert_eq!(acl.check(&from(PRODUCER, state), None), Ok(()));
+    }
+
+    #[test]
+    fn key_value_state_needs_broadcast_and_relay() {
+        let state = || Payload::KvState {
+            state: Bytes::new(),
+            reply: false,
+        };
+        let acl = producers_only();
+        let relay_only = Permissions {
+            relay: true,
+            ..Permissions::NONE
+        };
+        acl.grant(CONSUMER, relay_only);
+
+        assert_eq!(acl.check(&from(PRODUCER, state()), Some(PRODUCER)), Ok(()));
+        assert_eq!(
+            acl.check(&from(CONSUMER, state()), Some(CONSUMER)),
+            Err(Action::Broadcast)
+        );
+        acl.grant(
+            CONSUMER,
+            Permissions {
+                broadcast: true,
+                ..Permissions::NONE
+            },
+        );
+        assert_eq!(
+            acl.check(&from(CONSUMER, state()), Some(CONSUMER)),
+            Err(Action::Relay)
+        );
+    }
+
+    #[test]
+    fn grants_change_at_runtime() {
+        let acl = producers_only();
+
+        acl.grant(CONSUMER, Permissions::ALL);
+        assert_eq!(acl.check(&broadcast(CONSUMER), Some(CONSUMER)), Ok(()));
+        assert!(acl.remove_grant(CONSUMER));
+        assert!(!acl.remove_grant(CONSUMER));
+        assert!(!acl.allows(CONSUMER, Action::Broadcast));
+
+        acl.carry_grant(PRODUCER, CONSUMER);
+        assert!(acl.allows(CONSUMER, Action::Broadcast));
+        acl.remove_grant(CONSUMER);
+
+        acl.set(AclConfig::default());
+        assert!(acl.allows(CONSUMER, Action::Broadcast));
+        assert_eq!(acl.config(), AclConfig::default());
+    }
+
+    #[test]
+    fn validate_rejects_duplicate_grants() {
+        let grant = AclGrant {
+            peer: PRODUCER,
+            permissions: Permissions::ALL,
+        };
+        let config = AclConfig {
+            default: Permissions::ALL,
+            grants: vec![grant, grant],
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,443 @@
+//! Adaptive fanout and anti-entropy pacing.
+//!
+//! A static fanout that suits a handful of nodes leaves nodes unreached in a
+//! cluster of hundreds, and one tuned for hundreds floods a small cluster. In
+//! adaptive mode a node estimates the cluster's size `N` and gossips to
+//! `ceil(ln(N) + c)` peers, the fanout at which an epidemic reaches every node
+//! with probability about `exp(-exp(-c))` (Kermarrec et al. 2003), within
+//! configured bounds.
+//!
+//! The estimate counts the distinct nodes this node has heard of within
+//! [`AdaptiveConfig::member_ttl`]: the origins of the messages it receives and
+//! the addresses peers list in their peer-list responses, plus itself.
+//!
+//! The anti-entropy interval adapts to the repairs it observes. A round whose
+//! exchanges repaired messages halves the interval, since broadcasts are being
+//! missed; a round that repaired nothing lengthens it by half. The interval
+//! stays within configured bounds, starting from
+//! [`AntiEntropyConfig::interval`](crate::AntiEntropyConfig::interval).
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// Nodes tracked at most for the size estimate. Addresses heard of beyond it
+/// are not counted until expired ones are forgotten, so a peer listing
+/// invented addresses cannot exhaust memory.
+const MAX_MEMBERS: usize = 65_536;
+
+/// Adaptive fanout and anti-entropy pacing configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdaptiveConfig {
+    /// Adapt fanout and the anti-entropy interval; when disabled, `fanout` and
+    /// `anti_entropy.interval` are used as configured
+    pub enabled: bool,
+
+    /// Smallest fanout used
+    pub min_fanout: usize,
+
+    /// Largest fanout used
+    pub max_fanout: usize,
+
+    /// The `c` in `ln(N) + c`: higher values trade traffic for reliability
+    pub fanout_offset: f64,
+
+    /// How long a node counts towards the cluster size after it was last
+    /// heard of
+    pub member_ttl: Duration,
+
+    /// Shortest anti-entropy interval
+    pub min_anti_entropy_interval: Duration,
+
+    /// Longest anti-entropy interval
+    pub max_anti_entropy_interval: Duration,
+}
+
+impl Default for AdaptiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_fanout: 2,
+            max_fanout: 10,
+            fanout_offset: 2.0,
+            member_ttl: Duration::from_secs(300),
+            min_anti_entropy_interval: Duration::from_secs(5),
+            max_anti_entropy_interval: Duration::from_secs(120),
+        }
+    }
+}
+
+impl AdaptiveConfig {
+    /// Validate configuration.
+    ///
+    /// # Errors
+    /// Returns an error message if configuration is invalid.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_fanout == 0 {
+            return Err("adaptive min_fanout must be greater than 0".to_string());
+        }
+        if self.max_fanout < self.min_fanout {
+            return Err("adaptive max_fanout must be at least min_fanout".to_string());
+        }
+        if !self.fanout_offset.is_finite() || self.fanout_offset < 0.0 {
+            return Err("adaptive fanout_offset must be finite and non-negative".to_string());
+        }
+        if self.member_ttl.is_zero() {
+            return Err("adaptive member_ttl must be greater than 0".to_string());
+        }
+        if self.min_anti_entropy_interval.is_zero() {
+            return Err("adaptive min_anti_entropy_interval must be greater than 0".to_string());
+        }
+        if self.max_anti_entropy_interval < self.min_anti_entropy_interval {
+            return Err(
+                "adaptive max_anti_entropy_interval must be at least min_anti_entropy_interval"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The current adaptive settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveStats {
+    /// Estimated number of nodes in the cluster, this one included.
+    pub cluster_size: usize,
+
+    /// Peers each broadcast is pushed to.
+    pub fanout: usize,
+
+    /// Interval before the next anti-entropy round.
+    pub anti_entropy_interval: Duration,
+}
+
+/// Cluster size estimate, and the fanout and anti-entropy interval derived
+/// from it and from observed repairs.
+#[derive(Debug)]
+pub(crate) struct Adaptive {
+    config: AdaptiveConfig,
+
+    /// The fanout used when adaptation is disabled.
+    fanout: usize,
+
+    /// The nodes other than this one heard of within the member TTL.
+    members: Mutex<Members>,
+
+    /// Messages repaired by anti-entropy since the last round began.
+    repaired: AtomicU64,
+
+    anti_entropy_interval: Mutex<Duration>,
+}
+
+impl Adaptive {
+    /// Adaptation within `config`, falling back to the static `fanout` and
+    /// `anti_entropy_interval` when it is disabled.
+    pub(crate) fn new(
+        config: AdaptiveConfig,
+        fanout: usize,
+        anti_entropy_interval: Duration,
+    ) -> Self {
+        let anti_entropy_interval = if config.enabled {
+            anti_entropy_interval.clamp(
+                config.min_anti_entropy_interval,
+                config.max_anti_entropy_interval,
+            )
+        } else {
+            anti_entropy_interval
+        };
+        Self {
+            config,
+            fanout,
+            members: Mutex::new(Members::default()),
+            repaired: AtomicU64::new(0),
+            anti_entropy_interval: Mutex::new(anti_entropy_interval),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Record that each of `nodes` was heard of just now.
+    pub(crate) fn observe(&self, nodes: impl IntoIterator<Item = SocketAddr>) {
+        if !self.config.enabled {
+            return;
+        }
+        let now = Instant::now();
+        let mut members = lock(&self.members);
+        for node in nodes {
+            if let Some(heard) = members.heard.get_mut(&node) {
+                *heard = now;
+                continue;
+            }
+            if members.heard.len() >= MAX_MEMBERS {
+                members.expire(now, self.config.member_ttl);
+                if members.heard.len() >= MAX_MEMBERS {
+                    continue;
+                }
+            }
+            members.heard.insert(node, now);
+            members.expiry.push(Reverse((now, node)));
+        }
+    }
+
+    /// Estimated number of nodes in the cluster, this one included.
+    pub(crate) fn cluster_size(&self) -> usize {
+        let mut members = lock(&self.members);
+        members.expire(Instant::now(), self.config.member_ttl);
+        members.heard.len() + 1
+    }
+
+    /// Peers to push each broadcast to.
+    pub(crate) fn fanout(&self) -> usize {
+        if !self.config.enabled {
+            return self.fanout;
+        }
+        fanout_for(
+            self.cluster_size(),
+            self.config.fanout_offset,
+            self.config.min_fanout,
+            self.config.max_fanout,
+        )
+    }
+
+    /// Count messages repaired by anti-entropy.
+    pub(crate) fn record_repairs(&self, count: usize) {
+        let count = u64::try_from(count).unwrap_or(u64::MAX);
+        self.repaired.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// The interval to wait before the next anti-entropy round, adjusted for
+    /// the repairs observed since the last call.
+    pub(crate) fn next_anti_entropy_interval(&self) -> Duration {
+        let mut interval = lock(&self.anti_entropy_interval);
+        if !self.config.enabled {
+            return *interval;
+        }
+        let repaired = self.repaired.swap(0, Ordering::Relaxed);
+        let adjusted = if repaired > 0 {
+            *interval / 2
+        } else {
+            *interval + *interval / 2
+        };
+        let adjusted = adjusted.clamp(
+            self.config.min_anti_entropy_interval,
+            self.config.max_anti_entropy_interval,
+        );
+        if adjusted != *interval {
+            debug!(
+                "Anti-entropy interval {:?} -> {adjusted:?} after {repaired} repairs",
+                *interval
+            );
+        }
+        *interval = adjusted;
+        adjusted
+    }
+
+    pub(crate) fn stats(&self) -> AdaptiveStats {
+        AdaptiveStats {
+            cluster_size: self.cluster_size(),
+            fanout: self.fanout(),
+            anti_entropy_interval: *lock(&self.anti_entropy_interval),
+        }
+    }
+}
+
+/// Nodes heard of, with a queue of when each is next due to be checked for
+/// expiry so that counting them does not scan every one.
+#[derive(Debug, Default)]
+struct Members {
+    /// When each node was last heard of.
+    heard: HashMap<SocketAddr, Instant>,
+
+    /// One entry per node in `heard`, keyed by a time no later than when it
+    /// was last heard of, earliest first.
+    expiry: BinaryHeap<Reverse<(Instant, SocketAddr)>>,
+}
+
+impl Members {
+    /// Forget the nodes not heard of within `ttl` of `now`.
+    ///
+    /// A node heard of again since it was queued is queued anew at the time
+    /// it was last heard of, so each call costs time only for the entries
+    /// that have come due.
+    fn expire(&mut self, now: Instant, ttl: Duration) {
+        while let Some(&Reverse((queued, node))) = self.expiry.peek() {
+            if now.duration_since(queued) < ttl {
+                break;
+            }
+            self.expiry.pop();
+            match self.heard.get(&node) {
+                Some(&heard) if now.duration_since(heard) < ttl => {
+                    self.expiry.push(Reverse((heard, node)));
+                }
+                _ => {
+                    self.heard.remove(&node);
+                }
+            }
+        }
+    }
+}
+
+/// The smallest fanout within `min..=max` that is at least `ln(size) + offset`.
+fn fanout_for(size: usize, offset: f64, min: usize, max: usize) -> usize {
+    let size = f64::from(u32::try_from(size).unwrap_or(u32::MAX));
+    let target = size.ln() + offset;
+    (min..=max)
+        .find(|&fanout| f64::from(u32::try_from(fanout).unwrap_or(u32::MAX)) >= target)
+        .unwrap_or(max)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn enabled() -> AdaptiveConfig {
+        AdaptiveConfig {
+            enabled: true,
+            ..AdaptiveConfig::default()
+        }
+    }
+
+    #[test]
+    fn fanout_grows_with_the_log_of_the_cluster_size() {
+        assert_eq!(fanout_for(1, 2.0, 2, 10), 2);
+        assert_eq!(fanout_for(5, 2.0, 2, 10), 4);
+        assert_eq!(fanout_for(50, 2.0, 2, 10), 6);
+        assert_eq!(fanout_for(500, 2.0, 2, 10), 9);
+        assert_eq!(fanout_for(500, 2.0, 2, 6), 6);
+        assert_eq!(fanout_for(2, 0.0, 3, 10), 3);
+    }
+
+    #[test]
+    fn cluster_size_counts_distinct_nodes_heard_of() {
+        let adaptive = Adaptive::new(enabled(), 3, Duration::from_secs(30));
+        assert_eq!(adaptive.cluster_size(), 1);
+
+        adaptive.observe([addr(1), addr(2), addr(1)]);
+        adaptive.observe([addr(3)]);
+        assert_eq!(adaptive.cluster_size(), 4);
+        assert_eq!(adaptive.fanout(), 4);
+    }
+
+    #[test]
+    fn silent_nodes_stop_counting() {
+        let config = AdaptiveConfig {
+            member_ttl: Duration::from_millis(1),
+            ..enabled()
+        };
+        let adaptive = Adaptive::new(config, 3, Duration::from_secs(30));
+        adaptive.observe([addr(1), addr(2)]);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(adaptive.cluster_size(), 1);
+    }
+
+    #[test]
+    fn nodes_heard_of_again_keep_counting() {
+        let mut members = Members::default();
+        let ttl = Duration::from_secs(10);
+        let start = Instant::now();
+        for port in [1, 2] {
+            members.heard.insert(addr(port), start);
+            members.expiry.push(Reverse((start, addr(port))));
+        }
+        members
+            .heard
+            .insert(addr(1), start + Duration::from_secs(8));
+
+        members.expire(start + Duration::from_secs(12), ttl);
+        assert_eq!(members.heard.len(), 1);
+        assert!(members.heard.contains_key(&addr(1)));
+        assert_eq!(members.expiry.len(), 1);
+
+        members.expire(start + Duration::from_secs(18), ttl);
+        assert!(members.heard.is_empty());
+        assert!(members.expiry.is_empty());
+    }
+
+    #[test]
+    fn anti_entropy_speeds_up_on_repairs_and_backs_off_when_idle() {
+        let config = AdaptiveConfig {
+            min_anti_entropy_interval: Duration::from_secs(5),
+            max_anti_entropy_interval: Duration::from_secs(60),
+            ..enabled()
+        };
+        let adaptive = Adaptive::new(config, 3, Duration::from_secs(30));
+
+        adaptive.record_repairs(4);
+        assert_eq!(
+            adaptive.next_anti_entropy_interval(),
+            Duration::from_secs(15)
+        );
+        adaptive.record_repairs(1);
+        assert_eq!(
+            adaptive.next_anti_entropy_interval(),
+            Duration::from_millis(7500)
+        );
+        adaptive.record_repairs(1);
+        assert_eq!(
+            adaptive.next_anti_entropy_interval(),
+            Duration::from_secs(5)
+        );
+
+        let mut interval = Duration::ZERO;
+        for _ in 0..10 {
+            interval = adaptive.next_anti_entropy_interval();
+        }
+        assert_eq!(interval, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn disabled_adaptation_keeps_the_static_settings() {
+        let adaptive = Adaptive::new(AdaptiveConfig::default(), 3, Duration::from_secs(30));
+        adaptive.observe((1..100).map(addr));
+        adaptive.record_repairs(10);
+        assert_eq!(adaptive.fanout(), 3);
+        assert_eq!(adaptive.cluster_size(), 1);
+        assert_eq!(
+            adaptive.next_anti_entropy_interval(),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn validate_rejects_inverted_bounds() {
+        assert!(AdaptiveConfig::default().validate().is_ok());
+        for config in [
+            AdaptiveConfig {
+                min_fanout: 0,
+                ..AdaptiveConfig::default()
+            },
+            AdaptiveConfig {
+                min_fanout: 5,
+                max_fanout: 4,
+                ..AdaptiveConfig::default()
+            },
+            AdaptiveConfig {
+                fanout_offset: f64::NAN,
+                ..AdaptiveConfig::default()
+            },
+            AdaptiveConfig {
+                min_anti_entropy_interval: Duration::from_secs(10),
+                max_anti_entropy_interval: Duration::from_secs(5),
+                ..AdaptiveConfig::default()
+            },
+        ] {
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+    }
+}
@@ -0,0 +1,460 @@
+//! Cluster-wide aggregates by gossip.
+//!
+//! Each node may contribute a local value to any number of named metrics and
+//! read a converging estimate of the metric's average, sum, count, minimum and
+//! maximum across every contributing node, without broadcasting samples.
+//!
+//! - **Average** is computed by push-sum. Every node holds a value mass and a
+//!   weight mass per metric, starting at its contribution and `1` (or `0` and
+//!   `0` if it does not contribute). Each round it keeps half of both and
+//!   hands the other half to one random peer. Mass is conserved, so every
+//!   node's `sum / weight` converges to the cluster average, exponentially
+//!   fast in the number of rounds.
+//! - **Count** cannot come from push-sum without a designated node, so it is
+//!   estimated by extrema propagation: each contributor draws
+//!   [`AggregationConfig::count_samples`] exponential samples, nodes keep the
+//!   per-slot minimum of everything they have seen, and the count is
+//!   `(k - 1) / sum(minima)`. Its relative error is about `1 / sqrt(k - 2)`.
+//! - **Sum** is the average times the count, so it carries the count's error.
+//! - **Minimum** and **maximum** spread the same way as the count's minima.
+//!
+//! Rounds run on the gossip loop's ticks. Minima, maxima and departed
+//! contributors would otherwise linger forever, so aggregation restarts every
+//! [`AggregationConfig::rounds`] rounds, in a new *epoch*. The estimate of a
+//! finished epoch is kept for reading until the next one finishes. A node that
+//! hears of a later epoch joins it at once, which keeps the cluster's epochs
+//! together; shares of an earlier epoch are dropped.
+//!
+//! Peers are trusted: a malicious node can skew any aggregate by handing out
+//! invented mass.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{AggregateShare, Payload};
+
+/// Largest number of count samples a node may be configured with.
+const MAX_COUNT_SAMPLES: usize = 1024;
+
+/// Metrics a node tracks at most. Shares for further metrics are ignored, so a
+/// peer cannot exhaust memory by inventing metric names.
+const MAX_METRICS: usize = 1024;
+
+/// Gossip aggregation configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregationConfig {
+    /// Gossip rounds in an epoch, after which aggregation restarts from the
+    /// current contributions.
+    pub rounds: u32,
+
+    /// Exponential samples each contributor draws to estimate the count. More
+    /// samples give a more precise count and larger shares.
+    pub count_samples: usize,
+}
+
+impl Default for AggregationConfig {
+    fn default() -> Self {
+        Self {
+            rounds: 20,
+            count_samples: 64,
+        }
+    }
+}
+
+impl AggregationConfig {
+    /// Validate configuration.
+    ///
+    /// # Errors
+    /// Returns an error message if configuration is invalid.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.rounds == 0 {
+            return Err("aggregation rounds must be greater than 0".to_string());
+        }
+        if !(2..=MAX_COUNT_SAMPLES).contains(&self.count_samples) {
+            return Err(format!(
+                "aggregation count_samples must be between 2 and {MAX_COUNT_SAMPLES}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A node's estimate of a metric across the cluster.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    /// Average contribution.
+    pub average: f64,
+
+    /// Sum of the contributions.
+    pub sum: f64,
+
+    /// Number of contributing nodes.
+    pub count: f64,
+
+    /// Smallest contribution.
+    pub min: f64,
+
+    /// Largest contribution.
+    pub max: f64,
+
+    /// The epoch the estimate was computed in.
+    pub epoch: u64,
+}
+
+/// One metric's aggregation state for the current epoch.
+#[derive(Debug, Clone)]
+struct Metric {
+    sum: f64,
+    weight: f64,
+    min: f64,
+    max: f64,
+    count_sketch: Vec<f64>,
+}
+
+impl Metric {
+    /// State of a node that has contributed nothing yet.
+    fn empty(samples: usize) -> Self {
+        Self {
+            sum: 0.0,
+            weight: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            count_sketch: vec![f64::INFINITY; samples],
+        }
+    }
+
+    /// Add a contribution of `value`, replacing `previous` if the node had
+    /// already contributed this epoch.
+    fn contribute(&mut self, value: f64, previous: Option<f64>) {
+        match previous {
+            Some(previous) => self.sum += value - previous,
+            None => {
+                self.sum += value;
+                self.weight += 1.0;
+                for slot in &mut self.count_sketch {
+                    *slot = slot.min(exponential_sample());
+                }
+            }
+        }
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Keep half of the mass and return the other half, with the extrema, as
+    /// a share for a peer.
+    fn split(&mut self, metric: &str) -> AggregateShare {
+        self.sum /= 2.0;
+        self.weight /= 2.0;
+        AggregateShare {
+            metric: metric.to_string(),
+            sum: self.sum,
+            weight: self.weight,
+            min: self.min,
+            max: self.max,
+            count_sketch: self.count_sketch.clone(),
+        }
+    }
+
+    fn absorb(&mut self, share: &AggregateShare) {
+        self.sum += share.sum;
+        self.weight += share.weight;
+        self.min = self.min.min(share.min);
+        self.max = self.max.max(share.max);
+        for (slot, &sample) in self.count_sketch.iter_mut().zip(&share.count_sketch) {
+            *slot = slot.min(sample);
+        }
+    }
+
+    /// The estimate, or `None` if no contribution's mass has reached this node.
+    fn estimate(&self, epoch: u64) -> Option<Estimate> {
+        if self.weight <= 0.0 {
+            return None;
+        }
+        let average = self.sum / self.weight;
+        let samples = f64::from(u32::try_from(self.count_sketch.len()).unwrap_or(u32::MAX));
+        let minima: f64 = self.count_sketch.iter().sum();
+        let count = if minima.is_finite() && minima > 0.0 {
+            (samples - 1.0) / minima
+        } else {
+            0.0
+        };
+        Some(Estimate {
+            average,
+            sum: average * count,
+            count,
+            min: self.min,
+            max: self.max,
+            epoch,
+        })
+    }
+}
+
+/// A node's gossip aggregation state.
+#[derive(Debug)]
+pub(crate) struct Aggregator {
+    config: AggregationConfig,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    epoch: u64,
+
+    /// Rounds run in the current epoch.
+    round: u32,
+
+    /// This node's contributions.
+    local: HashMap<String, f64>,
+
+    /// Per metric, this epoch's state.
+    current: HashMap<String, Metric>,
+
+    /// Per metric, the estimate at the end of the last finished epoch.
+    settled: HashMap<String, Estimate>,
+}
+
+impl Aggregator {
+    pub(crate) fn new(config: AggregationConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Set this node's contribution to `metric`.
+    pub(crate) fn contribute(&self, metric: &str, value: f64) {
+        let mut state = self.lock();
+        let previous = state.local.insert(metric.to_string(), value);
+        let samples = self.config.count_samples;
+        state
+            .current
+            .entry(metric.to_string())
+            .or_insert_with(|| Metric::empty(samples))
+            .contribute(value, previous);
+    }
+
+    /// Stop contributing to `metric` from the next epoch.
+    pub(crate) fn withdraw(&self, metric: &str) {
+        self.lock().local.remove(metric);
+    }
+
+    /// The estimate of the last finished epoch, or of the current one if none
+    /// has finished since this node first saw the metric.
+    pub(crate) fn estimate(&self, metric: &str) -> Option<Estimate> {
+        let state = self.lock();
+        state.settled.get(metric).copied().or_else(|| {
+            state
+                .current
+                .get(metric)
+                .and_then(|current| current.estimate(state.epoch))
+        })
+    }
+
+    /// Run one round: start a new epoch if this one is over, then split every
+    /// metric's mass, returning the half to send to a random peer. `None` if
+    /// there is nothing to share.
+    pub(crate) fn round(&self) -> Option<Payload> {
+        let mut state = self.lock();
+        state.round += 1;
+        if state.round >= self.config.rounds {
+            let epoch = state.epoch + 1;
+            self.begin(&mut state, epoch);
+        }
+        let epoch = state.epoch;
+        let shares: Vec<AggregateShare> = state
+            .current
+            .iter_mut()
+            .map(|(name, metric)| metric.split(name))
+            .collect();
+        if shares.is_empty() {
+            return None;
+        }
+        Some(Payload::Aggregate { epoch, shares })
+    }
+
+    /// Merge shares from a peer, or shares this node failed to send.
+    pub(crate) fn absorb(&self, epoch: u64, shares: &[AggregateShare]) {
+        let mut state = self.lock();
+        if epoch < state.epoch {
+            debug!(
+                "Dropping aggregation shares from epoch {epoch}, now in {}",
+                state.epoch
+            );
+            return;
+        }
+        if epoch > state.epoch {
+            debug!("Joining aggregation epoch {epoch}");
+            self.begin(&mut state, epoch);
+        }
+        let samples = self.config.count_samples;
+        for share in shares {
+            if !is_valid(share, samples) {
+                warn!(
+                    "Ignoring malformed aggregation share for {:?}",
+                    share.metric
+                );
+                continue;
+            }
+            if !state.current.contains_key(&share.metric) && state.current.len() >= MAX_METRICS {
+                debug!("Ignoring share for {:?}: too many metrics", share.metric);
+                continue;
+            }
+            state
+                .current
+                .entry(share.metric.clone())
+                .or_insert_with(|| Metric::empty(samples))
+                .absorb(share);
+        }
+    }
+
+    /// Settle the current epoch's estimates and start `epoch` from this
+    /// node's contributions.
+    fn begin(&self, state: &mut State, epoch: u64) {
+        let settled = state.epoch;
+        state.settled = state
+            .current
+            .iter()
+            .filter_map(|(name, metric)| Some((name.clone(), metric.estimate(settled)?)))
+            .collect();
+        state.epoch = epoch;
+        state.round = 0;
+        let samples = self.config.count_samples;
+        state.current = state
+            .local
+            .iter()
+            .map(|(name, &value)| {
+                let mut metric = Metric::empty(samples);
+                metric.contribute(value, None);
+                (name.clone(), metric)
+            })
+            .collect();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Whether a share's mass is usable: finite, with a non-negative weight and a
+/// sketch of the configured size.
+fn is_valid(share: &AggregateShare, samples: usize) -> bool {
+    share.sum.is_finite()
+        && share.weight.is_finite()
+        && share.weight >= 0.0
+        && !share.min.is_nan()
+        && !share.max.is_nan()
+        && share.count_sketch.len() == samples
+        && share
+            .count_sketch
+            .iter()
+            .all(|sample| !sample.is_nan() && *sample > 0.0)
+}
+
+/// A sample from the exponential distribution with rate 1.
+fn exponential_sample() -> f64 {
+    // `random` is in [0, 1), so the logarithm's argument is never 0.
+    -(1.0 - rand::random::<f64>()).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    fn aggregator(rounds: u32) -> Aggregator {
+        Aggregator::new(AggregationConfig {
+            rounds,
+            count_samples: 256,
+        })
+    }
+
+    /// Run `rounds` rounds among `nodes`, each handing its shares to a random
+    /// other node.
+    fn gossip(nodes: &[Aggregator], rounds: usize) {
+        let mut rng = rand::rng();
+        for _ in 0..rounds {
+            for (i, node) in nodes.iter().enumerate() {
+                let Some(Payload::Aggregate { epoch, shares }) = node.round() else {
+                    continue;
+                };
+                let mut peer = rng.random_range(0..nodes.len() - 1);
+                if peer >= i {
+                    peer += 1;
+                }
+                nodes[peer].absorb(epoch, &shares);
+            }
+        }
+    }
+
+    #[test]
+    fn estimates_converge_to_the_cluster_aggregates() {
+        let nodes: Vec<Aggregator> = (0..21).map(|_| aggregator(1_000)).collect();
+        // The last node contributes nothing, but still learns the aggregate.
+        for (value, node) in (0..20u8).zip(&nodes) {
+            node.contribute("load", f64::from(value));
+        }
+
+        gossip(&nodes, 40);
+
+        for node in &nodes {
+            let estimate = node.estimate("load").unwrap();
+            assert!((estimate.average - 9.5).abs() < 0.01, "{estimate:?}");
+            assert_eq!(estimate.min, 0.0);
+            assert_eq!(estimate.max, 19.0);
+            assert!((estimate.count - 20.0).abs() < 5.0, "{estimate:?}");
+            assert!((estimate.sum - 190.0).abs() < 50.0, "{estimate:?}");
+        }
+    }
+
+    #[test]
+    fn a_new_epoch_forgets_withdrawn_contributions() {
+        let nodes: Vec<Aggregator> = (0..4).map(|_| aggregator(15)).collect();
+        for node in &nodes {
+            node.contribute("load", 1.0);
+        }
+        nodes[0].contribute("load", 100.0);
+        gossip(&nodes, 14);
+        assert_eq!(nodes[1].estimate("load").unwrap().max, 100.0);
+
+        nodes[0].withdraw("load");
+        gossip(&nodes, 30);
+        let estimate = nodes[1].estimate("load").unwrap();
+        assert!(estimate.epoch >= 1);
+        assert_eq!(estimate.max, 1.0);
+        assert!((estimate.average - 1.0).abs() < 0.01, "{estimate:?}");
+    }
+
+    #[test]
+    fn later_epochs_are_joined_and_earlier_ones_dropped() {
+        let node = aggregator(1_000);
+        node.contribute("load", 4.0);
+        let mut peer = Metric::empty(256);
+        peer.contribute(8.0, None);
+        let share = peer.split("load");
+
+        node.absorb(3, std::slice::from_ref(&share));
+        let estimate = node.estimate("load").unwrap();
+        assert_eq!(estimate.epoch, 0);
+        assert_eq!(estimate.max, 4.0);
+
+        node.absorb(2, std::slice::from_ref(&share));
+        node.absorb(4, std::slice::from_ref(&share));
+        assert_eq!(node.estimate("load").unwrap().epoch, 3);
+    }
+
+    #[test]
+    fn malformed_shares_are_ignored() {
+        let node = aggregator(1_000);
+        let mut share = Metric::empty(256).split("load");
+        share.weight = f64::NAN;
+        node.absorb(0, std::slice::from_ref(&share));
+        share.weight = 1.0;
+        share.count_sketch.pop();
+        node.absorb(0, &[share]);
+        assert!(node.estimate("load").is_none());
+    }
+}
@@ -1,26 +1,59 @@
 //! Anti-entropy protocol for ensuring message delivery.
 //!
 //! Periodically reconciles the broadcast set with peers: each node
-//! summarizes what it holds as a per-origin `origin -> next-needed sequence` map and
-//! exchanges deltas, instead of shipping the full set of known message identifiers every round.
+//! summarizes what it holds per origin and exchanges deltas, instead of
+//! shipping the full set of known message identifiers every round.
+//!
+//! Two summaries exist (see [`Reconciliation`]). A version vector carries each
+//! origin's contiguous prefix, so it is tiny, but a peer cannot tell what the
+//! sender holds past a gap and resends all of it. A range digest lists the
+//! sequence ranges held per origin, so only the messages the sender truly
+//! lacks are pushed; its size grows with the number of gaps, not the number of
+//! messages. Range digests are sent only to peers that understand them.
 
 use std::collections::{BTreeSet, HashMap};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::time::{Duration, Instant};
 
-use dashmap::DashMap;
-use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 use tokio::time;
 use tracing::{debug, trace, warn};
 
-use crate::{Identity, Message, MessageId, Payload, PeerId, Result, Tcp, authenticate};
-
-/// Space, in bytes, withheld from the frame budget so that bincode's
-/// variable-length count prefix can grow as a chunk fills without pushing the
-/// frame past the limit.
-const CHUNK_LENGTH_PREFIX_RESERVE: usize = 8;
+use crate::core::message_codec::FRAME_OVERHEAD;
+use crate::protocol::adaptive::Adaptive;
+use crate::{
+    Identity, Message, MessageStore, Payload, PeerInfo, PeerProtocol, PeerSelector, PinStore,
+    Result, Tcp, Uniform, WireEncoding, authenticate,
+};
+
+/// Space, in bytes, withheld from the frame budget so that the encoding's
+/// variable-length count prefix can grow as a chunk fills, and the framing can
+/// add its header, without pushing the frame past the limit.
+const CHUNK_LENGTH_PREFIX_RESERVE: usize = 8 + FRAME_OVERHEAD;
+
+/// Cap on the ranges a range digest lists per origin. The highest ranges past
+/// it are omitted: the peer then resends those messages, which deduplication
+/// absorbs, so the cap bounds the digest at the cost of some precision.
+const MAX_RANGES_PER_ORIGIN: usize = 256;
+
+/// Per-origin held sequence ranges, as carried by [`Payload::RangeDigest`].
+type HeldRanges = Vec<(SocketAddr, Vec<(u64, u64)>)>;
+
+/// How anti-entropy summarizes the broadcasts a node holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reconciliation {
+    /// Per-origin contiguous prefix ([`Payload::AntiEntropyDigest`]). After a
+    /// gap, the peer resends every message above it each round.
+    VersionVector,
+
+    /// Per-origin held ranges ([`Payload::RangeDigest`]): only missing
+    /// messages are sent. Peers that predate range digests are reconciled by
+    /// version vector.
+    #[default]
+    Ranges,
+}
 
 /// Anti-entropy configuration.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,6 +66,10 @@ pub struct AntiEntropyConfig {
 
     /// Enable anti-entropy protocol
     pub enabled: bool,
+
+    /// How holdings are summarized
+    #[serde(default)]
+    pub reconciliation: Reconciliation,
 }
 
 impl Default for AntiEntropyConfig {
@@ -41,6 +78,7 @@ impl Default for AntiEntropyConfig {
             interval: Duration::from_secs(30),
             fanout: 3,
             enabled: true,
+            reconciliation: Reconciliation::default(),
         }
     }
 }
@@ -52,14 +90,41 @@ pub struct MessageEntry {
     pub message: Message,
     /// When we first saw it
     pub first_seen: Instant,
+    /// The message's largest encoded length, measured on first use and shared
+    /// by every clone of the entry
+    wire_len: Arc<OnceLock<Option<usize>>>,
+}
+
+impl MessageEntry {
+    /// Track `message`, first seen at `first_seen`.
+    pub fn new(message: Message, first_seen: Instant) -> Self {
+        Self {
+            message,
+            first_seen,
+            wire_len: Arc::default(),
+        }
+    }
+
+    /// The message's length in the largest of the wire encodings, or `None`
+    /// if it cannot be encoded. Measured once, so repairing the message again
+    /// does not serialize it again.
+    pub fn wire_len(&self) -> Option<usize> {
+        *self.wire_len.get_or_init(|| max_encoded_len(&self.message))
+    }
 }
 
 /// Anti-entropy engine for message repair.
 pub struct AntiEntropy {
     config: AntiEntropyConfig,
     transport: Arc<Tcp>,
-    seen_messages: Arc<DashMap<MessageId, MessageEntry>>,
+    store: Arc<MessageStore>,
     identity: Arc<Identity>,
+
+    /// Paces rounds by observed repairs, in adaptive mode
+    pacing: Option<Arc<Adaptive>>,
+
+    /// Chooses each round's peers
+    selector: Arc<dyn PeerSelector>,
 }
 
 impl AntiEntropy {
@@ -67,17 +132,32 @@ impl AntiEntropy {
     pub fn new(
         config: AntiEntropyConfig,
         transport: Arc<Tcp>,
-        seen_messages: Arc<DashMap<MessageId, MessageEntry>>,
+        store: Arc<MessageStore>,
         identity: Arc<Identity>,
     ) -> Self {
         Self {
             config,
             transport,
-            seen_messages,
+            store,
             identity,
+            pacing: None,
+            selector: Arc::new(Uniform),
         }
     }
 
+    /// Choose each round's peers with `selector` rather than uniformly.
+    pub fn with_peer_selector(mut self, selector: Arc<dyn PeerSelector>) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    /// Wait the interval `pacing` adapts to observed repairs between rounds,
+    /// instead of the configured interval.
+    pub(crate) fn with_pacing(mut self, pacing: Arc<Adaptive>) -> Self {
+        self.pacing = Some(pacing);
+        self
+    }
+
     /// Start anti-entropy rounds.
     pub async fn start(&self) -> Result<()> {
         if !self.config.enabled {
@@ -87,46 +167,63 @@ impl AntiEntropy {
 
         let config = self.config.clone();
         let transport = Arc::clone(&self.transport);
-        let seen_messages = Arc::clone(&self.seen_messages);
+        let store = Arc::clone(&self.store);
         let identity = Arc::clone(&self.identity);
+        let pacing = self.pacing.clone();
+        let selector = Arc::clone(&self.selector);
 
         tokio::spawn(async move {
             let mut ticker = time::interval(config.interval);
             loop {
-                ticker.tick().await;
+                match pacing {
+                    Some(ref pacing) => time::sleep(pacing.next_anti_entropy_interval()).await,
+                    None => {
+                        ticker.tick().await;
+                    }
+                }
 
                 let local_addr = match transport.local_addr() {
                     Some(addr) => addr,
                     None => continue,
                 };
 
-                let mut peer_addrs = transport.peers();
-                if peer_addrs.is_empty() {
+                let candidates: Vec<PeerInfo> = transport
+                    .peer_infos()
+                    .into_iter()
+                    .map(|(_, info)| info)
+                    .collect();
+                if candidates.is_empty() {
                     continue;
                 }
 
-                let selected_peers: Vec<SocketAddr> = {
-                    let mut rng = rand::rng();
-                    peer_addrs.shuffle(&mut rng);
-                    peer_addrs.into_iter().take(config.fanout).collect()
-                };
+                let selected_peers = selector.select(&candidates, config.fanout);
 
-                let version_vector = build_version_vector(&seen_messages);
+                let range_digest = (config.reconciliation == Reconciliation::Ranges).then(|| {
+                    Payload::RangeDigest {
+                        held: build_held_ranges(&store),
+                    }
+                });
+                let version_digest = Payload::AntiEntropyDigest {
+                    version_vector: build_version_vector(&store),
+                };
 
                 trace!(
-                    "Anti-entropy round: sending version vector ({} origins) to {} peers",
-                    version_vector.len(),
+                    "Anti-entropy round: sending digests to {} peers",
                     selected_peers.len()
                 );
 
                 for peer_addr in selected_peers {
-                    let digest_msg = match identity.author(
-                        local_addr,
-                        0,
-                        Payload::AntiEntropyDigest {
-                            version_vector: version_vector.clone(),
-                        },
-                    ) {
+                    let digest = match range_digest {
+                        Some(ref digest)
+                            if transport
+                                .peer_protocol(peer_addr)
+                                .is_some_and(|protocol| protocol.understands(digest)) =>
+                        {
+                            digest.clone()
+                        }
+                        _ => version_digest.clone(),
+                    };
+                    let digest_msg = match identity.author(local_addr, 0, digest) {
                         Ok(message) => message,
                         Err(e) => {
                             warn!("Failed to author anti-entropy digest: {e}");
@@ -150,14 +247,14 @@ impl AntiEntropy {
         peer_addr: SocketAddr,
         remote_version_vec: Vec<(SocketAddr, u64)>,
         transport: &Arc<Tcp>,
-        seen_messages: &DashMap<MessageId, MessageEntry>,
+        store: &MessageStore,
         identity: &Identity,
     ) -> Result<()> {
         let remote = remote_version_vec
             .into_iter()
             .collect::<HashMap<SocketAddr, u64>>();
 
-        let to_send = messages_for_peer(seen_messages, &remote);
+        let to_send = messages_for_peer(store, &remote);
         if !to_send.is_empty() {
             debug!("Pushing {} messages to {}", to_send.len(), peer_addr);
             Self::send_message_responses(local_addr, peer_addr, to_send, transport, identity).await;
@@ -167,7 +264,7 @@ impl AntiEntropy {
             local_addr,
             0,
             Payload::MessageRequest {
-                version_vector: build_version_vector(seen_messages),
+                version_vector: build_version_vector(store),
             },
         )?;
         if let Err(e) = transport.send(peer_addr, request).await {
@@ -184,12 +281,12 @@ impl AntiEntropy {
         peer_addr: SocketAddr,
         remote_version_vec: Vec<(SocketAddr, u64)>,
         transport: &Arc<Tcp>,
-        seen_messages: &DashMap<MessageId, MessageEntry>,
+        store: &MessageStore,
         identity: &Identity,
     ) -> Result<()> {
         let remote: HashMap<SocketAddr, u64> = remote_version_vec.into_iter().collect();
 
-        let to_send = messages_for_peer(seen_messages, &remote);
+        let to_send = messages_for_peer(store, &remote);
         if !to_send.is_empty() {
             debug!(
                 "Pushing {} requested messages to {}",
@@ -202,14 +299,79 @@ impl AntiEntropy {
         Ok(())
     }
 
-    /// Send repaired `messages` as one or more frame-bounded `MessageResponse`s.
+    /// Handle an incoming range digest: push exactly the messages the peer
+    /// lacks, then ask for the ones we lack with our own ranges.
+    pub async fn handle_range_digest(
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        remote_held: HeldRanges,
+        transport: &Arc<Tcp>,
+        store: &MessageStore,
+        identity: &Identity,
+    ) -> Result<()> {
+        let remote: HashMap<SocketAddr, Vec<(u64, u64)>> = remote_held.into_iter().collect();
+
+        let to_send = messages_outside_ranges(store, &remote);
+        if !to_send.is_empty() {
+            debug!("Pushing {} messages to {}", to_send.len(), peer_addr);
+            Self::send_message_responses(local_addr, peer_addr, to_send, transport, identity).await;
+        }
+
+        let request = identity.author(
+            local_addr,
+            0,
+            Payload::RangeRequest {
+                held: build_held_ranges(store),
+            },
+        )?;
+        if let Err(e) = transport.send(peer_addr, request).await {
+            warn!("Failed to send range request to {peer_addr}: {e}");
+        }
+
+        Ok(())
+    }
+
+    /// Handle a range pull request: push exactly the messages the peer lacks.
+    /// Terminal in the exchange.
+    pub async fn handle_range_request(
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        remote_held: HeldRanges,
+        transport: &Arc<Tcp>,
+        store: &MessageStore,
+        identity: &Identity,
+    ) -> Result<()> {
+        let remote: HashMap<SocketAddr, Vec<(u64, u64)>> = remote_held.into_iter().collect();
+
+        let to_send = messages_outside_ranges(store, &remote);
+        if !to_send.is_empty() {
+            debug!(
+                "Pushing {} requested messages to {}",
+                to_send.len(),
+                peer_addr
+            );
+            Self::send_message_responses(local_addr, peer_addr, to_send, transport, identity).await;
+        }
+
+        Ok(())
+    }
+
+    /// Send repaired `messages` as one or more frame-bounded `MessageResponse`s,
+    /// leaving out those the peer cannot decode.
     async fn send_message_responses(
         local_addr: SocketAddr,
         peer_addr: SocketAddr,
-        messages: Vec<Message>,
+        mut messages: Vec<MessageEntry>,
         transport: &Arc<Tcp>,
         identity: &Identity,
     ) {
+        let protocol = transport
+            .peer_protocol(peer_addr)
+            .unwrap_or(PeerProtocol::LEGACY);
+        let withheld = retain_decodable(&mut messages, protocol);
+        if withheld > 0 {
+            debug!("Withholding {withheld} repaired messages {peer_addr} cannot decode");
+        }
         match chunk_message_responses(identity, local_addr, messages, transport.max_message_size())
         {
             Ok(responses) => {
@@ -223,61 +385,43 @@ impl AntiEntropy {
         }
     }
 
-    /// Handle message response containing missing messages.
+    /// Handle message response containing missing messages. Returns the
+    /// repaired messages that had not been seen before, for delivery.
     pub fn handle_message_response(
         messages: Vec<Message>,
-        seen_messages: &DashMap<MessageId, MessageEntry>,
-        pins: &DashMap<SocketAddr, PeerId>,
-        message_handler: &Option<Arc<dyn Fn(SocketAddr, bytes::Bytes) + Send + Sync>>,
-    ) {
+        store: &MessageStore,
+        pins: &PinStore,
+    ) -> Vec<Message> {
         debug!(
             "Received {} missing messages via anti-entropy",
             messages.len()
         );
 
-        for message in messages {
-            if let Err(e) = authenticate(&message, pins) {
-                warn!(
-                    "Dropping unauthenticated repaired message claiming origin {}: {e}",
-                    message.id.origin
-                );
-                continue;
-            }
-
-            if seen_messages.contains_key(&message.id) {
-                continue;
-            }
-
-            seen_messages.insert(
-                message.id,
-                MessageEntry {
-                    message: message.clone(),
-                    first_seen: Instant::now(),
-                },
-            );
-
-            if let Payload::Application(ref data) = message.payload
-                && let Some(handler) = message_handler
-            {
-                handler(message.id.origin, data.clone());
-            }
-        }
+        messages
+            .into_iter()
+            .filter(|message| {
+                if let Err(e) = authenticate(message, pins) {
+                    warn!(
+                        "Dropping unauthenticated repaired message claiming origin {}: {e}",
+                        message.id.origin
+                    );
+                    return false;
+                }
+                store.insert(message.clone())
+            })
+            .collect()
     }
 }
 
 /// Summarize the broadcast set as a per-origin version vector: for each origin,
-/// the lowest sequence not yet held (the length of the contiguous prefix from
+/// the lowest sequence not yet seen (the length of the contiguous prefix from
 /// `0`). A peer pushes back everything it holds at or above this sequence.
-fn build_version_vector(
-    seen_messages: &DashMap<MessageId, MessageEntry>,
-) -> Vec<(SocketAddr, u64)> {
-    let mut sequences: HashMap<SocketAddr, BTreeSet<u64>> = HashMap::new();
-    for entry in seen_messages.iter() {
-        let id = entry.key();
-        sequences.entry(id.origin).or_default().insert(id.sequence);
-    }
-
-    sequences
+///
+/// Seen ids are summarized, not retained messages: a message whose payload was
+/// evicted is still one the node has no use for.
+fn build_version_vector(store: &MessageStore) -> Vec<(SocketAddr, u64)> {
+    store
+        .seen_sequences()
         .into_iter()
         .map(|(origin, seqs)| (origin, next_needed_sequence(&seqs)))
         .collect()
@@ -299,52 +443,107 @@ fn next_needed_sequence(sequences: &BTreeSet<u64>) -> u64 {
 /// Every message held at or above the peer's per-origin need (default `0` for an
 /// origin the peer has never seen). Conservative under gaps: a peer that holds
 /// messages past its own gap may receive a few it already has, which it dedups.
-fn messages_for_peer(
-    seen_messages: &DashMap<MessageId, MessageEntry>,
-    remote: &HashMap<SocketAddr, u64>,
-) -> Vec<Message> {
-    seen_messages
-        .iter()
-        .filter(|entry| {
-            let id = entry.key();
-            id.sequence >= remote.get(&id.origin).copied().unwrap_or(0)
-        })
-        .map(|entry| entry.value().message.clone())
+fn messages_for_peer(store: &MessageStore, remote: &HashMap<SocketAddr, u64>) -> Vec<MessageEntry> {
+    store.retained_entries(|id| id.sequence >= remote.get(&id.origin).copied().unwrap_or(0))
+}
+
+/// Summarize the broadcast set as per-origin held ranges: for each origin, the
+/// sorted, disjoint `[start, end)` runs of sequences seen, at most
+/// [`MAX_RANGES_PER_ORIGIN`] of them. As with the version vector, an evicted
+/// message still counts as held.
+fn build_held_ranges(store: &MessageStore) -> HeldRanges {
+    store
+        .seen_sequences()
+        .into_iter()
+        .map(|(origin, seqs)| (origin, held_ranges(&seqs)))
         .collect()
 }
 
+/// Coalesce sorted `sequences` into half-open runs, keeping the lowest
+/// [`MAX_RANGES_PER_ORIGIN`].
+fn held_ranges(sequences: &BTreeSet<u64>) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for &sequence in sequences {
+        if let Some((_, end)) = ranges.last_mut()
+            && *end == sequence
+        {
+            *end = sequence.saturating_add(1);
+        } else if ranges.len() == MAX_RANGES_PER_ORIGIN {
+            break;
+        } else {
+            ranges.push((sequence, sequence.saturating_add(1)));
+        }
+    }
+    ranges
+}
+
+/// Whether `sequence` falls in one of the sorted, disjoint `ranges`.
+fn in_ranges(ranges: &[(u64, u64)], sequence: u64) -> bool {
+    let after = ranges.partition_point(|&(start, _)| start <= sequence);
+    after > 0 && sequence < ranges[after - 1].1
+}
+
+/// Collect every held message outside the peer's `remote` ranges: exactly the
+/// ones it lacks (up to the per-origin range cap).
+fn messages_outside_ranges(
+    store: &MessageStore,
+    remote: &HashMap<SocketAddr, Vec<(u64, u64)>>,
+) -> Vec<MessageEntry> {
+    store.retained_entries(|id| {
+        !remote
+            .get(&id.origin)
+            .is_some_and(|ranges| in_ranges(ranges, id.sequence))
+    })
+}
+
+/// Drop the `messages` a peer speaking `protocol` cannot decode, returning how
+/// many were dropped. One such message nested in a repair or snapshot chunk
+/// would fail the whole frame, and the peer would close the connection.
+pub(crate) fn retain_decodable(messages: &mut Vec<MessageEntry>, protocol: PeerProtocol) -> usize {
+    let before = messages.len();
+    messages.retain(|entry| protocol.understands(&entry.message.payload));
+    before - messages.len()
+}
+
 /// Split repaired `messages` into one or more signed `MessageResponse`s, each of
 /// which serializes within `max_frame_size`.
 ///
-/// The signed and unsigned envelopes serialize to the same length (the
-/// public-key and signature fields are fixed-width), so the empty unsigned
-/// envelope is used to measure the per-frame budget while the emitted frames are
-/// authored and signed by `identity`.
-///
 /// # Errors
 /// Returns [`Error::Serialization`](crate::Error::Serialization) if a response
 /// envelope cannot be authored.
 fn chunk_message_responses(
     identity: &Identity,
     local_addr: SocketAddr,
-    messages: Vec<Message>,
+    messages: Vec<MessageEntry>,
     max_frame_size: usize,
 ) -> Result<Vec<Message>> {
-    let encoded_len = |message: &Message| {
-        bincode::serde::encode_to_vec(message, bincode::config::standard())
-            .ok()
-            .map(|bytes| bytes.len())
+    let envelope = Payload::MessageResponse {
+        messages: Vec::new(),
     };
+    batch_within_frame(local_addr, messages, max_frame_size, envelope)
+        .into_iter()
+        .map(|messages| identity.author(local_addr, 0, Payload::MessageResponse { messages }))
+        .collect()
+}
 
-    let envelope = Message::new(
-        local_addr,
-        0,
-        Payload::MessageResponse {
-            messages: Vec::new(),
-        },
-    );
-    let Some(envelope_len) = encoded_len(&envelope) else {
-        return Ok(Vec::new());
+/// Split `messages`, in order, into batches that each serialize within
+/// `max_frame_size` once placed in `envelope`, an empty carrier payload whose
+/// other fields are at their largest. A message too large to fit alone is
+/// dropped.
+///
+/// The signed and unsigned envelopes serialize to the same length (the
+/// public-key and signature fields are fixed-width), so the unsigned envelope
+/// is used to measure the per-frame budget. Each message is measured with
+/// [`MessageEntry::wire_len`], which serializes it only the first time.
+pub(crate) fn batch_within_frame(
+    local_addr: SocketAddr,
+    messages: Vec<MessageEntry>,
+    max_frame_size: usize,
+    envelope: Payload,
+) -> Vec<Vec<Message>> {
+    let envelope = Message::new(local_addr, 0, envelope);
+    let Some(envelope_len) = max_encoded_len(&envelope) else {
+        return Vec::new();
     };
     let budget = max_frame_size
         .saturating_sub(envelope_len)
@@ -354,13 +553,14 @@ fn chunk_message_responses(
     let mut batch: Vec<Message> = Vec::new();
     let mut batch_len = 0usize;
 
-    for message in messages {
-        let Some(len) = encoded_len(&message) else {
+    for entry in messages {
+        let Some(len) = entry.wire_len() else {
             continue;
         };
+        let message = entry.message;
         if len > budget {
             warn!(
-                "Dropping un-chunkable repair message {} ({len} B over frame budget {budget} B)",
+                "Dropping un-chunkable message {} ({len} B over frame budget {budget} B)",
                 message.id
             );
             continue;
@@ -376,17 +576,30 @@ fn chunk_message_responses(
     if !batch.is_empty() {
         batches.push(batch);
     }
-
     batches
+}
+
+/// `message`'s length in the largest of the wire encodings, which are chosen
+/// per connection; `None` if it cannot be encoded.
+fn max_encoded_len(message: &Message) -> Option<usize> {
+    WireEncoding::ALL
         .into_iter()
-        .map(|messages| identity.author(local_addr, 0, Payload::MessageResponse { messages }))
-        .collect()
+        .map(|wire| {
+            wire.encoding()
+                .encode(message)
+                .ok()
+                .map(|bytes| bytes.len())
+        })
+        .try_fold(0, |max, len| len.map(|len| len.max(max)))
 }
 
 #[cfg(test)]
 mod tests {
+    use bytes::Bytes;
+
     use super::*;
     use crate::core::message_codec::MAX_FRAME_SIZE;
+    use crate::{MessageId, MessageStoreConfig};
 
     fn addr(port: u16) -> SocketAddr {
         SocketAddr::from(([127, 0, 0, 1], port))
@@ -396,18 +609,12 @@ mod tests {
         Message::new(origin, sequence, Payload::Application(vec![0u8; 8].into()))
     }
 
-    fn seen(messages: impl IntoIterator<Item = Message>) -> DashMap<MessageId, MessageEntry> {
-        let map = DashMap::new();
+    fn seen(messages: impl IntoIterator<Item = Message>) -> MessageStore {
+        let store = MessageStore::new(MessageStoreConfig::default());
         for message in messages {
-            map.insert(
-                message.id,
-                MessageEntry {
-                    message,
-                    first_seen: Instant::now(),
-                },
-            );
+            store.insert(message);
         }
-        map
+        store
     }
 
     fn app_message(origin: SocketAddr, byte: u8, len: usize) -> Message {
@@ -418,6 +625,13 @@ mod tests {
         )
     }
 
+    fn entries(messages: impl IntoIterator<Item = Message>) -> Vec<MessageEntry> {
+        messages
+            .into_iter()
+            .map(|message| MessageEntry::new(message, Instant::now()))
+            .collect()
+    }
+
     fn frame_len(message: &Message) -> usize {
         bincode::serde::encode_to_vec(message, bincode::config::standard())
             .expect("message encodes")
@@ -467,7 +681,7 @@ mod tests {
         let remote = HashMap::from([(a, 1u64)]);
         let mut seqs = messages_for_peer(&map, &remote)
             .iter()
-            .map(|m| m.id.sequence)
+            .map(|entry| entry.message.id.sequence)
             .collect::<Vec<u64>>();
         seqs.sort_unstable();
         assert_eq!(seqs, vec![1, 2], "only sequences >= the need are pushed");
@@ -480,6 +694,71 @@ mod tests {
         );
     }
 
+    #[test]
+    fn held_ranges_coalesce_runs_around_gaps() {
+        let a = addr(10);
+        let b = addr(11);
+        let map = seen(
+            [0, 2, 3, 4, 9]
+                .map(|sequence| broadcast(a, sequence))
+                .into_iter()
+                .chain([broadcast(b, 5)]),
+        );
+
+        let held = build_held_ranges(&map)
+            .into_iter()
+            .collect::<HashMap<SocketAddr, Vec<(u64, u64)>>>();
+        assert_eq!(held[&a], vec![(0, 1), (2, 5), (9, 10)]);
+        assert_eq!(held[&b], vec![(5, 6)]);
+    }
+
+    #[test]
+    fn held_ranges_are_capped_per_origin() {
+        let alternating = (0..2 * MAX_RANGES_PER_ORIGIN as u64)
+            .step_by(2)
+            .chain([u64::MAX - 1])
+            .collect::<BTreeSet<u64>>();
+        let ranges = held_ranges(&alternating);
+        assert_eq!(ranges.len(), MAX_RANGES_PER_ORIGIN);
+        assert_eq!(ranges.last(), Some(&(510, 511)), "the lowest runs are kept");
+    }
+
+    #[test]
+    fn only_messages_outside_the_remote_ranges_are_pushed() {
+        let a = addr(10);
+        let b = addr(11);
+        let map = seen(
+            (0..50)
+                .map(|sequence| broadcast(a, sequence))
+                .chain([broadcast(b, 0)]),
+        );
+
+        // The peer lacks only a's sequence 1 and everything from b.
+        let remote = HashMap::from([(a, vec![(0, 1), (2, 50)])]);
+        let mut missing = messages_outside_ranges(&map, &remote)
+            .iter()
+            .map(|entry| (entry.message.id.origin, entry.message.id.sequence))
+            .collect::<Vec<_>>();
+        missing.sort_unstable();
+        assert_eq!(missing, vec![(a, 1), (b, 0)]);
+
+        // The version vector for the same holdings resends all of a's tail.
+        let needed = HashMap::from([(a, 1u64), (b, 0)]);
+        assert_eq!(messages_for_peer(&map, &needed).len(), 50);
+    }
+
+    #[test]
+    fn range_membership_respects_half_open_bounds() {
+        let ranges = [(0, 1), (5, 8)];
+        assert!(in_ranges(&ranges, 0));
+        assert!(!in_ranges(&ranges, 1));
+        assert!(!in_ranges(&ranges, 4));
+        assert!(in_ranges(&ranges, 5));
+        assert!(in_ranges(&ranges, 7));
+        assert!(!in_ranges(&ranges, 8));
+        assert!(!in_ranges(&[], 0));
+    }
+
     #[test]
     fn chunking_keeps_every_frame_within_the_limit() {
         let local = addr(1);
@@ -493,7 +772,7 @@ mod tests {
 
         let identity = Identity::generate();
         let responses =
-            chunk_message_responses(&identity, local, messages, max_frame_size).unwrap();
+            chunk_message_responses(&identity, local, entries(messages), max_frame_size).unwrap();
 
         assert!(responses.len() > 1, "an over-budget batch must split");
         let mut carried = 0;
@@ -519,7 +798,7 @@ mod tests {
 
         let identity = Identity::generate();
         let responses =
-            chunk_message_responses(&identity, local, vec![small.clone(), oversized], 2048)
+            chunk_message_responses(&identity, local, entries([small.clone(), oversized]), 2048)
                 .unwrap();
 
         let carried = responses
@@ -536,6 +815,52 @@ mod tests {
         );
     }
 
+    #[test]
+    fn repairs_leave_out_what_the_peer_cannot_decode() {
+        let origin = addr(2);
+        let causal = Message::new(
+            origin,
+            1,
+            Payload::CausalApplication {
+                data: Bytes::new(),
+                clock: Vec::new(),
+            },
+        );
+        let messages = [broadcast(origin, 0), causal];
+        // A version 2 peer speaks versioned frames, but predates causal
+        // broadcasts.
+        let versioned = PeerProtocol {
+            version: 2,
+            ..PeerProtocol::LOCAL
+        };
+
+        let mut repair = entries(messages.clone());
+        assert_eq!(retain_decodable(&mut repair, PeerProtocol::LOCAL), 0);
+        for protocol in [PeerProtocol::LEGACY, versioned] {
+            let mut repair = entries(messages.clone());
+            assert_eq!(retain_decodable(&mut repair, protocol), 1);
+            assert_eq!(repair[0].message.id.sequence, 0);
+        }
+    }
+
+    #[test]
+    fn wire_length_is_measured_once_and_shared_with_the_store() {
+        let origin = addr(2);
+        let message = app_message(origin, 1, 64);
+        let store = seen([message.clone()]);
+
+        let first = store.retained_entries(|_| true);
+        assert_eq!(first[0].wire_len.get(), None);
+        assert_eq!(first[0].wire_len(), max_encoded_len(&message));
+
+        let again = store.retained_entries(|_| true);
+        assert_eq!(
+            again[0].wire_len.get().copied(),
+            Some(max_encoded_len(&message)),
+            "a later repair reuses the measurement"
+        );
+    }
+
     #[test]
     fn chunking_fits_a_small_batch_in_one_frame() {
         let local = addr(1);
@@ -544,7 +869,7 @@ mod tests {
 
         let identity = Identity::generate();
         let responses =
-            chunk_message_responses(&identity, local, messages, MAX_FRAME_SIZE).unwrap();
+            chunk_message_responses(&identity, local, entries(messages), MAX_FRAME_SIZE).unwrap();
         assert_eq!(responses.len(), 1, "a small batch needs a single frame");
     }
 }
@@ -0,0 +1,654 @@
+//! Ordered delivery of broadcasts to the application.
+//!
+//! Broadcasts arrive out of order: epidemic forwarding races along different
+//! paths, and anti-entropy repairs a gap long after the messages around it
+//! were received. By default each broadcast is handed to the application as
+//! soon as it arrives ([`DeliveryOrder::Immediate`]). The other modes hold
+//! messages back until they can be delivered in order:
+//!
+//! - [`DeliveryOrder::Fifo`]: each origin's broadcasts are delivered in
+//!   sequence order. A message waits until every earlier sequence from its
+//!   origin has been delivered.
+//! - [`DeliveryOrder::Causal`]: additionally, a broadcast is delivered only
+//!   after everything its origin had delivered when sending it. Broadcasts
+//!   carry the origin's vector clock ([`Payload::CausalApplication`]) for this.
+//!
+//! A gap may never fill: the missing message can have expired from every
+//! peer's store. The hold-back queue is therefore bounded in time
+//! ([`DeliveryConfig::gap_timeout`]) and size ([`DeliveryConfig::max_held`]).
+//! When either bound is hit, the oldest held message is released according to
+//! the [`GapPolicy`], and delivery continues past it. A message that arrives
+//! after its place in the order has been given up is dropped.
+//!
+//! Retractions ([`Payload::Retraction`]) take their place in the origin's
+//! sequence like any broadcast, so under FIFO and causal ordering the
+//! application learns of a retraction after the message it retracts. A
+//! message whose deadline ([`Message::expires_at`]) passes while it is held is
+//! dropped instead of delivered.
+//!
+//! Key-value store updates ([`Payload::KvDelta`]) also hold their place in the
+//! origin's sequence. They are released to the node's replica rather than the
+//! application.
+//!
+//! Ordering starts at sequence `0` for every origin. A node that joins after
+//! an origin's early broadcasts have expired everywhere waits out one gap
+//! timeout before delivering from that origin.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{HlcTimestamp, Message, MessageId, Payload};
+
+/// The order in which broadcasts are handed to the application.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryOrder {
+    /// As soon as they arrive.
+    #[default]
+    Immediate,
+
+    /// In sequence order per origin.
+    Fifo,
+
+    /// In an order consistent with causality: per-origin FIFO, and never
+    /// before a broadcast its origin had delivered when sending.
+    Causal,
+}
+
+/// What to do with a held message whose place in the order cannot be reached
+/// within the bounds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GapPolicy {
+    /// Deliver it, giving up on whatever it was waiting for.
+    #[default]
+    Skip,
+
+    /// Drop it without delivering.
+    Discard,
+}
+
+/// Delivery ordering configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryConfig {
+    /// Delivery order
+    pub order: DeliveryOrder,
+
+    /// Maximum number of messages held back
+    pub max_held: usize,
+
+    /// How long a message may be held back
+    pub gap_timeout: Duration,
+
+    /// What to do with a message held past a bound
+    pub on_gap: GapPolicy,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        Self {
+            order: DeliveryOrder::default(),
+            max_held: 10_000,
+            gap_timeout: Duration::from_secs(30),
+            on_gap: GapPolicy::default(),
+        }
+    }
+}
+
+impl DeliveryConfig {
+    /// Validate the configuration.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_held == 0 {
+            return Err("delivery max_held must be greater than 0".to_string());
+        }
+        if self.gap_timeout.is_zero() {
+            return Err("delivery gap_timeout must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Counters describing the hold-back queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeliveryStats {
+    /// Messages currently held back
+    pub held: usize,
+
+    /// Messages delivered out of order under [`GapPolicy::Skip`]
+    pub skipped: u64,
+
+    /// Messages dropped under [`GapPolicy::Discard`], or because they arrived
+    /// after their place in the order was given up
+    pub discarded: u64,
+}
+
+/// A message handed to the application.
+#[derive(Debug, Clone)]
+pub struct Delivered {
+    /// The message's identifier: its origin, sequence, and timestamp.
+    pub id: MessageId,
+
+    /// The application data.
+    pub data: Bytes,
+}
+
+impl Delivered {
+    /// The node that originated the message.
+    pub fn origin(&self) -> SocketAddr {
+        self.id.origin
+    }
+
+    /// The origin's signed hybrid logical clock stamp, if the message carries
+    /// one (see [`MessageId::hlc`]).
+    pub fn timestamp(&self) -> Option<HlcTimestamp> {
+        self.id.hlc()
+    }
+}
+
+/// A retraction handed to the application: the origin has withdrawn one of
+/// its broadcasts.
+#[derive(Debug, Clone, Copy)]
+pub struct Retracted {
+    /// The tombstone's own id, carrying the origin and its clock stamp.
+    pub tombstone: MessageId,
+
+    /// The retracted broadcast's id. Only its origin and sequence are known;
+    /// its timestamp is `0`.
+    pub target: MessageId,
+}
+
+/// What the hold-back queue hands over, in order: to the application, or to
+/// the key-value replica for a store update.
+#[derive(Debug)]
+pub(crate) enum Released {
+    Message(Delivered),
+    Retraction(Retracted),
+    Delta(Bytes),
+}
+
+impl Released {
+    /// What `message` releases, or `None` if it is not a broadcast or its
+    /// deadline has passed.
+    fn of(message: Message) -> Option<Self> {
+        if message.is_expired() {
+            debug!("Not delivering {}: its deadline has passed", message.id);
+            return None;
+        }
+        let id = message.id;
+        match message.payload {
+            Payload::Application(data)
+            | Payload::CausalApplication { data, .. }
+            | Payload::ExpiringApplication { data, .. } => {
+                Some(Self::Message(Delivered { id, data }))
+            }
+            Payload::Retraction { sequence } => Some(Self::Retraction(Retracted {
+                tombstone: id,
+                target: MessageId {
+                    origin: id.origin,
+                    sequence,
+                    timestamp: 0,
+                },
+            })),
+            Payload::KvDelta { delta } => Some(Self::Delta(delta)),
+            _ => None,
+        }
+    }
+}
+
+/// The hold-back queue.
+#[derive(Debug)]
+pub(crate) struct Delivery {
+    config: DeliveryConfig,
+    state: Mutex<State>,
+
+    /// Held from releasing messages until they have been handed over, so
+    /// concurrent releases reach the application in the order they were made.
+    handover: Mutex<()>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Per origin, the next sequence to deliver: everything below it has been
+    /// delivered or given up.
+    next: HashMap<SocketAddr, u64>,
+
+    /// Per origin, the messages held back, by sequence.
+    held: HashMap<SocketAddr, BTreeMap<u64, Held>>,
+
+    held_count: usize,
+    skipped: u64,
+    discarded: u64,
+}
+
+#[derive(Debug)]
+struct Held {
+    message: Message,
+    clock: Vec<(SocketAddr, u64)>,
+    since: Instant,
+}
+
+impl Delivery {
+    pub(crate) fn new(config: DeliveryConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::default()),
+            handover: Mutex::new(()),
+        }
+    }
+
+    pub(crate) fn config(&self) -> &DeliveryConfig {
+        &self.config
+    }
+
+    /// Accept a newly seen broadcast and pass every broadcast or retraction
+    /// that can now be delivered to `deliver`, in order. A payload that is not
+    /// a broadcast is ignored.
+    pub(crate) fn accept(&self, message: &Message, deliver: impl FnMut(Released)) {
+        let _handover = lock(&self.handover);
+        hand_over(self.release(message), deliver);
+    }
+
+    /// Release every message held longer than the gap timeout, passing
+    /// whatever can then be delivered to `deliver`, in order.
+    pub(crate) fn expire(&self, deliver: impl FnMut(Released)) {
+        let _handover = lock(&self.handover);
+        hand_over(self.release_expired(), deliver);
+    }
+
+    fn release(&self, message: &Message) -> Vec<Released> {
+        let clock = match &message.payload {
+            Payload::Application(_) | Payload::Retraction { .. } | Payload::KvDelta { .. } => {
+                &[][..]
+            }
+            Payload::CausalApplication { clock, .. }
+            | Payload::ExpiringApplication { clock, .. } => &clock[..],
+            _ => return Vec::new(),
+        };
+        let origin = message.id.origin;
+        if self.config.order == DeliveryOrder::Immediate {
+            return Released::of(message.clone()).into_iter().collect();
+        }
+
+        let mut state = lock(&self.state);
+        let sequence = message.id.sequence;
+        if sequence < state.next(origin) {
+            debug!(
+                "Dropping {} that arrived after its gap was given up",
+                message.id
+            );
+            state.discarded += 1;
+            return Vec::new();
+        }
+        let clock = if self.config.order == DeliveryOrder::Causal {
+            clock.to_vec()
+        } else {
+            Vec::new()
+        };
+        let held = state.held.entry(origin).or_default();
+        if held
+            .insert(
+                sequence,
+                Held {
+                    message: message.clone(),
+                    clock,
+                    since: Instant::now(),
+                },
+            )
+            .is_none()
+        {
+            state.held_count += 1;
+        }
+
+        let mut delivered = state.drain();
+        while state.held_count > self.config.max_held {
+            debug!(
+                "Hold-back queue over {} messages, releasing the oldest",
+                self.config.max_held
+            );
+            delivered.extend(state.release_oldest(self.config.on_gap));
+            delivered.extend(state.drain());
+        }
+        delivered
+    }
+
+    /// Record that this node broadcast `sequence` itself, so its own
+    /// broadcasts count towards the clocks it sends and the ones it checks.
+    pub(crate) fn record_own(&self, origin: SocketAddr, sequence: u64) {
+        if self.config.order == DeliveryOrder::Causal {
+            let mut state = lock(&self.state);
+            let next = state.next.entry(origin).or_default();
+            *next = (*next).max(sequence.saturating_add(1));
+        }
+    }
+
+    /// The vector clock to attach to a broadcast from `origin`: how many
+    /// broadcasts from each other origin have been delivered.
+    pub(crate) fn clock(&self, origin: SocketAddr) -> Vec<(SocketAddr, u64)> {
+        lock(&self.state)
+            .next
+            .iter()
+            .filter(|&(&other, &next)| other != origin && next > 0)
+            .map(|(&other, &next)| (other, next))
+            .collect()
+    }
+
+    fn release_expired(&self) -> Vec<Released> {
+        let mut state = lock(&self.state);
+        let mut delivered = Vec::new();
+        while state
+            .oldest()
+            .is_some_and(|(_, _, since)| since.elapsed() > self.config.gap_timeout)
+        {
+            delivered.extend(state.release_oldest(self.config.on_gap));
+            delivered.extend(state.drain());
+        }
+        delivered
+    }
+
+    pub(crate) fn stats(&self) -> DeliveryStats {
+        let state = lock(&self.state);
+        DeliveryStats {
+            held: state.held_count,
+            skipped: state.skipped,
+            discarded: state.discarded,
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // Neither lock guards state a panicking handler could leave half-updated.
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn hand_over(delivered: Vec<Released>, deliver: impl FnMut(Released)) {
+    delivered.into_iter().for_each(deliver);
+}
+
+impl State {
+    fn next(&self, origin: SocketAddr) -> u64 {
+        self.next.get(&origin).copied().unwrap_or(0)
+    }
+
+    /// Whether everything `clock` depends on has been delivered.
+    fn satisfied(&self, clock: &[(SocketAddr, u64)]) -> bool {
+        clock
+            .iter()
+            .all(|&(origin, count)| self.next(origin) >= count)
+    }
+
+    /// Deliver held messages until none is deliverable.
+    fn drain(&mut self) -> Vec<Released> {
+        let mut delivered = Vec::new();
+        loop {
+            let ready: Vec<SocketAddr> = self
+                .held
+                .iter()
+                .filter(|&(&origin, held)| {
+                    held.first_key_value().is_some_and(|(&sequence, message)| {
+                        sequence == self.next(origin) && self.satisfied(&message.clock)
+                    })
+                })
+                .map(|(&origin, _)| origin)
+                .collect();
+            if ready.is_empty() {
+                return delivered;
+            }
+            for origin in ready {
+                if let Some((sequence, message)) = self.take_first(origin) {
+                    self.next.insert(origin, sequence + 1);
+                    delivered.extend(Released::of(message.message));
+                }
+            }
+        }
+    }
+
+    /// The held message waiting longest: its origin, sequence, and arrival.
+    fn oldest(&self) -> Option<(SocketAddr, u64, Instant)> {
+        self.held
+            .iter()
+            .flat_map(|(&origin, held)| {
+                held.iter()
+                    .map(move |(&sequence, message)| (origin, sequence, message.since))
+            })
+            .min_by_key(|&(_, _, since)| since)
+    }
+
+    /// Give up on whatever the oldest held message, or the lowest one held
+    /// from its origin, is waiting for.
+    fn release_oldest(&mut self, policy: GapPolicy) -> Option<Released> {
+        let (origin, _, _) = self.oldest()?;
+        let (sequence, message) = self.take_first(origin)?;
+        self.next.insert(origin, sequence + 1);
+        match policy {
+            GapPolicy::Skip => {
+                debug!("Delivering {origin}:{sequence} past a gap");
+                self.skipped += 1;
+                Released::of(message.message)
+            }
+            GapPolicy::Discard => {
+                debug!("Discarding {origin}:{sequence}, held past a gap");
+                self.discarded += 1;
+                None
+            }
+        }
+    }
+
+    fn take_first(&mut self, origin: SocketAddr) -> Option<(u64, Held)> {
+        let held = self.held.get_mut(&origin)?;
+        let first = held.pop_first();
+        if held.is_empty() {
+            self.held.remove(&origin);
+        }
+        if first.is_some() {
+            self.held_count -= 1;
+        }
+        first
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn broadcast(origin: SocketAddr, sequence: u64) -> Message {
+        Message::new(
+            origin,
+            sequence,
+            Payload::Application(Bytes::from(format!("{sequence}"))),
+        )
+    }
+
+    fn causal(origin: SocketAddr, sequence: u64, clock: Vec<(SocketAddr, u64)>) -> Message {
+        Message::new(
+            origin,
+            sequence,
+            Payload::CausalApplication {
+                data: Bytes::from(format!("{}:{sequence}", origin.port())),
+                clock,
+            },
+        )
+    }
+
+    /// A delivered message's data or store delta, or `retract:<sequence>` for a
+    /// retraction.
+    fn label(released: Released) -> Bytes {
+        match released {
+            Released::Message(message) => message.data,
+            Released::Retraction(retracted) => {
+                Bytes::from(format!("retract:{}", retracted.target.sequence))
+            }
+            Released::Delta(delta) => delta,
+        }
+    }
+
+    fn delivery(order: DeliveryOrder) -> Delivery {
+        Delivery::new(DeliveryConfig {
+            order,
+            ..DeliveryConfig::default()
+        })
+    }
+
+    fn accept(delivery: &Delivery, message: Message) -> Vec<Bytes> {
+        let mut delivered = Vec::new();
+        delivery.accept(&message, |released| delivered.push(label(released)));
+        delivered
+    }
+
+    fn expire(delivery: &Delivery) -> Vec<Bytes> {
+        let mut delivered = Vec::new();
+        delivery.expire(|released| delivered.push(label(released)));
+        delivered
+    }
+
+    #[test]
+    fn immediate_delivers_on_arrival() {
+        let delivery = delivery(DeliveryOrder::Immediate);
+        assert_eq!(accept(&delivery, broadcast(addr(1), 5)), ["5"]);
+        assert_eq!(delivery.stats().held, 0);
+    }
+
+    #[test]
+    fn fifo_holds_until_the_gap_fills() {
+        let delivery = delivery(DeliveryOrder::Fifo);
+        let origin = addr(1);
+
+        assert!(accept(&delivery, broadcast(origin, 1)).is_empty());
+        assert!(accept(&delivery, broadcast(origin, 2)).is_empty());
+        assert_eq!(delivery.stats().held, 2);
+        assert_eq!(accept(&delivery, broadcast(origin, 0)), ["0", "1", "2"]);
+        assert_eq!(delivery.stats().held, 0);
+    }
+
+    #[test]
+    fn fifo_orders_each_origin_independently() {
+        let delivery = delivery(DeliveryOrder::Fifo);
+        assert!(accept(&delivery, broadcast(addr(1), 1)).is_empty());
+        assert_eq!(accept(&delivery, broadcast(addr(2), 0)), ["0"]);
+    }
+
+    #[test]
+    fn causal_waits_for_dependencies() {
+        let delivery = delivery(DeliveryOrder::Causal);
+        let (a, b) = (addr(1), addr(2));
+
+        // b sent its first broadcast after delivering a's first.
+        assert!(accept(&delivery, causal(b, 0, vec![(a, 1)])).is_empty());
+        assert_eq!(accept(&delivery, causal(a, 0, Vec::new())), ["1:0", "2:0"]);
+    }
+
+    #[test]
+    fn fifo_ignores_vector_clocks() {
+        let delivery = delivery(DeliveryOrder::Fifo);
+        assert_eq!(
+            accept(&delivery, causal(addr(2), 0, vec![(addr(1), 1)])),
+            ["2:0"]
+        );
+    }
+
+    #[test]
+    fn clocks_count_delivered_and_own_broadcasts() {
+        let delivery = delivery(DeliveryOrder::Causal);
+        let (own, a) = (addr(1), addr(2));
+        accept(&delivery, causal(a, 0, Vec::new()));
+        accept(&delivery, causal(a, 1, Vec::new()));
+        delivery.record_own(own, 0);
+
+        assert_eq!(delivery.clock(own), vec![(a, 2)]);
+        let mut clock = delivery.clock(addr(3));
+        clock.sort_unstable();
+        assert_eq!(clock, vec![(own, 1), (a, 2)]);
+    }
+
+    #[test]
+    fn overflow_skips_the_oldest_gap() {
+        let delivery = Delivery::new(DeliveryConfig {
+            order: DeliveryOrder::Fifo,
+            max_held: 2,
+            ..DeliveryConfig::default()
+        });
+        let origin = addr(1);
+
+        accept(&delivery, broadcast(origin, 2));
+        accept(&delivery, broadcast(origin, 3));
+        assert_eq!(accept(&delivery, broadcast(origin, 5)), ["2", "3"]);
+        assert_eq!(
+            delivery.stats(),
+            DeliveryStats {
+                held: 1,
+                skipped: 1,
+                discarded: 0,
+            }
+        );
+
+        // Sequences 0 and 1 were given up on: arriving now, they are dropped.
+        assert!(accept(&delivery, broadcast(origin, 0)).is_empty());
+        assert_eq!(delivery.stats().discarded, 1);
+    }
+
+    #[test]
+    fn timeout_discards_under_the_discard_policy() {
+        let delivery = Delivery::new(DeliveryConfig {
+            order: DeliveryOrder::Fifo,
+            gap_timeout: Duration::from_millis(1),
+            on_gap: GapPolicy::Discard,
+            ..DeliveryConfig::default()
+        });
+        let origin = addr(1);
+
+        accept(&delivery, broadcast(origin, 1));
+        accept(&delivery, broadcast(origin, 2));
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(expire(&delivery), ["2"]);
+        assert_eq!(delivery.stats().discarded, 1);
+        assert_eq!(accept(&delivery, broadcast(origin, 3)), ["3"]);
+    }
+
+    #[test]
+    fn timeout_leaves_recent_messages_held() {
+        let delivery = delivery(DeliveryOrder::Fifo);
+        accept(&delivery, broadcast(addr(1), 1));
+        assert!(expire(&delivery).is_empty());
+        assert_eq!(delivery.stats().held, 1);
+    }
+
+    #[test]
+    fn fifo_reports_a_retraction_after_the_message_it_retracts() {
+        let delivery = delivery(DeliveryOrder::Fifo);
+        let origin = addr(1);
+        let tombstone = Message::new(origin, 1, Payload::Retraction { sequence: 0 });
+
+        assert!(accept(&delivery, tombstone).is_empty());
+        assert_eq!(accept(&delivery, broadcast(origin, 0)), ["0", "retract:0"]);
+    }
+
+    #[test]
+    fn messages_expiring_while_held_are_not_delivered() {
+        let delivery = delivery(DeliveryOrder::Fifo);
+        let origin = addr(1);
+        let expiring = Message::new(
+            origin,
+            1,
+            Payload::ExpiringApplication {
+                data: Bytes::from_static(b"stale"),
+                clock: Vec::new(),
+                expires_at: crate::core::hlc::wall_clock_ms() + 10,
+            },
+        );
+
+        assert!(accept(&delivery, expiring).is_empty());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(accept(&delivery, broadcast(origin, 0)), ["0"]);
+        assert_eq!(accept(&delivery, broadcast(origin, 2)), ["2"]);
+    }
+}
@@ -1,24 +1,44 @@
 //! Core gossip protocol engine.
 
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
-use std::sync::{Arc, OnceLock};
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use bytes::Bytes;
-use dashmap::DashMap;
-use rand::seq::SliceRandom;
+use dashmap::{DashMap, DashSet};
+use rand::seq::IndexedRandom;
 use tokio::sync::broadcast;
 use tokio::time;
 use tracing::{debug, info, trace, warn};
 
+use crate::core::hlc::wall_clock_ms;
+use crate::discovery::{Discovery, DnsSeed, Multicast, SeedFile};
+use crate::kv::Replica;
+use crate::protocol::acl::Acl;
+use crate::protocol::adaptive::Adaptive;
+use crate::protocol::aggregation::Aggregator;
+use crate::protocol::delivery::{Delivery, Released, Retracted};
+use crate::protocol::peer_exchange::PeerExchange;
+use crate::protocol::peer_selection::RttProbes;
+use crate::protocol::reconnect::Reconnector;
+use crate::protocol::replay::{Connection, ReplayGuard};
+use crate::protocol::snapshot::{self, StateTransfer};
+use crate::protocol::zone::{self, Zones};
 use crate::{
-    AntiEntropy, EpidemicConfig, Error, Identity, Message, MessageEntry, MessageId, NodeConfig,
-    Payload, PeerId, PeerInfo, PeerState, Result, Tcp, authenticate,
+    AclConfig, AdaptiveStats, AntiEntropy, Ban, BanTarget, ConnectionStatus, Delivered,
+    DeliveryOrder, DeliveryStats, EpidemicConfig, Error, Estimate, HlcTimestamp, HybridClock,
+    Identity, KeyRevocation, KeyRotation, Message, MessageId, MessageStore, MessageStoreStats,
+    Misbehaviour, NodeConfig, OverflowPolicy, Payload, PeerExchangeStats, PeerId, PeerInfo,
+    PeerProtocol, PeerSelector, PeerState, Permissions, PinStore, Result, SendOutcome,
+    SnapshotProgress, Tcp, TrafficStats, authenticate,
 };
 
+/// Most key rotations, and revocations, sent in one message.
+const KEY_STATEMENTS_PER_MESSAGE: usize = 64;
+
 /// Maps a peer's canonical address to its connection address.
 type ListeningAddrs = DashMap<SocketAddr, SocketAddr>;
 
@@ -31,6 +51,70 @@ const PEER_MAINTENANCE_INTERVAL_SECS: u64 = 10;
 /// Message deduplication cleanup interval (seconds).
 const MESSAGE_CLEANUP_INTERVAL_SECS: u64 = 30;
 
+/// Longest interval between checks for held messages past the gap timeout.
+const DELIVERY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest interval between checks on this node's snapshot transfer.
+const SNAPSHOT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest interval between checks for peers to dial.
+const RECONNECT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shortest interval between peer list requests the connection manager sends
+/// when its address book has no candidates left.
+const PEER_LIST_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Application message handler.
+type MessageHandler = Arc<dyn Fn(Delivered) + Send + Sync>;
+
+/// Application retraction handler.
+type RetractionHandler = Arc<dyn Fn(Retracted) + Send + Sync>;
+
+/// How far a broadcast got: the peers it was queued for, and those it
+/// was not.
+///
+/// Peers are named by connection address. Forwarding by other nodes is not
+/// covered; anti-entropy repairs what the fanout misses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BroadcastReport {
+    /// The broadcast's id
+    pub id: MessageId,
+
+    /// Peers the broadcast was queued for
+    pub enqueued: Vec<SocketAddr>,
+
+    /// Peers whose full queue dropped the broadcast
+    pub dropped: Vec<SocketAddr>,
+
+    /// Peers whose connection failed before the broadcast was queued
+    pub failed: Vec<SocketAddr>,
+
+    /// Peers skipped because their protocol version cannot decode the
+    /// broadcast's kind
+    pub skipped: Vec<SocketAddr>,
+
+    /// Older messages dropped from full queues to make room for the broadcast
+    pub displaced: usize,
+}
+
+impl BroadcastReport {
+    fn new(id: MessageId) -> Self {
+        Self {
+            id,
+            enqueued: Vec::new(),
+            dropped: Vec::new(),
+            failed: Vec::new(),
+            skipped: Vec::new(),
+            displaced: 0,
+        }
+    }
+
+    /// Whether every peer chosen for the fanout had the broadcast queued.
+    pub fn is_complete(&self) -> bool {
+        self.dropped.is_empty() && self.failed.is_empty() && self.skipped.is_empty()
+    }
+}
+
 /// Main gossip protocol engine.
 pub struct Gossip {
     /// Node configuration
@@ -39,8 +123,8 @@ pub struct Gossip {
     /// TCP transport, which owns the authoritative peer registry
     transport: Arc<Tcp>,
 
-    /// Seen messages with full message data and metadata
-    seen_messages: Arc<DashMap<MessageId, MessageEntry>>,
+    /// Seen message ids, and the messages retained for forwarding
+    store: Arc<MessageStore>,
 
     /// Maps canonical peer addresses to connection addresses (ephemeral ports).
     /// When peer A connects to peer B, B sees the connection from an ephemeral port, but messages
@@ -49,7 +133,19 @@ pub struct Gossip {
     listening_addrs: Arc<ListeningAddrs>,
 
     /// Application message handler, set once before the node starts.
-    message_handler: OnceLock<Arc<dyn Fn(SocketAddr, Bytes) + Send + Sync>>,
+    message_handler: OnceLock<MessageHandler>,
+
+    /// Application retraction handler, set once before the node starts.
+    retraction_handler: OnceLock<RetractionHandler>,
+
+    /// Hold-back queue ordering broadcasts for the handler
+    delivery: Arc<Delivery>,
+
+    /// This node's replica of the key-value store
+    kv: Arc<Replica>,
+
+    /// Push-sum aggregation state, advanced on gossip loop ticks
+    aggregator: Arc<Aggregator>,
 
     /// Shutdown signal broadcaster
     shutdown_tx: broadcast::Sender<()>,
@@ -57,18 +153,61 @@ pub struct Gossip {
     /// Anti-entropy engine
     anti_entropy: Option<Arc<AntiEntropy>>,
 
+    /// Snapshot state transfer: this node's own as a joiner, and those it serves
+    transfer: Arc<StateTransfer>,
+
+    /// Cluster size estimate, and the fanout and anti-entropy pacing it drives
+    adaptive: Arc<Adaptive>,
+
+    /// Chooses the peers fanout sends to, preferring this node's zone
+    selector: Arc<dyn PeerSelector>,
+
+    /// This node's zone label and those it has heard of
+    zones: Arc<Zones>,
+
+    /// Pings awaiting pongs, timing each peer's round trip
+    probes: Arc<RttProbes>,
+
+    /// Bootstrap and lost peers to redial, and their backoff
+    reconnector: Arc<Reconnector>,
+
+    /// Addresses learned by peer exchange, and requests awaiting answers
+    exchange: Arc<PeerExchange>,
+
+    /// Stamps of recent control messages, rejecting replays
+    replay: Arc<ReplayGuard>,
+
+    /// Which keys may broadcast, send direct messages and relay
+    acl: Arc<Acl>,
+
+    /// Discovery providers added by the application, polled from start
+    discoveries: Mutex<Vec<Arc<dyn Discovery>>>,
+
     /// Epidemic broadcast config
     epidemic_config: EpidemicConfig,
 
     /// Monotonic per-origin sequence counter for this node's own broadcasts.
     sequence: AtomicU64,
 
+    /// Sequences of this node's own retractions and key-value deltas, which
+    /// share the counter with application broadcasts but may not be
+    /// retracted. Drawing a sequence holds the lock, so a retraction never
+    /// sees one drawn but not yet recorded.
+    unretractable: Mutex<BTreeSet<u64>>,
+
     /// This node's signing identity; every authored message is signed with it.
     identity: Arc<Identity>,
 
+    /// Hybrid logical clock stamping authored messages, advanced on receipt
+    clock: Arc<HybridClock>,
+
     /// Trust-on-first-use bindings of origin address to public key, populated by
-    /// [`authenticate`] and shared with the anti-entropy repair path.
-    pins: Arc<DashMap<SocketAddr, PeerId>>,
+    /// [`authenticate`] and shared with the anti-entropy repair path, and the
+    /// key rotations and revocations that amend them.
+    pins: Arc<PinStore>,
+
+    /// Connections already sent every key statement held
+    announced_keys: Arc<DashSet<SocketAddr>>,
 }
 
 impl Gossip {
@@ -80,24 +219,64 @@ impl Gossip {
     pub fn new(config: NodeConfig) -> Result<Self> {
         let (shutdown_tx, _) = broadcast::channel(SHUTDOWN_CHANNEL_CAPACITY);
 
-        let mut transport =
-            Tcp::with_max_message_size(config.max_message_size).set_max_peers(config.max_peers);
+        let clock = Arc::new(HybridClock::new(config.clock.max_drift));
+        let identity = if config.clock.signed {
+            Identity::generate().with_clock(Arc::clone(&clock))
+        } else {
+            Identity::generate()
+        };
+        let identity = Arc::new(identity);
+
+        let mut transport = Tcp::with_max_message_size(config.max_message_size)
+            .set_max_peers(config.max_peers)
+            .set_connection_limits(config.connection_limits.clone())
+            .set_outbound(config.outbound.clone())
+            .set_misbehaviour(config.misbehaviour.clone())
+            .set_compression(config.compression)
+            .set_encoding(config.encoding)
+            .set_identity(Arc::clone(&identity));
         if config.rate_limit.enabled {
-            transport = transport
-                .set_rate_limit(config.rate_limit.capacity, config.rate_limit.refill_rate)?;
+            transport = transport.set_rate_limiting(config.rate_limit.clone())?;
         }
         let transport = Arc::new(transport);
-        let seen_messages = Arc::new(DashMap::new());
+        let store = Arc::new(MessageStore::new(config.message_store.clone()));
+        let delivery = Arc::new(Delivery::new(config.delivery.clone()));
+        let kv = Arc::new(Replica::new(&config.kv, Arc::clone(&clock)));
+        let aggregator = Arc::new(Aggregator::new(config.aggregation.clone()));
+        let transfer = Arc::new(StateTransfer::new(
+            config.snapshot.clone(),
+            !config.bootstrap_peers.is_empty(),
+        ));
+        let adaptive = Arc::new(Adaptive::new(
+            config.adaptive.clone(),
+            config.fanout,
+            config.anti_entropy.interval,
+        ));
+        let selector = zone::zone_aware(&config.zone, config.peer_selection.selector());
+        let zones = Arc::new(Zones::new(&config.zone));
+        let reconnector = Arc::new(Reconnector::new(
+            config.reconnect.clone(),
+            &config.bootstrap_peers,
+            config.max_peers,
+        ));
+        let exchange = Arc::new(PeerExchange::new(config.peer_exchange.clone()));
+        let replay = Arc::new(ReplayGuard::new(config.replay.clone()));
+        let acl = Arc::new(Acl::new(config.acl.clone()));
         let epidemic_config = config.epidemic.clone();
-        let identity = Arc::new(Identity::generate());
 
         let anti_entropy = if config.anti_entropy.enabled {
-            Some(Arc::new(AntiEntropy::new(
+            let anti_entropy = AntiEntropy::new(
                 config.anti_entropy.clone(),
                 Arc::clone(&transport),
-                Arc::clone(&seen_messages),
+                Arc::clone(&store),
                 Arc::clone(&identity),
-            )))
+            )
+            .with_peer_selector(Arc::clone(&selector));
+            Some(Arc::new(if adaptive.is_enabled() {
+                anti_entropy.with_pacing(Arc::clone(&adaptive))
+            } else {
+                anti_entropy
+            }))
         } else {
             None
         };
@@ -105,15 +284,32 @@ impl Gossip {
         Ok(Self {
             config,
             transport,
-            seen_messages,
+            store,
             listening_addrs: Arc::new(DashMap::new()),
             message_handler: OnceLock::new(),
+            retraction_handler: OnceLock::new(),
+            delivery,
+            kv,
+            aggregator,
             shutdown_tx,
             anti_entropy,
+            transfer,
+            adaptive,
+            selector,
+            zones,
+            probes: Arc::new(RttProbes::new()),
+            reconnector,
+            exchange,
+            replay,
+            acl,
+            discoveries: Mutex::new(Vec::new()),
             epidemic_config,
             sequence: AtomicU64::new(0),
+            unretractable: Mutex::new(BTreeSet::new()),
             identity,
-            pins: Arc::new(DashMap::new()),
+            clock,
+            pins: Arc::new(PinStore::new()),
+            announced_keys: Arc::new(DashSet::new()),
         })
     }
 
@@ -125,14 +321,57 @@ impl Gossip {
     /// Set the application message handler.
     ///
     /// The handler is captured when the node starts, so it must be set before
-    /// [`Gossip::start`]; a second call has no effect.
+    /// [`Gossip::start`]; a second call, of this or
+    /// [`Gossip::set_delivered_handler`], has no effect.
     pub fn set_message_handler<F>(&self, handler: F)
     where
         F: Fn(SocketAddr, Bytes) + Send + Sync + 'static,
+    {
+        self.set_delivered_handler(move |message| handler(message.origin(), message.data));
+    }
+
+    /// Set the application message handler, receiving each message's id and
+    /// clock stamp along with its data.
+    ///
+    /// As for [`Gossip::set_message_handler`], the handler must be set before
+    /// [`Gossip::start`], and only the first handler set takes effect.
+    pub fn set_delivered_handler<F>(&self, handler: F)
+    where
+        F: Fn(Delivered) + Send + Sync + 'static,
     {
         let _ = self.message_handler.set(Arc::new(handler));
     }
 
+    /// Set the handler told when an origin retracts one of its broadcasts.
+    ///
+    /// As for [`Gossip::set_message_handler`], the handler must be set before
+    /// [`Gossip::start`], and a second call has no effect.
+    pub fn set_retraction_handler<F>(&self, handler: F)
+    where
+        F: Fn(Retracted) + Send + Sync + 'static,
+    {
+        let _ = self.retraction_handler.set(Arc::new(handler));
+    }
+
+    /// Add a peer discovery provider, polled alongside those configured in
+    /// `NodeConfig::discovery`.
+    ///
+    /// Providers are captured when the node starts, so this must be called
+    /// before [`Gossip::start`].
+    pub fn add_discovery(&self, discovery: Arc<dyn Discovery>) {
+        lock(&self.discoveries).push(discovery);
+    }
+
+    /// Where released broadcasts go: the application's handlers, as set so
+    /// far, and the key-value replica.
+    fn handlers(&self) -> Handlers {
+        Handlers {
+            message: self.message_handler.get().cloned(),
+            retraction: self.retraction_handler.get().cloned(),
+            kv: Arc::clone(&self.kv),
+        }
+    }
+
     /// Start the gossip protocol.
     pub async fn start(&self) -> Result<()> {
         self.transport.listen(self.config.bind_addr).await?;
@@ -142,9 +381,20 @@ impl Gossip {
             .ok_or_else(|| Error::internal("Transport has no local address after listening"))?;
         info!("Gossip node started on {local_addr}");
 
+        let discoveries = self.discoveries()?;
+        if !discoveries.is_empty() && !self.config.reconnect.enabled {
+            return Err(Error::Config(
+                "discovery requires reconnect to be enabled".into(),
+            ));
+        }
+
         for peer in &self.config.bootstrap_peers {
-            if let Err(e) = self.connect_to_peer(*peer).await {
-                warn!("Failed to connect to bootstrap peer {peer}: {e}");
+            match self.connect_to_peer(*peer).await {
+                Ok(()) => self.reconnector.record_success(*peer),
+                Err(e) => {
+                    warn!("Failed to connect to bootstrap peer {peer}: {e}");
+                    self.reconnector.record_failure(*peer, Instant::now());
+                }
             }
         }
 
@@ -152,60 +402,160 @@ impl Gossip {
         self.spawn_gossip_loop();
         self.spawn_peer_maintenance();
         self.spawn_message_cleanup();
+        self.spawn_delivery_timeouts();
+        self.spawn_kv_sync();
+        self.spawn_connection_manager();
+        if !discoveries.is_empty() {
+            self.spawn_discovery(discoveries, local_addr);
+        }
 
-        if let Some(ref anti_entropy) = self.anti_entropy {
+        if self.transfer.is_transferring() {
+            self.spawn_state_transfer();
+        } else if let Some(ref anti_entropy) = self.anti_entropy {
             anti_entropy.start().await?;
         }
 
         Ok(())
     }
 
-    /// Connect to a peer.
+    /// Connect to a peer, giving up after the configured connection timeout.
     pub async fn connect_to_peer(&self, addr: SocketAddr) -> Result<()> {
-        let transport = &self.transport;
-        transport.connect(addr).await?;
+        self.dialer().dial(addr).await
+    }
 
-        let local_addr = transport
-            .local_addr()
-            .ok_or_else(|| Error::internal("No local address"))?;
+    fn dialer(&self) -> Dialer {
+        Dialer {
+            transport: Arc::clone(&self.transport),
+            identity: Arc::clone(&self.identity),
+            exchange: Arc::clone(&self.exchange),
+            zones: Arc::clone(&self.zones),
+            timeout: self.config.connection_timeout,
+        }
+    }
 
-        // Send immediate heartbeat to establish canonical address mapping
-        let heartbeat =
-            self.identity
-                .author(local_addr, 0, Payload::Heartbeat { from: local_addr })?;
-        transport.send(addr, heartbeat).await?;
+    /// Broadcast a message to the network.
+    pub async fn broadcast(&self, data: Bytes) -> Result<()> {
+        self.broadcast_with_report(data).await.map(|_| ())
+    }
 
-        // Request peer list
-        let message = self
-            .identity
-            .author(local_addr, 0, Payload::PeerListRequest)?;
-        transport.send(addr, message).await?;
+    /// Broadcast a message to the network, returning its id.
+    pub async fn broadcast_id(&self, data: Bytes) -> Result<MessageId> {
+        self.broadcast_with_report(data)
+            .await
+            .map(|report| report.id)
+    }
 
-        info!("Connected to peer {addr}");
-        Ok(())
+    /// Broadcast a message to the network, reporting which peers of the
+    /// fanout it was queued for.
+    ///
+    /// Under [`OverflowPolicy::Block`] this
+    /// waits, up to the policy's timeout, for room in full peer queues.
+    pub async fn broadcast_with_report(&self, data: Bytes) -> Result<BroadcastReport> {
+        let causal = self.delivery.config().order == DeliveryOrder::Causal;
+        self.publish(|clock| {
+            if causal {
+                Payload::CausalApplication { data, clock }
+            } else {
+                Payload::Application(data)
+            }
+        })
+        .await
     }
 
-    /// Broadcast a message to the network.
-    pub async fn broadcast(&self, data: Bytes) -> Result<()> {
+    /// Broadcast a message that nodes stop delivering, forwarding, and
+    /// repairing once `deadline` passes, returning its id.
+    ///
+    /// The deadline is signed with the message. It is compared with each
+    /// node's wall clock, so it is only as precise as the cluster's clocks
+    /// agree. `1.1.0` peers are never sent the message.
+    pub async fn broadcast_until(&self, data: Bytes, deadline: SystemTime) -> Result<MessageId> {
+        let expires_at = deadline
+            .duration_since(UNIX_EPOCH)
+            .map(|since| u64::try_from(since.as_millis()).unwrap_or(u64::MAX))
+            .unwrap_or(0);
+        self.publish(|clock| Payload::ExpiringApplication {
+            data,
+            clock,
+            expires_at,
+        })
+        .await
+        .map(|report| report.id)
+    }
+
+    /// Retract one of this node's earlier broadcasts, returning the id of the
+    /// tombstone.
+    ///
+    /// The tombstone is signed and gossiped like a broadcast: nodes stop
+    /// forwarding and repairing the retracted message, drop it if it arrives
+    /// later, and tell the application through the retraction handler. Nodes
+    /// that already delivered it are told, but the delivery cannot be undone.
+    /// `1.1.0` peers are never sent the tombstone.
+    ///
+    /// # Errors
+    /// Returns [`Error::NotOwnBroadcast`] if `id` is not an application
+    /// broadcast this node originated.
+    pub async fn retract(&self, id: MessageId) -> Result<MessageId> {
+        let local_addr = self.transport.local_addr();
+        let own = {
+            let unretractable = lock(&self.unretractable);
+            local_addr == Some(id.origin)
+                && id.sequence < self.sequence.load(AtomicOrdering::Relaxed)
+                && !unretractable.contains(&id.sequence)
+        };
+        if !own {
+            return Err(Error::NotOwnBroadcast(id));
+        }
+        self.store.retract(id);
+        self.publish(|_| Payload::Retraction {
+            sequence: id.sequence,
+        })
+        .await
+        .map(|report| report.id)
+    }
+
+    /// Gossip an encoded key-value store delta, already applied locally.
+    pub(crate) async fn publish_delta(&self, delta: Bytes) -> Result<MessageId> {
+        self.publish(|_| Payload::KvDelta { delta })
+            .await
+            .map(|report| report.id)
+    }
+
+    /// This node's replica of the key-value store.
+    pub(crate) fn kv(&self) -> &Replica {
+        &self.kv
+    }
+
+    /// Author the next broadcast in this node's sequence and gossip it.
+    /// `payload` builds it from the vector clock to attach, which is empty
+    /// unless delivery is causal.
+    async fn publish(
+        &self,
+        payload: impl FnOnce(Vec<(SocketAddr, u64)>) -> Payload,
+    ) -> Result<BroadcastReport> {
         let local_addr = self
             .transport
             .local_addr()
             .ok_or_else(|| Error::internal("No local address"))?;
 
-        let sequence = self.sequence.fetch_add(1, AtomicOrdering::Relaxed);
-        let message = self
-            .identity
-            .author(local_addr, sequence, Payload::Application(data))?;
-
-        self.seen_messages.insert(
-            message.id,
-            MessageEntry {
-                message: message.clone(),
-                first_seen: Instant::now(),
-            },
-        );
+        let clock = if self.delivery.config().order == DeliveryOrder::Causal {
+            self.delivery.clock(local_addr)
+        } else {
+            Vec::new()
+        };
+        let payload = payload(clock);
+        let sequence = {
+            let mut unretractable = lock(&self.unretractable);
+            let sequence = self.sequence.fetch_add(1, AtomicOrdering::Relaxed);
+            if !payload.is_application() {
+                unretractable.insert(sequence);
+            }
+            sequence
+        };
+        let message = self.identity.author(local_addr, sequence, payload)?;
+        self.delivery.record_own(local_addr, sequence);
 
-        self.gossip_message(message).await
+        self.store.insert(message.clone());
+        Ok(self.gossip_message(message).await)
     }
 
     /// Send a direct message to a specific peer.
@@ -241,8 +591,12 @@ impl Gossip {
             },
         )?;
 
-        // Send to the connection address, not the canonical address
-        self.transport.send(connection_addr, message).await
+        // Send to the connection address, not the canonical address. The
+        // application's own messages wait for room under a blocking policy.
+        self.transport
+            .enqueue(connection_addr, message)
+            .await
+            .map(|_| ())
     }
 
     /// Get local address.
@@ -272,6 +626,233 @@ impl Gossip {
             .collect()
     }
 
+    /// The wire protocol a connected peer speaks, by canonical address.
+    pub fn peer_protocol(&self, peer: SocketAddr) -> Option<PeerProtocol> {
+        let connection_addr = self
+            .listening_addrs
+            .get(&peer)
+            .map(|entry| *entry.value())
+            .unwrap_or(peer);
+        self.transport.peer_protocol(connection_addr)
+    }
+
+    /// The smoothed round-trip time to a connected peer, by canonical address.
+    pub fn peer_rtt(&self, peer: SocketAddr) -> Option<Duration> {
+        let connection_addr = self
+            .listening_addrs
+            .get(&peer)
+            .map(|entry| *entry.value())
+            .unwrap_or(peer);
+        self.transport.peer_rtt(connection_addr)
+    }
+
+    /// The zone label of a connected peer, by canonical address.
+    pub fn peer_zone(&self, peer: SocketAddr) -> Option<String> {
+        let connection_addr = self
+            .listening_addrs
+            .get(&peer)
+            .map(|entry| *entry.value())
+            .unwrap_or(peer);
+        self.transport.peer_zone(connection_addr)
+    }
+
+    /// Handle `overflow` for the peer listening at `peer` from now on, on its
+    /// current connection and any later one.
+    pub fn set_peer_overflow(&self, peer: SocketAddr, overflow: OverflowPolicy) {
+        self.transport.set_peer_overflow(peer, overflow);
+        if let Some(connection_addr) = self.listening_addrs.get(&peer).map(|entry| *entry.value()) {
+            self.transport.apply_peer_overflow(peer, connection_addr);
+        }
+    }
+
+    /// Set this node's contribution to the cluster-wide aggregate `metric`.
+    ///
+    /// # Errors
+    /// Returns [`Error::NonFiniteContribution`] if `value` is NaN or infinite.
+    pub fn contribute(&self, metric: &str, value: f64) -> Result<()> {
+        if !value.is_finite() {
+            return Err(Error::NonFiniteContribution(metric.to_string()));
+        }
+        self.aggregator.contribute(metric, value);
+        Ok(())
+    }
+
+    /// Stop contributing to `metric`. Estimates include the last contribution
+    /// until the next aggregation epoch.
+    pub fn withdraw(&self, metric: &str) {
+        self.aggregator.withdraw(metric);
+    }
+
+    /// This node's estimate of `metric` across the cluster, or `None` if no
+    /// contribution to it has reached this node.
+    pub fn estimate(&self, metric: &str) -> Option<Estimate> {
+        self.aggregator.estimate(metric)
+    }
+
+    /// A timestamp from this node's hybrid logical clock, later than every
+    /// stamp it has issued or received.
+    pub fn now(&self) -> HlcTimestamp {
+        self.clock.now()
+    }
+
+    /// The state of the hold-back queue ordering broadcasts for delivery.
+    pub fn delivery_stats(&self) -> DeliveryStats {
+        self.delivery.stats()
+    }
+
+    /// The estimated cluster size, and the fanout and anti-entropy interval
+    /// currently in use.
+    pub fn adaptive_stats(&self) -> AdaptiveStats {
+        self.adaptive.stats()
+    }
+
+    /// Connected peers, and the bootstrap and lost peers being redialed.
+    pub fn connection_status(&self) -> ConnectionStatus {
+        let canonical: HashSet<SocketAddr> =
+            known_canonical_peers(&self.transport, &self.listening_addrs)
+                .into_iter()
+                .collect();
+        let connections: HashSet<SocketAddr> = self.transport.peers().into_iter().collect();
+        self.reconnector
+            .status(&canonical, &connections, Instant::now())
+    }
+
+    /// The size of the address book peer exchange fills.
+    pub fn peer_exchange_stats(&self) -> PeerExchangeStats {
+        self.exchange.stats()
+    }
+
+    /// How far this node's snapshot transfer has got.
+    pub fn snapshot_progress(&self) -> SnapshotProgress {
+        self.transfer.progress()
+    }
+
+    /// The size of the seen-message store.
+    pub fn message_store_stats(&self) -> MessageStoreStats {
+        self.store.stats()
+    }
+
+    /// Bytes the transport has moved over every connection.
+    pub fn traffic(&self) -> TrafficStats {
+        self.transport.traffic()
+    }
+
+    /// Ban `target` for `duration`, closing the connections it covers.
+    pub fn ban(&self, target: BanTarget, duration: Duration) {
+        self.transport.ban(target, duration);
+    }
+
+    /// Lift the ban on `target`, returning whether there was one.
+    pub fn unban(&self, target: BanTarget) -> bool {
+        self.transport.unban(target)
+    }
+
+    /// The bans in force, placed for misbehaviour or by [`Gossip::ban`].
+    pub fn bans(&self) -> Vec<Ban> {
+        self.transport.bans()
+    }
+
+    /// The publish policy in force.
+    pub fn acl(&self) -> AclConfig {
+        self.acl.config()
+    }
+
+    /// Replace the publish policy.
+    ///
+    /// # Errors
+    /// Returns [`Error::Config`] if the policy is invalid.
+    pub fn set_acl(&self, config: AclConfig) -> Result<()> {
+        config.validate().map_err(Error::Config)?;
+        self.acl.set(config);
+        Ok(())
+    }
+
+    /// Grant `peer` `permissions` in place of the default, replacing any grant
+    /// it had.
+    pub fn grant(&self, peer: PeerId, permissions: Permissions) {
+        self.acl.grant(peer, permissions);
+    }
+
+    /// Drop `peer`'s grant, leaving it the default permissions. Returns
+    /// whether it had one.
+    pub fn remove_grant(&self, peer: PeerId) -> bool {
+        self.acl.remove_grant(peer)
+    }
+
+    /// Switch this node to a fresh key, returning the rotation, signed with
+    /// the old key, that tells peers to accept the new one.
+    ///
+    /// The rotation is sent to every connected peer and flooded from there,
+    /// and sent to peers as they connect. Messages signed with the old key are
+    /// rejected once it arrives, so any still in flight may be lost. `1.1.0`
+    /// peers are never told, and reject the node's messages from then on.
+    pub async fn rotate_key(&self) -> KeyRotation {
+        let rotation = self.identity.rotate(wall_clock_ms());
+        info!("Rotated key {} to {}", rotation.old, rotation.new);
+        if let Err(e) = self.pins.hold_rotation(&rotation) {
+            warn!("Failed to record own key rotation: {e}");
+        }
+        self.acl.carry_grant(rotation.old, rotation.new);
+        let statements = Payload::KeyStatements {
+            rotations: vec![rotation],
+            revocations: Vec::new(),
+        };
+        self.spread_key_statements(statements).await;
+        rotation
+    }
+
+    /// A statement revoking this node's current key, to be kept somewhere safe
+    /// and passed to [`Gossip::revoke_key`] should the key be stolen.
+    ///
+    /// Creating it revokes nothing. It stays valid after the key is rotated,
+    /// and revokes the key and the rotation away from it.
+    pub fn revocation_certificate(&self) -> KeyRevocation {
+        self.identity.revocation(wall_clock_ms())
+    }
+
+    /// Revoke a key with a statement it signed, returning whether it was new.
+    ///
+    /// The revocation is sent to every connected peer and flooded from there,
+    /// and sent to peers as they connect; every node then rejects messages
+    /// signed with the key, and pins the origins that used it afresh. This node
+    /// keeps it for as long as it runs, however many other revocations
+    /// arrive. If it revokes this node's own key, the node switches to a fresh
+    /// key first.
+    ///
+    /// # Errors
+    /// Returns [`Error::Crypto`] if the revocation's signature does not verify.
+    pub async fn revoke_key(&self, revocation: KeyRevocation) -> Result<bool> {
+        if !self.pins.hold_revocation(&revocation)? {
+            return Ok(false);
+        }
+        warn!("Revoked key {}", revocation.key);
+        if revocation.key == self.identity.peer_id() {
+            let _ = self.identity.rotate(wall_clock_ms());
+            info!("Switched to fresh key {}", self.identity.peer_id());
+        }
+        let statements = Payload::KeyStatements {
+            rotations: Vec::new(),
+            revocations: vec![revocation],
+        };
+        self.spread_key_statements(statements).await;
+        Ok(true)
+    }
+
+    /// Send key `statements` to every connected peer that understands them.
+    async fn spread_key_statements(&self, statements: Payload) {
+        let Some(local_addr) = self.transport.local_addr() else {
+            return;
+        };
+        spread_key_statements(
+            &self.transport,
+            &self.identity,
+            local_addr,
+            statements,
+            None,
+        )
+        .await;
+    }
+
     /// Shutdown the node gracefully.
     pub async fn shutdown(&self) -> Result<()> {
         info!("Initiating graceful shutdown");
@@ -305,7 +886,7 @@ impl Gossip {
         self.transport.shutdown().await;
 
         debug!("Clearing message cache");
-        self.seen_messages.clear();
+        self.store.clear();
 
         info!("Graceful shutdown complete");
         Ok(())
@@ -313,13 +894,25 @@ impl Gossip {
 
     fn spawn_message_receiver(&self) {
         let transport = Arc::clone(&self.transport);
-        let seen_messages = Arc::clone(&self.seen_messages);
+        let store = Arc::clone(&self.store);
         let canonical_addrs = Arc::clone(&self.listening_addrs);
-        let message_handler = self.message_handler.get().cloned();
-        let config = self.config.clone();
+        let handlers = self.handlers();
+        let delivery = Arc::clone(&self.delivery);
         let epidemic_config = self.epidemic_config.clone();
         let identity = Arc::clone(&self.identity);
         let pins = Arc::clone(&self.pins);
+        let clock = Arc::clone(&self.clock);
+        let kv = Arc::clone(&self.kv);
+        let aggregator = Arc::clone(&self.aggregator);
+        let transfer = Arc::clone(&self.transfer);
+        let adaptive = Arc::clone(&self.adaptive);
+        let selector = Arc::clone(&self.selector);
+        let zones = Arc::clone(&self.zones);
+        let probes = Arc::clone(&self.probes);
+        let reconnector = Arc::clone(&self.reconnector);
+        let exchange = Arc::clone(&self.exchange);
+        let replay = Arc::clone(&self.replay);
+        let acl = Arc::clone(&self.acl);
         let mut shutdown_rx = self.shutdown_tx.subscribe();
 
         tokio::spawn(async move {
@@ -342,13 +935,84 @@ impl Gossip {
                     }
                 };
 
+                // Key statements verify on their own, and must be applied before
+                // the message carrying them is authenticated: it may be signed
+                // with the key a rotation introduces.
+                if let Payload::KeyStatements {
+                    rotations,
+                    revocations,
+                } = &message.payload
+                {
+                    let fresh =
+                        apply_key_statements(&pins, &acl, rotations, revocations, peer_addr);
+                    if let (Some(fresh), Some(local_addr)) = (fresh, transport.local_addr()) {
+                        spread_key_statements(
+                            &transport,
+                            &identity,
+                            local_addr,
+                            fresh,
+                            Some(peer_addr),
+                        )
+                        .await;
+                    }
+                    continue;
+                }
                 if let Err(e) = authenticate(&message, &pins) {
                     warn!(
                         "Rejecting message from {peer_addr} claiming origin {}: {e}",
                         message.id.origin
                     );
+                    // A relay authenticates what it forwards against its own
+                    // pins, which may differ from ours, so only a mismatch in
+                    // the sender's own messages counts against it.
+                    let misbehaviour = match e {
+                        Error::InvalidSignature(_) => Some(Misbehaviour::InvalidSignature),
+                        Error::OriginKeyMismatch(_) if !message.payload.is_broadcast() => {
+                            Some(Misbehaviour::KeyMismatch)
+                        }
+                        _ => None,
+                    };
+                    if let Some(misbehaviour) = misbehaviour {
+                        transport.penalize(peer_addr, misbehaviour);
+                    }
+                    continue;
+                }
+                // A connection stays bound to the key its peer proved, unless
+                // that key was rotated to the one the peer now signs with.
+                let sender = match transport.peer_id(peer_addr) {
+                    Some(proven)
+                        if proven != message.origin_key
+                            && !message.payload.is_broadcast()
+                            && pins.is_rotated_to(proven, message.origin_key) =>
+                    {
+                        if !transport.follow_rotation(peer_addr, message.origin_key) {
+                            continue;
+                        }
+                        Some(message.origin_key)
+                    }
+                    sender => sender,
+                };
+                let connection = match sender {
+                    Some(key) => Connection::Proven(key),
+                    None => Connection::Unproven {
+                        key_proven: transport.has_proven(message.origin_key),
+                    },
+                };
+                if let Err(rejection) = replay.check(&message, connection, wall_clock_ms()) {
+                    debug!(
+                        "Dropping message {} from {peer_addr}: {rejection}",
+                        message.id
+                    );
                     continue;
                 }
+                if let Err(action) = acl.check(&message, sender) {
+                    debug!(
+                        "Dropping message {} from {peer_addr}: {} may not {action}",
+                        message.id, message.origin_key
+                    );
+                    continue;
+                }
+                observe_stamp(&clock, &message);
 
                 let local_addr = match transport.local_addr() {
                     Some(addr) => addr,
@@ -360,8 +1024,12 @@ impl Gossip {
                 // We always create the mapping, even if addresses match (outbound connections).
                 let canonical_addr = message.id.origin;
                 canonical_addrs.insert(canonical_addr, peer_addr);
+                if canonical_addr != local_addr {
+                    adaptive.observe([canonical_addr]);
+                }
                 if canonical_addr != peer_addr {
                     debug!("Mapped canonical {canonical_addr} -> connection {peer_addr}");
+                    transport.apply_peer_overflow(canonical_addr, peer_addr);
                 } else {
                     debug!("Mapped canonical {canonical_addr} -> self (outbound connection)");
                 }
@@ -372,23 +1040,70 @@ impl Gossip {
                     Payload::Heartbeat { from } => {
                         trace!("Heartbeat from {from}");
                     }
+                    // Applied above, before authentication.
+                    Payload::KeyStatements { .. } => {}
+                    // Taken by the transport; never passed on.
+                    Payload::ConnectionProof { .. } => {}
+                    Payload::Ping { nonce } => {
+                        let pong = Payload::Pong { nonce: *nonce };
+                        send_payload(&transport, &identity, local_addr, peer_addr, pong).await;
+                    }
+                    Payload::Pong { nonce } => {
+                        if let Some(rtt) = probes.answer(peer_addr, *nonce) {
+                            trace!("Round trip to {peer_addr}: {rtt:?}");
+                            transport.record_rtt(peer_addr, rtt);
+                        }
+                    }
+                    Payload::PeerZones { zones: labels } => {
+                        if let Some(zone) = zones.learn(canonical_addr, labels, local_addr) {
+                            transport.set_peer_zone(peer_addr, &zone);
+                        }
+                    }
                     Payload::PeerListRequest => {
                         Self::handle_peer_list_request(
                             &transport,
                             &canonical_addrs,
                             &identity,
+                            &zones,
+                            &exchange,
                             peer_addr,
                         )
                         .await;
                     }
                     Payload::PeerListResponse { peers: peer_list } => {
-                        Self::handle_peer_list_response(
-                            &transport,
-                            &canonical_addrs,
-                            local_addr,
+                        let learned = exchange.learn_addresses(
+                            peer_addr,
+                            canonical_addr,
                             peer_list,
-                        )
-                        .await;
+                            local_addr,
+                        );
+                        adaptive.observe(learned);
+                    }
+                    Payload::PeerAdvertisements { ads } => {
+                        let learned = exchange.learn_advertisements(
+                            peer_addr,
+                            canonical_addr,
+                            ads,
+                            &pins,
+                            local_addr,
+                            wall_clock_ms(),
+                        );
+                        adaptive.observe(learned);
+                        // Advertise back, so the sender can vouch for this node
+                        // in the peer lists it sends.
+                        if exchange.advertise_to(peer_addr, Instant::now()) {
+                            let own = Payload::PeerAdvertisements {
+                                ads: vec![identity.advertise(local_addr, wall_clock_ms())],
+                            };
+                            send_payload(&transport, &identity, local_addr, peer_addr, own).await;
+                        }
+                    }
+                    Payload::AntiEntropyDigest { .. } | Payload::RangeDigest { .. }
+                        if transfer.is_transferring() =>
+                    {
+                        trace!(
+                            "Ignoring anti-entropy digest from {peer_addr} during snapshot transfer"
+                        );
                     }
                     Payload::AntiEntropyDigest { version_vector } => {
                         let _ = AntiEntropy::handle_digest(
@@ -396,7 +1111,7 @@ impl Gossip {
                             peer_addr,
                             version_vector.clone(),
                             &transport,
-                            &seen_messages,
+                            &store,
                             &identity,
                         )
                         .await;
@@ -407,32 +1122,105 @@ impl Gossip {
                             peer_addr,
                             version_vector.clone(),
                             &transport,
-                            &seen_messages,
+                            &store,
                             &identity,
                         )
                         .await;
                     }
-                    Payload::MessageResponse { messages: msgs } => {
-                        AntiEntropy::handle_message_response(
-                            msgs.clone(),
-                            &seen_messages,
-                            &pins,
-                            &message_handler,
-                        );
-                    }
-                    Payload::Goodbye { reason } => {
-                        let canonical_addr = message.id.origin;
-                        info!("Peer {canonical_addr} is leaving: {reason}");
+                    Payload::RangeDigest { held } => {
+                        let _ = AntiEntropy::handle_range_digest(
+                            local_addr,
+                            peer_addr,
+                            held.clone(),
+                            &transport,
+                            &store,
+                            &identity,
+                        )
+                        .await;
+                    }
+                    Payload::RangeRequest { held } => {
+                        let _ = AntiEntropy::handle_range_request(
+                            local_addr,
+                            peer_addr,
+                            held.clone(),
+                            &transport,
+                            &store,
+                            &identity,
+                        )
+                        .await;
+                    }
+                    Payload::MessageResponse { messages: msgs } => {
+                        let mut msgs = msgs.clone();
+                        drop_unpermitted(&acl, &mut msgs, peer_addr);
+                        let repaired = AntiEntropy::handle_message_response(msgs, &store, &pins);
+                        adaptive.record_repairs(repaired.len());
+                        for message in &repaired {
+                            observe_stamp(&clock, message);
+                            accept_broadcast(message, &store, &delivery, &handlers);
+                        }
+                    }
+                    Payload::SnapshotRequest {
+                        session,
+                        after,
+                        window,
+                    } => {
+                        let chunks = transfer.serve(peer_addr, *session, *window, || {
+                            let protocol = transport
+                                .peer_protocol(peer_addr)
+                                .unwrap_or(PeerProtocol::LEGACY);
+                            snapshot::capture(
+                                &store,
+                                *after,
+                                protocol,
+                                local_addr,
+                                transport.max_message_size(),
+                            )
+                        });
+                        for chunk in chunks {
+                            send_payload(&transport, &identity, local_addr, peer_addr, chunk).await;
+                        }
+                    }
+                    Payload::SnapshotChunk {
+                        session,
+                        messages: msgs,
+                        done,
+                    } => {
+                        if !transfer.accept_chunk(peer_addr, *session, msgs, *done) {
+                            debug!("Ignoring stale snapshot chunk from {peer_addr}");
+                            continue;
+                        }
+                        let mut msgs = msgs.clone();
+                        drop_unpermitted(&acl, &mut msgs, peer_addr);
+                        let repaired = AntiEntropy::handle_message_response(msgs, &store, &pins);
+                        for message in &repaired {
+                            observe_stamp(&clock, message);
+                            accept_broadcast(message, &store, &delivery, &handlers);
+                        }
+                        if !done {
+                            let ack = Payload::SnapshotAck { session: *session };
+                            send_payload(&transport, &identity, local_addr, peer_addr, ack).await;
+                        }
+                    }
+                    Payload::SnapshotAck { session } => {
+                        if let Some(chunk) = transfer.acknowledge(peer_addr, *session) {
+                            send_payload(&transport, &identity, local_addr, peer_addr, chunk).await;
+                        }
+                    }
+                    Payload::Goodbye { reason } => {
+                        let canonical_addr = message.id.origin;
+                        info!("Peer {canonical_addr} is leaving: {reason}");
                         if transport.disconnect(peer_addr) {
                             canonical_addrs.retain(|_, conn| *conn != peer_addr);
                             debug!("Removed peer {canonical_addr} from registry");
                         }
+                        reconnector.depart(canonical_addr, Instant::now());
                     }
                     Payload::DirectMessage { recipient, data } => {
                         if *recipient == local_addr {
-                            if let Some(ref handler) = message_handler {
-                                handler(message.id.origin, data.clone());
-                            }
+                            handlers.deliver(Delivered {
+                                id: message.id,
+                                data: data.clone(),
+                            });
                             debug!("Received direct message from {}", message.id.origin);
                         } else {
                             trace!(
@@ -441,24 +1229,43 @@ impl Gossip {
                             );
                         }
                     }
-                    Payload::Application(data) => {
-                        if seen_messages.contains_key(&message.id) {
+                    Payload::KvDigest { digest } => {
+                        let differs = kv.digest().map(|ours| ours != *digest);
+                        if let Ok(true) = differs {
+                            send_kv_state(&transport, &identity, &kv, local_addr, peer_addr, true)
+                                .await;
+                        }
+                    }
+                    Payload::KvState { state, reply } => match kv.merge_state(state) {
+                        Ok(lacking) => {
+                            if *reply && lacking {
+                                send_kv_state(
+                                    &transport, &identity, &kv, local_addr, peer_addr, false,
+                                )
+                                .await;
+                            }
+                        }
+                        Err(e) => warn!("Dropping key-value state from {peer_addr}: {e}"),
+                    },
+                    Payload::Aggregate { epoch, shares } => {
+                        aggregator.absorb(*epoch, shares);
+                    }
+                    Payload::Application(_)
+                    | Payload::CausalApplication { .. }
+                    | Payload::ExpiringApplication { .. }
+                    | Payload::Retraction { .. }
+                    | Payload::KvDelta { .. } => {
+                        if !store.insert(message.clone()) {
                             trace!("Duplicate message {}, ignoring", message.id);
                             continue;
                         }
-
-                        seen_messages.insert(
-                            message.id,
-                            MessageEntry {
-                                message: message.clone(),
-                                first_seen: Instant::now(),
-                            },
-                        );
-
-                        if let Some(ref handler) = message_handler {
-                            handler(message.id.origin, data.clone());
+                        if message.is_expired() {
+                            debug!("Message {} has expired, dropping", message.id);
+                            continue;
                         }
 
+                        accept_broadcast(&message, &store, &delivery, &handlers);
+
                         if message.ttl > 1 && epidemic_config.should_forward() {
                             let exclude =
                                 fanout_exclusions(peer_addr, message.id.origin, &canonical_addrs);
@@ -466,9 +1273,11 @@ impl Gossip {
                             new_message.decrement_ttl();
                             let _ = Self::gossip_to_fanout(
                                 &transport,
+                                selector.as_ref(),
                                 new_message,
-                                config.fanout,
+                                adaptive.fanout(),
                                 &exclude,
+                                false,
                             )
                             .await;
                         }
@@ -478,10 +1287,288 @@ impl Gossip {
         });
     }
 
+    fn spawn_delivery_timeouts(&self) {
+        if self.delivery.config().order == DeliveryOrder::Immediate {
+            return;
+        }
+        let delivery = Arc::clone(&self.delivery);
+        let handlers = self.handlers();
+        let period = (self.delivery.config().gap_timeout / 2).min(DELIVERY_CHECK_INTERVAL);
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+
+        tokio::spawn(async move {
+            let mut ticker = time::interval(period);
+            loop {
+                tokio::select! {
+                    _ = shutdown_rx.recv() => {
+                        debug!("Delivery timeouts shutting down");
+                        break;
+                    }
+                    _ = ticker.tick() => {
+                        delivery.expire(|released| handlers.release(released));
+                    }
+                }
+            }
+        });
+    }
+
+    /// Periodically offer the key-value store's digest to random peers, who
+    /// answer with their full state if theirs differs.
+    fn spawn_kv_sync(&self) {
+        let transport = Arc::clone(&self.transport);
+        let identity = Arc::clone(&self.identity);
+        let kv = Arc::clone(&self.kv);
+        let config = self.config.kv.clone();
+        let selector = Arc::clone(&self.selector);
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+
+        tokio::spawn(async move {
+            let mut ticker = time::interval(config.sync_interval);
+            loop {
+                tokio::select! {
+                    _ = shutdown_rx.recv() => {
+                        debug!("Key-value sync shutting down");
+                        break;
+                    }
+                    _ = ticker.tick() => {
+                        let Some(local_addr) = transport.local_addr() else {
+                            continue;
+                        };
+                        if kv.is_empty() {
+                            continue;
+                        }
+                        let digest = match kv
+                            .digest()
+                            .and_then(|digest| identity.author(local_addr, 0, Payload::KvDigest { digest }))
+                        {
+                            Ok(message) => message,
+                            Err(e) => {
+                                warn!("Failed to author key-value digest: {e}");
+                                continue;
+                            }
+                        };
+                        let _ = Self::gossip_to_fanout(
+                            &transport,
+                            selector.as_ref(),
+                            digest,
+                            config.sync_fanout,
+                            &HashSet::new(),
+                            false,
+                        )
+                        .await;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Drive this node's snapshot transfer: ask a peer for the snapshot, ask
+    /// again from the checkpoint when the transfer stalls, and start
+    /// anti-entropy once it is over.
+    fn spawn_state_transfer(&self) {
+        let transport = Arc::clone(&self.transport);
+        let identity = Arc::clone(&self.identity);
+        let transfer = Arc::clone(&self.transfer);
+        let anti_entropy = self.anti_entropy.clone();
+        let bootstrap_peers = self.config.bootstrap_peers.clone();
+        let period = (self.config.snapshot.timeout / 2).min(SNAPSHOT_CHECK_INTERVAL);
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+
+        tokio::spawn(async move {
+            let probe = Payload::SnapshotRequest {
+                session: 0,
+                after: None,
+                window: 1,
+            };
+            let mut ticker = time::interval(period);
+            while transfer.is_transferring() {
+                tokio::select! {
+                    _ = shutdown_rx.recv() => {
+                        debug!("Snapshot transfer shutting down");
+                        return;
+                    }
+                    _ = ticker.tick() => {
+                        let Some(local_addr) = transport.local_addr() else {
+                            continue;
+                        };
+                        let candidates: Vec<SocketAddr> = transport
+                            .peers()
+                            .into_iter()
+                            .filter(|&peer| {
+                                transport
+                                    .peer_protocol(peer)
+                                    .is_some_and(|protocol| protocol.understands(&probe))
+                            })
+                            .collect();
+                        if let Some((peer, request)) =
+                            transfer.next_request(&candidates, &bootstrap_peers)
+                        {
+                            send_payload(&transport, &identity, local_addr, peer, request).await;
+                        }
+                    }
+                }
+            }
+
+            if let Some(anti_entropy) = anti_entropy
+                && let Err(e) = anti_entropy.start().await
+            {
+                warn!("Failed to start anti-entropy after snapshot transfer: {e}");
+            }
+        });
+    }
+
+    /// The configured discovery providers, then those the application added.
+    fn discoveries(&self) -> Result<Vec<Arc<dyn Discovery>>> {
+        let config = &self.config.discovery;
+        let mut discoveries: Vec<Arc<dyn Discovery>> = Vec::new();
+        if let Some(path) = &config.seed_file {
+            discoveries.push(Arc::new(SeedFile::new(path.clone())));
+        }
+        if let Some(dns) = &config.dns {
+            discoveries.push(Arc::new(DnsSeed::new(dns.clone())));
+        }
+        if let Some(multicast) = &config.multicast {
+            discoveries.push(Arc::new(Multicast::bind(multicast)?));
+        }
+        discoveries.extend(lock(&self.discoveries).iter().cloned());
+        Ok(discoveries)
+    }
+
+    /// Poll each discovery provider every discovery interval, handing the
+    /// peers found to the connection manager.
+    fn spawn_discovery(&self, discoveries: Vec<Arc<dyn Discovery>>, local_addr: SocketAddr) {
+        let reconnector = Arc::clone(&self.reconnector);
+        let interval = self.config.discovery.interval;
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = shutdown_rx.recv() => {
+                        debug!("Discovery shutting down");
+                        return;
+                    }
+                    _ = ticker.tick() => {
+                        for discovery in &discoveries {
+                            match discovery.discover(local_addr).await {
+                                Ok(mut peers) => {
+                                    peers.retain(|peer| *peer != local_addr);
+                                    trace!("{} discovery found {peers:?}", discovery.name());
+                                    reconnector.discover(&peers, Instant::now());
+                                }
+                                Err(e) => warn!("{} discovery failed: {e}", discovery.name()),
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// While this node has fewer connections than its target, redial
+    /// bootstrap and lost peers, with backoff, if reconnection is enabled, and
+    /// then dial addresses drawn from the peer exchange address book.
+    fn spawn_connection_manager(&self) {
+        let transport = Arc::clone(&self.transport);
+        let canonical_addrs = Arc::clone(&self.listening_addrs);
+        let reconnector = Arc::clone(&self.reconnector);
+        let exchange = Arc::clone(&self.exchange);
+        let dialer = self.dialer();
+        let reconnect = self.config.reconnect.enabled;
+        let target = self
+            .config
+            .reconnect
+            .target_peers
+            .min(self.config.max_peers);
+        let period = self
+            .config
+            .reconnect
+            .initial_backoff
+            .min(RECONNECT_CHECK_INTERVAL);
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+
+        tokio::spawn(async move {
+            let mut ticker = time::interval(period);
+            let mut refreshed: Option<Instant> = None;
+            loop {
+                tokio::select! {
+                    _ = shutdown_rx.recv() => {
+                        debug!("Connection manager shutting down");
+                        return;
+                    }
+                    _ = ticker.tick() => {
+                        let Some(local_addr) = transport.local_addr() else {
+                            continue;
+                        };
+                        let canonical: HashSet<SocketAddr> =
+                            known_canonical_peers(&transport, &canonical_addrs)
+                                .into_iter()
+                                .collect();
+                        let peers = transport.peers();
+                        let connections: HashSet<SocketAddr> = peers.iter().copied().collect();
+                        let planned = if reconnect {
+                            reconnector.plan(&canonical, &connections, Instant::now())
+                        } else {
+                            Vec::new()
+                        };
+                        for &peer in &planned {
+                            match dialer.dial(peer).await {
+                                Ok(()) => reconnector.record_success(peer),
+                                Err(e) => {
+                                    debug!("Failed to reconnect to {peer}: {e}");
+                                    reconnector.record_failure(peer, Instant::now());
+                                }
+                            }
+                        }
+
+                        let wanted = target.saturating_sub(connections.len() + planned.len());
+                        if wanted == 0 {
+                            continue;
+                        }
+                        let mut exclude: HashSet<SocketAddr> =
+                            canonical.union(&connections).copied().collect();
+                        exclude.extend(planned);
+                        exclude.insert(local_addr);
+                        let selected = exchange.select(wanted, &exclude, &peers);
+                        let exhausted = selected.len() < wanted;
+                        for peer in selected {
+                            match dialer.dial(peer).await {
+                                Ok(()) => exchange.mark_good(peer),
+                                Err(e) => {
+                                    debug!("Failed to connect to exchanged peer {peer}: {e}");
+                                    exchange.mark_failed(peer);
+                                }
+                            }
+                        }
+
+                        // Out of candidates: ask a random peer for more.
+                        let due = exhausted
+                            && refreshed.is_none_or(|at| at.elapsed() >= PEER_LIST_REFRESH_INTERVAL);
+                        let source = peers.choose(&mut rand::rng()).copied();
+                        if due && let Some(peer) = source {
+                            refreshed = Some(Instant::now());
+                            if let Err(e) = dialer.request_peers(peer, local_addr).await {
+                                debug!("Failed to request peers from {peer}: {e}");
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    }
+
     fn spawn_gossip_loop(&self) {
         let interval = self.config.gossip_interval;
         let transport = Arc::clone(&self.transport);
         let identity = Arc::clone(&self.identity);
+        let aggregator = Arc::clone(&self.aggregator);
+        let probes = Arc::clone(&self.probes);
+        let zones = Arc::clone(&self.zones);
+        let pins = Arc::clone(&self.pins);
+        let announced_keys = Arc::clone(&self.announced_keys);
+        let exchange = Arc::clone(&self.exchange);
+        let canonical_addrs = Arc::clone(&self.listening_addrs);
         let mut shutdown_rx = self.shutdown_tx.subscribe();
 
         tokio::spawn(async move {
@@ -505,12 +1592,59 @@ impl Gossip {
                                 continue;
                             }
                         };
+                        let nonce = rand::random();
+                        let ping = match identity.author(local_addr, 0, Payload::Ping { nonce }) {
+                            Ok(message) => message,
+                            Err(e) => {
+                                warn!("Failed to author ping: {e}");
+                                continue;
+                            }
+                        };
                         let peer_addrs = transport.peers();
+                        exchange.retain(&peer_addrs);
+                        let own_ad = Payload::PeerAdvertisements {
+                            ads: vec![identity.advertise(local_addr, wall_clock_ms())],
+                        };
 
                         for peer_addr in peer_addrs {
-                            if let Err(e) = transport.send(peer_addr, heartbeat.clone()).await {
+                            let pings = transport
+                                .peer_protocol(peer_addr)
+                                .is_some_and(|protocol| protocol.understands(&ping.payload));
+                            let message = if pings {
+                                if let Some(waited) = probes.probe(peer_addr, nonce) {
+                                    transport.record_rtt(peer_addr, waited);
+                                }
+                                ping.clone()
+                            } else {
+                                heartbeat.clone()
+                            };
+                            if let Err(e) = transport.send(peer_addr, message).await {
                                 debug!("Failed to send heartbeat to {peer_addr}: {e}");
                             }
+                            if pings && zones.announce(peer_addr) {
+                                let peers = known_canonical_peers(&transport, &canonical_addrs);
+                                if let Some(table) = zones.table(local_addr, peers) {
+                                    send_payload(&transport, &identity, local_addr, peer_addr, table).await;
+                                }
+                            }
+                            if pings && announced_keys.insert(peer_addr) {
+                                for statements in key_statement_batches(&pins) {
+                                    let understands = transport
+                                        .peer_protocol(peer_addr)
+                                        .is_some_and(|protocol| protocol.understands(&statements));
+                                    if understands {
+                                        send_payload(&transport, &identity, local_addr, peer_addr, statements).await;
+                                    }
+                                }
+                            }
+                            if pings && exchange.advertise_to(peer_addr, Instant::now()) {
+                                send_payload(&transport, &identity, local_addr, peer_addr, own_ad.clone()).await;
+                            }
+                        }
+
+                        if let Some(shares) = aggregator.round() {
+                            hand_off_shares(&transport, &identity, &aggregator, local_addr, shares)
+                                .await;
                         }
                     }
                 }
@@ -521,6 +1655,9 @@ impl Gossip {
     fn spawn_peer_maintenance(&self) {
         let transport = Arc::clone(&self.transport);
         let canonical_addrs = Arc::clone(&self.listening_addrs);
+        let probes = Arc::clone(&self.probes);
+        let zones = Arc::clone(&self.zones);
+        let announced_keys = Arc::clone(&self.announced_keys);
         let timeout = self.config.peer_timeout;
         let max_peers = self.config.max_peers;
         let interval = (timeout / 2).clamp(
@@ -554,6 +1691,9 @@ impl Gossip {
                         }
                         let live: HashSet<SocketAddr> = transport.peers().into_iter().collect();
                         canonical_addrs.retain(|_, conn| live.contains(conn));
+                        probes.retain(&live);
+                        zones.retain(&live);
+                        announced_keys.retain(|peer| live.contains(peer));
                     }
                 }
             }
@@ -561,7 +1701,7 @@ impl Gossip {
     }
 
     fn spawn_message_cleanup(&self) {
-        let seen_messages = Arc::clone(&self.seen_messages);
+        let store = Arc::clone(&self.store);
         let ttl = self.config.message_dedup_ttl;
         let mut shutdown_rx = self.shutdown_tx.subscribe();
 
@@ -574,18 +1714,7 @@ impl Gossip {
                         break;
                     }
                     _ = ticker.tick() => {
-                        let now = Instant::now();
-                        let stale_messages: Vec<MessageId> = seen_messages
-                            .iter()
-                            .filter(|entry| now.duration_since(entry.value().first_seen) > ttl)
-                            .map(|entry| *entry.key())
-                            .collect();
-
-                        let count = stale_messages.len();
-                        for message_id in stale_messages {
-                            seen_messages.remove(&message_id);
-                        }
-
+                        let count = store.expire(ttl);
                         if count > 0 {
                             debug!("Cleaned up {count} stale message IDs");
                         }
@@ -595,55 +1724,97 @@ impl Gossip {
         });
     }
 
-    async fn gossip_message(&self, message: Message) -> Result<()> {
+    async fn gossip_message(&self, message: Message) -> BroadcastReport {
         Self::gossip_to_fanout(
             &self.transport,
+            self.selector.as_ref(),
             message,
-            self.config.fanout,
+            self.adaptive.fanout(),
             &HashSet::new(),
+            true,
         )
         .await
     }
 
-    /// Push `message` to up to `fanout` randomly selected peers, skipping any
-    /// connection in `exclude`. The exclusion set carries the connection the
-    /// message arrived on and the origin so a rumor is never echoed straight
-    /// back to the node it came from.
+    /// Push `message` to up to `fanout` peers chosen by `selector`, skipping
+    /// any connection in `exclude`, and report which peers it was queued for.
+    /// The exclusion set carries the connection the message arrived on and
+    /// the origin so a rumor is never echoed straight back to the node it
+    /// came from.
+    ///
+    /// Only the application's own broadcasts `wait` for room in full queues
+    /// under [`OverflowPolicy::Block`]; what
+    /// the node forwards on its own behalf never does, so a slow peer cannot
+    /// stall the receiver. Peers are sent to concurrently, so blocked queues
+    /// wait out their timeouts together.
     async fn gossip_to_fanout(
         transport: &Arc<Tcp>,
+        selector: &dyn PeerSelector,
         message: Message,
         fanout: usize,
         exclude: &HashSet<SocketAddr>,
-    ) -> Result<()> {
-        let mut peer_addrs: Vec<SocketAddr> = transport
-            .peers()
+        wait: bool,
+    ) -> BroadcastReport {
+        let mut report = BroadcastReport::new(message.id);
+        let candidates: Vec<PeerInfo> = transport
+            .peer_infos()
             .into_iter()
-            .filter(|addr| !exclude.contains(addr))
+            .filter(|(addr, _)| !exclude.contains(addr))
+            .map(|(_, info)| info)
             .collect();
 
-        if peer_addrs.is_empty() {
-            return Ok(());
+        if candidates.is_empty() {
+            return report;
         }
 
-        let selected: Vec<_> = {
-            let mut rng = rand::rng();
-            peer_addrs.shuffle(&mut rng);
-            peer_addrs.into_iter().take(fanout).collect()
-        };
+        // The codec withholds a payload from a peer whose version predates
+        // its kind, so such a peer is skipped rather than counted as reached.
+        let (selected, skipped): (Vec<_>, Vec<_>) = selector
+            .select(&candidates, fanout)
+            .into_iter()
+            .partition(|addr| {
+                transport
+                    .peer_protocol(*addr)
+                    .unwrap_or(PeerProtocol::LEGACY)
+                    .understands(&message.payload)
+            });
+        report.skipped = skipped;
+        let sends = selected.into_iter().map(|addr| {
+            let message = message.clone();
+            async move {
+                let outcome = if wait {
+                    transport.enqueue(addr, message).await
+                } else {
+                    transport.try_enqueue(addr, message)
+                };
+                (addr, outcome)
+            }
+        });
 
-        for addr in selected {
-            if let Err(e) = transport.send(addr, message.clone()).await {
-                warn!("Failed to gossip to {addr}: {e}");
+        for (addr, outcome) in futures::future::join_all(sends).await {
+            match outcome {
+                Ok(SendOutcome::Queued) => report.enqueued.push(addr),
+                Ok(SendOutcome::DisplacedOldest) => {
+                    report.enqueued.push(addr);
+                    report.displaced += 1;
+                }
+                Ok(SendOutcome::Dropped) => report.dropped.push(addr),
+                Err(e) => {
+                    warn!("Failed to gossip to {addr}: {e}");
+                    report.failed.push(addr);
+                }
             }
         }
 
-        Ok(())
+        report
     }
 
     async fn handle_peer_list_request(
         transport: &Arc<Tcp>,
         canonical_addrs: &ListeningAddrs,
         identity: &Identity,
+        zones: &Zones,
+        exchange: &PeerExchange,
         sender: SocketAddr,
     ) {
         let Some(local_addr) = transport.local_addr() else {
@@ -651,7 +1822,28 @@ impl Gossip {
         };
 
         let peers = known_canonical_peers(transport, canonical_addrs);
-        let response = match identity.author(local_addr, 0, Payload::PeerListResponse { peers }) {
+        // Zones go first, so the requester knows them when it connects to the
+        // peers listed.
+        if let Some(table) = zones.table(local_addr, peers.iter().copied()) {
+            zones.announce(sender);
+            send_payload(transport, identity, local_addr, sender, table).await;
+        }
+        // Peers that know advertisements get signed addresses; older ones the
+        // plain list.
+        let now_ms = wall_clock_ms();
+        let ads = Payload::PeerAdvertisements { ads: Vec::new() };
+        let payload = if transport
+            .peer_protocol(sender)
+            .is_some_and(|protocol| protocol.understands(&ads))
+        {
+            let own = identity.advertise(local_addr, now_ms);
+            Payload::PeerAdvertisements {
+                ads: exchange.advertisements(own, &peers, now_ms),
+            }
+        } else {
+            Payload::PeerListResponse { peers }
+        };
+        let response = match identity.author(local_addr, 0, payload) {
             Ok(message) => message,
             Err(e) => {
                 warn!("Failed to author peer list response: {e}");
@@ -662,31 +1854,241 @@ impl Gossip {
             warn!("Failed to send peer list to {sender}: {e}");
         }
     }
+}
 
-    async fn handle_peer_list_response(
-        transport: &Arc<Tcp>,
-        canonical_addrs: &ListeningAddrs,
-        local_addr: SocketAddr,
-        peer_list: &[SocketAddr],
-    ) {
-        let connected: HashSet<SocketAddr> = transport.peers().into_iter().collect();
-
-        for &peer in peer_list {
-            let already = connected.contains(&peer)
-                || canonical_addrs
-                    .get(&peer)
-                    .is_some_and(|conn| connected.contains(conn.value()));
-            if peer == local_addr || already {
-                continue;
+/// Where released broadcasts go, captured by the tasks that release them.
+#[derive(Clone)]
+struct Handlers {
+    message: Option<MessageHandler>,
+    retraction: Option<RetractionHandler>,
+    kv: Arc<Replica>,
+}
+
+impl Handlers {
+    /// Hand a message to the application.
+    fn deliver(&self, message: Delivered) {
+        if let Some(handler) = &self.message {
+            handler(message);
+        }
+    }
+
+    /// Hand whatever the hold-back queue released to the application, or a
+    /// store delta to the key-value replica.
+    fn release(&self, released: Released) {
+        match released {
+            Released::Message(message) => self.deliver(message),
+            Released::Retraction(retracted) => {
+                if let Some(handler) = &self.retraction {
+                    handler(retracted);
+                }
+            }
+            Released::Delta(delta) => self.kv.apply_delta(&delta),
+        }
+    }
+}
+
+/// Send a round's aggregation shares to one random peer that understands
+/// them. Shares that cannot be sent are absorbed back, so no mass is lost.
+async fn hand_off_shares(
+    transport: &Tcp,
+    identity: &Identity,
+    aggregator: &Aggregator,
+    local_addr: SocketAddr,
+    shares: Payload,
+) {
+    let peer = {
+        let candidates: Vec<SocketAddr> = transport
+            .peers()
+            .into_iter()
+            .filter(|&peer| {
+                transport
+                    .peer_protocol(peer)
+                    .is_some_and(|protocol| protocol.understands(&shares))
+            })
+            .collect();
+        candidates.choose(&mut rand::rng()).copied()
+    };
+    let sent = match peer {
+        Some(peer) => match identity.author(local_addr, 0, shares.clone()) {
+            Ok(message) => match transport.send(peer, message).await {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!("Failed to send aggregation shares to {peer}: {e}");
+                    false
+                }
+            },
+            Err(e) => {
+                warn!("Failed to author aggregation shares: {e}");
+                false
             }
+        },
+        None => false,
+    };
+    if !sent && let Payload::Aggregate { epoch, shares } = shares {
+        aggregator.absorb(epoch, &shares);
+    }
+}
+
+/// Author `payload` and send it to `peer`, logging any failure.
+async fn send_payload(
+    transport: &Tcp,
+    identity: &Identity,
+    local_addr: SocketAddr,
+    peer: SocketAddr,
+    payload: Payload,
+) {
+    let message = match identity.author(local_addr, 0, payload) {
+        Ok(message) => message,
+        Err(e) => {
+            warn!("Failed to author message for {peer}: {e}");
+            return;
+        }
+    };
+    if let Err(e) = transport.send(peer, message).await {
+        debug!("Failed to send to {peer}: {e}");
+    }
+}
+
+/// Send the key-value store's full state to `peer`, asking for its own back
+/// if `reply` is set.
+async fn send_kv_state(
+    transport: &Tcp,
+    identity: &Identity,
+    kv: &Replica,
+    local_addr: SocketAddr,
+    peer: SocketAddr,
+    reply: bool,
+) {
+    let message = match kv
+        .state()
+        .and_then(|state| identity.author(local_addr, 0, Payload::KvState { state, reply }))
+    {
+        Ok(message) => message,
+        Err(e) => {
+            warn!("Failed to author key-value state: {e}");
+            return;
+        }
+    };
+    if let Err(e) = transport.send(peer, message).await {
+        debug!("Failed to send key-value state to {peer}: {e}");
+    }
+}
 
-            if let Err(e) = transport.connect(peer).await {
-                debug!("Failed to connect to advertised peer {peer}: {e}");
+/// Act on a newly seen broadcast or retraction: apply a retraction to the
+/// store, then pass the message through the hold-back queue.
+fn accept_broadcast(
+    message: &Message,
+    store: &MessageStore,
+    delivery: &Delivery,
+    handlers: &Handlers,
+) {
+    if let Payload::Retraction { sequence } = message.payload {
+        store.retract(MessageId::new(message.id.origin, sequence));
+    }
+    delivery.accept(message, |released| handlers.release(released));
+}
+
+/// Drop the repaired broadcasts from `peer_addr` whose origins may not
+/// broadcast.
+fn drop_unpermitted(acl: &Acl, messages: &mut Vec<Message>, peer_addr: SocketAddr) {
+    let dropped = acl.retain_permitted(messages);
+    if dropped > 0 {
+        debug!("Dropping {dropped} repaired messages from {peer_addr} by unpermitted origins");
+    }
+}
+
+/// Record the key `rotations` and `revocations` from `peer_addr`, carrying
+/// each rotated key's grant to its successor. Returns those that were new, to
+/// pass on, if any.
+fn apply_key_statements(
+    pins: &PinStore,
+    acl: &Acl,
+    rotations: &[KeyRotation],
+    revocations: &[KeyRevocation],
+    peer_addr: SocketAddr,
+) -> Option<Payload> {
+    let mut fresh_rotations = Vec::new();
+    for rotation in rotations {
+        match pins.rotate(rotation) {
+            Ok(true) => {
+                info!("Key {} rotated to {}", rotation.old, rotation.new);
+                acl.carry_grant(rotation.old, rotation.new);
+                fresh_rotations.push(*rotation);
+            }
+            Ok(false) => {}
+            Err(e) => debug!("Ignoring key rotation from {peer_addr}: {e}"),
+        }
+    }
+    let mut fresh_revocations = Vec::new();
+    for revocation in revocations {
+        match pins.revoke(revocation) {
+            Ok(true) => {
+                warn!("Key {} revoked", revocation.key);
+                fresh_revocations.push(*revocation);
             }
+            Ok(false) => {}
+            Err(e) => debug!("Ignoring key revocation from {peer_addr}: {e}"),
+        }
+    }
+    if fresh_rotations.is_empty() && fresh_revocations.is_empty() {
+        return None;
+    }
+    Some(Payload::KeyStatements {
+        rotations: fresh_rotations,
+        revocations: fresh_revocations,
+    })
+}
+
+/// Every key statement in `pins`, in messages of at most
+/// [`KEY_STATEMENTS_PER_MESSAGE`] of each kind.
+fn key_statement_batches(pins: &PinStore) -> Vec<Payload> {
+    let rotations = pins.rotations();
+    let revocations = pins.revocations();
+    let mut rotations = rotations.chunks(KEY_STATEMENTS_PER_MESSAGE);
+    let mut revocations = revocations.chunks(KEY_STATEMENTS_PER_MESSAGE);
+    let mut batches = Vec::new();
+    loop {
+        match (rotations.next(), revocations.next()) {
+            (None, None) => return batches,
+            (rotated, revoked) => batches.push(Payload::KeyStatements {
+                rotations: rotated.unwrap_or_default().to_vec(),
+                revocations: revoked.unwrap_or_default().to_vec(),
+            }),
+        }
+    }
+}
+
+/// Send key `statements` to every connected peer that understands them, bar
+/// `except`.
+async fn spread_key_statements(
+    transport: &Tcp,
+    identity: &Identity,
+    local_addr: SocketAddr,
+    statements: Payload,
+    except: Option<SocketAddr>,
+) {
+    for peer in transport.peers() {
+        let understands = transport
+            .peer_protocol(peer)
+            .is_some_and(|protocol| protocol.understands(&statements));
+        if Some(peer) != except && understands {
+            send_payload(transport, identity, local_addr, peer, statements.clone()).await;
         }
     }
 }
 
+/// Advance the clock past an authenticated message's stamp, if it has one.
+fn observe_stamp(clock: &HybridClock, message: &Message) {
+    if let Some(stamp) = message.id.hlc()
+        && clock.observe(stamp).is_none()
+    {
+        debug!(
+            "Not advancing the clock to {stamp} from {}: beyond max drift",
+            message.id
+        );
+    }
+}
+
 /// Connections to skip when re-disseminating a received rumor
 fn fanout_exclusions(
     sender: SocketAddr,
@@ -700,6 +2102,59 @@ fn fanout_exclusions(
     exclude
 }
 
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Dials peers and introduces this node to them.
+struct Dialer {
+    transport: Arc<Tcp>,
+    identity: Arc<Identity>,
+    exchange: Arc<PeerExchange>,
+    zones: Arc<Zones>,
+    timeout: Duration,
+}
+
+impl Dialer {
+    /// Connect to `addr`, within the connection timeout, and introduce this
+    /// node: a heartbeat to establish its canonical address, then a peer list
+    /// request.
+    async fn dial(&self, addr: SocketAddr) -> Result<()> {
+        let transport = &self.transport;
+        time::timeout(self.timeout, transport.connect(addr))
+            .await
+            .map_err(|_| Error::network(format!("timed out connecting to {addr}")))??;
+        if let Some(zone) = self.zones.zone_of(addr) {
+            transport.set_peer_zone(addr, &zone);
+        }
+
+        let local_addr = transport
+            .local_addr()
+            .ok_or_else(|| Error::internal("No local address"))?;
+
+        // Send immediate heartbeat to establish canonical address mapping
+        let heartbeat =
+            self.identity
+                .author(local_addr, 0, Payload::Heartbeat { from: local_addr })?;
+        transport.send(addr, heartbeat).await?;
+
+        self.request_peers(addr, local_addr).await?;
+
+        info!("Connected to peer {addr}");
+        Ok(())
+    }
+
+    /// Ask the peer on connection `addr` for its peer list, noting the
+    /// request first so the answer is taken.
+    async fn request_peers(&self, addr: SocketAddr, local_addr: SocketAddr) -> Result<()> {
+        let message = self
+            .identity
+            .author(local_addr, 0, Payload::PeerListRequest)?;
+        self.exchange.requested(addr);
+        self.transport.send(addr, message).await
+    }
+}
+
 fn known_canonical_peers(transport: &Tcp, canonical_addrs: &ListeningAddrs) -> Vec<SocketAddr> {
     let live: HashSet<SocketAddr> = transport.peers().into_iter().collect();
     canonical_addrs
@@ -0,0 +1,594 @@
+//! Bounded store of seen broadcasts.
+//!
+//! The store keeps two things apart:
+//!
+//! - **Seen ids**, used for deduplication and for the anti-entropy digests. An
+//!   id is remembered until `message_dedup_ttl` passes, whatever happens to its
+//!   payload, so evicting a payload never causes a message to be delivered
+//!   twice or requested again.
+//! - **Retained messages**, kept in full so they can be forwarded to peers that
+//!   lack them. These are capped by count and by bytes
+//!   ([`MessageStoreConfig`]); when a cap is exceeded, messages are evicted as
+//!   the [`EvictionPolicy`] directs.
+//!
+//! A seen id costs a few dozen bytes regardless of the payload, so the caps
+//! bound the store's memory to roughly `max_bytes` plus the id set.
+//!
+//! A message past its deadline ([`Message::expires_at`]) is seen but never
+//! retained, and a retracted message ([`MessageStore::retract`]) is marked
+//! seen and its payload dropped, so neither is forwarded, repaired, or
+//! requested again.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::trace;
+
+use crate::{AggregateShare, Message, MessageEntry, MessageId, Payload};
+
+/// Which retained message to evict when the store is over a cap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// The message received first.
+    #[default]
+    Oldest,
+
+    /// The message least recently received or sent to a peer.
+    LeastRecentlyUsed,
+
+    /// The oldest message of the origin retaining the most bytes, so one
+    /// origin's burst evicts its own messages before anyone else's.
+    PerOrigin,
+}
+
+/// Caps on the messages retained for forwarding.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageStoreConfig {
+    /// Maximum number of retained messages
+    pub max_messages: usize,
+
+    /// Maximum total size of retained messages, in bytes
+    pub max_bytes: usize,
+
+    /// Which message to evict when a cap is exceeded
+    pub eviction: EvictionPolicy,
+}
+
+impl Default for MessageStoreConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 100_000,
+            max_bytes: 256 * 1024 * 1024,
+            eviction: EvictionPolicy::default(),
+        }
+    }
+}
+
+impl MessageStoreConfig {
+    /// Validate the configuration.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_messages == 0 {
+            return Err("message_store max_messages must be greater than 0".to_string());
+        }
+        if self.max_bytes == 0 {
+            return Err("message_store max_bytes must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot of the store's size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageStoreStats {
+    /// Message ids remembered for deduplication
+    pub seen: usize,
+
+    /// Messages retained in full
+    pub retained: usize,
+
+    /// Total size of the retained messages, in bytes
+    pub retained_bytes: usize,
+
+    /// Messages evicted to stay within the caps, since the store was created
+    pub evicted: u64,
+}
+
+/// Seen-message ids and the bounded set of messages retained for forwarding.
+#[derive(Debug)]
+pub struct MessageStore {
+    config: MessageStoreConfig,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Every id seen within the dedup TTL, with when it was first seen.
+    seen: HashMap<MessageId, Instant>,
+
+    /// Retained messages.
+    retained: HashMap<MessageId, Retained>,
+
+    /// Retained ids by eviction rank: insertion order, or recency under
+    /// [`EvictionPolicy::LeastRecentlyUsed`].
+    order: BTreeMap<u64, MessageId>,
+
+    /// Per-origin retained bytes and ranks, for [`EvictionPolicy::PerOrigin`].
+    origins: HashMap<SocketAddr, OriginUsage>,
+
+    /// Source of ranks.
+    clock: u64,
+
+    bytes: usize,
+    evicted: u64,
+}
+
+#[derive(Debug)]
+struct Retained {
+    entry: MessageEntry,
+    rank: u64,
+    size: usize,
+}
+
+#[derive(Debug, Default)]
+struct OriginUsage {
+    bytes: usize,
+    order: BTreeMap<u64, MessageId>,
+}
+
+impl MessageStore {
+    /// Create an empty store.
+    pub fn new(config: MessageStoreConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Record `message` as seen and retain it, evicting as needed to stay
+    /// within the caps. Returns `false`, changing nothing, if its id has
+    /// already been seen. An expired message is recorded but not retained.
+    pub fn insert(&self, message: Message) -> bool {
+        let mut inner = self.lock();
+        let now = Instant::now();
+        if inner.seen.contains_key(&message.id) {
+            return false;
+        }
+        inner.seen.insert(message.id, now);
+
+        if message.is_expired() {
+            trace!("Message {} has expired, not retained", message.id);
+            return true;
+        }
+
+        let size = footprint(&message);
+        if size > self.config.max_bytes {
+            trace!(
+                "Message {} exceeds the store's byte cap, not retained",
+                message.id
+            );
+            return true;
+        }
+
+        inner.retain(MessageEntry::new(message, now), size);
+        while inner.retained.len() > self.config.max_messages || inner.bytes > self.config.max_bytes
+        {
+            if !inner.evict(self.config.eviction) {
+                break;
+            }
+        }
+        true
+    }
+
+    /// Record that `id` was retracted by its origin: mark it seen, so it is
+    /// neither delivered nor requested if it arrives later, and drop its
+    /// payload, so it is no longer forwarded.
+    pub fn retract(&self, id: MessageId) {
+        let mut inner = self.lock();
+        inner.seen.entry(id).or_insert_with(Instant::now);
+        inner.release(&id);
+    }
+
+    /// Whether `id` has been seen within the dedup TTL, retained or not.
+    pub fn contains(&self, id: &MessageId) -> bool {
+        self.lock().seen.contains_key(id)
+    }
+
+    /// The unexpired retained messages whose ids satisfy `filter`. Under
+    /// [`EvictionPolicy::LeastRecentlyUsed`], returning a message counts as a
+    /// use.
+    pub fn retained_matching(&self, filter: impl Fn(&MessageId) -> bool) -> Vec<Message> {
+        self.retained_entries(filter)
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect()
+    }
+
+    /// The entries of the unexpired retained messages whose ids satisfy
+    /// `filter`, as [`MessageStore::retained_matching`] selects them. Clones
+    /// share each entry's measured wire length with the store.
+    pub fn retained_entries(&self, filter: impl Fn(&MessageId) -> bool) -> Vec<MessageEntry> {
+        let mut inner = self.lock();
+        let ids: Vec<MessageId> = inner
+            .retained
+            .iter()
+            .filter(|(id, retained)| filter(id) && !retained.entry.message.is_expired())
+            .map(|(id, _)| *id)
+            .collect();
+        if self.config.eviction == EvictionPolicy::LeastRecentlyUsed {
+            for id in &ids {
+                inner.touch(id);
+            }
+        }
+        ids.iter()
+            .filter_map(|id| inner.retained.get(id))
+            .map(|retained| retained.entry.clone())
+            .collect()
+    }
+
+    /// The sequences seen from each origin, retained or not.
+    pub fn seen_sequences(&self) -> HashMap<SocketAddr, BTreeSet<u64>> {
+        let mut sequences: HashMap<SocketAddr, BTreeSet<u64>> = HashMap::new();
+        for id in self.lock().seen.keys() {
+            sequences.entry(id.origin).or_default().insert(id.sequence);
+        }
+        sequences
+    }
+
+    /// Forget every id first seen more than `ttl` ago, and its payload, and
+    /// drop the payload of every message past its deadline. Returns how many
+    /// ids were forgotten.
+    pub fn expire(&self, ttl: Duration) -> usize {
+        let mut inner = self.lock();
+        let now = Instant::now();
+        let past_deadline: Vec<MessageId> = inner
+            .retained
+            .iter()
+            .filter(|(_, retained)| retained.entry.message.is_expired())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &past_deadline {
+            inner.release(id);
+        }
+
+        let stale: Vec<MessageId> = inner
+            .seen
+            .iter()
+            .filter(|(_, first_seen)| now.duration_since(**first_seen) > ttl)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &stale {
+            inner.seen.remove(id);
+            inner.release(id);
+        }
+        stale.len()
+    }
+
+    /// Forget everything.
+    pub fn clear(&self) {
+        let mut inner = self.lock();
+        let evicted = inner.evicted;
+        *inner = Inner {
+            evicted,
+            ..Inner::default()
+        };
+    }
+
+    /// The store's current size.
+    pub fn stats(&self) -> MessageStoreStats {
+        let inner = self.lock();
+        MessageStoreStats {
+            seen: inner.seen.len(),
+            retained: inner.retained.len(),
+            retained_bytes: inner.bytes,
+            evicted: inner.evicted,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        // The store's invariants hold between statements, so a panic while
+        // holding the lock cannot leave it inconsistent.
+        self.inner
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl Inner {
+    fn next_rank(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn retain(&mut self, entry: MessageEntry, size: usize) {
+        let id = entry.message.id;
+        let rank = self.next_rank();
+        self.order.insert(rank, id);
+        let origin = self.origins.entry(id.origin).or_default();
+        origin.order.insert(rank, id);
+        origin.bytes += size;
+        self.bytes += size;
+        self.retained.insert(id, Retained { entry, rank, size });
+    }
+
+    /// Drop `id`'s payload, if retained.
+    fn release(&mut self, id: &MessageId) -> bool {
+        let Some(retained) = self.retained.remove(id) else {
+            return false;
+        };
+        self.order.remove(&retained.rank);
+        self.bytes -= retained.size;
+        if let Some(origin) = self.origins.get_mut(&id.origin) {
+            origin.order.remove(&retained.rank);
+            origin.bytes -= retained.size;
+            if origin.order.is_empty() {
+                self.origins.remove(&id.origin);
+            }
+        }
+        true
+    }
+
+    /// Move `id` to the most recently used rank.
+    fn touch(&mut self, id: &MessageId) {
+        let rank = self.next_rank();
+        let Some(retained) = self.retained.get_mut(id) else {
+            return;
+        };
+        let old = mem::replace(&mut retained.rank, rank);
+        self.order.remove(&old);
+        self.order.insert(rank, *id);
+        if let Some(origin) = self.origins.get_mut(&id.origin) {
+            origin.order.remove(&old);
+            origin.order.insert(rank, *id);
+        }
+    }
+
+    /// Evict one retained message chosen by `policy`. Returns `false` if
+    /// nothing is retained.
+    fn evict(&mut self, policy: EvictionPolicy) -> bool {
+        let victim = match policy {
+            EvictionPolicy::Oldest | EvictionPolicy::LeastRecentlyUsed => {
+                self.order.values().next().copied()
+            }
+            EvictionPolicy::PerOrigin => self
+                .origins
+                .values()
+                .max_by_key(|origin| origin.bytes)
+                .and_then(|origin| origin.order.values().next().copied()),
+        };
+        let Some(victim) = victim else {
+            return false;
+        };
+        trace!("Evicting retained message {victim}");
+        self.evicted += 1;
+        self.release(&victim)
+    }
+}
+
+/// Approximate memory a retained message occupies: the message itself plus
+/// its payload's heap data.
+fn footprint(message: &Message) -> usize {
+    let heap = match &message.payload {
+        Payload::Application(data) | Payload::DirectMessage { data, .. } => data.len(),
+        Payload::KvDelta { delta: data } | Payload::KvState { state: data, .. } => data.len(),
+        Payload::CausalApplication { data, clock }
+        | Payload::ExpiringApplication { data, clock, .. } => {
+            data.len() + mem::size_of_val(clock.as_slice())
+        }
+        Payload::Goodbye { reason } => reason.len(),
+        Payload::Aggregate { shares, .. } => shares
+            .iter()
+            .map(|share| {
+                mem::size_of::<AggregateShare>()
+                    + share.metric.len()
+                    + mem::size_of_val(share.count_sketch.as_slice())
+            })
+            .sum(),
+        Payload::MessageResponse { messages } | Payload::SnapshotChunk { messages, .. } => {
+            messages.iter().map(footprint).sum()
+        }
+        Payload::PeerListResponse { peers } => mem::size_of_val(peers.as_slice()),
+        Payload::PeerAdvertisements { ads } => mem::size_of_val(ads.as_slice()),
+        Payload::KeyStatements {
+            rotations,
+            revocations,
+        } => mem::size_of_val(rotations.as_slice()) + mem::size_of_val(revocations.as_slice()),
+        Payload::PeerZones { zones } => zones
+            .iter()
+            .map(|(_, zone)| mem::size_of::<(SocketAddr, String)>() + zone.len())
+            .sum(),
+        Payload::AntiEntropyDigest { version_vector }
+        | Payload::MessageRequest { version_vector } => mem::size_of_val(version_vector.as_slice()),
+        Payload::RangeDigest { held } | Payload::RangeRequest { held } => held
+            .iter()
+            .map(|(_, ranges)| mem::size_of::<SocketAddr>() + mem::size_of_val(ranges.as_slice()))
+            .sum(),
+        Payload::Heartbeat { .. }
+        | Payload::PeerListRequest
+        | Payload::Retraction { .. }
+        | Payload::KvDigest { .. }
+        | Payload::SnapshotRequest { .. }
+        | Payload::SnapshotAck { .. }
+        | Payload::Ping { .. }
+        | Payload::Pong { .. }
+        | Payload::ConnectionProof { .. } => 0,
+    };
+    mem::size_of::<Message>() + heap
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn message(origin: &str, sequence: u64, size: usize) -> Message {
+        Message::new(
+            origin.parse().unwrap(),
+            sequence,
+            Payload::Application(Bytes::from(vec![0; size])),
+        )
+    }
+
+    fn store(max_messages: usize, max_bytes: usize, eviction: EvictionPolicy) -> MessageStore {
+        MessageStore::new(MessageStoreConfig {
+            max_messages,
+            max_bytes,
+            eviction,
+        })
+    }
+
+    fn retained_sequences(store: &MessageStore) -> BTreeSet<u64> {
+        store
+            .retained_matching(|_| true)
+            .iter()
+            .map(|message| message.id.sequence)
+            .collect()
+    }
+
+    #[test]
+    fn count_cap_evicts_the_oldest() {
+        let store = store(3, usize::MAX, EvictionPolicy::Oldest);
+        for sequence in 0..5 {
+            assert!(store.insert(message("127.0.0.1:8000", sequence, 10)));
+        }
+
+        assert_eq!(retained_sequences(&store), BTreeSet::from([2, 3, 4]));
+        let stats = store.stats();
+        assert_eq!((stats.seen, stats.retained, stats.evicted), (5, 3, 2));
+    }
+
+    #[test]
+    fn byte_cap_bounds_retained_size() {
+        let one = footprint(&message("127.0.0.1:8000", 0, 1000));
+        let store = store(usize::MAX, one * 2 + one / 2, EvictionPolicy::Oldest);
+        for sequence in 0..10 {
+            store.insert(message("127.0.0.1:8000", sequence, 1000));
+        }
+
+        let stats = store.stats();
+        assert_eq!(stats.retained, 2);
+        assert_eq!(stats.retained_bytes, one * 2);
+        assert_eq!(retained_sequences(&store), BTreeSet::from([8, 9]));
+    }
+
+    #[test]
+    fn oversized_messages_are_seen_but_not_retained() {
+        let store = store(10, 100, EvictionPolicy::Oldest);
+        assert!(store.insert(message("127.0.0.1:8000", 0, 1000)));
+
+        assert!(store.contains(&message("127.0.0.1:8000", 0, 0).id));
+        assert_eq!(store.stats().retained, 0);
+    }
+
+    #[test]
+    fn evicted_messages_are_still_deduplicated() {
+        let store = store(1, usize::MAX, EvictionPolicy::Oldest);
+        store.insert(message("127.0.0.1:8000", 0, 10));
+        store.insert(message("127.0.0.1:8000", 1, 10));
+
+        assert_eq!(store.stats().retained, 1);
+        assert!(!store.insert(message("127.0.0.1:8000", 0, 10)));
+        assert_eq!(
+            store.seen_sequences()[&"127.0.0.1:8000".parse().unwrap()],
+            BTreeSet::from([0, 1])
+        );
+    }
+
+    #[test]
+    fn lru_keeps_recently_forwarded_messages() {
+        let store = store(3, usize::MAX, EvictionPolicy::LeastRecentlyUsed);
+        for sequence in 0..3 {
+            store.insert(message("127.0.0.1:8000", sequence, 10));
+        }
+        store.retained_matching(|id| id.sequence == 0);
+        store.insert(message("127.0.0.1:8000", 3, 10));
+
+        assert_eq!(retained_sequences(&store), BTreeSet::from([0, 2, 3]));
+    }
+
+    #[test]
+    fn per_origin_eviction_sheds_the_heaviest_origin() {
+        let store = store(4, usize::MAX, EvictionPolicy::PerOrigin);
+        store.insert(message("10.0.0.1:8000", 0, 10));
+        for sequence in 0..5 {
+            store.insert(message("10.0.0.2:8000", sequence, 10));
+        }
+
+        let retained = store.retained_matching(|_| true);
+        assert_eq!(retained.len(), 4);
+        assert!(
+            retained
+                .iter()
+                .any(|message| message.id.origin == "10.0.0.1:8000".parse().unwrap()),
+            "the quiet origin keeps its message"
+        );
+    }
+
+    #[test]
+    fn expiry_forgets_ids_and_payloads() {
+        let store = store(10, usize::MAX, EvictionPolicy::Oldest);
+        store.insert(message("127.0.0.1:8000", 0, 10));
+        std::thread::sleep(Duration::from_millis(1));
+
+        assert_eq!(store.expire(Duration::from_secs(60)), 0);
+        assert_eq!(store.expire(Duration::ZERO), 1);
+        assert_eq!(store.stats(), MessageStoreStats::default());
+        assert!(store.insert(message("127.0.0.1:8000", 0, 10)));
+    }
+
+    #[test]
+    fn messages_past_their_deadline_are_seen_but_not_served() {
+        let expiring = |sequence, expires_at| {
+            Message::new(
+                "127.0.0.1:8000".parse().unwrap(),
+                sequence,
+                Payload::ExpiringApplication {
+                    data: Bytes::from_static(b"x"),
+                    clock: Vec::new(),
+                    expires_at,
+                },
+            )
+        };
+        let store = store(10, usize::MAX, EvictionPolicy::Oldest);
+        let now = crate::core::hlc::wall_clock_ms();
+
+        assert!(store.insert(expiring(0, now - 1)));
+        assert!(store.contains(&MessageId::new("127.0.0.1:8000".parse().unwrap(), 0)));
+        assert_eq!(store.stats().retained, 0);
+
+        store.insert(expiring(1, now + 50));
+        assert_eq!(store.retained_matching(|_| true).len(), 1);
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(store.retained_matching(|_| true).is_empty());
+        store.expire(Duration::from_secs(60));
+        assert_eq!(store.stats().retained, 0);
+        assert_eq!(store.stats().seen, 2);
+    }
+
+    #[test]
+    fn retraction_drops_the_payload_and_blocks_late_arrivals() {
+        let store = store(10, usize::MAX, EvictionPolicy::Oldest);
+        store.insert(message("127.0.0.1:8000", 0, 10));
+
+        store.retract(MessageId::new("127.0.0.1:8000".parse().unwrap(), 0));
+        assert!(store.retained_matching(|_| true).is_empty());
+
+        // A retracted message that was never seen is not accepted later.
+        store.retract(MessageId::new("127.0.0.1:8000".parse().unwrap(), 1));
+        assert!(!store.insert(message("127.0.0.1:8000", 1, 10)));
+        assert_eq!(store.stats().seen, 2);
+    }
+
+    #[test]
+    fn validate_rejects_zero_caps() {
+        let mut config = MessageStoreConfig::default();
+        assert!(config.validate().is_ok());
+        config.max_bytes = 0;
+        assert!(config.validate().is_err());
+    }
+}
@@ -1,9 +1,31 @@
 //! Gossip protocol implementations.
 
+pub mod acl;
+pub mod adaptive;
+pub mod aggregation;
 pub mod anti_entropy;
+pub mod delivery;
 pub mod epidemic;
 pub mod gossip;
+pub mod message_store;
+pub mod peer_exchange;
+pub mod peer_selection;
+pub mod reconnect;
+pub mod replay;
+pub mod snapshot;
+pub mod zone;
 
-pub use anti_entropy::{AntiEntropy, AntiEntropyConfig, MessageEntry};
+pub use acl::{AclConfig, AclGrant, Action, Permissions};
+pub use adaptive::{AdaptiveConfig, AdaptiveStats};
+pub use aggregation::{AggregationConfig, Estimate};
+pub use anti_entropy::{AntiEntropy, AntiEntropyConfig, MessageEntry, Reconciliation};
+pub use delivery::{Delivered, DeliveryConfig, DeliveryOrder, DeliveryStats, GapPolicy, Retracted};
 pub use epidemic::EpidemicConfig;
-pub use gossip::Gossip;
+pub use gossip::{BroadcastReport, Gossip};
+pub use message_store::{EvictionPolicy, MessageStore, MessageStoreConfig, MessageStoreStats};
+pub use peer_exchange::{PeerExchangeConfig, PeerExchangeStats};
+pub use peer_selection::{HealthWeighted, PeerSelection, PeerSelector, RttWeighted, Uniform};
+pub use reconnect::{ConnectionStatus, ReconnectCandidate, ReconnectConfig};
+pub use replay::ReplayConfig;
+pub use snapshot::{SnapshotConfig, SnapshotProgress, SnapshotState};
+pub use zone::ZoneConfig;
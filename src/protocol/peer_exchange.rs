@@ -0,0 +1,890 @@
+//! Peer exchange hardened against eclipse attacks.
+//!
+//! A node learns most of its peers from the peer lists its peers send it. If
+//! it dialed every address in every list, one malicious peer could fill its
+//! connection slots with hosts of the attacker's choosing and cut it off from
+//! the honest network (an eclipse attack), or aim its dials at third parties.
+//! Peer exchange therefore keeps learned addresses in an address book, modeled
+//! on Bitcoin Core's `addrman`, and lets the connection manager draw from it:
+//!
+//! - **Signed advertisements.** Every node signs a [`PeerAdvertisement`] of
+//!   its own listening address, sends a fresh one to each peer periodically, and answers a peer
+//!   list request with its own and the ones it holds for its peers, in
+//!   `PeerAdvertisements`. A relay cannot forge an advertisement, an
+//!   advertisement older than [`PeerExchangeConfig::max_advertisement_age`] is
+//!   dropped, and one whose key differs from the key a node has pinned for the
+//!   address is rejected. Peers that predate advertisements still answer with
+//!   an unsigned `PeerListResponse`, which is accepted unless
+//!   [`PeerExchangeConfig::require_signed`] is set.
+//! - **Solicited, capped responses.** A peer list is accepted only from a peer
+//!   this node asked, once per request, and at most
+//!   [`PeerExchangeConfig::max_addresses`] of its addresses, chosen at random,
+//!   are kept. An unsolicited `PeerAdvertisements` is accepted only for the
+//!   sender's own address.
+//! - **New and tried tables.** Addresses heard of go into the *new* table;
+//!   an address is moved to the *tried* table once this node has connected to
+//!   it. Each table is an array of fixed-size buckets. An address's new bucket
+//!   is chosen by a keyed hash of its network group and the group of the peer
+//!   it came from, such that one source group can reach only a few buckets;
+//!   its tried bucket, by a keyed hash of its own group and address, such that
+//!   one group can reach only a few. The key is random per node, so an
+//!   attacker cannot aim addresses at a bucket, and a full bucket evicts its
+//!   least reliable entry, so a flood from one source only displaces that
+//!   source's own addresses.
+//! - **Diverse outbound connections.** The connection manager draws candidates
+//!   from both tables, and skips any in a network group (an IPv4 /16 or IPv6
+//!   /32) that already holds [`PeerExchangeConfig::max_per_group`] of this
+//!   node's connections. Addresses that are not globally routable (loopback,
+//!   private, link-local and unique local ones) each form a group of their
+//!   own: the operator of a private network controls its address space.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use dashmap::{DashMap, DashSet};
+use rand::Rng;
+use rand::seq::IndexedRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::transport::limits::is_global;
+use crate::{PeerAdvertisement, PinStore};
+
+/// New-table buckets an address group heard from one source group can reach.
+const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 8;
+
+/// Tried-table buckets the addresses of one group can reach.
+const TRIED_BUCKETS_PER_GROUP: u64 = 4;
+
+/// Failed dials in a row after which a new-table address is forgotten.
+const MAX_NEW_FAILURES: u32 = 3;
+
+/// Failed dials in a row after which a tried-table address is forgotten.
+const MAX_TRIED_FAILURES: u32 = 10;
+
+/// How far in the future an advertisement's issue time may be, to allow for
+/// clock skew between nodes.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(10 * 60);
+
+/// Peer exchange configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerExchangeConfig {
+    /// Most addresses accepted from, and sent in, one peer list
+    pub max_addresses: usize,
+
+    /// Buckets in the table of addresses heard of
+    pub new_buckets: usize,
+
+    /// Buckets in the table of addresses connected to
+    pub tried_buckets: usize,
+
+    /// Addresses each bucket holds
+    pub bucket_size: usize,
+
+    /// Most connections within one network group (IPv4 /16, IPv6 /32) that
+    /// the connection manager dials into
+    pub max_per_group: usize,
+
+    /// Age beyond which an advertisement is dropped; each node re-sends its
+    /// own to its peers every quarter of this
+    pub max_advertisement_age: Duration,
+
+    /// Ignore unsigned peer lists from peers that predate advertisements
+    pub require_signed: bool,
+}
+
+impl Default for PeerExchangeConfig {
+    fn default() -> Self {
+        Self {
+            max_addresses: 32,
+            new_buckets: 256,
+            tried_buckets: 64,
+            bucket_size: 16,
+            max_per_group: 2,
+            max_advertisement_age: Duration::from_secs(24 * 60 * 60),
+            require_signed: false,
+        }
+    }
+}
+
+impl PeerExchangeConfig {
+    /// Validate the configuration.
+    ///
+    /// # Errors
+    /// Returns a description of the first invalid field.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_addresses == 0 {
+            return Err("peer_exchange max_addresses must be > 0".into());
+        }
+        if self.new_buckets == 0 || self.tried_buckets == 0 {
+            return Err("peer_exchange new_buckets and tried_buckets must be > 0".into());
+        }
+        if self.bucket_size == 0 {
+            return Err("peer_exchange bucket_size must be > 0".into());
+        }
+        if self.max_per_group == 0 {
+            return Err("peer_exchange max_per_group must be > 0".into());
+        }
+        if self.max_advertisement_age.is_zero() {
+            return Err("peer_exchange max_advertisement_age must be > 0".into());
+        }
+        Ok(())
+    }
+
+    /// How often a node re-sends its own advertisement to each peer.
+    fn readvertise_interval(&self) -> Duration {
+        self.max_advertisement_age / 4
+    }
+}
+
+/// The size of a node's address book.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerExchangeStats {
+    /// Addresses heard of but not yet connected to
+    pub new: usize,
+
+    /// Addresses this node has connected to
+    pub tried: usize,
+}
+
+/// A network group: addresses an attacker is likely to hold many of at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Group {
+    /// A globally routable IPv4 /16
+    V4([u8; 2]),
+
+    /// A globally routable IPv6 /32
+    V6([u8; 4]),
+
+    /// An address that is not globally routable, in a group of its own
+    Local(SocketAddr),
+}
+
+fn group(addr: SocketAddr) -> Group {
+    match addr.ip().to_canonical() {
+        ip if !is_global(ip) => Group::Local(addr),
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            Group::V4([a, b])
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, d, ..] = ip.octets();
+            Group::V6([a, b, c, d])
+        }
+    }
+}
+
+/// Whether `addr` could be a peer's listening address.
+fn is_dialable(addr: SocketAddr) -> bool {
+    let ip = addr.ip().to_canonical();
+    let broadcast = matches!(ip, IpAddr::V4(ip) if ip.is_broadcast());
+    addr.port() != 0 && !ip.is_unspecified() && !ip.is_multicast() && !broadcast
+}
+
+/// One address in the book.
+#[derive(Debug)]
+struct Entry {
+    /// The peer the address was first heard from
+    source: SocketAddr,
+
+    /// The newest advertisement held for it, if it was advertised
+    ad: Option<PeerAdvertisement>,
+
+    /// Whether it is in the tried table
+    tried: bool,
+
+    /// Its bucket in its table
+    bucket: usize,
+
+    /// Dials that have failed in a row
+    failures: u32,
+}
+
+/// Learned addresses, in new and tried tables of buckets.
+#[derive(Debug)]
+pub(crate) struct AddrBook {
+    /// Keys the bucket hashes; random per node
+    key: RandomState,
+    new: Vec<Vec<SocketAddr>>,
+    tried: Vec<Vec<SocketAddr>>,
+    bucket_size: usize,
+    entries: HashMap<SocketAddr, Entry>,
+}
+
+impl AddrBook {
+    pub(crate) fn new(config: &PeerExchangeConfig) -> Self {
+        Self {
+            key: RandomState::new(),
+            new: vec![Vec::new(); config.new_buckets],
+            tried: vec![Vec::new(); config.tried_buckets],
+            bucket_size: config.bucket_size,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn hash(&self, value: impl Hash) -> u64 {
+        self.key.hash_one(value)
+    }
+
+    fn bucket_index(&self, value: impl Hash, buckets: usize) -> usize {
+        let buckets = u64::try_from(buckets).unwrap_or(u64::MAX);
+        usize::try_from(self.hash(value) % buckets).unwrap_or(0)
+    }
+
+    /// The new-table bucket for `addr` heard from `source`: one of the few
+    /// that `source`'s group can reach.
+    fn new_bucket(&self, addr: SocketAddr, source: SocketAddr) -> usize {
+        let source_group = group(source);
+        let slot = self.hash((group(addr), source_group)) % NEW_BUCKETS_PER_SOURCE_GROUP;
+        self.bucket_index((source_group, slot), self.new.len())
+    }
+
+    /// The tried-table bucket for `addr`: one of the few its group can reach.
+    fn tried_bucket(&self, addr: SocketAddr) -> usize {
+        let addr_group = group(addr);
+        let slot = self.hash(addr) % TRIED_BUCKETS_PER_GROUP;
+        self.bucket_index((addr_group, slot), self.tried.len())
+    }
+
+    /// Add `addr`, heard from `source`, to the new table. An address already
+    /// held keeps its place; its advertisement is replaced by a newer one
+    /// under the same key, or by any the address advertised itself. Returns
+    /// whether the address is new.
+    pub(crate) fn add(
+        &mut self,
+        addr: SocketAddr,
+        source: SocketAddr,
+        ad: Option<PeerAdvertisement>,
+    ) -> bool {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            if let Some(ad) = ad {
+                let replace = match &entry.ad {
+                    None => true,
+                    Some(held) if held.peer_id == ad.peer_id => held.issued_at < ad.issued_at,
+                    Some(_) => source == addr,
+                };
+                if replace {
+                    entry.ad = Some(ad);
+                }
+            }
+            return false;
+        }
+
+        let bucket = self.new_bucket(addr, source);
+        if self.new[bucket].len() >= self.bucket_size {
+            self.evict_new(bucket);
+        }
+        self.new[bucket].push(addr);
+        self.entries.insert(
+            addr,
+            Entry {
+                source,
+                ad,
+                tried: false,
+                bucket,
+                failures: 0,
+            },
+        );
+        true
+    }
+
+    /// Make room in a full new bucket: forget its entry with the most
+    /// failures, the oldest among equals.
+    fn evict_new(&mut self, bucket: usize) {
+        let victim = self.new[bucket]
+            .iter()
+            .enumerate()
+            .max_by_key(|&(index, addr)| {
+                let failures = self.entries.get(addr).map_or(0, |entry| entry.failures);
+                (failures, std::cmp::Reverse(index))
+            })
+            .map(|(index, _)| index);
+        if let Some(index) = victim {
+            let addr = self.new[bucket].remove(index);
+            self.entries.remove(&addr);
+        }
+    }
+
+    /// Record a successful connection to `addr`, moving it to the tried
+    /// table. A full tried bucket sends its oldest entry back to the new
+    /// table.
+    pub(crate) fn mark_good(&mut self, addr: SocketAddr) {
+        let Some(entry) = self.entries.get_mut(&addr) else {
+            return;
+        };
+        entry.failures = 0;
+        if entry.tried {
+            return;
+        }
+        let new_bucket = entry.bucket;
+        self.new[new_bucket].retain(|held| *held != addr);
+
+        let bucket = self.tried_bucket(addr);
+        if self.tried[bucket].len() >= self.bucket_size {
+            let demoted = self.tried[bucket].remove(0);
+            self.demote(demoted);
+        }
+        self.tried[bucket].push(addr);
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.tried = true;
+            entry.bucket = bucket;
+        }
+    }
+
+    /// Move an address evicted from the tried table back to the new table,
+    /// if its bucket there has room.
+    fn demote(&mut self, addr: SocketAddr) {
+        let Some(source) = self.entries.get(&addr).map(|entry| entry.source) else {
+            return;
+        };
+        let bucket = self.new_bucket(addr, source);
+        if self.new[bucket].len() >= self.bucket_size {
+            self.entries.remove(&addr);
+            return;
+        }
+        self.new[bucket].push(addr);
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.tried = false;
+            entry.bucket = bucket;
+        }
+    }
+
+    /// Record a failed dial of `addr`, forgetting it after too many in a row.
+    pub(crate) fn mark_failed(&mut self, addr: SocketAddr) {
+        let Some(entry) = self.entries.get_mut(&addr) else {
+            return;
+        };
+        entry.failures += 1;
+        let limit = if entry.tried {
+            MAX_TRIED_FAILURES
+        } else {
+            MAX_NEW_FAILURES
+        };
+        if entry.failures >= limit {
+            self.remove(addr);
+        }
+    }
+
+    fn remove(&mut self, addr: SocketAddr) {
+        if let Some(entry) = self.entries.remove(&addr) {
+            let table = if entry.tried {
+                &mut self.tried
+            } else {
+                &mut self.new
+            };
+            table[entry.bucket].retain(|held| *held != addr);
+        }
+    }
+
+    /// A random address to dial that is not in `exclude` and whose group
+    /// `allowed` accepts: from the tried or the new table with equal odds,
+    /// then from a random bucket, so no one bucket's addresses dominate.
+    fn select(
+        &self,
+        exclude: &HashSet<SocketAddr>,
+        allowed: impl Fn(Group) -> bool,
+    ) -> Option<SocketAddr> {
+        let eligible = |table: &[Vec<SocketAddr>]| -> Vec<Vec<SocketAddr>> {
+            table
+                .iter()
+                .map(|bucket| {
+                    bucket
+                        .iter()
+                        .copied()
+                        .filter(|addr| !exclude.contains(addr) && allowed(group(*addr)))
+                        .collect::<Vec<_>>()
+                })
+                .filter(|bucket| !bucket.is_empty())
+                .collect()
+        };
+        let new = eligible(&self.new);
+        let tried = eligible(&self.tried);
+        let mut rng = rand::rng();
+        let buckets = match (new.is_empty(), tried.is_empty()) {
+            (true, true) => return None,
+            (false, true) => new,
+            (true, false) => tried,
+            (false, false) if rng.random_bool(0.5) => tried,
+            (false, false) => new,
+        };
+        buckets.choose(&mut rng)?.choose(&mut rng).copied()
+    }
+
+    /// The advertisement held for `addr`, if it is still fresh at `now_ms`.
+    fn fresh_ad(
+        &self,
+        addr: SocketAddr,
+        now_ms: u64,
+        max_age: Duration,
+    ) -> Option<PeerAdvertisement> {
+        self.entries
+            .get(&addr)
+            .and_then(|entry| entry.ad)
+            .filter(|ad| age_ms(ad, now_ms) <= millis(max_age))
+    }
+
+    fn stats(&self) -> PeerExchangeStats {
+        let tried = self.entries.values().filter(|entry| entry.tried).count();
+        PeerExchangeStats {
+            new: self.entries.len() - tried,
+            tried,
+        }
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+fn age_ms(ad: &PeerAdvertisement, now_ms: u64) -> u64 {
+    now_ms.saturating_sub(ad.issued_at)
+}
+
+/// Peer exchange state: the address book, the peer list requests awaiting an
+/// answer, and when this node last advertised itself to each peer.
+pub(crate) struct PeerExchange {
+    config: PeerExchangeConfig,
+    book: Mutex<AddrBook>,
+
+    /// Connections sent a peer list request they have not yet answered
+    requested: DashSet<SocketAddr>,
+
+    /// Connections, and when this node last sent them its advertisement
+    advertised: DashMap<SocketAddr, Instant>,
+}
+
+impl PeerExchange {
+    pub(crate) fn new(config: PeerExchangeConfig) -> Self {
+        Self {
+            book: Mutex::new(AddrBook::new(&config)),
+            config,
+            requested: DashSet::new(),
+            advertised: DashMap::new(),
+        }
+    }
+
+    /// Note that the peer on connection `peer` was asked for its peer list.
+    pub(crate) fn requested(&self, peer: SocketAddr) {
+        self.requested.insert(peer);
+    }
+
+    /// Whether this node should send its advertisement to `peer` now; if so,
+    /// the send is recorded.
+    pub(crate) fn advertise_to(&self, peer: SocketAddr, now: Instant) -> bool {
+        let due = self.advertised.get(&peer).is_none_or(|sent| {
+            now.saturating_duration_since(*sent) >= self.config.readvertise_interval()
+        });
+        if due {
+            self.advertised.insert(peer, now);
+        }
+        due
+    }
+
+    /// Forget the request and advertisement state of connections not in `live`.
+    pub(crate) fn retain(&self, live: &[SocketAddr]) {
+        self.requested.retain(|peer| live.contains(peer));
+        self.advertised.retain(|peer, _| live.contains(peer));
+    }
+
+    /// Take in an unsigned peer list, received on connection `peer` from the
+    /// node at `source`. Returns the addresses accepted.
+    pub(crate) fn learn_addresses(
+        &self,
+        peer: SocketAddr,
+        source: SocketAddr,
+        addrs: &[SocketAddr],
+        local_addr: SocketAddr,
+    ) -> Vec<SocketAddr> {
+        if self.config.require_signed || self.requested.remove(&peer).is_none() {
+            return Vec::new();
+        }
+        let accepted: Vec<SocketAddr> = addrs
+            .choose_multiple(&mut rand::rng(), self.config.max_addresses)
+            .copied()
+            .filter(|&addr| addr != local_addr && is_dialable(addr))
+            .collect();
+        let mut book = lock(&self.book);
+        for &addr in &accepted {
+            book.add(addr, source, None);
+        }
+        accepted
+    }
+
+    /// Take in advertisements, received on connection `peer` from the node at
+    /// `source`, checking each against `pins` and the clock at `now_ms`.
+    /// Unless they answer this node's request, only `source`'s own
+    /// advertisement is considered. Returns the addresses accepted.
+    pub(crate) fn learn_advertisements(
+        &self,
+        peer: SocketAddr,
+        source: SocketAddr,
+        ads: &[PeerAdvertisement],
+        pins: &PinStore,
+        local_addr: SocketAddr,
+        now_ms: u64,
+    ) -> Vec<SocketAddr> {
+        let solicited = self.requested.remove(&peer).is_some();
+        let accepted: Vec<PeerAdvertisement> = ads
+            .choose_multiple(&mut rand::rng(), self.config.max_addresses)
+            .copied()
+            .filter(|ad| solicited || ad.addr == source)
+            .filter(|ad| ad.addr != local_addr && self.is_acceptable(ad, pins, now_ms))
+            .collect();
+        let mut book = lock(&self.book);
+        for ad in &accepted {
+            book.add(ad.addr, source, Some(*ad));
+        }
+        accepted.iter().map(|ad| ad.addr).collect()
+    }
+
+    /// Whether `ad` verifies, is fresh at `now_ms`, names a dialable address,
+    /// and carries the key pinned for that address, if any.
+    fn is_acceptable(&self, ad: &PeerAdvertisement, pins: &PinStore, now_ms: u64) -> bool {
+        is_dialable(ad.addr)
+            && ad.issued_at <= now_ms.saturating_add(millis(MAX_CLOCK_SKEW))
+            && age_ms(ad, now_ms) <= millis(self.config.max_advertisement_age)
+            && !pins.is_revoked(ad.peer_id)
+            && pins
+                .pinned(ad.addr)
+                .is_none_or(|pinned| pinned == ad.peer_id)
+            && ad.verify().is_ok()
+    }
+
+    /// The advertisements to answer a peer list request with: `own`, then
+    /// those held for `peers` (this node's connected peers), then those of
+    /// other tried addresses, up to the configured maximum.
+    pub(crate) fn advertisements(
+        &self,
+        own: PeerAdvertisement,
+        peers: &[SocketAddr],
+        now_ms: u64,
+    ) -> Vec<PeerAdvertisement> {
+        let max_age = self.config.max_advertisement_age;
+        let book = lock(&self.book);
+        let mut held: Vec<PeerAdvertisement> = peers
+            .iter()
+            .filter_map(|&addr| book.fresh_ad(addr, now_ms, max_age))
+            .collect();
+        let room = self.config.max_addresses.saturating_sub(1);
+        let mut rng = rand::rng();
+        if held.len() < room {
+            let listed: HashSet<SocketAddr> = peers.iter().copied().collect();
+            let tried: Vec<PeerAdvertisement> = book
+                .tried
+                .iter()
+                .flatten()
+                .filter(|addr| !listed.contains(addr))
+                .filter_map(|&addr| book.fresh_ad(addr, now_ms, max_age))
+                .collect();
+            held.extend(tried.choose_multiple(&mut rng, room - held.len()).copied());
+        }
+        let mut ads = vec![own];
+        ads.extend(held.choose_multiple(&mut rng, room).copied());
+        ads
+    }
+
+    /// Up to `count` addresses to dial, none in `exclude`, keeping the
+    /// connections within each network group, `connections` included, at or
+    /// below the configured maximum.
+    pub(crate) fn select(
+        &self,
+        count: usize,
+        exclude: &HashSet<SocketAddr>,
+        connections: &[SocketAddr],
+    ) -> Vec<SocketAddr> {
+        let mut per_group: HashMap<Group, usize> = HashMap::new();
+        for &addr in connections {
+            *per_group.entry(group(addr)).or_default() += 1;
+        }
+        let mut exclude = exclude.clone();
+        let mut chosen = Vec::new();
+        let book = lock(&self.book);
+        while chosen.len() < count {
+            let max_per_group = self.config.max_per_group;
+            let Some(addr) = book.select(&exclude, |g| {
+                per_group.get(&g).copied().unwrap_or(0) < max_per_group
+            }) else {
+                break;
+            };
+            *per_group.entry(group(addr)).or_default() += 1;
+            exclude.insert(addr);
+            chosen.push(addr);
+        }
+        chosen
+    }
+
+    /// Record a successful dial of `addr`.
+    pub(crate) fn mark_good(&self, addr: SocketAddr) {
+        lock(&self.book).mark_good(addr);
+    }
+
+    /// Record a failed dial of `addr`.
+    pub(crate) fn mark_failed(&self, addr: SocketAddr) {
+        lock(&self.book).mark_failed(addr);
+    }
+
+    pub(crate) fn stats(&self) -> PeerExchangeStats {
+        lock(&self.book).stats()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Identity;
+
+    const NOW_MS: u64 = 1_750_000_000_000;
+
+    fn public(a: u8, b: u8, c: u8, port: u16) -> SocketAddr {
+        SocketAddr::from(([a, b, c, 1], port))
+    }
+
+    fn exchange(config: PeerExchangeConfig) -> PeerExchange {
+        PeerExchange::new(config)
+    }
+
+    #[test]
+    fn groups_public_addresses_by_prefix() {
+        assert_eq!(group(public(8, 8, 1, 1)), group(public(8, 8, 200, 2)));
+        assert_ne!(group(public(8, 8, 1, 1)), group(public(8, 9, 1, 1)));
+        let v6_a: SocketAddr = "[2a00:1450::1]:1".parse().unwrap();
+        let v6_b: SocketAddr = "[2a00:1450:ffff::1]:2".parse().unwrap();
+        assert_eq!(group(v6_a), group(v6_b));
+        // An IPv4-mapped address is grouped as IPv4.
+        let mapped: SocketAddr = "[::ffff:8.8.3.3]:1".parse().unwrap();
+        assert_eq!(group(mapped), group(public(8, 8, 1, 1)));
+        // Local addresses each form their own group.
+        let local = SocketAddr::from(([127, 0, 0, 1], 1));
+        let private = SocketAddr::from(([10, 0, 0, 1], 1));
+        assert_eq!(group(local), Group::Local(local));
+        assert_eq!(group(private), Group::Local(private));
+    }
+
+    #[test]
+    fn one_source_reaches_few_new_buckets() {
+        let config = PeerExchangeConfig::default();
+        let mut book = AddrBook::new(&config);
+        let source = public(6, 6, 6, 7946);
+        for i in 0..=u8::MAX {
+            for j in 0..8 {
+                book.add(public(i, j, 1, 7946), source, None);
+            }
+        }
+        let reached = book.new.iter().filter(|bucket| !bucket.is_empty()).count();
+        let held = book.stats().new;
+        assert!(
+            u64::try_from(reached).unwrap() <= NEW_BUCKETS_PER_SOURCE_GROUP,
+            "{reached}"
+        );
+        assert!(held <= reached * config.bucket_size, "{held}");
+
+        // Addresses are held once, whoever else lists them.
+        let held = *book.entries.keys().next().unwrap();
+        assert!(!book.add(held, public(9, 9, 9, 7946), None));
+    }
+
+    #[test]
+    fn successful_dials_move_addresses_to_tried() {
+        let mut book = AddrBook::new(&PeerExchangeConfig::default());
+        let addr = public(1, 2, 3, 7946);
+        book.add(addr, public(4, 5, 6, 7946), None);
+        book.mark_good(addr);
+        assert_eq!(book.stats(), PeerExchangeStats { new: 0, tried: 1 });
+
+        for _ in 0..MAX_TRIED_FAILURES - 1 {
+            book.mark_failed(addr);
+        }
+        assert_eq!(book.stats().tried, 1);
+        book.mark_failed(addr);
+        assert_eq!(book.stats(), PeerExchangeStats::default());
+
+        let flaky = public(1, 2, 4, 7946);
+        book.add(flaky, public(4, 5, 6, 7946), None);
+        for _ in 0..MAX_NEW_FAILURES {
+            book.mark_failed(flaky);
+        }
+        assert_eq!(book.stats(), PeerExchangeStats::default());
+    }
+
+    #[test]
+    fn unsolicited_and_oversized_lists_are_limited() {
+        let exchange = exchange(PeerExchangeConfig {
+            max_addresses: 4,
+            ..PeerExchangeConfig::default()
+        });
+        let peer = public(7, 7, 7, 7946);
+        let local = public(7, 7, 7, 7947);
+        let addrs: Vec<SocketAddr> = (1..=20).map(|i| public(i, i, i, 7946)).collect();
+
+        assert!(
+            exchange
+                .learn_addresses(peer, peer, &addrs, local)
+                .is_empty()
+        );
+
+        exchange.requested(peer);
+        assert_eq!(exchange.learn_addresses(peer, peer, &addrs, local).len(), 4);
+        // The request is answered only once.
+        assert!(
+            exchange
+                .learn_addresses(peer, peer, &addrs, local)
+                .is_empty()
+        );
+        assert_eq!(exchange.stats().new, 4);
+
+        let signed_only = PeerExchange::new(PeerExchangeConfig {
+            require_signed: true,
+            ..PeerExchangeConfig::default()
+        });
+        signed_only.requested(peer);
+        assert!(
+            signed_only
+                .learn_addresses(peer, peer, &addrs, local)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn advertisements_are_checked() {
+        let exchange = exchange(PeerExchangeConfig::default());
+        let pins = PinStore::new();
+        let peer = public(7, 7, 7, 7946);
+        let local = public(7, 7, 7, 7947);
+        let relayed = Identity::generate().advertise(public(1, 1, 1, 7946), NOW_MS);
+
+        // Unsolicited, only the sender's own advertisement is taken.
+        let own = Identity::generate().advertise(peer, NOW_MS);
+        let learned =
+            exchange.learn_advertisements(peer, peer, &[own, relayed], &pins, local, NOW_MS);
+        assert_eq!(learned, vec![peer]);
+
+        let stale = Identity::generate().advertise(public(2, 2, 2, 7946), 0);
+        let future = Identity::generate()
+            .advertise(public(3, 3, 3, 7946), NOW_MS + millis(MAX_CLOCK_SKEW) + 1);
+        let forged = PeerAdvertisement {
+            addr: public(4, 4, 4, 7946),
+            ..relayed
+        };
+        let pinned_addr = public(5, 5, 5, 7946);
+        pins.pin(pinned_addr, Identity::generate().peer_id())
+            .unwrap();
+        let impostor = Identity::generate().advertise(pinned_addr, NOW_MS);
+
+        exchange.requested(peer);
+        let learned = exchange.learn_advertisements(
+            peer,
+            peer,
+            &[relayed, stale, future, forged, impostor],
+            &pins,
+            local,
+            NOW_MS,
+        );
+        assert_eq!(learned, vec![relayed.addr]);
+    }
+
+    #[test]
+    fn relays_cannot_replace_a_key() {
+        let mut book = AddrBook::new(&PeerExchangeConfig::default());
+        let addr = public(1, 1, 1, 7946);
+        let relay = public(2, 2, 2, 7946);
+        let owner = Identity::generate();
+        let impostor = Identity::generate();
+
+        book.add(addr, relay, Some(impostor.advertise(addr, NOW_MS)));
+        book.add(addr, relay, Some(owner.advertise(addr, NOW_MS + 1)));
+        let held = |book: &AddrBook| book.entries[&addr].ad.map(|ad| ad.peer_id);
+        assert_eq!(held(&book), Some(impostor.peer_id()));
+
+        // The address's own advertisement wins.
+        book.add(addr, addr, Some(owner.advertise(addr, NOW_MS)));
+        assert_eq!(held(&book), Some(owner.peer_id()));
+    }
+
+    #[test]
+    fn selection_spreads_across_groups() {
+        let exchange = exchange(PeerExchangeConfig::default());
+        let peer = public(7, 7, 7, 7946);
+        let crowded: Vec<SocketAddr> = (1..=20).map(|i| public(1, 1, i, 7946)).collect();
+        let other = public(2, 2, 2, 7946);
+        exchange.requested(peer);
+        let mut addrs = crowded.clone();
+        addrs.push(other);
+        exchange.learn_addresses(peer, peer, &addrs, public(9, 9, 9, 1));
+
+        let chosen = exchange.select(10, &HashSet::new(), &[]);
+        let in_crowded = chosen.iter().filter(|addr| crowded.contains(addr)).count();
+        assert_eq!(in_crowded, 2, "{chosen:?}");
+        assert!(chosen.contains(&other));
+
+        // Existing connections count against their group.
+        let chosen = exchange.select(10, &HashSet::new(), &[crowded[0], crowded[1]]);
+        assert_eq!(chosen, vec![other]);
+        let exclude = HashSet::from([other]);
+        assert!(
+            exchange
+                .select(10, &exclude, &[crowded[0], crowded[1]])
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn responses_lead_with_the_own_advertisement() {
+        let exchange = exchange(PeerExchangeConfig {
+            max_addresses: 3,
+            ..PeerExchangeConfig::default()
+        });
+        let pins = PinStore::new();
+        let peer = public(7, 7, 7, 7946);
+        let ads: Vec<PeerAdvertisement> = (1..=5)
+            .map(|i| Identity::generate().advertise(public(i, i, i, 7946), NOW_MS))
+            .collect();
+        exchange.requested(peer);
+        exchange.learn_advertisements(peer, peer, &ads, &pins, public(9, 9, 9, 1), NOW_MS);
+
+        let own = Identity::generate().advertise(public(9, 9, 9, 1), NOW_MS);
+        let peers: Vec<SocketAddr> = ads.iter().map(|ad| ad.addr).collect();
+        let response = exchange.advertisements(own, &peers, NOW_MS);
+        assert_eq!(response.len(), 3);
+        assert_eq!(response[0], own);
+
+        // Stale advertisements are not relayed.
+        let later = NOW_MS + millis(PeerExchangeConfig::default().max_advertisement_age) + 1;
+        assert_eq!(exchange.advertisements(own, &peers, later), vec![own]);
+    }
+
+    #[test]
+    fn validate_rejects_bad_values() {
+        assert!(PeerExchangeConfig::default().validate().is_ok());
+        let bad = [
+            PeerExchangeConfig {
+                max_addresses: 0,
+                ..PeerExchangeConfig::default()
+            },
+            PeerExchangeConfig {
+                tried_buckets: 0,
+                ..PeerExchangeConfig::default()
+            },
+            PeerExchangeConfig {
+                bucket_size: 0,
+                ..PeerExchangeConfig::default()
+            },
+            PeerExchangeConfig {
+                max_per_group: 0,
+                ..PeerExchangeConfig::default()
+            },
+            PeerExchangeConfig {
+                max_advertisement_age: Duration::ZERO,
+                ..PeerExchangeConfig::default()
+            },
+        ];
+        for config in bad {
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+    }
+}
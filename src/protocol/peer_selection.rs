@@ -0,0 +1,300 @@
+//! Choosing which peers to gossip to.
+//!
+//! Epidemic fanout and each anti-entropy round send to a few of the connected
+//! peers. A [`PeerSelector`] picks them. Three ship with the crate:
+//!
+//! - [`Uniform`]: every peer equally likely. The default, and the classic
+//!   analysis of gossip assumes it.
+//! - [`HealthWeighted`]: in proportion to [`PeerInfo::health_score`], so peers
+//!   whose sends keep failing are chosen less often.
+//! - [`RttWeighted`]: in proportion to the inverse of the peer's smoothed
+//!   round-trip time, so nearby peers are chosen more often.
+//!
+//! Weighted selectors floor every weight, so no connected peer is starved
+//! entirely: a peer that recovers is still sent to, and its score can climb
+//! back. A node uses the selector named by `NodeConfig::peer_selection`
+//! ([`PeerSelection`]).
+//!
+//! Round-trip times come from the heartbeat. On each gossip loop tick a node
+//! sends peers that speak protocol version 3 a [`Payload::Ping`] with a fresh
+//! nonce instead of a heartbeat, and times the [`Payload::Pong`] echoing it.
+//! A ping still unanswered when the next one goes out counts as a sample of
+//! the time it waited, so a peer slower than the gossip interval is measured
+//! as at least that slow rather than not at all.
+//!
+//! [`Payload::Ping`]: crate::Payload::Ping
+//! [`Payload::Pong`]: crate::Payload::Pong
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use rand::seq::IndexedRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::PeerInfo;
+
+/// Smallest weight a weighted selector gives a peer.
+const MIN_WEIGHT: f64 = 0.05;
+
+/// Round-trip times below this are counted as this.
+const MIN_RTT: Duration = Duration::from_millis(1);
+
+/// A strategy for choosing the peers to send to.
+pub trait PeerSelector: Send + Sync {
+    /// Choose up to `count` distinct peers from `candidates`, returning their
+    /// connection addresses.
+    fn select(&self, candidates: &[PeerInfo], count: usize) -> Vec<SocketAddr>;
+}
+
+/// Every peer equally likely.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Uniform;
+
+impl PeerSelector for Uniform {
+    fn select(&self, candidates: &[PeerInfo], count: usize) -> Vec<SocketAddr> {
+        candidates
+            .choose_multiple(&mut rand::rng(), count)
+            .map(|info| info.addr)
+            .collect()
+    }
+}
+
+/// Peers weighted by [`PeerInfo::health_score`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HealthWeighted;
+
+impl PeerSelector for HealthWeighted {
+    fn select(&self, candidates: &[PeerInfo], count: usize) -> Vec<SocketAddr> {
+        weighted(candidates, count, PeerInfo::health_score)
+    }
+}
+
+/// Peers weighted by the inverse of their smoothed round-trip time, relative
+/// to the nearest candidate's.
+///
+/// A peer not yet measured is weighted as if its round-trip time were the
+/// mean of the measured candidates'; when none are measured, selection is
+/// uniform.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RttWeighted;
+
+impl PeerSelector for RttWeighted {
+    fn select(&self, candidates: &[PeerInfo], count: usize) -> Vec<SocketAddr> {
+        let measured: Vec<f64> = candidates
+            .iter()
+            .filter_map(|info| info.rtt)
+            .map(|rtt| rtt.max(MIN_RTT).as_secs_f64())
+            .collect();
+        if measured.is_empty() {
+            return Uniform.select(candidates, count);
+        }
+        let len = f64::from(u32::try_from(measured.len()).unwrap_or(u32::MAX));
+        let mean = measured.iter().sum::<f64>() / len;
+        let nearest = measured.iter().copied().fold(mean, f64::min);
+        weighted(candidates, count, |info| {
+            let rtt = info.rtt.map_or(mean, |rtt| rtt.max(MIN_RTT).as_secs_f64());
+            nearest / rtt
+        })
+    }
+}
+
+/// Choose up to `count` of `candidates` without replacement, each draw in
+/// proportion to `weight`, floored at [`MIN_WEIGHT`].
+fn weighted(
+    candidates: &[PeerInfo],
+    count: usize,
+    weight: impl Fn(&PeerInfo) -> f64,
+) -> Vec<SocketAddr> {
+    let chosen = candidates.choose_multiple_weighted(&mut rand::rng(), count, |info| {
+        let weight = weight(info);
+        if weight.is_finite() {
+            weight.max(MIN_WEIGHT)
+        } else {
+            MIN_WEIGHT
+        }
+    });
+    match chosen {
+        Ok(chosen) => chosen.map(|info| info.addr).collect(),
+        Err(_) => Uniform.select(candidates, count),
+    }
+}
+
+/// Selects a [`PeerSelector`] by name, for configuration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerSelection {
+    /// [`Uniform`]
+    #[default]
+    Uniform,
+    /// [`HealthWeighted`]
+    HealthWeighted,
+    /// [`RttWeighted`]
+    RttWeighted,
+}
+
+impl PeerSelection {
+    /// The implementation.
+    pub fn selector(self) -> &'static dyn PeerSelector {
+        match self {
+            Self::Uniform => &Uniform,
+            Self::HealthWeighted => &HealthWeighted,
+            Self::RttWeighted => &RttWeighted,
+        }
+    }
+}
+
+/// Outstanding pings, one per connection, awaiting their pongs.
+#[derive(Debug, Default)]
+pub(crate) struct RttProbes {
+    pending: DashMap<SocketAddr, (u64, Instant)>,
+}
+
+impl RttProbes {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a ping with `nonce` sent to `peer`. If the previous ping to
+    /// `peer` is still unanswered, returns how long it waited: a lower bound
+    /// on the peer's round-trip time.
+    pub(crate) fn probe(&self, peer: SocketAddr, nonce: u64) -> Option<Duration> {
+        self.pending
+            .insert(peer, (nonce, Instant::now()))
+            .map(|(_, sent)| sent.elapsed())
+    }
+
+    /// Match a pong from `peer` to its ping, returning the round-trip time.
+    /// A pong that answers no outstanding ping returns `None`.
+    pub(crate) fn answer(&self, peer: SocketAddr, nonce: u64) -> Option<Duration> {
+        self.pending
+            .remove_if(&peer, |_, &(pending, _)| pending == nonce)
+            .map(|(_, (_, sent))| sent.elapsed())
+    }
+
+    /// Forget pings to connections no longer in `live`.
+    pub(crate) fn retain(&self, live: &HashSet<SocketAddr>) {
+        self.pending.retain(|peer, _| live.contains(peer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRIALS: usize = 2_000;
+
+    fn peer(port: u16) -> PeerInfo {
+        PeerInfo::new(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    /// How often each candidate is the single peer chosen over [`TRIALS`].
+    fn tally(selector: &dyn PeerSelector, candidates: &[PeerInfo]) -> Vec<usize> {
+        let mut counts = vec![0; candidates.len()];
+        for _ in 0..TRIALS {
+            let chosen = selector.select(candidates, 1);
+            assert_eq!(chosen.len(), 1);
+            let index = candidates
+                .iter()
+                .position(|info| info.addr == chosen[0])
+                .unwrap();
+            counts[index] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn selectors_choose_distinct_candidates_up_to_the_count() {
+        let mut candidates: Vec<PeerInfo> = (1..=5).map(peer).collect();
+        candidates[0].rtt = Some(Duration::from_millis(5));
+        candidates[1].record_failure();
+
+        for selection in [
+            PeerSelection::Uniform,
+            PeerSelection::HealthWeighted,
+            PeerSelection::RttWeighted,
+        ] {
+            let selector = selection.selector();
+            let chosen = selector.select(&candidates, 3);
+            assert_eq!(chosen.len(), 3, "{selection:?}");
+            assert_eq!(
+                chosen.iter().collect::<HashSet<_>>().len(),
+                3,
+                "{selection:?}"
+            );
+
+            assert_eq!(selector.select(&candidates, 10).len(), 5, "{selection:?}");
+            assert!(selector.select(&[], 3).is_empty(), "{selection:?}");
+        }
+    }
+
+    #[test]
+    fn health_weighted_prefers_healthy_peers_without_starving_others() {
+        let healthy = peer(1);
+        let mut failing = peer(2);
+        for _ in 0..5 {
+            failing.record_failure();
+        }
+        assert_eq!(failing.health_score(), 0.0);
+
+        let counts = tally(&HealthWeighted, &[healthy, failing]);
+        assert!(counts[0] > TRIALS * 9 / 10, "{counts:?}");
+        assert!(counts[1] > 0, "{counts:?}");
+    }
+
+    #[test]
+    fn rtt_weighted_prefers_nearby_peers() {
+        let mut near = peer(1);
+        near.rtt = Some(Duration::from_millis(2));
+        let mut far = peer(2);
+        far.rtt = Some(Duration::from_millis(200));
+
+        let counts = tally(&RttWeighted, &[near, far]);
+        assert!(counts[0] > TRIALS * 9 / 10, "{counts:?}");
+    }
+
+    #[test]
+    fn rtt_weighted_treats_unmeasured_peers_as_average() {
+        let mut near = peer(1);
+        near.rtt = Some(Duration::from_millis(10));
+        let mut far = peer(2);
+        far.rtt = Some(Duration::from_millis(190));
+        let unmeasured = peer(3);
+
+        // Weights 1, 10/190 and 10/100: the unmeasured peer sits between.
+        let counts = tally(&RttWeighted, &[near, far, unmeasured]);
+        assert!(counts[2] > counts[1], "{counts:?}");
+        assert!(counts[0] > counts[2], "{counts:?}");
+    }
+
+    #[test]
+    fn pong_answers_only_the_outstanding_ping() {
+        let probes = RttProbes::new();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+
+        assert_eq!(probes.probe(addr, 7), None);
+        assert_eq!(probes.answer(addr, 8), None);
+        assert!(probes.answer(addr, 7).is_some());
+        assert_eq!(probes.answer(addr, 7), None, "a pong is counted once");
+    }
+
+    #[test]
+    fn unanswered_ping_is_a_lower_bound_sample() {
+        let probes = RttProbes::new();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+
+        probes.probe(addr, 1);
+        std::thread::sleep(Duration::from_millis(5));
+        let waited = probes.probe(addr, 2).unwrap();
+        assert!(waited >= Duration::from_millis(5));
+        assert_eq!(
+            probes.answer(addr, 1),
+            None,
+            "a superseded ping is forgotten"
+        );
+
+        probes.retain(&HashSet::new());
+        assert_eq!(probes.answer(addr, 2), None);
+    }
+}
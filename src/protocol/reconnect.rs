@@ -0,0 +1,526 @@
+//! Reconnection to bootstrap and lost peers.
+//!
+//! A node dials its bootstrap peers once when it starts, and draws peers it
+//! learns from peer lists from the address book (see
+//! [`peer_exchange`](super::peer_exchange)). Without more, a node that starts
+//! before its seeds, or loses its connections, would stay isolated. The
+//! connection manager keeps dialing instead:
+//!
+//! - **Candidates.** It remembers the bootstrap peers for good, and every other
+//!   peer it has been connected to, by canonical (listening) address. A peer
+//!   that says goodbye is forgotten unless it is a bootstrap peer.
+//! - **Target.** While fewer than [`ReconnectConfig::target_peers`] peers are
+//!   connected, it dials the candidates that are due, bootstrap peers first.
+//! - **Backoff.** Each failed dial pushes a candidate's next attempt back
+//!   exponentially, from [`ReconnectConfig::initial_backoff`] up to
+//!   [`ReconnectConfig::max_backoff`], scaled by a random factor within
+//!   [`ReconnectConfig::jitter`] so nodes that lost a peer together do not
+//!   redial it in lockstep. A lost peer is first redialled after one initial
+//!   backoff. A candidate that is not a bootstrap peer is forgotten after
+//!   [`ReconnectConfig::max_attempts`] failures in a row; bootstrap peers are
+//!   retried for as long as the node runs.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Most candidates remembered besides the bootstrap peers.
+const MAX_CANDIDATES: usize = 1_024;
+
+/// Reconnection configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReconnectConfig {
+    /// Redial bootstrap and lost peers
+    pub enabled: bool,
+
+    /// Connected peers below which candidates are dialed; capped at
+    /// `NodeConfig::max_peers`
+    pub target_peers: usize,
+
+    /// Wait before the first retry of a failed or lost peer
+    pub initial_backoff: Duration,
+
+    /// Longest wait between retries
+    pub max_backoff: Duration,
+
+    /// Factor the wait grows by with each failure in a row
+    pub multiplier: f64,
+
+    /// Largest fraction by which each wait is randomly lengthened or shortened
+    pub jitter: f64,
+
+    /// Failures in a row after which a peer other than a bootstrap peer is
+    /// forgotten
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            target_peers: 8,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: 10,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// Validate the configuration.
+    ///
+    /// # Errors
+    /// Returns a description of the first invalid field.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.target_peers == 0 {
+            return Err("reconnect target_peers must be > 0".into());
+        }
+        if self.initial_backoff.is_zero() {
+            return Err("reconnect initial_backoff must be > 0".into());
+        }
+        if self.max_backoff < self.initial_backoff {
+            return Err("reconnect max_backoff must be >= initial_backoff".into());
+        }
+        if !self.multiplier.is_finite() || self.multiplier < 1.0 {
+            return Err("reconnect multiplier must be a finite number >= 1".into());
+        }
+        if !(0.0..1.0).contains(&self.jitter) {
+            return Err("reconnect jitter must be in [0, 1)".into());
+        }
+        if self.max_attempts == 0 {
+            return Err("reconnect max_attempts must be > 0".into());
+        }
+        Ok(())
+    }
+
+    /// The wait after `failures` failures in a row, before jitter.
+    fn backoff(&self, failures: u32) -> Duration {
+        let exponent = i32::try_from(failures.saturating_sub(1)).unwrap_or(i32::MAX);
+        let secs = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        if secs.is_finite() && secs < self.max_backoff.as_secs_f64() {
+            Duration::from_secs_f64(secs)
+        } else {
+            self.max_backoff
+        }
+    }
+}
+
+/// A peer the connection manager dials when it is not connected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectCandidate {
+    /// Canonical (listening) address
+    pub addr: SocketAddr,
+
+    /// Whether it is a bootstrap peer, retried for as long as the node runs
+    pub bootstrap: bool,
+
+    /// Whether it is connected
+    pub connected: bool,
+
+    /// Dials that have failed in a row
+    pub failures: u32,
+
+    /// Time until it may next be dialed; `None` while it is connected
+    pub retry_in: Option<Duration>,
+}
+
+/// The connection manager's view of this node's connections.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionStatus {
+    /// Connected peers
+    pub connected: usize,
+
+    /// Connected peers the manager dials candidates to reach
+    pub target: usize,
+
+    /// Peers it redials when they are not connected
+    pub candidates: Vec<ReconnectCandidate>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    bootstrap: bool,
+    connected: bool,
+    failures: u32,
+    next_attempt: Instant,
+}
+
+/// Candidates and their backoff state.
+#[derive(Debug)]
+pub(crate) struct Reconnector {
+    config: ReconnectConfig,
+    entries: Mutex<HashMap<SocketAddr, Entry>>,
+}
+
+impl Reconnector {
+    pub(crate) fn new(
+        mut config: ReconnectConfig,
+        bootstrap_peers: &[SocketAddr],
+        max_peers: usize,
+    ) -> Self {
+        config.target_peers = config.target_peers.min(max_peers);
+        let now = Instant::now();
+        let entries = bootstrap_peers
+            .iter()
+            .map(|&addr| {
+                let entry = Entry {
+                    bootstrap: true,
+                    connected: false,
+                    failures: 0,
+                    next_attempt: now,
+                };
+                (addr, entry)
+            })
+            .collect();
+        Self {
+            config,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Bring the candidates up to date with the live `connections` and the
+    /// `canonical` addresses known for them, and return the candidates to
+    /// dial now.
+    ///
+    /// Only canonical addresses become candidates: the connection address of
+    /// an inbound peer is an ephemeral port no one listens on.
+    pub(crate) fn plan(
+        &self,
+        canonical: &HashSet<SocketAddr>,
+        connections: &HashSet<SocketAddr>,
+        now: Instant,
+    ) -> Vec<SocketAddr> {
+        let mut entries = lock(&self.entries);
+        for (addr, entry) in entries.iter_mut() {
+            let now_connected = canonical.contains(addr) || connections.contains(addr);
+            if now_connected {
+                entry.failures = 0;
+            } else if entry.connected {
+                entry.next_attempt = now + self.jittered(self.config.initial_backoff);
+            }
+            entry.connected = now_connected;
+        }
+        for &addr in canonical {
+            if entries.len() >= MAX_CANDIDATES + self.bootstrap_count(&entries) {
+                break;
+            }
+            entries.entry(addr).or_insert(Entry {
+                bootstrap: false,
+                connected: true,
+                failures: 0,
+                next_attempt: now,
+            });
+        }
+
+        let wanted = self.config.target_peers.saturating_sub(connections.len());
+        let mut due: Vec<(SocketAddr, bool, Instant)> = entries
+            .iter()
+            .filter(|(_, entry)| !entry.connected && entry.next_attempt <= now)
+            .map(|(&addr, entry)| (addr, entry.bootstrap, entry.next_attempt))
+            .collect();
+        due.sort_by_key(|&(addr, bootstrap, next_attempt)| (!bootstrap, next_attempt, addr));
+        due.into_iter()
+            .take(wanted)
+            .map(|(addr, ..)| addr)
+            .collect()
+    }
+
+    /// Add the `discovered` addresses not yet known as candidates, due now.
+    pub(crate) fn discover(&self, discovered: &[SocketAddr], now: Instant) {
+        let mut entries = lock(&self.entries);
+        for &addr in discovered {
+            if entries.len() >= MAX_CANDIDATES + self.bootstrap_count(&entries) {
+                break;
+            }
+            entries.entry(addr).or_insert(Entry {
+                bootstrap: false,
+                connected: false,
+                failures: 0,
+                next_attempt: now,
+            });
+        }
+    }
+
+    /// Record a successful dial of `addr`.
+    pub(crate) fn record_success(&self, addr: SocketAddr) {
+        if let Some(entry) = lock(&self.entries).get_mut(&addr) {
+            entry.connected = true;
+            entry.failures = 0;
+        }
+    }
+
+    /// Record a failed dial of `addr`, scheduling its next attempt or, after
+    /// too many failures, forgetting it.
+    pub(crate) fn record_failure(&self, addr: SocketAddr, now: Instant) {
+        let mut entries = lock(&self.entries);
+        let Some(entry) = entries.get_mut(&addr) else {
+            return;
+        };
+        entry.connected = false;
+        entry.failures = entry.failures.saturating_add(1);
+        if !entry.bootstrap && entry.failures >= self.config.max_attempts {
+            entries.remove(&addr);
+            return;
+        }
+        entry.next_attempt = now + self.jittered(self.config.backoff(entry.failures));
+    }
+
+    /// `addr` said goodbye: forget it, unless it is a bootstrap peer, which is
+    /// retried as if lost.
+    pub(crate) fn depart(&self, addr: SocketAddr, now: Instant) {
+        let mut entries = lock(&self.entries);
+        match entries.get_mut(&addr) {
+            Some(entry) if entry.bootstrap => {
+                entry.connected = false;
+                entry.next_attempt = now + self.jittered(self.config.initial_backoff);
+            }
+            Some(_) => {
+                entries.remove(&addr);
+            }
+            None => {}
+        }
+    }
+
+    /// The candidates and their state, bootstrap peers first. Whether each
+    /// is connected is read from the live `connections` and the `canonical`
+    /// addresses known for them, which may be ahead of the last [`plan`].
+    ///
+    /// [`plan`]: Reconnector::plan
+    pub(crate) fn status(
+        &self,
+        canonical: &HashSet<SocketAddr>,
+        connections: &HashSet<SocketAddr>,
+        now: Instant,
+    ) -> ConnectionStatus {
+        let entries = lock(&self.entries);
+        let mut candidates: Vec<ReconnectCandidate> = entries
+            .iter()
+            .map(|(&addr, entry)| {
+                let connected = canonical.contains(&addr) || connections.contains(&addr);
+                ReconnectCandidate {
+                    addr,
+                    bootstrap: entry.bootstrap,
+                    connected,
+                    failures: entry.failures,
+                    retry_in: (!connected)
+                        .then(|| entry.next_attempt.saturating_duration_since(now)),
+                }
+            })
+            .collect();
+        candidates.sort_by_key(|candidate| (!candidate.bootstrap, candidate.addr));
+        ConnectionStatus {
+            connected: connections.len(),
+            target: self.config.target_peers,
+            candidates,
+        }
+    }
+
+    fn bootstrap_count(&self, entries: &HashMap<SocketAddr, Entry>) -> usize {
+        entries.values().filter(|entry| entry.bootstrap).count()
+    }
+
+    /// `wait`, scaled by a random factor within the configured jitter.
+    fn jittered(&self, wait: Duration) -> Duration {
+        if self.config.jitter == 0.0 {
+            return wait;
+        }
+        let jitter = self.config.jitter;
+        wait.mul_f64(rand::rng().random_range(1.0 - jitter..=1.0 + jitter))
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn config() -> ReconnectConfig {
+        ReconnectConfig {
+            target_peers: 2,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(400),
+            jitter: 0.0,
+            max_attempts: 2,
+            ..ReconnectConfig::default()
+        }
+    }
+
+    #[test]
+    fn validate_rejects_bad_values() {
+        assert!(ReconnectConfig::default().validate().is_ok());
+        let bad = [
+            ReconnectConfig {
+                target_peers: 0,
+                ..config()
+            },
+            ReconnectConfig {
+                initial_backoff: Duration::ZERO,
+                ..config()
+            },
+            ReconnectConfig {
+                max_backoff: Duration::from_millis(50),
+                ..config()
+            },
+            ReconnectConfig {
+                multiplier: 0.5,
+                ..config()
+            },
+            ReconnectConfig {
+                jitter: 1.0,
+                ..config()
+            },
+            ReconnectConfig {
+                max_attempts: 0,
+                ..config()
+            },
+        ];
+        for config in bad {
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_the_cap() {
+        let config = config();
+        assert_eq!(config.backoff(1), Duration::from_millis(100));
+        assert_eq!(config.backoff(2), Duration::from_millis(200));
+        assert_eq!(config.backoff(3), Duration::from_millis(400));
+        assert_eq!(config.backoff(4), Duration::from_millis(400));
+        assert_eq!(config.backoff(u32::MAX), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction() {
+        let reconnector = Reconnector::new(
+            ReconnectConfig {
+                jitter: 0.5,
+                ..config()
+            },
+            &[],
+            50,
+        );
+        for _ in 0..100 {
+            let wait = reconnector.jittered(Duration::from_millis(100));
+            assert!(wait >= Duration::from_millis(50) && wait <= Duration::from_millis(150));
+        }
+    }
+
+    #[test]
+    fn bootstrap_peers_are_retried_with_backoff_for_good() {
+        let reconnector = Reconnector::new(config(), &[addr(1)], 50);
+        let start = Instant::now();
+
+        let none = HashSet::new();
+        assert_eq!(reconnector.plan(&none, &none, start), vec![addr(1)]);
+        for failures in 1..=5 {
+            reconnector.record_failure(addr(1), start);
+            let status = reconnector.status(&none, &none, start);
+            assert_eq!(status.candidates[0].failures, failures);
+            assert_eq!(
+                status.candidates[0].retry_in,
+                Some(config().backoff(failures))
+            );
+            assert!(reconnector.plan(&none, &none, start).is_empty());
+        }
+        let later = start + config().max_backoff;
+        assert_eq!(reconnector.plan(&none, &none, later), vec![addr(1)]);
+    }
+
+    #[test]
+    fn lost_peers_are_redialled_then_forgotten() {
+        let reconnector = Reconnector::new(config(), &[], 50);
+        let start = Instant::now();
+
+        let none = HashSet::new();
+        let connected: HashSet<SocketAddr> = [addr(1)].into();
+        assert!(reconnector.plan(&connected, &connected, start).is_empty());
+        let status = reconnector.status(&connected, &connected, start);
+        assert!(status.candidates[0].connected);
+
+        // Lost: redialled after one initial backoff.
+        assert!(reconnector.plan(&none, &none, start).is_empty());
+        let due = start + config().initial_backoff;
+        assert_eq!(reconnector.plan(&none, &none, due), vec![addr(1)]);
+
+        reconnector.record_failure(addr(1), due);
+        reconnector.record_failure(addr(1), due);
+        assert!(reconnector.status(&none, &none, due).candidates.is_empty());
+    }
+
+    #[test]
+    fn dials_only_up_to_the_target_bootstrap_first() {
+        let reconnector = Reconnector::new(config(), &[addr(3)], 50);
+        let start = Instant::now();
+        let connected: HashSet<SocketAddr> = [addr(1), addr(2)].into();
+        reconnector.plan(&connected, &connected, start);
+
+        let due = start + config().initial_backoff;
+        let one: HashSet<SocketAddr> = [addr(2)].into();
+        assert_eq!(
+            reconnector.plan(&one, &one, due),
+            vec![addr(3)],
+            "one short of the target: the bootstrap peer goes first"
+        );
+        assert!(
+            reconnector.plan(&connected, &connected, due).is_empty(),
+            "at the target nothing is dialed"
+        );
+
+        let capped = Reconnector::new(config(), &[], 1);
+        let status = capped.status(&connected, &connected, start);
+        assert_eq!(status.target, 1, "capped at max_peers");
+    }
+
+    #[test]
+    fn discovered_peers_are_dialed_at_once() {
+        let reconnector = Reconnector::new(config(), &[], 50);
+        let start = Instant::now();
+        let none = HashSet::new();
+
+        reconnector.discover(&[addr(1), addr(2), addr(1)], start);
+        let mut due = reconnector.plan(&none, &none, start);
+        due.sort();
+        assert_eq!(due, vec![addr(1), addr(2)]);
+
+        reconnector.record_failure(addr(1), start);
+        reconnector.discover(&[addr(1)], start);
+        assert_eq!(
+            reconnector.status(&none, &none, start).candidates[0].failures,
+            1,
+            "rediscovery keeps the backoff"
+        );
+    }
+
+    #[test]
+    fn goodbye_forgets_all_but_bootstrap_peers() {
+        let reconnector = Reconnector::new(config(), &[addr(1)], 50);
+        let start = Instant::now();
+        let connected: HashSet<SocketAddr> = [addr(1), addr(2)].into();
+        reconnector.plan(&connected, &connected, start);
+
+        reconnector.depart(addr(1), start);
+        reconnector.depart(addr(2), start);
+        let none = HashSet::new();
+        let status = reconnector.status(&none, &none, start);
+        assert_eq!(status.candidates.len(), 1);
+        assert_eq!(status.candidates[0].addr, addr(1));
+        assert!(!status.candidates[0].connected);
+
+        reconnector.record_success(addr(1));
+        let status = reconnector.status(&connected, &connected, start);
+        assert_eq!(status.candidates[0].retry_in, None);
+    }
+}
@@ -0,0 +1,328 @@
+//! Replay protection for control messages.
+//!
+//! Broadcasts carry a per-origin sequence number, so the message store drops a
+//! copy it has seen. Everything else a node sends (heartbeats, pings, peer
+//! lists, digests, goodbyes, direct messages) is authored at sequence 0, and
+//! without more to go on a captured frame could be sent again forever: a
+//! replayed `Goodbye`, say, would make its recipient drop a live peer.
+//!
+//! A control message is therefore accepted only if its signed hybrid logical
+//! clock stamp lies within [`ReplayConfig::window`] of the local wall clock,
+//! either way, and has not been seen from the same key before. A node's clock
+//! never issues the same stamp twice, so an honest message is never mistaken
+//! for a replay, and stamps older than the window need not be remembered:
+//! anything that old is rejected as stale.
+//!
+//! Stamps are not needed to stop a frame being replayed to another node or on
+//! another connection, which is all a peer can do with a frame it captured:
+//! each connection is bound to the key its peer proved at handshake (see
+//! [`handshake`](crate::transport::handshake)), and a control message is
+//! accepted only if that key signed it. A control message signed by a key
+//! proven on some other connection, arriving over one that proved none, was
+//! passed on by someone else and is rejected too. So replays are caught with
+//! the default configuration, with or without stamps.
+//!
+//! Messages without a stamp over connections that proved no key, from `1.1.0`
+//! nodes, cannot be checked; they are accepted unless
+//! [`ReplayConfig::require_stamped`] is set.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{HlcTimestamp, Message, PeerId};
+
+/// Replay protection configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayConfig {
+    /// How far a control message's stamp may be from the local wall clock,
+    /// in the past or the future
+    pub window: Duration,
+
+    /// Reject control messages without a signed clock stamp, as sent by
+    /// `1.1.0` nodes and nodes with `clock.signed` off
+    pub require_stamped: bool,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(5 * 60),
+            require_stamped: false,
+        }
+    }
+}
+
+impl ReplayConfig {
+    /// Validate the configuration.
+    ///
+    /// # Errors
+    /// Returns a description of the first invalid field.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.window.is_zero() {
+            return Err("replay window must be > 0".into());
+        }
+        Ok(())
+    }
+}
+
+/// Why a control message was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rejection {
+    /// It carries no stamp, and stamps are required
+    Unstamped,
+
+    /// Its stamp is further from the local clock than the window allows
+    Stale,
+
+    /// It was seen before
+    Replayed,
+
+    /// The connection it arrived on proved a key other than the one that
+    /// signed it
+    NotSender,
+
+    /// It arrived on a connection that proved no key, signed by a key proven
+    /// on another
+    PassedOn,
+}
+
+/// What is known of the key at the other end of the connection a message
+/// arrived on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Connection {
+    /// The peer proved this key at handshake.
+    Proven(PeerId),
+
+    /// The peer proved no key; `key_proven` says whether the message's key
+    /// was proven on another connection.
+    Unproven { key_proven: bool },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Unstamped => "no clock stamp",
+            Self::Stale => "stamp outside the replay window",
+            Self::Replayed => "already seen",
+            Self::NotSender => "not signed by the key the connection proved",
+            Self::PassedOn => "signed by a key proven on another connection",
+        })
+    }
+}
+
+/// The stamps of the control messages accepted within the window.
+#[derive(Debug)]
+pub(crate) struct ReplayGuard {
+    config: ReplayConfig,
+
+    /// Stamp and signing key of each control message accepted, oldest first
+    seen: Mutex<BTreeSet<(HlcTimestamp, [u8; 32])>>,
+}
+
+impl ReplayGuard {
+    pub(crate) fn new(config: ReplayConfig) -> Self {
+        Self {
+            config,
+            seen: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Check an authenticated `message` that arrived on `connection` at
+    /// wall-clock time `now_ms`, remembering it if it is a control message
+    /// that is accepted.
+    /// Broadcasts always pass: the message store deduplicates them.
+    pub(crate) fn check(
+        &self,
+        message: &Message,
+        connection: Connection,
+        now_ms: u64,
+    ) -> Result<(), Rejection> {
+        if message.payload.is_broadcast() {
+            return Ok(());
+        }
+        match connection {
+            Connection::Proven(key) if key != message.origin_key => {
+                return Err(Rejection::NotSender);
+            }
+            Connection::Unproven { key_proven: true } => return Err(Rejection::PassedOn),
+            _ => {}
+        }
+        let Some(stamp) = message.id.hlc() else {
+            return if self.config.require_stamped {
+                Err(Rejection::Unstamped)
+            } else {
+                Ok(())
+            };
+        };
+
+        let window = u64::try_from(self.config.window.as_millis()).unwrap_or(u64::MAX);
+        let oldest = now_ms.saturating_sub(window);
+        if stamp.physical < oldest || stamp.physical > now_ms.saturating_add(window) {
+            return Err(Rejection::Stale);
+        }
+
+        let mut seen = lock(&self.seen);
+        let cutoff = HlcTimestamp {
+            physical: oldest,
+            logical: 0,
+        };
+        *seen = seen.split_off(&(cutoff, [0; 32]));
+        if seen.insert((stamp, message.origin_key.0)) {
+            Ok(())
+        } else {
+            Err(Rejection::Replayed)
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{MessageId, Payload, PeerId};
+
+    const NOW: u64 = 1_800_000_000_000;
+
+    const UNPROVEN: Connection = Connection::Unproven { key_proven: false };
+
+    fn stamped(physical: u64, logical: u16, key: u8, payload: Payload) -> Message {
+        let mut message = Message::new("127.0.0.1:7946".parse().unwrap(), 0, payload);
+        message.id = MessageId::stamped(message.id.origin, 0, HlcTimestamp { physical, logical });
+        message.origin_key = PeerId([key; 32]);
+        message
+    }
+
+    fn goodbye(physical: u64, logical: u16, key: u8) -> Message {
+        let reason = "leaving".to_string();
+        stamped(physical, logical, key, Payload::Goodbye { reason })
+    }
+
+    #[test]
+    fn rejects_a_control_message_seen_before() {
+        let guard = ReplayGuard::new(ReplayConfig::default());
+
+        assert_eq!(guard.check(&goodbye(NOW, 0, 1), UNPROVEN, NOW), Ok(()));
+        assert_eq!(
+            guard.check(&goodbye(NOW, 0, 1), UNPROVEN, NOW + 10),
+            Err(Rejection::Replayed)
+        );
+        // The next stamp from the same key, and the same stamp from another,
+        // are new.
+        assert_eq!(guard.check(&goodbye(NOW, 1, 1), UNPROVEN, NOW), Ok(()));
+        assert_eq!(guard.check(&goodbye(NOW, 0, 2), UNPROVEN, NOW), Ok(()));
+    }
+
+    #[test]
+    fn rejects_stamps_outside_the_window() {
+        let guard = ReplayGuard::new(ReplayConfig::default());
+        let window = 5 * 60 * 1000;
+
+        assert_eq!(
+            guard.check(&goodbye(NOW - window - 1, 0, 1), UNPROVEN, NOW),
+            Err(Rejection::Stale)
+        );
+        assert_eq!(
+            guard.check(&goodbye(NOW + window + 1, 0, 1), UNPROVEN, NOW),
+            Err(Rejection::Stale)
+        );
+        assert_eq!(
+            guard.check(&goodbye(NOW - window, 0, 1), UNPROVEN, NOW),
+            Ok(())
+        );
+        assert_eq!(
+            guard.check(&goodbye(NOW + window, 0, 1), UNPROVEN, NOW),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn forgets_stamps_once_they_leave_the_window() {
+        let guard = ReplayGuard::new(ReplayConfig::default());
+        let window = 5 * 60 * 1000;
+
+        for logical in 0..10 {
+            guard
+                .check(&goodbye(NOW, logical, 1), UNPROVEN, NOW)
+                .unwrap();
+        }
+        guard
+            .check(&goodbye(NOW + window + 1, 0, 1), UNPROVEN, NOW + window + 1)
+            .unwrap();
+        assert_eq!(lock(&guard.seen).len(), 1);
+    }
+
+    #[test]
+    fn unstamped_messages_pass_unless_stamps_are_required() {
+        let unstamped = Message::new(
+            "127.0.0.1:7946".parse().unwrap(),
+            0,
+            Payload::PeerListRequest,
+        );
+        let lenient = ReplayGuard::new(ReplayConfig::default());
+        assert_eq!(lenient.check(&unstamped, UNPROVEN, NOW), Ok(()));
+        assert_eq!(lenient.check(&unstamped, UNPROVEN, NOW), Ok(()));
+
+        let strict = ReplayGuard::new(ReplayConfig {
+            require_stamped: true,
+            ..ReplayConfig::default()
+        });
+        assert_eq!(
+            strict.check(&unstamped, UNPROVEN, NOW),
+            Err(Rejection::Unstamped)
+        );
+    }
+
+    #[test]
+    fn control_messages_must_come_from_the_key_the_connection_proved() {
+        let guard = ReplayGuard::new(ReplayConfig::default());
+        let unstamped = |key: u8| {
+            let mut message = Message::new(
+                "127.0.0.1:7946".parse().unwrap(),
+                0,
+                Payload::Goodbye {
+                    reason: "leaving".to_string(),
+                },
+            );
+            message.origin_key = PeerId([key; 32]);
+            message
+        };
+
+        let proven = Connection::Proven(PeerId([1; 32]));
+        assert_eq!(guard.check(&unstamped(1), proven, NOW), Ok(()));
+        assert_eq!(
+            guard.check(&unstamped(2), proven, NOW),
+            Err(Rejection::NotSender)
+        );
+        assert_eq!(
+            guard.check(&goodbye(NOW, 0, 2), proven, NOW),
+            Err(Rejection::NotSender)
+        );
+        assert_eq!(
+            guard.check(
+                &unstamped(1),
+                Connection::Unproven { key_proven: true },
+                NOW
+            ),
+            Err(Rejection::PassedOn)
+        );
+    }
+
+    #[test]
+    fn broadcasts_are_left_to_the_message_store() {
+        let guard = ReplayGuard::new(ReplayConfig::default());
+        let broadcast = stamped(0, 0, 1, Payload::Application(Bytes::from_static(b"x")));
+        let relay = Connection::Proven(PeerId([2; 32]));
+
+        assert_eq!(guard.check(&broadcast, UNPROVEN, NOW), Ok(()));
+        assert_eq!(guard.check(&broadcast, relay, NOW), Ok(()));
+    }
+}
@@ -0,0 +1,649 @@
+//! Snapshot state transfer for joining nodes.
+//!
+//! A node that joins through bootstrap peers would otherwise learn the
+//! cluster's history from anti-entropy alone: every peer it reconciles with
+//! pushes everything it lacks, so a large history arrives several times over,
+//! from several peers at once. Instead, the joiner picks one peer and asks it
+//! for a snapshot of its retained messages, and starts anti-entropy only once
+//! the transfer is over.
+//!
+//! - **Consistency.** The serving peer captures the snapshot in one step when
+//!   the request arrives, then streams it in `(origin, sequence)` order, in
+//!   chunks that each fit a frame. Messages that arrive later reach the joiner
+//!   by gossip or by anti-entropy afterwards.
+//! - **Flow control.** The request names a window: the peer sends that many
+//!   chunks, then one more for each chunk the joiner acknowledges once it has
+//!   applied it.
+//! - **Checkpoints.** The joiner remembers the last message it applied. If no
+//!   chunk arrives for [`SnapshotConfig::timeout`], it asks another peer (or
+//!   the same one, if it knows no other) to resume after that checkpoint, in a
+//!   new session. After [`SnapshotConfig::max_attempts`] unanswered requests
+//!   in a row, or if no connected peer serves snapshots, it gives up and
+//!   relies on anti-entropy.
+//!
+//! While the transfer runs, the joiner ignores anti-entropy digests from its
+//! peers, so they do not push the same history alongside the snapshot.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use rand::seq::IndexedRandom;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::protocol::anti_entropy::{batch_within_frame, retain_decodable};
+use crate::{Message, MessageStore, Payload, PeerProtocol};
+
+/// Largest window a serving peer honours; larger requests are clamped to it.
+const MAX_WINDOW: u32 = 64;
+
+/// Snapshot state transfer configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotConfig {
+    /// Fetch a snapshot from one peer when joining through bootstrap peers
+    pub enabled: bool,
+
+    /// Chunks a serving peer may send ahead of the joiner's acknowledgements
+    pub window: u32,
+
+    /// How long the joiner waits for a chunk before resuming from its
+    /// checkpoint, and how long a serving peer keeps an idle session
+    pub timeout: Duration,
+
+    /// Requests in a row that may go unanswered before the joiner gives up
+    /// and relies on anti-entropy
+    pub max_attempts: u32,
+
+    /// Joiners a node serves snapshots to at once; further requests are
+    /// ignored, so those joiners time out and ask another peer
+    pub max_sessions: usize,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: 4,
+            timeout: Duration::from_secs(5),
+            max_attempts: 3,
+            max_sessions: 4,
+        }
+    }
+}
+
+impl SnapshotConfig {
+    /// Validate configuration.
+    ///
+    /// # Errors
+    /// Returns an error message if configuration is invalid.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_WINDOW).contains(&self.window) {
+            return Err(format!(
+                "snapshot window must be between 1 and {MAX_WINDOW}"
+            ));
+        }
+        if self.timeout.is_zero() {
+            return Err("snapshot timeout must be greater than 0".to_string());
+        }
+        if self.max_attempts == 0 {
+            return Err("snapshot max_attempts must be greater than 0".to_string());
+        }
+        if self.max_sessions == 0 {
+            return Err("snapshot max_sessions must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Where a node's own snapshot transfer stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotState {
+    /// No transfer: snapshots are disabled, or the node had no bootstrap
+    /// peers to join through.
+    Skipped,
+
+    /// Waiting for a connected peer that serves snapshots.
+    Waiting,
+
+    /// Receiving a snapshot.
+    Transferring,
+
+    /// The whole snapshot was received.
+    Complete,
+
+    /// The transfer was given up; anti-entropy fetches the history instead.
+    Abandoned,
+}
+
+/// Progress of a node's own snapshot transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotProgress {
+    /// Where the transfer stands.
+    pub state: SnapshotState,
+
+    /// The peer currently asked for the snapshot, if any.
+    pub peer: Option<SocketAddr>,
+
+    /// Messages received in snapshot chunks, across every session.
+    pub received: u64,
+
+    /// The `(origin, sequence)` of the last message applied, from which an
+    /// interrupted transfer resumes.
+    pub checkpoint: Option<(SocketAddr, u64)>,
+}
+
+/// Both sides of snapshot state transfer: this node's own transfer as a
+/// joiner, and the sessions it serves to joiners.
+#[derive(Debug)]
+pub(crate) struct StateTransfer {
+    config: SnapshotConfig,
+    joiner: Mutex<Joiner>,
+    sessions: Mutex<HashMap<SocketAddr, Session>>,
+}
+
+#[derive(Debug)]
+struct Joiner {
+    state: SnapshotState,
+    session: u64,
+    peer: Option<SocketAddr>,
+    checkpoint: Option<(SocketAddr, u64)>,
+    received: u64,
+
+    /// Requests sent since the last chunk arrived.
+    attempts: u32,
+
+    /// When the last chunk arrived or request was sent, or waiting began.
+    since: Instant,
+}
+
+/// A snapshot being served to one joiner.
+#[derive(Debug)]
+struct Session {
+    id: u64,
+    chunks: VecDeque<Vec<Message>>,
+    finished: bool,
+    last_active: Instant,
+}
+
+impl Session {
+    /// The next chunk to send, or `None` once the last has been sent. An
+    /// empty snapshot is sent as one empty, final chunk.
+    fn next_chunk(&mut self) -> Option<Payload> {
+        if self.finished {
+            return None;
+        }
+        let messages = self.chunks.pop_front().unwrap_or_default();
+        self.finished = self.chunks.is_empty();
+        Some(Payload::SnapshotChunk {
+            session: self.id,
+            messages,
+            done: self.finished,
+        })
+    }
+}
+
+impl StateTransfer {
+    /// Transfer state for a node that is `joining` through bootstrap peers,
+    /// or not.
+    pub(crate) fn new(config: SnapshotConfig, joining: bool) -> Self {
+        let state = if config.enabled && joining {
+            SnapshotState::Waiting
+        } else {
+            SnapshotState::Skipped
+        };
+        Self {
+            config,
+            joiner: Mutex::new(Joiner {
+                state,
+                session: 0,
+                peer: None,
+                checkpoint: None,
+                received: 0,
+                attempts: 0,
+                since: Instant::now(),
+            }),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether this node's own transfer has yet to finish, one way or the
+    /// other. Anti-entropy waits until it has.
+    pub(crate) fn is_transferring(&self) -> bool {
+        matches!(
+            lock(&self.joiner).state,
+            SnapshotState::Waiting | SnapshotState::Transferring
+        )
+    }
+
+    pub(crate) fn progress(&self) -> SnapshotProgress {
+        let joiner = lock(&self.joiner);
+        SnapshotProgress {
+            state: joiner.state,
+            peer: joiner.peer,
+            received: joiner.received,
+            checkpoint: joiner.checkpoint,
+        }
+    }
+
+    /// The request to send next, and to whom, if the transfer has not started
+    /// or has stalled. `candidates` are the connected peers that serve
+    /// snapshots; one of `preferred` among them is picked if possible.
+    pub(crate) fn next_request(
+        &self,
+        candidates: &[SocketAddr],
+        preferred: &[SocketAddr],
+    ) -> Option<(SocketAddr, Payload)> {
+        let mut joiner = lock(&self.joiner);
+        let stalled = joiner.since.elapsed() >= self.config.timeout;
+        match joiner.state {
+            SnapshotState::Waiting if candidates.is_empty() => {
+                if stalled {
+                    warn!("No connected peer serves snapshots, relying on anti-entropy");
+                    joiner.state = SnapshotState::Abandoned;
+                }
+                return None;
+            }
+            SnapshotState::Waiting => {}
+            SnapshotState::Transferring if !stalled => return None,
+            SnapshotState::Transferring => {
+                if joiner.attempts >= self.config.max_attempts {
+                    warn!(
+                        "Snapshot transfer stalled {} times, relying on anti-entropy",
+                        joiner.attempts
+                    );
+                    joiner.state = SnapshotState::Abandoned;
+                    return None;
+                }
+                if candidates.is_empty() {
+                    joiner.attempts += 1;
+                    joiner.since = Instant::now();
+                    return None;
+                }
+            }
+            SnapshotState::Skipped | SnapshotState::Complete | SnapshotState::Abandoned => {
+                return None;
+            }
+        }
+
+        // Avoid a peer that stalled, then prefer a bootstrap peer.
+        let narrow = |peers: Vec<SocketAddr>, keep: &dyn Fn(SocketAddr) -> bool| {
+            let kept: Vec<SocketAddr> = peers.iter().copied().filter(|&peer| keep(peer)).collect();
+            if kept.is_empty() { peers } else { kept }
+        };
+        let stalled_peer = joiner.peer;
+        let pool = narrow(candidates.to_vec(), &|peer| Some(peer) != stalled_peer);
+        let pool = narrow(pool, &|peer| preferred.contains(&peer));
+        let peer = *pool.choose(&mut rand::rng())?;
+
+        joiner.state = SnapshotState::Transferring;
+        joiner.session = rand::random();
+        joiner.peer = Some(peer);
+        joiner.attempts += 1;
+        joiner.since = Instant::now();
+        match joiner.checkpoint {
+            Some((origin, sequence)) => {
+                debug!("Resuming snapshot from {peer} after {origin}#{sequence}");
+            }
+            None => debug!("Requesting snapshot from {peer}"),
+        }
+        Some((
+            peer,
+            Payload::SnapshotRequest {
+                session: joiner.session,
+                after: joiner.checkpoint,
+                window: self.config.window,
+            },
+        ))
+    }
+
+    /// Record a chunk of this node's own transfer from `peer`. Returns `false`
+    /// if it belongs to no current session, in which case it must be ignored;
+    /// otherwise the caller applies the messages and, unless `done`,
+    /// acknowledges the chunk.
+    pub(crate) fn accept_chunk(
+        &self,
+        peer: SocketAddr,
+        session: u64,
+        messages: &[Message],
+        done: bool,
+    ) -> bool {
+        let mut joiner = lock(&self.joiner);
+        if joiner.state != SnapshotState::Transferring
+            || joiner.session != session
+            || joiner.peer != Some(peer)
+        {
+            return false;
+        }
+        if let Some(last) = messages.last() {
+            joiner.checkpoint = Some((last.id.origin, last.id.sequence));
+        }
+        joiner.received += u64::try_from(messages.len()).unwrap_or(u64::MAX);
+        joiner.attempts = 0;
+        joiner.since = Instant::now();
+        if done {
+            info!(
+                "Snapshot transfer from {peer} complete: {} messages",
+                joiner.received
+            );
+            joiner.state = SnapshotState::Complete;
+        }
+        true
+    }
+
+    /// Begin serving the snapshot `capture` takes, as chunks (see
+    /// [`capture`]), to `peer`, replacing any session it already had, and
+    /// return the chunks to send straight away. Returns nothing, without
+    /// taking the snapshot, if this node already serves as many joiners as it
+    /// may.
+    pub(crate) fn serve(
+        &self,
+        peer: SocketAddr,
+        session: u64,
+        window: u32,
+        capture: impl FnOnce() -> Vec<Vec<Message>>,
+    ) -> Vec<Payload> {
+        let mut sessions = lock(&self.sessions);
+        sessions.retain(|_, session| session.last_active.elapsed() < self.config.timeout);
+        if !sessions.contains_key(&peer) && sessions.len() >= self.config.max_sessions {
+            debug!(
+                "Already serving {} snapshots, ignoring {peer}",
+                sessions.len()
+            );
+            return Vec::new();
+        }
+
+        let chunks = capture();
+        debug!("Serving a snapshot of {} chunks to {peer}", chunks.len());
+        let mut served = Session {
+            id: session,
+            chunks: chunks.into(),
+            finished: false,
+            last_active: Instant::now(),
+        };
+        let chunks = (0..window.clamp(1, MAX_WINDOW))
+            .map_while(|_| served.next_chunk())
+            .collect();
+        if served.finished {
+            sessions.remove(&peer);
+        } else {
+            sessions.insert(peer, served);
+        }
+        chunks
+    }
+
+    /// `peer` applied a chunk of `session`: return the next chunk to send, if
+    /// any remain.
+    pub(crate) fn acknowledge(&self, peer: SocketAddr, session: u64) -> Option<Payload> {
+        let mut sessions = lock(&self.sessions);
+        let served = sessions
+            .get_mut(&peer)
+            .filter(|served| served.id == session)?;
+        served.last_active = Instant::now();
+        let chunk = served.next_chunk();
+        if served.finished {
+            sessions.remove(&peer);
+        }
+        chunk
+    }
+}
+
+/// Take a snapshot of the unexpired messages `store` retains, ordered after
+/// `after`, in `(origin, sequence)` order, and split it into chunks that each
+/// fit within `max_frame_size` when sent from `local_addr`. Messages a joiner
+/// speaking `protocol` cannot decode are left out.
+pub(crate) fn capture(
+    store: &MessageStore,
+    after: Option<(SocketAddr, u64)>,
+    protocol: PeerProtocol,
+    local_addr: SocketAddr,
+    max_frame_size: usize,
+) -> Vec<Vec<Message>> {
+    let mut snapshot =
+        store.retained_entries(|id| after.is_none_or(|after| (id.origin, id.sequence) > after));
+    let withheld = retain_decodable(&mut snapshot, protocol);
+    if withheld > 0 {
+        debug!("Leaving {withheld} messages the joiner cannot decode out of its snapshot");
+    }
+    snapshot.sort_by_key(|entry| (entry.message.id.origin, entry.message.id.sequence));
+    let envelope = Payload::SnapshotChunk {
+        session: u64::MAX,
+        messages: Vec::new(),
+        done: true,
+    };
+    batch_within_frame(local_addr, snapshot, max_frame_size, envelope)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::MessageStoreConfig;
+
+    const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn message(origin: SocketAddr, sequence: u64, size: usize) -> Message {
+        Message::new(
+            origin,
+            sequence,
+            Payload::Application(Bytes::from(vec![0u8; size])),
+        )
+    }
+
+    fn store(messages: impl IntoIterator<Item = Message>) -> MessageStore {
+        let store = MessageStore::new(MessageStoreConfig::default());
+        for message in messages {
+            store.insert(message);
+        }
+        store
+    }
+
+    fn config(timeout: Duration) -> SnapshotConfig {
+        SnapshotConfig {
+            timeout,
+            max_attempts: 2,
+            max_sessions: 1,
+            ..SnapshotConfig::default()
+        }
+    }
+
+    fn serve(
+        transfer: &StateTransfer,
+        peer: SocketAddr,
+        session: u64,
+        after: Option<(SocketAddr, u64)>,
+        window: u32,
+        store: &MessageStore,
+        max_frame_size: usize,
+    ) -> Vec<Payload> {
+        transfer.serve(peer, session, window, || {
+            capture(store, after, PeerProtocol::LOCAL, addr(0), max_frame_size)
+        })
+    }
+
+    fn chunk(payload: &Payload) -> (u64, Vec<Message>, bool) {
+        match payload {
+            Payload::SnapshotChunk {
+                session,
+                messages,
+                done,
+            } => (*session, messages.clone(), *done),
+            other => panic!("expected a snapshot chunk, got {other:?}"),
+        }
+    }
+
+    fn ids(messages: &[Message]) -> Vec<(SocketAddr, u64)> {
+        messages
+            .iter()
+            .map(|message| (message.id.origin, message.id.sequence))
+            .collect()
+    }
+
+    #[test]
+    fn snapshot_is_ordered_and_starts_after_the_checkpoint() {
+        let (a, b) = (addr(1), addr(2));
+        let store = store([message(b, 0, 8), message(a, 1, 8), message(a, 0, 8)]);
+        let transfer = StateTransfer::new(SnapshotConfig::default(), false);
+
+        let chunks = serve(&transfer, addr(9), 7, None, 4, &store, MAX_FRAME_SIZE);
+        let (session, messages, done) = chunk(&chunks[0]);
+        assert_eq!((session, done, chunks.len()), (7, true, 1));
+        assert_eq!(ids(&messages), vec![(a, 0), (a, 1), (b, 0)]);
+
+        let chunks = serve(
+            &transfer,
+            addr(9),
+            8,
+            Some((a, 0)),
+            4,
+            &store,
+            MAX_FRAME_SIZE,
+        );
+        assert_eq!(ids(&chunk(&chunks[0]).1), vec![(a, 1), (b, 0)]);
+    }
+
+    #[test]
+    fn chunks_beyond_the_window_wait_for_acknowledgements() {
+        let origin = addr(1);
+        let store = store((0..12).map(|sequence| message(origin, sequence, 400)));
+        let transfer = StateTransfer::new(SnapshotConfig::default(), false);
+        let joiner = addr(9);
+
+        let mut received = Vec::new();
+        let first = serve(&transfer, joiner, 1, None, 2, &store, 2048);
+        assert_eq!(first.len(), 2, "only the window is sent up front");
+        for payload in &first {
+            let (_, messages, done) = chunk(payload);
+            assert!(!done);
+            received.extend(messages);
+        }
+
+        assert!(transfer.acknowledge(joiner, 2).is_none(), "wrong session");
+        while let Some(payload) = transfer.acknowledge(joiner, 1) {
+            received.extend(chunk(&payload).1);
+        }
+        assert_eq!(
+            ids(&received),
+            (0..12)
+                .map(|sequence| (origin, sequence))
+                .collect::<Vec<_>>()
+        );
+        assert!(
+            transfer.acknowledge(joiner, 1).is_none(),
+            "session finished"
+        );
+    }
+
+    #[test]
+    fn an_empty_snapshot_is_one_final_chunk() {
+        let transfer = StateTransfer::new(SnapshotConfig::default(), false);
+        let chunks = serve(&transfer, addr(9), 3, None, 4, &store([]), MAX_FRAME_SIZE);
+        assert_eq!(chunks.len(), 1);
+        let (session, messages, done) = chunk(&chunks[0]);
+        assert_eq!((session, messages.len(), done), (3, 0, true));
+    }
+
+    #[test]
+    fn joiners_beyond_the_session_cap_are_ignored() {
+        let store = store((0..12).map(|sequence| message(addr(1), sequence, 400)));
+        let transfer = StateTransfer::new(config(Duration::from_secs(60)), false);
+
+        assert!(!serve(&transfer, addr(8), 1, None, 1, &store, 2048).is_empty());
+        assert!(serve(&transfer, addr(9), 1, None, 1, &store, 2048).is_empty());
+        assert!(!serve(&transfer, addr(8), 2, None, 1, &store, 2048).is_empty());
+    }
+
+    #[test]
+    fn a_stalled_transfer_resumes_from_its_checkpoint_elsewhere() {
+        let transfer = StateTransfer::new(config(Duration::from_millis(1)), true);
+        let (first, second) = (addr(1), addr(2));
+
+        let (peer, request) = transfer.next_request(&[first, second], &[first]).unwrap();
+        assert_eq!(peer, first, "a bootstrap peer is preferred");
+        let Payload::SnapshotRequest { session, after, .. } = request else {
+            panic!("expected a snapshot request");
+        };
+        assert_eq!(after, None);
+
+        let messages = [message(addr(5), 0, 8), message(addr(5), 1, 8)];
+        assert!(!transfer.accept_chunk(second, session, &messages, false));
+        assert!(transfer.accept_chunk(first, session, &messages, false));
+
+        std::thread::sleep(Duration::from_millis(5));
+        let (peer, request) = transfer.next_request(&[first, second], &[first]).unwrap();
+        assert_eq!(peer, second, "the stalled peer is avoided");
+        let Payload::SnapshotRequest { after, .. } = request else {
+            panic!("expected a snapshot request");
+        };
+        assert_eq!(after, Some((addr(5), 1)));
+        assert_eq!(transfer.progress().received, 2);
+    }
+
+    #[test]
+    fn repeated_stalls_abandon_the_transfer() {
+        let transfer = StateTransfer::new(config(Duration::from_millis(1)), true);
+        for _ in 0..2 {
+            assert!(transfer.next_request(&[addr(1)], &[]).is_some());
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(transfer.next_request(&[addr(1)], &[]).is_none());
+        assert_eq!(transfer.progress().state, SnapshotState::Abandoned);
+        assert!(!transfer.is_transferring());
+    }
+
+    #[test]
+    fn the_final_chunk_completes_the_transfer() {
+        let transfer = StateTransfer::new(SnapshotConfig::default(), true);
+        assert!(transfer.is_transferring());
+        let (peer, request) = transfer.next_request(&[addr(1)], &[]).unwrap();
+        let Payload::SnapshotRequest { session, .. } = request else {
+            panic!("expected a snapshot request");
+        };
+
+        assert!(transfer.accept_chunk(peer, session, &[], true));
+        assert_eq!(transfer.progress().state, SnapshotState::Complete);
+        assert!(transfer.next_request(&[addr(1)], &[]).is_none());
+    }
+
+    #[test]
+    fn nodes_without_bootstrap_peers_skip_the_transfer() {
+        let transfer = StateTransfer::new(SnapshotConfig::default(), false);
+        assert_eq!(transfer.progress().state, SnapshotState::Skipped);
+        assert!(!transfer.is_transferring());
+    }
+
+    #[test]
+    fn validate_rejects_bad_values() {
+        assert!(SnapshotConfig::default().validate().is_ok());
+        for config in [
+            SnapshotConfig {
+                window: 0,
+                ..SnapshotConfig::default()
+            },
+            SnapshotConfig {
+                window: MAX_WINDOW + 1,
+                ..SnapshotConfig::default()
+            },
+            SnapshotConfig {
+                timeout: Duration::ZERO,
+                ..SnapshotConfig::default()
+            },
+            SnapshotConfig {
+                max_attempts: 0,
+                ..SnapshotConfig::default()
+            },
+        ] {
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+    }
+}
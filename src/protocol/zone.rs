@@ -0,0 +1,357 @@
+//! Zone-aware topology.
+//!
+//! A node may declare the zone it runs in (an availability zone, a rack, a
+//! datacenter: any label its operator chooses) through
+//! `NodeConfig::zone` ([`ZoneConfig`]). Labels spread by peer exchange in
+//! [`Payload::PeerZones`]: a node sends its own label, and those it has heard
+//! for its peers, in answer to a peer list request and once to every other
+//! connected peer that speaks protocol version 3.
+//!
+//! A labelled node prefers peers in its own zone. Each fanout and each
+//! anti-entropy round sends to [`ZoneConfig::cross_zone_fanout`] peers outside
+//! the zone, or whose zone is unknown, and fills the rest from inside it; when
+//! the zone holds too few peers the remainder comes from outside after all.
+//! Within each side the configured [`PeerSelector`] chooses. Cross-zone traffic
+//! is thereby bounded per round, while the guaranteed cross-zone links keep
+//! every zone reachable, so a broadcast still reaches the whole cluster.
+//!
+//! [`Payload::PeerZones`]: crate::Payload::PeerZones
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use dashmap::{DashMap, DashSet};
+use serde::{Deserialize, Serialize};
+
+use crate::{Payload, PeerInfo, PeerSelector};
+
+/// Longest zone label accepted, in bytes.
+pub const MAX_ZONE_LEN: usize = 64;
+
+/// Most labels remembered for other nodes, bounding what peer exchange can
+/// make a node store.
+const MAX_KNOWN_ZONES: usize = 4_096;
+
+/// Zone-aware topology configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZoneConfig {
+    /// This node's zone label; `None` turns zone awareness off
+    pub label: Option<String>,
+
+    /// Peers outside the zone each fanout and anti-entropy round sends to,
+    /// when there are that many
+    pub cross_zone_fanout: usize,
+}
+
+impl Default for ZoneConfig {
+    fn default() -> Self {
+        Self {
+            label: None,
+            cross_zone_fanout: 1,
+        }
+    }
+}
+
+impl ZoneConfig {
+    /// Validate the configuration.
+    ///
+    /// # Errors
+    /// Returns a description of the first invalid field.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(label) = &self.label
+            && !is_valid_label(label)
+        {
+            return Err(format!("zone label must be 1 to {MAX_ZONE_LEN} bytes long"));
+        }
+        if self.cross_zone_fanout == 0 {
+            return Err("zone cross_zone_fanout must be > 0".into());
+        }
+        Ok(())
+    }
+}
+
+fn is_valid_label(label: &str) -> bool {
+    !label.is_empty() && label.len() <= MAX_ZONE_LEN
+}
+
+/// This node's zone, and the zones it has heard of.
+#[derive(Debug)]
+pub(crate) struct Zones {
+    local: Option<String>,
+
+    /// Labels heard of, by canonical address
+    known: DashMap<SocketAddr, String>,
+
+    /// Connections already sent this node's zone table
+    announced: DashSet<SocketAddr>,
+}
+
+impl Zones {
+    pub(crate) fn new(config: &ZoneConfig) -> Self {
+        Self {
+            local: config.label.clone(),
+            known: DashMap::new(),
+            announced: DashSet::new(),
+        }
+    }
+
+    /// Record the labels in a [`Payload::PeerZones`] from `sender`, returning
+    /// the label `sender` gave for itself, if any. Invalid labels, and any
+    /// claimed for this node, are ignored.
+    pub(crate) fn learn(
+        &self,
+        sender: SocketAddr,
+        zones: &[(SocketAddr, String)],
+        local_addr: SocketAddr,
+    ) -> Option<String> {
+        let mut own = None;
+        for (addr, zone) in zones {
+            if *addr == local_addr || !is_valid_label(zone) {
+                continue;
+            }
+            if *addr == sender {
+                own = Some(zone.clone());
+            } else if self.known.len() >= MAX_KNOWN_ZONES && !self.known.contains_key(addr) {
+                continue;
+            }
+            self.known.insert(*addr, zone.clone());
+        }
+        own
+    }
+
+    /// The label heard for the node at canonical address `addr`.
+    pub(crate) fn zone_of(&self, addr: SocketAddr) -> Option<String> {
+        self.known.get(&addr).map(|zone| zone.value().clone())
+    }
+
+    /// This node's label and those heard for `peers`, as a
+    /// [`Payload::PeerZones`]; `None` if there are none to send.
+    pub(crate) fn table(
+        &self,
+        local_addr: SocketAddr,
+        peers: impl IntoIterator<Item = SocketAddr>,
+    ) -> Option<Payload> {
+        let mut zones: Vec<(SocketAddr, String)> = self
+            .local
+            .iter()
+            .map(|zone| (local_addr, zone.clone()))
+            .collect();
+        zones.extend(
+            peers
+                .into_iter()
+                .filter_map(|peer| self.zone_of(peer).map(|zone| (peer, zone))),
+        );
+        (!zones.is_empty()).then_some(Payload::PeerZones { zones })
+    }
+
+    /// Note that the connection `peer` is being sent the zone table,
+    /// returning whether it had not been already.
+    pub(crate) fn announce(&self, peer: SocketAddr) -> bool {
+        self.announced.insert(peer)
+    }
+
+    /// Forget the announcements to connections no longer in `live`.
+    pub(crate) fn retain(&self, live: &HashSet<SocketAddr>) {
+        self.announced.retain(|peer| live.contains(peer));
+    }
+}
+
+/// Make `inner` zone-aware if `config` gives this node a zone.
+pub(crate) fn zone_aware(
+    config: &ZoneConfig,
+    inner: &'static dyn PeerSelector,
+) -> Arc<dyn PeerSelector> {
+    match &config.label {
+        Some(label) => Arc::new(Zoned {
+            local: label.clone(),
+            cross_zone: config.cross_zone_fanout,
+            inner,
+        }),
+        None => Arc::new(Unzoned(inner)),
+    }
+}
+
+/// A selector that ignores zones.
+struct Unzoned(&'static dyn PeerSelector);
+
+impl PeerSelector for Unzoned {
+    fn select(&self, candidates: &[PeerInfo], count: usize) -> Vec<SocketAddr> {
+        self.0.select(candidates, count)
+    }
+}
+
+/// A selector preferring peers in the local zone, with a fixed number of
+/// cross-zone picks.
+struct Zoned {
+    local: String,
+    cross_zone: usize,
+    inner: &'static dyn PeerSelector,
+}
+
+impl PeerSelector for Zoned {
+    fn select(&self, candidates: &[PeerInfo], count: usize) -> Vec<SocketAddr> {
+        let (in_zone, cross_zone): (Vec<PeerInfo>, Vec<PeerInfo>) = candidates
+            .iter()
+            .cloned()
+            .partition(|info| info.zone.as_deref() == Some(self.local.as_str()));
+
+        let mut selected = self.inner.select(&cross_zone, self.cross_zone.min(count));
+        selected.extend(
+            self.inner
+                .select(&in_zone, count.saturating_sub(selected.len())),
+        );
+        if selected.len() < count {
+            let rest: Vec<PeerInfo> = cross_zone
+                .into_iter()
+                .filter(|info| !selected.contains(&info.addr))
+                .collect();
+            selected.extend(self.inner.select(&rest, count - selected.len()));
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Uniform;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn peer(port: u16, zone: Option<&str>) -> PeerInfo {
+        let mut info = PeerInfo::new(addr(port));
+        info.zone = zone.map(str::to_string);
+        info
+    }
+
+    fn zoned(cross_zone: usize) -> Arc<dyn PeerSelector> {
+        let config = ZoneConfig {
+            label: Some("a".into()),
+            cross_zone_fanout: cross_zone,
+        };
+        zone_aware(&config, &Uniform)
+    }
+
+    #[test]
+    fn validate_rejects_bad_labels_and_no_cross_zone_links() {
+        assert!(ZoneConfig::default().validate().is_ok());
+        for label in [String::new(), "z".repeat(MAX_ZONE_LEN + 1)] {
+            let config = ZoneConfig {
+                label: Some(label),
+                ..ZoneConfig::default()
+            };
+            assert!(config.validate().is_err());
+        }
+        let config = ZoneConfig {
+            cross_zone_fanout: 0,
+            ..ZoneConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn fanout_prefers_the_zone_with_fixed_cross_zone_links() {
+        let candidates = [
+            peer(1, Some("a")),
+            peer(2, Some("a")),
+            peer(3, Some("a")),
+            peer(4, Some("b")),
+            peer(5, Some("b")),
+            peer(6, None),
+        ];
+        let in_zone: HashSet<SocketAddr> = [addr(1), addr(2), addr(3)].into();
+
+        for _ in 0..50 {
+            let selected = zoned(1).select(&candidates, 3);
+            assert_eq!(selected.len(), 3);
+            let inside = selected.iter().filter(|a| in_zone.contains(a)).count();
+            assert_eq!(inside, 2, "{selected:?}");
+
+            let selected = zoned(2).select(&candidates, 3);
+            let inside = selected.iter().filter(|a| in_zone.contains(a)).count();
+            assert_eq!(inside, 1, "{selected:?}");
+        }
+    }
+
+    #[test]
+    fn a_small_zone_is_topped_up_from_outside() {
+        let candidates = [
+            peer(1, Some("a")),
+            peer(2, Some("b")),
+            peer(3, Some("b")),
+            peer(4, Some("c")),
+        ];
+        let selected = zoned(1).select(&candidates, 3);
+        assert_eq!(selected.len(), 3);
+        assert!(selected.contains(&addr(1)));
+        assert_eq!(selected.iter().collect::<HashSet<_>>().len(), 3);
+
+        assert_eq!(zoned(1).select(&candidates, 10).len(), 4);
+        assert!(zoned(1).select(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn unlabelled_nodes_ignore_zones() {
+        let selector = zone_aware(&ZoneConfig::default(), &Uniform);
+        let candidates = [peer(1, Some("b")), peer(2, Some("b"))];
+        assert_eq!(selector.select(&candidates, 2).len(), 2);
+    }
+
+    #[test]
+    fn learns_valid_labels_and_reports_the_senders_own() {
+        let zones = Zones::new(&ZoneConfig::default());
+        let local = addr(9);
+        let sender = addr(1);
+
+        let own = zones.learn(
+            sender,
+            &[
+                (sender, "a".into()),
+                (addr(2), "b".into()),
+                (addr(3), String::new()),
+                (local, "spoofed".into()),
+            ],
+            local,
+        );
+        assert_eq!(own.as_deref(), Some("a"));
+        assert_eq!(zones.zone_of(addr(2)).as_deref(), Some("b"));
+        assert_eq!(zones.zone_of(addr(3)), None);
+        assert_eq!(zones.zone_of(local), None);
+    }
+
+    #[test]
+    fn table_lists_the_local_label_and_known_peers() {
+        let config = ZoneConfig {
+            label: Some("a".into()),
+            ..ZoneConfig::default()
+        };
+        let zones = Zones::new(&config);
+        let local = addr(9);
+        zones.learn(addr(1), &[(addr(1), "b".into())], local);
+
+        let Some(Payload::PeerZones { zones: table }) = zones.table(local, [addr(1), addr(2)])
+        else {
+            panic!("expected a zone table");
+        };
+        assert_eq!(table, vec![(local, "a".into()), (addr(1), "b".into())]);
+
+        assert!(
+            Zones::new(&ZoneConfig::default())
+                .table(local, [addr(1)])
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn each_connection_is_announced_to_once() {
+        let zones = Zones::new(&ZoneConfig::default());
+        assert!(zones.announce(addr(1)));
+        assert!(!zones.announce(addr(1)));
+
+        zones.retain(&HashSet::new());
+        assert!(zones.announce(addr(1)));
+    }
+}
@@ -0,0 +1,364 @@
+//! Misbehaviour scoring and temporary bans.
+//!
+//! Dropping a bad frame costs its sender nothing, so a peer could send garbage
+//! for as long as it liked. Instead each kind of [`Misbehaviour`] adds a
+//! penalty to a score kept per IP address, and once the score reaches
+//! [`MisbehaviourConfig::ban_threshold`] the address is banned for
+//! [`MisbehaviourConfig::ban_duration`]: its connections are closed, new ones
+//! from it are refused and it is not dialed. The key the peer signs its own
+//! messages with, if it has sent one, is banned along with it, so moving to
+//! another address does not help. Scores halve every
+//! [`MisbehaviourConfig::score_half_life`], so an honest peer that trips the
+//! rate limiter now and then never adds up to a ban.
+//!
+//! Bans are by IP address, covering every port on it: peers sharing a host
+//! share its bans.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::PeerId;
+
+/// Scores kept before those that have decayed to nothing are dropped.
+const MAX_SCORES: usize = 4096;
+
+/// Longest ban placed; longer ones are cut to this.
+const MAX_BAN_DURATION: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// Misbehaviour scoring configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MisbehaviourConfig {
+    /// Score and ban misbehaving peers
+    pub enabled: bool,
+
+    /// Score at which an address is banned
+    pub ban_threshold: u32,
+
+    /// How long a ban earned by misbehaviour lasts
+    pub ban_duration: Duration,
+
+    /// Time in which a score halves
+    pub score_half_life: Duration,
+}
+
+impl Default for MisbehaviourConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ban_threshold: 100,
+            ban_duration: Duration::from_secs(60 * 60),
+            score_half_life: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl MisbehaviourConfig {
+    /// Validate the configuration.
+    ///
+    /// # Errors
+    /// Returns a description of the first invalid field.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ban_threshold == 0 {
+            return Err("misbehaviour ban_threshold must be > 0".into());
+        }
+        if self.ban_duration.is_zero() {
+            return Err("misbehaviour ban_duration must be > 0".into());
+        }
+        if self.score_half_life.is_zero() {
+            return Err("misbehaviour score_half_life must be > 0".into());
+        }
+        Ok(())
+    }
+}
+
+/// Something a peer did that an honest peer would not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Misbehaviour {
+    /// Sent a frame that could not be decoded, or was over the size limit
+    MalformedFrame,
+
+    /// Sent a message whose signature does not verify
+    InvalidSignature,
+
+    /// Sent a message of its own signed with a key other than the one pinned
+    /// for its origin
+    KeyMismatch,
+
+    /// Sent a message beyond its rate limit
+    RateLimited,
+}
+
+impl Misbehaviour {
+    /// The points this adds to a score.
+    pub fn penalty(self) -> u32 {
+        match self {
+            Self::MalformedFrame => 50,
+            Self::InvalidSignature => 20,
+            Self::KeyMismatch => 10,
+            Self::RateLimited => 1,
+        }
+    }
+}
+
+impl fmt::Display for Misbehaviour {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::MalformedFrame => "malformed frame",
+            Self::InvalidSignature => "invalid signature",
+            Self::KeyMismatch => "key mismatch",
+            Self::RateLimited => "rate limited",
+        })
+    }
+}
+
+/// What a ban applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BanTarget {
+    /// Every connection from, and dial to, an IP address
+    Ip(IpAddr),
+
+    /// Every connection whose peer signs its own messages with this key
+    Peer(PeerId),
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "{ip}"),
+            Self::Peer(id) => write!(f, "peer {id}"),
+        }
+    }
+}
+
+/// Why a ban was placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanReason {
+    /// The address's score reached the threshold; this was the last straw
+    Misbehaviour(Misbehaviour),
+
+    /// The application asked for it
+    Manual,
+}
+
+/// A ban in force.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ban {
+    /// What is banned
+    pub target: BanTarget,
+
+    /// Why it was banned
+    pub reason: BanReason,
+
+    /// Time until the ban lifts
+    pub remaining: Duration,
+}
+
+/// A decaying misbehaviour score.
+#[derive(Debug, Clone, Copy)]
+struct Score {
+    points: f64,
+    updated: Instant,
+}
+
+impl Score {
+    fn decayed(self, half_life: Duration, now: Instant) -> f64 {
+        let half_lives =
+            now.saturating_duration_since(self.updated).as_secs_f64() / half_life.as_secs_f64();
+        self.points * 0.5f64.powf(half_lives)
+    }
+}
+
+/// Misbehaviour scores per address, and the bans in force.
+#[derive(Debug)]
+pub(crate) struct BanList {
+    config: MisbehaviourConfig,
+    scores: DashMap<IpAddr, Score>,
+    bans: DashMap<BanTarget, (Instant, BanReason)>,
+}
+
+impl BanList {
+    pub(crate) fn new(config: MisbehaviourConfig) -> Self {
+        Self {
+            config,
+            scores: DashMap::new(),
+            bans: DashMap::new(),
+        }
+    }
+
+    /// Add `misbehaviour`'s penalty to the score of `ip`, banning it, and
+    /// `peer_id` if known, once the score reaches the threshold. Returns
+    /// whether it did.
+    pub(crate) fn penalize(
+        &self,
+        ip: IpAddr,
+        peer_id: Option<PeerId>,
+        misbehaviour: Misbehaviour,
+        now: Instant,
+    ) -> bool {
+        if !self.config.enabled {
+            return false;
+        }
+        let half_life = self.config.score_half_life;
+        if self.scores.len() >= MAX_SCORES {
+            self.scores
+                .retain(|_, score| score.decayed(half_life, now) >= 1.0);
+        }
+
+        let points = {
+            let mut score = self.scores.entry(ip).or_insert(Score {
+                points: 0.0,
+                updated: now,
+            });
+            score.points = score.decayed(half_life, now) + f64::from(misbehaviour.penalty());
+            score.updated = now;
+            score.points
+        };
+        if points < f64::from(self.config.ban_threshold) {
+            return false;
+        }
+
+        self.scores.remove(&ip);
+        let reason = BanReason::Misbehaviour(misbehaviour);
+        let until = now + self.config.ban_duration.min(MAX_BAN_DURATION);
+        self.bans.insert(BanTarget::Ip(ip), (until, reason));
+        if let Some(id) = peer_id {
+            self.bans.insert(BanTarget::Peer(id), (until, reason));
+        }
+        true
+    }
+
+    /// Ban `target` for `duration`, replacing any ban it is under.
+    pub(crate) fn ban(&self, target: BanTarget, duration: Duration, now: Instant) {
+        let until = now + duration.min(MAX_BAN_DURATION);
+        self.bans.insert(target, (until, BanReason::Manual));
+    }
+
+    /// Lift the ban on `target`, returning whether there was one.
+    pub(crate) fn unban(&self, target: BanTarget) -> bool {
+        if let BanTarget::Ip(ip) = target {
+            self.scores.remove(&ip);
+        }
+        self.bans.remove(&target).is_some()
+    }
+
+    /// Whether `target` is banned.
+    pub(crate) fn is_banned(&self, target: BanTarget, now: Instant) -> bool {
+        let until = self.bans.get(&target).map(|entry| entry.0);
+        match until {
+            Some(until) if until > now => true,
+            Some(_) => {
+                self.bans.remove_if(&target, |_, (until, _)| *until <= now);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// The bans in force, dropping those that have lifted.
+    pub(crate) fn list(&self, now: Instant) -> Vec<Ban> {
+        self.bans.retain(|_, (until, _)| *until > now);
+        self.bans
+            .iter()
+            .map(|entry| {
+                let (until, reason) = *entry.value();
+                Ban {
+                    target: *entry.key(),
+                    reason,
+                    remaining: until.saturating_duration_since(now),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MisbehaviourConfig {
+        MisbehaviourConfig {
+            ban_threshold: 50,
+            ..MisbehaviourConfig::default()
+        }
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[test]
+    fn bans_the_address_and_key_at_the_threshold() {
+        let bans = BanList::new(config());
+        let now = Instant::now();
+        let id = PeerId([7; 32]);
+
+        assert!(!bans.penalize(ip(1), Some(id), Misbehaviour::InvalidSignature, now));
+        assert!(!bans.penalize(ip(1), Some(id), Misbehaviour::InvalidSignature, now));
+        assert!(!bans.is_banned(BanTarget::Ip(ip(1)), now));
+        assert!(bans.penalize(ip(1), Some(id), Misbehaviour::KeyMismatch, now));
+
+        assert!(bans.is_banned(BanTarget::Ip(ip(1)), now));
+        assert!(bans.is_banned(BanTarget::Peer(id), now));
+        assert!(!bans.is_banned(BanTarget::Ip(ip(2)), now));
+        let reason = BanReason::Misbehaviour(Misbehaviour::KeyMismatch);
+        assert!(bans.list(now).iter().all(|ban| ban.reason == reason));
+    }
+
+    #[test]
+    fn scores_decay_by_half_life() {
+        let bans = BanList::new(config());
+        let start = Instant::now();
+        let half_life = config().score_half_life;
+
+        for _ in 0..2 {
+            assert!(!bans.penalize(ip(1), None, Misbehaviour::InvalidSignature, start));
+        }
+        // Forty points halve to twenty, and twenty more fall short of fifty.
+        let later = start + half_life;
+        assert!(!bans.penalize(ip(1), None, Misbehaviour::InvalidSignature, later));
+        assert!(bans.penalize(ip(1), None, Misbehaviour::MalformedFrame, later));
+    }
+
+    #[test]
+    fn bans_lift_when_they_expire_or_are_lifted() {
+        let bans = BanList::new(config());
+        let now = Instant::now();
+        let target = BanTarget::Ip(ip(1));
+
+        bans.ban(target, Duration::from_secs(60), now);
+        assert!(bans.is_banned(target, now));
+        assert_eq!(
+            bans.list(now + Duration::from_secs(15)),
+            vec![Ban {
+                target,
+                reason: BanReason::Manual,
+                remaining: Duration::from_secs(45),
+            }]
+        );
+        assert!(!bans.is_banned(target, now + Duration::from_secs(60)));
+        assert!(bans.list(now).is_empty());
+
+        bans.ban(target, Duration::from_secs(60), now);
+        assert!(bans.unban(target));
+        assert!(!bans.unban(target));
+        assert!(!bans.is_banned(target, now));
+    }
+
+    #[test]
+    fn nothing_is_scored_when_disabled() {
+        let bans = BanList::new(MisbehaviourConfig {
+            enabled: false,
+            ..config()
+        });
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            assert!(!bans.penalize(ip(1), None, Misbehaviour::MalformedFrame, now));
+        }
+        assert!(bans.list(now).is_empty());
+    }
+}
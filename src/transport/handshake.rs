@@ -0,0 +1,155 @@
+//! Connection handshake: binding a connection to the key its peer holds.
+//!
+//! A signature proves who wrote a message, not who sent it: any peer can pass
+//! on a frame someone else signed. Bans by key, relay permissions and replay
+//! protection all need the key at the other end of a connection, so a
+//! transport with an identity (see [`Tcp::set_identity`]) has its peers prove
+//! theirs:
+//!
+//! 1. Each side's hello advertises [`Capabilities::HANDSHAKE`] and carries a
+//!    random challenge, fresh for the connection.
+//! 2. On reading the peer's hello, each side answers with a
+//!    [`Payload::ConnectionProof`]: a message signed with its key that echoes
+//!    the peer's challenge.
+//! 3. A proof that verifies and echoes this side's challenge binds the
+//!    connection to its key. The binding is made once: later proofs are
+//!    ignored, so a peer cannot take on another key by passing on a frame
+//!    that key signed. Only a rotation signed by the bound key moves it, to
+//!    the key rotated to.
+//!
+//! Until the peer's proof arrives, what it sends is held back rather than
+//! passed on, so the protocol sees every message from a peer that proves its
+//! key with the key known. At most 64 messages are held; a peer that sends
+//! more before proving its key is disconnected.
+//!
+//! Peers that do not advertise the capability (nodes predating protocol
+//! version 4, and transports without an identity) cannot prove a key, and
+//! their connections stay unbound. The keys proven on any connection are
+//! remembered, so a message signed by one that arrives over an unbound
+//! connection can be told apart: its author proves its key, so whoever sent
+//! it passed on someone else's frame.
+//!
+//! [`Tcp::set_identity`]: crate::Tcp::set_identity
+//! [`Capabilities::HANDSHAKE`]: crate::Capabilities::HANDSHAKE
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::core::identity::verify_message;
+use crate::{Message, Payload, PeerId};
+
+/// Messages held back from a peer that has yet to prove its key.
+pub(crate) const MAX_HELD: usize = 64;
+
+/// Proven keys remembered at most; the longest remembered are forgotten first.
+const MAX_PROVEN_KEYS: usize = 65_536;
+
+/// What a [`Payload::ConnectionProof`] proves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Proof {
+    /// The sender holds this key.
+    Valid(PeerId),
+
+    /// Nothing: it answers a challenge this side did not issue.
+    Unasked,
+
+    /// Nothing: its signature does not verify.
+    Invalid,
+}
+
+/// Check `message`, a connection proof, against the challenge this side
+/// `issued` on its connection.
+pub(crate) fn check(message: &Message, issued: Option<u64>) -> Proof {
+    let Payload::ConnectionProof { challenge } = message.payload else {
+        return Proof::Unasked;
+    };
+    if issued != Some(challenge) {
+        return Proof::Unasked;
+    }
+    if verify_message(message).is_err() {
+        return Proof::Invalid;
+    }
+    Proof::Valid(message.origin_key)
+}
+
+/// The keys proven on this transport's connections, on current ones or past.
+#[derive(Debug, Default)]
+pub(crate) struct ProvenKeys {
+    keys: Mutex<Remembered>,
+}
+
+#[derive(Debug, Default)]
+struct Remembered {
+    keys: HashSet<PeerId>,
+    order: VecDeque<PeerId>,
+}
+
+impl ProvenKeys {
+    /// Remember that `key` was proven.
+    pub(crate) fn insert(&self, key: PeerId) {
+        let mut remembered = lock(&self.keys);
+        if !remembered.keys.insert(key) {
+            return;
+        }
+        remembered.order.push_back(key);
+        if remembered.order.len() > MAX_PROVEN_KEYS
+            && let Some(oldest) = remembered.order.pop_front()
+        {
+            remembered.keys.remove(&oldest);
+        }
+    }
+
+    /// Whether `key` was proven.
+    pub(crate) fn contains(&self, key: PeerId) -> bool {
+        lock(&self.keys).keys.contains(&key)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Identity;
+
+    fn proof(identity: &Identity, challenge: u64) -> Message {
+        let addr = "127.0.0.1:7946".parse().unwrap();
+        identity
+            .author(addr, 0, Payload::ConnectionProof { challenge })
+            .unwrap()
+    }
+
+    #[test]
+    fn a_proof_must_answer_the_challenge_issued() {
+        let identity = Identity::generate();
+        assert_eq!(
+            check(&proof(&identity, 7), Some(7)),
+            Proof::Valid(identity.peer_id())
+        );
+        assert_eq!(check(&proof(&identity, 7), Some(8)), Proof::Unasked);
+        assert_eq!(check(&proof(&identity, 7), None), Proof::Unasked);
+
+        let mut forged = proof(&identity, 7);
+        forged.origin_key = Identity::generate().peer_id();
+        assert_eq!(check(&forged, Some(7)), Proof::Invalid);
+    }
+
+    #[test]
+    fn the_longest_remembered_keys_are_forgotten_first() {
+        let proven = ProvenKeys::default();
+        let key = |n: u32| {
+            let mut bytes = [0; 32];
+            bytes[..4].copy_from_slice(&n.to_be_bytes());
+            PeerId(bytes)
+        };
+        let count = u32::try_from(MAX_PROVEN_KEYS).unwrap();
+        for n in 0..=count {
+            proven.insert(key(n));
+        }
+        assert!(!proven.contains(key(0)));
+        assert!(proven.contains(key(1)));
+        assert!(proven.contains(key(count)));
+    }
+}
@@ -0,0 +1,374 @@
+//! Inbound connection limits.
+//!
+//! `max_peers` alone lets one host fill every slot a node has: it only has to
+//! open connections faster than anyone else. The accept loop therefore
+//! checks each inbound connection against [`ConnectionLimitsConfig`] before
+//! admitting it:
+//!
+//! - **Per address and per subnet.** A connection is refused once its IP
+//!   address holds [`ConnectionLimitsConfig::max_inbound_per_ip`] inbound
+//!   connections, or its subnet (an IPv4 /24 or IPv6 /48, the smallest blocks
+//!   commonly handed to one customer) holds
+//!   [`ConnectionLimitsConfig::max_inbound_per_subnet`]. Addresses that are
+//!   not globally routable are exempt unless
+//!   [`ConnectionLimitsConfig::limit_local`] is set, so nodes sharing a host
+//!   or a private network can still connect to each other freely.
+//! - **Reserved outbound slots.** Inbound connections may take at most
+//!   `max_peers - reserved_outbound` slots, so a node flooded with inbound
+//!   connections can still dial peers of its own choosing, which an attacker
+//!   cannot pick for it.
+//! - **Eviction.** When the inbound slots are full, the newcomer takes the
+//!   place of the least valuable inbound peer rather than being turned away,
+//!   so an attacker who got there first cannot hold the slots forever. The
+//!   [`ConnectionLimitsConfig::protected_inbound`] longest-connected inbound
+//!   peers are never evicted, as an attacker cannot fake age; of the rest,
+//!   the one with the lowest [`PeerInfo::health_score`] goes, the most
+//!   recently connected among equals. If every inbound peer is protected the
+//!   newcomer is refused.
+
+use std::cmp::Ordering;
+use std::net::{IpAddr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::PeerInfo;
+
+/// Inbound connection limits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionLimitsConfig {
+    /// Most inbound connections from one IP address
+    pub max_inbound_per_ip: usize,
+
+    /// Most inbound connections from one IPv4 /24 or IPv6 /48
+    pub max_inbound_per_subnet: usize,
+
+    /// Connection slots only outbound connections may take; at most a quarter
+    /// of `max_peers` is reserved, so small nodes still accept inbound peers
+    pub reserved_outbound: usize,
+
+    /// Longest-connected inbound peers that a newcomer never evicts
+    pub protected_inbound: usize,
+
+    /// Apply the per-address and per-subnet limits to addresses that are not
+    /// globally routable (loopback, private, link-local) too
+    pub limit_local: bool,
+}
+
+impl Default for ConnectionLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_inbound_per_ip: 4,
+            max_inbound_per_subnet: 8,
+            reserved_outbound: 8,
+            protected_inbound: 4,
+            limit_local: false,
+        }
+    }
+}
+
+impl ConnectionLimitsConfig {
+    /// Validate the configuration.
+    ///
+    /// # Errors
+    /// Returns a description of the first invalid field.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_inbound_per_ip == 0 {
+            return Err("connection_limits max_inbound_per_ip must be > 0".into());
+        }
+        if self.max_inbound_per_subnet < self.max_inbound_per_ip {
+            return Err(
+                "connection_limits max_inbound_per_subnet must be >= max_inbound_per_ip".into(),
+            );
+        }
+        Ok(())
+    }
+
+    /// The slots inbound connections may take on a node with `max_peers`.
+    fn inbound_capacity(&self, max_peers: usize) -> usize {
+        max_peers.saturating_sub(self.reserved_outbound.min(max_peers / 4))
+    }
+}
+
+/// What to do with a new inbound connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Admission {
+    /// Admit it into a free slot
+    Accept,
+
+    /// Admit it in place of the inbound peer at this address
+    Evict(SocketAddr),
+
+    /// Close it, for the given reason
+    Refuse(&'static str),
+}
+
+/// Decide whether an inbound connection from `addr` is admitted, given the
+/// node's current `peers`.
+pub(crate) fn admit(
+    config: &ConnectionLimitsConfig,
+    max_peers: usize,
+    addr: SocketAddr,
+    peers: &[PeerInfo],
+) -> Admission {
+    let inbound: Vec<&PeerInfo> = peers.iter().filter(|peer| peer.inbound).collect();
+
+    let ip = addr.ip().to_canonical();
+    if config.limit_local || is_global(ip) {
+        let same_ip = inbound
+            .iter()
+            .filter(|peer| peer.addr.ip().to_canonical() == ip)
+            .count();
+        if same_ip >= config.max_inbound_per_ip {
+            return Admission::Refuse("too many connections from its address");
+        }
+        let same_subnet = inbound
+            .iter()
+            .filter(|peer| subnet(peer.addr.ip()) == subnet(ip))
+            .count();
+        if same_subnet >= config.max_inbound_per_subnet {
+            return Admission::Refuse("too many connections from its subnet");
+        }
+    }
+
+    if inbound.len() < config.inbound_capacity(max_peers) && peers.len() < max_peers {
+        return Admission::Accept;
+    }
+
+    let mut candidates = inbound;
+    candidates.sort_by_key(|peer| peer.connected_at);
+    candidates
+        .iter()
+        .skip(config.protected_inbound)
+        .min_by(|a, b| {
+            a.health_score()
+                .partial_cmp(&b.health_score())
+                .unwrap_or(Ordering::Equal)
+                .then(b.connected_at.cmp(&a.connected_at))
+        })
+        .map_or(Admission::Refuse("no inbound slot free"), |peer| {
+            Admission::Evict(peer.addr)
+        })
+}
+
+/// The block an address is likely allocated in: its IPv4 /24 or IPv6 /48.
+fn subnet(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = [0; 16];
+            octets[..6].copy_from_slice(&ip.octets()[..6]);
+            IpAddr::from(octets)
+        }
+    }
+}
+
+/// Whether `ip` is globally routable, as opposed to loopback, private,
+/// link-local or shared (carrier-grade NAT) address space.
+pub(crate) fn is_global(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            // 100.64.0.0/10 is shared address space behind carrier-grade NAT.
+            let shared = a == 100 && (b & 0xc0) == 64;
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || shared)
+        }
+        IpAddr::V6(ip) => !(ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn inbound(addr: &str, age_secs: u64) -> PeerInfo {
+        let mut info = PeerInfo::new(addr.parse().unwrap());
+        info.inbound = true;
+        info.connected_at = Instant::now() - Duration::from_secs(age_secs);
+        info
+    }
+
+    fn outbound(addr: &str) -> PeerInfo {
+        PeerInfo::new(addr.parse().unwrap())
+    }
+
+    fn open() -> ConnectionLimitsConfig {
+        ConnectionLimitsConfig {
+            max_inbound_per_ip: 2,
+            max_inbound_per_subnet: 3,
+            reserved_outbound: 0,
+            protected_inbound: 0,
+            limit_local: false,
+        }
+    }
+
+    #[test]
+    fn limits_inbound_per_address_and_subnet() {
+        let config = open();
+        let mut peers = vec![
+            inbound("203.0.113.1:1000", 1),
+            inbound("203.0.113.1:1001", 1),
+            outbound("203.0.113.3:7946"),
+        ];
+        let check =
+            |peers: &[PeerInfo], addr: &str| admit(&config, 50, addr.parse().unwrap(), peers);
+
+        assert!(matches!(
+            check(&peers, "203.0.113.1:1002"),
+            Admission::Refuse(_)
+        ));
+        // The outbound connection does not count against the subnet...
+        assert_eq!(check(&peers, "203.0.113.2:1000"), Admission::Accept);
+        // ...but a third inbound one fills it.
+        peers.push(inbound("203.0.113.2:1000", 1));
+        assert!(matches!(
+            check(&peers, "203.0.113.4:1000"),
+            Admission::Refuse(_)
+        ));
+        assert_eq!(check(&peers, "203.0.114.1:1000"), Admission::Accept);
+    }
+
+    #[test]
+    fn limits_ipv6_per_48() {
+        let config = open();
+        let peers = [
+            inbound("[2001:db8:1:1::1]:1000", 1),
+            inbound("[2001:db8:1:2::1]:1000", 1),
+            inbound("[2001:db8:1:3::1]:1000", 1),
+        ];
+        let admit = |addr: &str| admit(&config, 50, addr.parse().unwrap(), &peers);
+
+        assert!(matches!(
+            admit("[2001:db8:1:4::1]:1000"),
+            Admission::Refuse(_)
+        ));
+        assert_eq!(admit("[2001:db8:2::1]:1000"), Admission::Accept);
+    }
+
+    #[test]
+    fn local_addresses_are_exempt_unless_configured() {
+        let peers: Vec<PeerInfo> = (0..4)
+            .map(|port| inbound(&format!("127.0.0.1:{}", 1000 + port), 1))
+            .collect();
+        let addr = "127.0.0.1:2000".parse().unwrap();
+
+        assert_eq!(admit(&open(), 50, addr, &peers), Admission::Accept);
+        let config = ConnectionLimitsConfig {
+            limit_local: true,
+            ..open()
+        };
+        assert!(matches!(
+            admit(&config, 50, addr, &peers),
+            Admission::Refuse(_)
+        ));
+    }
+
+    #[test]
+    fn reserved_outbound_slots_are_not_given_to_inbound() {
+        let config = ConnectionLimitsConfig {
+            reserved_outbound: 2,
+            protected_inbound: 8,
+            ..open()
+        };
+        let mut peers: Vec<PeerInfo> = (1..=5)
+            .map(|host| inbound(&format!("10.0.{host}.1:1000"), 1))
+            .collect();
+        let addr = "10.0.9.1:1000".parse().unwrap();
+
+        // Eight slots, two reserved: the sixth inbound peer is admitted...
+        assert_eq!(admit(&config, 8, addr, &peers), Admission::Accept);
+        // ...the seventh is not, though a slot is free...
+        peers.push(inbound("10.0.6.1:1000", 1));
+        assert!(matches!(
+            admit(&config, 8, addr, &peers),
+            Admission::Refuse(_)
+        ));
+        // ...and outbound connections may fill the slots inbound ones left.
+        peers.truncate(2);
+        peers.extend((1..=6).map(|host| outbound(&format!("10.1.{host}.1:7946"))));
+        assert!(matches!(
+            admit(&config, 8, addr, &peers),
+            Admission::Refuse(_)
+        ));
+    }
+
+    #[test]
+    fn at_most_a_quarter_of_the_slots_is_reserved() {
+        let config = ConnectionLimitsConfig {
+            reserved_outbound: 8,
+            ..open()
+        };
+        assert_eq!(config.inbound_capacity(50), 42);
+        assert_eq!(config.inbound_capacity(8), 6);
+        assert_eq!(config.inbound_capacity(2), 2);
+    }
+
+    #[test]
+    fn evicts_the_least_healthy_unprotected_inbound_peer() {
+        let config = ConnectionLimitsConfig {
+            protected_inbound: 1,
+            ..open()
+        };
+        let mut unhealthy_veteran = inbound("10.0.1.1:1000", 600);
+        unhealthy_veteran.message_failures = 10;
+        unhealthy_veteran.consecutive_failures = 10;
+        let mut failing = inbound("10.0.2.1:1000", 300);
+        failing.messages_sent = 1;
+        failing.message_failures = 3;
+        let peers = [
+            unhealthy_veteran,
+            failing,
+            inbound("10.0.3.1:1000", 200),
+            outbound("10.0.4.1:7946"),
+        ];
+        let addr = "10.0.9.1:1000".parse().unwrap();
+
+        // The oldest is protected however unhealthy; of the rest the failing
+        // peer goes.
+        assert_eq!(
+            admit(&config, 4, addr, &peers),
+            Admission::Evict("10.0.2.1:1000".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn evicts_the_youngest_among_equally_healthy_peers() {
+        let config = ConnectionLimitsConfig {
+            protected_inbound: 1,
+            ..open()
+        };
+        let peers = [
+            inbound("10.0.1.1:1000", 30),
+            inbound("10.0.2.1:1000", 20),
+            inbound("10.0.3.1:1000", 10),
+        ];
+        let addr = "10.0.9.1:1000".parse().unwrap();
+
+        assert_eq!(
+            admit(&config, 3, addr, &peers),
+            Admission::Evict("10.0.3.1:1000".parse().unwrap())
+        );
+        let config = ConnectionLimitsConfig {
+            protected_inbound: 3,
+            ..config
+        };
+        assert!(matches!(
+            admit(&config, 3, addr, &peers),
+            Admission::Refuse(_)
+        ));
+    }
+
+    #[test]
+    fn recognizes_global_addresses() {
+        for ip in ["8.8.8.8", "2001:4860::8888", "::ffff:8.8.8.8"] {
+            assert!(is_global(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["127.0.0.1", "10.1.2.3", "100.64.0.1", "::1", "fd00::1"] {
+            assert!(!is_global(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
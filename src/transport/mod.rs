@@ -1,9 +1,16 @@
 //! Network transport implementations.
 
+pub mod bans;
+pub mod handshake;
+pub mod limits;
+pub mod outbound;
 pub mod tcp;
 
+pub use bans::{Ban, BanReason, BanTarget, Misbehaviour, MisbehaviourConfig};
+pub use limits::ConnectionLimitsConfig;
+pub use outbound::{OutboundConfig, OutboundQueue, OverflowPolicy, PeerOverflow, SendOutcome};
 use serde::{Deserialize, Serialize};
-pub use tcp::Tcp;
+pub use tcp::{Tcp, TrafficStats};
 
 /// Transport protocol configuration.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,442 @@
+//! Per-peer outbound queues.
+//!
+//! Every connection has a bounded queue its writer task drains onto the
+//! socket. A peer that reads more slowly than the node sends fills its queue,
+//! and what happens next is the [`OverflowPolicy`]:
+//!
+//! - **Drop newest** (the default) refuses the new message, keeping what is
+//!   already queued.
+//! - **Drop oldest** makes room by dropping the message at the head of the
+//!   queue, favouring fresh messages over stale ones.
+//! - **Block** waits for room, up to a timeout, and drops the new message if
+//!   none comes. The sender is slowed to the pace of its slowest peer, so
+//!   producers feel the backpressure instead of losing messages.
+//!
+//! Each peer can have a policy of its own (see [`OutboundConfig::peers`]), so
+//! a producer can wait for the peers it must reach and drop for the rest.
+//! Only the application's own messages wait: what the node sends on its own
+//! behalf, forwarding rumors and answering peers, never blocks, and a full
+//! queue under [`OverflowPolicy::Block`] drops it instead. Otherwise one slow
+//! peer would stall the node's receiver.
+//!
+//! Each enqueue reports its [`SendOutcome`], which the gossip engine gathers
+//! into a [`BroadcastReport`](crate::BroadcastReport).
+
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::{Error, Message};
+
+/// Longest a blocked send may wait for room.
+const MAX_BLOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What to do with a message for a peer whose queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the new message.
+    #[default]
+    DropNewest,
+
+    /// Drop the oldest queued message to make room for the new one.
+    DropOldest,
+
+    /// Wait up to `timeout` for room, then drop the new message.
+    Block {
+        /// Longest to wait for room
+        timeout: Duration,
+    },
+}
+
+/// The overflow policy of one peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerOverflow {
+    /// The peer's listening address
+    pub peer: SocketAddr,
+
+    /// What to do with a message for it when its queue is full, in place of
+    /// the default
+    pub overflow: OverflowPolicy,
+}
+
+/// Outbound queue configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboundConfig {
+    /// Messages queued for one peer before its queue is full
+    pub queue_capacity: usize,
+
+    /// What to do with a message for a peer whose queue is full
+    pub overflow: OverflowPolicy,
+
+    /// Overflow policies of particular peers
+    #[serde(default)]
+    pub peers: Vec<PeerOverflow>,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            queue_capacity: 1024,
+            overflow: OverflowPolicy::default(),
+            peers: Vec::new(),
+        }
+    }
+}
+
+impl OutboundConfig {
+    /// Validate the configuration.
+    ///
+    /// # Errors
+    /// Returns a description of the first invalid field.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.queue_capacity == 0 {
+            return Err("outbound queue_capacity must be > 0".into());
+        }
+        self.overflow.validate()?;
+        let mut seen = HashSet::new();
+        for peer in &self.peers {
+            if !seen.insert(peer.peer) {
+                return Err(format!("outbound peers list {} twice", peer.peer));
+            }
+            peer.overflow.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl OverflowPolicy {
+    fn validate(self) -> Result<(), String> {
+        if let Self::Block { timeout } = self
+            && (timeout.is_zero() || timeout > MAX_BLOCK_TIMEOUT)
+        {
+            return Err("outbound block timeout must be > 0 and <= 60 seconds".into());
+        }
+        Ok(())
+    }
+}
+
+/// What became of a message queued for a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendOutcome {
+    /// Queued for the peer's writer
+    Queued,
+
+    /// Queued, after dropping the oldest queued message to make room
+    DisplacedOldest,
+
+    /// Dropped because the peer's queue was full
+    Dropped,
+}
+
+impl fmt::Display for SendOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Queued => "queued",
+            Self::DisplacedOldest => "queued, displacing the oldest",
+            Self::Dropped => "dropped",
+        })
+    }
+}
+
+/// A bounded queue of messages for one peer's writer task.
+///
+/// Closing the queue refuses further messages; the writer still drains what
+/// was queued before it stops.
+#[derive(Debug)]
+pub struct OutboundQueue {
+    capacity: usize,
+    state: Mutex<State>,
+    readable: Notify,
+    writable: Notify,
+}
+
+#[derive(Debug)]
+struct State {
+    messages: VecDeque<Message>,
+    overflow: OverflowPolicy,
+    closed: bool,
+}
+
+impl OutboundQueue {
+    /// Create an empty queue with the capacity and default overflow policy
+    /// `config` describes.
+    pub fn new(config: &OutboundConfig) -> Self {
+        Self {
+            capacity: config.queue_capacity.max(1),
+            state: Mutex::new(State {
+                messages: VecDeque::new(),
+                overflow: config.overflow,
+                closed: false,
+            }),
+            readable: Notify::new(),
+            writable: Notify::new(),
+        }
+    }
+
+    /// The policy applied when the queue is full.
+    pub fn overflow(&self) -> OverflowPolicy {
+        lock(&self.state).overflow
+    }
+
+    /// Apply `overflow` from now on when the queue is full. Senders already
+    /// waiting keep to the policy they started under.
+    pub fn set_overflow(&self, overflow: OverflowPolicy) {
+        lock(&self.state).overflow = overflow;
+    }
+
+    /// Queue `message`, applying the overflow policy if the queue is full.
+    ///
+    /// Only [`OverflowPolicy::Block`] waits.
+    ///
+    /// # Errors
+    /// Returns [`Error::Channel`] if the queue is closed.
+    pub async fn push(&self, message: Message) -> crate::Result<SendOutcome> {
+        let OverflowPolicy::Block { timeout } = self.overflow() else {
+            return self.try_push(message);
+        };
+        let wait = async {
+            let mut message = message;
+            loop {
+                let writable = self.writable.notified();
+                match self.push_if_room(message)? {
+                    Ok(()) => return Ok(SendOutcome::Queued),
+                    Err(full) => message = full,
+                }
+                writable.await;
+            }
+        };
+        tokio::time::timeout(timeout, wait)
+            .await
+            .unwrap_or(Ok(SendOutcome::Dropped))
+    }
+
+    /// Queue `message` without waiting: a full queue drops the newest message
+    /// unless the policy is [`OverflowPolicy::DropOldest`].
+    ///
+    /// # Errors
+    /// Returns [`Error::Channel`] if the queue is closed.
+    pub fn try_push(&self, message: Message) -> crate::Result<SendOutcome> {
+        let mut state = lock(&self.state);
+        if state.closed {
+            return Err(closed());
+        }
+        let outcome = if state.messages.len() < self.capacity {
+            SendOutcome::Queued
+        } else if state.overflow == OverflowPolicy::DropOldest {
+            state.messages.pop_front();
+            SendOutcome::DisplacedOldest
+        } else {
+            return Ok(SendOutcome::Dropped);
+        };
+        state.messages.push_back(message);
+        drop(state);
+        self.readable.notify_one();
+        Ok(outcome)
+    }
+
+    /// Queue `message` if there is room, handing it back if the queue is
+    /// full.
+    fn push_if_room(&self, message: Message) -> crate::Result<Result<(), Message>> {
+        let mut state = lock(&self.state);
+        if state.closed {
+            return Err(closed());
+        }
+        if state.messages.len() >= self.capacity {
+            return Ok(Err(message));
+        }
+        state.messages.push_back(message);
+        drop(state);
+        self.readable.notify_one();
+        Ok(Ok(()))
+    }
+
+    /// Take the oldest queued message, waiting for one; `None` once the queue
+    /// is closed and drained.
+    pub async fn pop(&self) -> Option<Message> {
+        loop {
+            let readable = self.readable.notified();
+            {
+                let mut state = lock(&self.state);
+                if let Some(message) = state.messages.pop_front() {
+                    drop(state);
+                    self.writable.notify_one();
+                    return Some(message);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            readable.await;
+        }
+    }
+
+    /// Refuse further messages, and wake the writer and any blocked senders.
+    pub fn close(&self) {
+        lock(&self.state).closed = true;
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+
+    /// Messages queued.
+    pub fn len(&self) -> usize {
+        lock(&self.state).messages.len()
+    }
+
+    /// Whether no messages are queued.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn closed() -> Error {
+    Error::Channel("peer write channel closed".to_string())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::Payload;
+
+    fn message(sequence: u64) -> Message {
+        Message::new(
+            "127.0.0.1:9000".parse().unwrap(),
+            sequence,
+            Payload::Application(Bytes::from_static(b"test")),
+        )
+    }
+
+    fn queue(capacity: usize, overflow: OverflowPolicy) -> OutboundQueue {
+        OutboundQueue::new(&OutboundConfig {
+            queue_capacity: capacity,
+            overflow,
+            ..OutboundConfig::default()
+        })
+    }
+
+    async fn sequences(queue: &OutboundQueue) -> Vec<u64> {
+        queue.close();
+        let mut sequences = Vec::new();
+        while let Some(message) = queue.pop().await {
+            sequences.push(message.id.sequence);
+        }
+        sequences
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_what_is_queued() {
+        let queue = queue(2, OverflowPolicy::DropNewest);
+        for sequence in 1..=2 {
+            assert_eq!(
+                queue.push(message(sequence)).await.unwrap(),
+                SendOutcome::Queued
+            );
+        }
+        assert_eq!(queue.push(message(3)).await.unwrap(), SendOutcome::Dropped);
+        assert_eq!(sequences(&queue).await, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room() {
+        let queue = queue(2, OverflowPolicy::DropOldest);
+        for sequence in 1..=2 {
+            queue.push(message(sequence)).await.unwrap();
+        }
+        assert_eq!(
+            queue.push(message(3)).await.unwrap(),
+            SendOutcome::DisplacedOldest
+        );
+        assert_eq!(sequences(&queue).await, [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room_then_gives_up() {
+        let queue = Arc::new(queue(
+            1,
+            OverflowPolicy::Block {
+                timeout: Duration::from_millis(100),
+            },
+        ));
+        queue.push(message(1)).await.unwrap();
+        assert_eq!(queue.push(message(2)).await.unwrap(), SendOutcome::Dropped);
+
+        let writer = Arc::clone(&queue);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            writer.pop().await
+        });
+        assert_eq!(queue.push(message(3)).await.unwrap(), SendOutcome::Queued);
+        assert_eq!(sequences(&queue).await, [3]);
+    }
+
+    #[tokio::test]
+    async fn try_push_never_waits() {
+        let queue = queue(
+            1,
+            OverflowPolicy::Block {
+                timeout: Duration::from_secs(60),
+            },
+        );
+        queue.try_push(message(1)).unwrap();
+        assert_eq!(queue.try_push(message(2)).unwrap(), SendOutcome::Dropped);
+
+        queue.set_overflow(OverflowPolicy::DropOldest);
+        assert_eq!(
+            queue.try_push(message(3)).unwrap(),
+            SendOutcome::DisplacedOldest
+        );
+        assert_eq!(sequences(&queue).await, [3]);
+    }
+
+    #[tokio::test]
+    async fn closed_queue_refuses_but_drains() {
+        let queue = queue(4, OverflowPolicy::default());
+        queue.push(message(1)).await.unwrap();
+        queue.close();
+        assert!(matches!(
+            queue.push(message(2)).await,
+            Err(Error::Channel(_))
+        ));
+        assert_eq!(queue.pop().await.map(|m| m.id.sequence), Some(1));
+        assert!(queue.pop().await.is_none());
+    }
+
+    #[test]
+    fn validation() {
+        assert!(OutboundConfig::default().validate().is_ok());
+        let empty = OutboundConfig {
+            queue_capacity: 0,
+            ..OutboundConfig::default()
+        };
+        assert!(empty.validate().is_err());
+        let no_wait = OutboundConfig {
+            overflow: OverflowPolicy::Block {
+                timeout: Duration::ZERO,
+            },
+            ..OutboundConfig::default()
+        };
+        assert!(no_wait.validate().is_err());
+        let peer = PeerOverflow {
+            peer: "127.0.0.1:9001".parse().unwrap(),
+            overflow: OverflowPolicy::DropOldest,
+        };
+        let twice = OutboundConfig {
+            peers: vec![peer, peer],
+            ..OutboundConfig::default()
+        };
+        assert!(twice.validate().is_err());
+    }
+}
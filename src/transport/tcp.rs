@@ -1,26 +1,114 @@
 //! TCP transport implementation.
 
+use std::io;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, OnceLock};
-use std::time::Duration;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use dashmap::DashMap;
 use futures::SinkExt;
 use futures::stream::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::task::{AbortHandle, JoinHandle};
 use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{debug, error, warn};
+use tracing::{debug, error, trace, warn};
 
+use super::bans::BanList;
+use super::handshake::{self, MAX_HELD, Proof, ProvenKeys};
+use super::limits::{self, Admission};
 use crate::core::message_codec::MAX_FRAME_SIZE;
-use crate::{Error, Message, MessageCodec, Peer, PeerInfo, RateLimiter, Result};
+use crate::{
+    Ban, BanTarget, BudgetExceeded, CompressionConfig, ConnectionLimitsConfig, Error, Identity,
+    Message, MessageCodec, Misbehaviour, MisbehaviourConfig, OutboundConfig, OutboundQueue,
+    OverflowPolicy, Payload, Peer, PeerId, PeerInfo, PeerProtocol, RateLimitConfig, RateLimiter,
+    Result, SendOutcome, TrafficClass, WireEncoding,
+};
 
-const WRITE_CHANNEL_CAPACITY: usize = 1024;
 const RECV_CHANNEL_CAPACITY: usize = 1024;
 const SHUTDOWN_DRAIN_GRACE_MS: u64 = 500;
 
+/// Bytes a transport has moved over its sockets, framing included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    /// Bytes written to peers
+    pub bytes_sent: u64,
+    /// Bytes read from peers
+    pub bytes_received: u64,
+}
+
+#[derive(Debug, Default)]
+struct TrafficCounters {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+/// A socket half that adds the bytes it moves to the transport's counters.
+struct Metered<S> {
+    inner: S,
+    counters: Arc<TrafficCounters>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = u64::try_from(buf.filled().len() - before).unwrap_or(u64::MAX);
+            self.counters.received.fetch_add(read, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            let written = u64::try_from(written).unwrap_or(u64::MAX);
+            self.counters.sent.fetch_add(written, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Transport state every connection's tasks hold a handle to.
+#[derive(Clone)]
+struct Shared {
+    local_addr: Arc<OnceLock<SocketAddr>>,
+    identity: Option<Arc<Identity>>,
+    proven: Arc<ProvenKeys>,
+    peers: Arc<DashMap<SocketAddr, Peer>>,
+    connections: Arc<DashMap<SocketAddr, ConnectionTask>>,
+    message_tx: Sender<(SocketAddr, Message)>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    outbound: OutboundConfig,
+    peer_overflow: Arc<DashMap<SocketAddr, OverflowPolicy>>,
+    bans: Arc<BanList>,
+    traffic: Arc<TrafficCounters>,
+}
+
 struct ConnectionTask {
     supervisor: JoinHandle<()>,
     read_abort: AbortHandle,
@@ -30,7 +118,13 @@ struct ConnectionTask {
 /// TCP transport for gossip messages.
 pub struct Tcp {
     /// Local listening address, set once when the transport begins listening.
-    local_addr: OnceLock<SocketAddr>,
+    local_addr: Arc<OnceLock<SocketAddr>>,
+
+    /// The identity this node proves its key with on each connection
+    identity: Option<Arc<Identity>>,
+
+    /// Keys peers have proven they hold
+    proven: Arc<ProvenKeys>,
 
     /// Active peer connections
     peers: Arc<DashMap<SocketAddr, Peer>>,
@@ -45,14 +139,35 @@ pub struct Tcp {
     message_tx: Sender<(SocketAddr, Message)>,
 
     /// Rate limiting configuration
-    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
 
     /// Maximum message size in bytes
     max_message_size: usize,
 
+    /// Outbound compression policy, negotiated per connection
+    compression: CompressionConfig,
+
+    /// Preferred outbound encoding, negotiated per connection
+    encoding: WireEncoding,
+
     /// Maximum number of simultaneous peer connections
     max_peers: usize,
 
+    /// Limits on inbound connections within `max_peers`
+    limits: ConnectionLimitsConfig,
+
+    /// Size of each peer's outbound queue, and what a full one does
+    outbound: OutboundConfig,
+
+    /// Overflow policies of particular peers, by listening address
+    peer_overflow: Arc<DashMap<SocketAddr, OverflowPolicy>>,
+
+    /// Misbehaviour scores and the bans in force
+    bans: Arc<BanList>,
+
+    /// Bytes moved over every connection
+    traffic: Arc<TrafficCounters>,
+
     /// Accept-loop task handle, set once when the transport begins listening.
     accept_handle: Mutex<Option<JoinHandle<()>>>,
 }
@@ -68,14 +183,23 @@ impl Tcp {
         let (message_tx, message_rx) = mpsc::channel(RECV_CHANNEL_CAPACITY);
 
         Self {
-            local_addr: OnceLock::new(),
+            local_addr: Arc::new(OnceLock::new()),
+            identity: None,
+            proven: Arc::default(),
             peers: Arc::new(DashMap::new()),
             connections: Arc::new(DashMap::new()),
             message_rx: Arc::new(Mutex::new(message_rx)),
             message_tx,
             rate_limiter: None,
             max_message_size,
+            compression: CompressionConfig::default(),
+            encoding: WireEncoding::default(),
             max_peers: usize::MAX,
+            limits: ConnectionLimitsConfig::default(),
+            outbound: OutboundConfig::default(),
+            peer_overflow: Arc::new(DashMap::new()),
+            bans: Arc::new(BanList::new(MisbehaviourConfig::default())),
+            traffic: Arc::default(),
             accept_handle: Mutex::new(None),
         }
     }
@@ -85,22 +209,100 @@ impl Tcp {
     /// # Errors
     /// Returns [`Error::Config`] if `capacity` or `refill_rate` is zero.
     pub fn set_rate_limit(mut self, capacity: u32, refill_rate: u32) -> Result<Self> {
-        self.rate_limiter = Some(Arc::new(Mutex::new(RateLimiter::try_with_params(
+        self.rate_limiter = Some(Arc::new(RateLimiter::try_with_params(
             capacity,
             refill_rate,
-        )?)));
+        )?));
+        Ok(self)
+    }
+
+    /// Enable rate limiting with per-class, byte and global budgets, as
+    /// `config` describes.
+    ///
+    /// Each connection gets buckets of its own. A connection over budget has
+    /// the message dropped; messages dropped for the peer's budget count
+    /// against its misbehaviour score, those dropped for the global budget do
+    /// not. With `drop_excess` unset, its reads pause until the budget refills
+    /// instead.
+    ///
+    /// # Errors
+    /// Returns [`Error::Config`] if `config` is invalid.
+    pub fn set_rate_limiting(mut self, config: RateLimitConfig) -> Result<Self> {
+        self.rate_limiter = Some(Arc::new(RateLimiter::try_new(config)?));
         Ok(self)
     }
 
     /// Cap the number of simultaneous peer connections.
     ///
-    /// Connections beyond `max_peers` are refused on both the inbound (accept)
-    /// and outbound (connect) paths. The default is unbounded.
+    /// Outbound connections beyond `max_peers` are refused; inbound ones are
+    /// refused or evict an inbound peer, as the connection limits decide. The
+    /// default is unbounded.
     pub fn set_max_peers(mut self, max_peers: usize) -> Self {
         self.max_peers = max_peers;
         self
     }
 
+    /// Limit inbound connections within `max_peers` as `limits` describes.
+    ///
+    /// A new inbound connection beyond the per-address or per-subnet limit is
+    /// refused; one beyond the inbound slots evicts the least valuable inbound
+    /// peer, if any may be evicted. See [`limits`] for the rules.
+    pub fn set_connection_limits(mut self, limits: ConnectionLimitsConfig) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Queue messages for each peer, and handle full queues, as `config`
+    /// describes.
+    pub fn set_outbound(mut self, config: OutboundConfig) -> Self {
+        self.peer_overflow = Arc::new(
+            config
+                .peers
+                .iter()
+                .map(|peer| (peer.peer, peer.overflow))
+                .collect(),
+        );
+        self.outbound = config;
+        self
+    }
+
+    /// Score misbehaving peers and ban them as `config` describes.
+    ///
+    /// Undecodable frames and messages beyond the rate limit are scored here;
+    /// the protocol above reports what it finds with [`Tcp::penalize`].
+    pub fn set_misbehaviour(mut self, config: MisbehaviourConfig) -> Self {
+        self.bans = Arc::new(BanList::new(config));
+        self
+    }
+
+    /// Prove `identity`'s key to each peer, and have each peer prove its own.
+    ///
+    /// Connections to peers that prove a key are bound to it for good; see
+    /// [`handshake`]. Without an identity no connection is bound, and
+    /// [`Tcp::peer_id`] is always `None`.
+    pub fn set_identity(mut self, identity: Arc<Identity>) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Compress outbound frames according to `compression`.
+    ///
+    /// Compression is negotiated per connection, so peers that cannot
+    /// decompress keep receiving plain frames.
+    pub fn set_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Prefer `encoding` for outbound frames.
+    ///
+    /// Like compression it is negotiated per connection: peers that did not
+    /// advertise support for `encoding` are sent bincode.
+    pub fn set_encoding(mut self, encoding: WireEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
     /// Start listening on the given address.
     pub async fn listen(&self, addr: SocketAddr) -> Result<()> {
         let listener = TcpListener::bind(addr)
@@ -112,30 +314,46 @@ impl Tcp {
 
         debug!("TCP transport listening on {local_addr}");
 
-        let peers = Arc::clone(&self.peers);
-        let connections = Arc::clone(&self.connections);
-        let message_tx = self.message_tx.clone();
-        let rate_limiter = self.rate_limiter.clone();
+        let shared = self.shared();
         let max_message_size = self.max_message_size;
+        let compression = self.compression;
+        let encoding = self.encoding;
+        let handshake = self.identity.is_some();
         let max_peers = self.max_peers;
+        let limits = self.limits.clone();
 
         let handle = tokio::spawn(async move {
             loop {
                 match listener.accept().await {
                     Ok((stream, peer_addr)) => {
-                        if peers.len() >= max_peers {
-                            debug!("At max_peers ({max_peers}), refusing inbound from {peer_addr}");
+                        let ip = peer_addr.ip().to_canonical();
+                        if shared.bans.is_banned(BanTarget::Ip(ip), Instant::now()) {
+                            debug!("Refusing inbound from banned {peer_addr}");
                             continue;
                         }
+                        let infos: Vec<PeerInfo> = shared
+                            .peers
+                            .iter()
+                            .map(|entry| entry.value().info.clone())
+                            .collect();
+                        match limits::admit(&limits, max_peers, peer_addr, &infos) {
+                            Admission::Accept => {}
+                            Admission::Evict(evicted) => {
+                                debug!("Evicting inbound peer {evicted} for {peer_addr}");
+                                close(&shared.peers, &shared.connections, evicted);
+                            }
+                            Admission::Refuse(reason) => {
+                                debug!("Refusing inbound from {peer_addr}: {reason}");
+                                continue;
+                            }
+                        }
                         debug!("Accepted connection from {peer_addr}");
                         Self::handle_connection(
                             stream,
                             peer_addr,
-                            Arc::clone(&peers),
-                            Arc::clone(&connections),
-                            message_tx.clone(),
-                            rate_limiter.clone(),
-                            max_message_size,
+                            true,
+                            shared.clone(),
+                            connection_codec(max_message_size, compression, encoding, handshake),
                         );
                     }
                     Err(e) => {
@@ -161,6 +379,15 @@ impl Tcp {
             debug!("Already connected to {addr}");
             return Ok(());
         }
+        if self
+            .bans
+            .is_banned(BanTarget::Ip(addr.ip().to_canonical()), Instant::now())
+        {
+            return Err(Error::network(format!(
+                "{} is banned, refusing connection to {addr}",
+                addr.ip()
+            )));
+        }
         if self.peers.len() >= self.max_peers {
             return Err(Error::network(format!(
                 "at max_peers ({}), refusing connection to {addr}",
@@ -177,30 +404,109 @@ impl Tcp {
         Self::handle_connection(
             stream,
             addr,
-            Arc::clone(&self.peers),
-            Arc::clone(&self.connections),
-            self.message_tx.clone(),
-            self.rate_limiter.clone(),
-            self.max_message_size,
+            false,
+            self.shared(),
+            connection_codec(
+                self.max_message_size,
+                self.compression,
+                self.encoding,
+                self.identity.is_some(),
+            ),
         );
 
         Ok(())
     }
 
-    /// Queue a message for delivery to a peer.
+    /// Queue a message for delivery to a peer, without waiting.
     ///
     /// The message is encoded exactly once, by the peer's writer task at the
     /// socket; this only enqueues it. Returns [`Error::PeerNotFound`] if the
-    /// peer is not connected. A full write channel drops the frame rather than
-    /// erroring (see [`Peer::send`]).
+    /// peer is not connected. A full queue is handled by the overflow policy
+    /// rather than erroring, except that [`OverflowPolicy::Block`] drops the
+    /// message instead of waiting; use [`Tcp::try_enqueue`] to learn what
+    /// became of it, and [`Tcp::enqueue`] to wait for room.
     pub async fn send(&self, peer: SocketAddr, message: Message) -> Result<()> {
-        if let Some(mut conn) = self.peers.get_mut(&peer) {
-            conn.send(message)
-        } else {
-            Err(Error::PeerNotFound(peer))
+        self.try_enqueue(peer, message).map(|_| ())
+    }
+
+    /// Queue a message for delivery to a peer, reporting whether it was
+    /// queued or dropped for a full queue.
+    ///
+    /// Under [`OverflowPolicy::Block`] this waits for room in the peer's
+    /// queue, up to the policy's timeout.
+    ///
+    /// # Errors
+    /// Returns [`Error::PeerNotFound`] if the peer is not connected, and
+    /// [`Error::Channel`] if its connection closed.
+    pub async fn enqueue(&self, peer: SocketAddr, message: Message) -> Result<SendOutcome> {
+        let queue = self.queue(peer)?;
+        let outcome = queue.push(message).await;
+        self.record(peer, &queue, outcome)
+    }
+
+    /// Queue a message for delivery to a peer without waiting, reporting
+    /// whether it was queued or dropped for a full queue. Under
+    /// [`OverflowPolicy::Block`] a full queue drops it.
+    ///
+    /// # Errors
+    /// Returns [`Error::PeerNotFound`] if the peer is not connected, and
+    /// [`Error::Channel`] if its connection closed.
+    pub fn try_enqueue(&self, peer: SocketAddr, message: Message) -> Result<SendOutcome> {
+        let queue = self.queue(peer)?;
+        let outcome = queue.try_push(message);
+        self.record(peer, &queue, outcome)
+    }
+
+    /// Handle `overflow` for the peer listening at `peer` from now on, on its
+    /// current connection and any it makes later.
+    pub fn set_peer_overflow(&self, peer: SocketAddr, overflow: OverflowPolicy) {
+        self.peer_overflow.insert(peer, overflow);
+        if let Some(conn) = self.peers.get(&peer) {
+            conn.queue().set_overflow(overflow);
+        }
+    }
+
+    /// Apply the overflow policy set for the peer listening at `listening`,
+    /// if any, to its connection at `connection`: an inbound connection's
+    /// address is not the one the peer listens on.
+    pub(crate) fn apply_peer_overflow(&self, listening: SocketAddr, connection: SocketAddr) {
+        let Some(overflow) = self.peer_overflow.get(&listening).map(|entry| *entry) else {
+            return;
+        };
+        if let Some(conn) = self.peers.get(&connection) {
+            conn.queue().set_overflow(overflow);
+        }
+    }
+
+    fn queue(&self, peer: SocketAddr) -> Result<Arc<OutboundQueue>> {
+        match self.peers.get(&peer) {
+            Some(conn) => Ok(Arc::clone(conn.queue())),
+            None => Err(Error::PeerNotFound(peer)),
         }
     }
 
+    /// Count `outcome` against the connection at `peer`, if `queue` is still
+    /// its queue.
+    fn record(
+        &self,
+        peer: SocketAddr,
+        queue: &Arc<OutboundQueue>,
+        outcome: Result<SendOutcome>,
+    ) -> Result<SendOutcome> {
+        if let Some(mut conn) = self.peers.get_mut(&peer)
+            && Arc::ptr_eq(conn.queue(), queue)
+        {
+            match outcome {
+                Ok(SendOutcome::Dropped) => {
+                    debug!("Write queue full for {peer}, dropping message");
+                }
+                Ok(_) => conn.info.increment_sent(),
+                Err(_) => conn.info.record_failure(),
+            }
+        }
+        outcome
+    }
+
     /// Receive a message from any peer.
     pub async fn recv(&self) -> Result<(SocketAddr, Message)> {
         self.message_rx
@@ -235,6 +541,24 @@ impl Tcp {
             .collect()
     }
 
+    /// The protocol a connected peer speaks: what it advertised, or
+    /// [`PeerProtocol::LEGACY`] if it has not advertised anything. `None` if
+    /// the peer is not connected.
+    pub fn peer_protocol(&self, addr: SocketAddr) -> Option<PeerProtocol> {
+        self.peers
+            .get(&addr)
+            .map(|peer| peer.info.protocol.unwrap_or(PeerProtocol::LEGACY))
+    }
+
+    /// Bytes moved over every connection since the transport was created,
+    /// frame headers included.
+    pub fn traffic(&self) -> TrafficStats {
+        TrafficStats {
+            bytes_sent: self.traffic.sent.load(Ordering::Relaxed),
+            bytes_received: self.traffic.received.load(Ordering::Relaxed),
+        }
+    }
+
     /// Mark a connected peer as stale.
     pub fn mark_stale(&self, addr: SocketAddr) {
         if let Some(mut peer) = self.peers.get_mut(&addr) {
@@ -242,14 +566,104 @@ impl Tcp {
         }
     }
 
+    /// The smoothed round-trip time to a connected peer; `None` if it is not
+    /// connected or not yet measured.
+    pub fn peer_rtt(&self, addr: SocketAddr) -> Option<Duration> {
+        self.peers.get(&addr).and_then(|peer| peer.info.rtt)
+    }
+
+    /// Fold a round-trip time sample into a connected peer's smoothed RTT.
+    pub fn record_rtt(&self, addr: SocketAddr, sample: Duration) {
+        if let Some(mut peer) = self.peers.get_mut(&addr) {
+            peer.info.record_rtt(sample);
+        }
+    }
+
+    /// The zone label of a connected peer; `None` if it is not connected or
+    /// its zone is not known.
+    pub fn peer_zone(&self, addr: SocketAddr) -> Option<String> {
+        self.peers
+            .get(&addr)
+            .and_then(|peer| peer.info.zone.clone())
+    }
+
+    /// Record the zone label of a connected peer.
+    pub fn set_peer_zone(&self, addr: SocketAddr, zone: &str) {
+        if let Some(mut peer) = self.peers.get_mut(&addr)
+            && peer.info.zone.as_deref() != Some(zone)
+        {
+            peer.info.zone = Some(zone.to_string());
+        }
+    }
+
+    /// The key a connected peer proved it holds when the connection was
+    /// made; `None` if it is not connected or has not proved one.
+    pub fn peer_id(&self, addr: SocketAddr) -> Option<PeerId> {
+        self.peers.get(&addr).and_then(|peer| peer.info.peer_id)
+    }
+
+    /// Whether `key` has been proven on some connection, current or past.
+    /// A peer that proves its key on one connection does on every other, so
+    /// a message it signed arriving over an unbound connection was passed on
+    /// by someone else.
+    pub fn has_proven(&self, key: PeerId) -> bool {
+        self.proven.contains(key)
+    }
+
+    /// Move the connection at `addr` from the key it proved to `new`, which
+    /// that key was rotated to. The caller checks the rotation: nothing else
+    /// rebinds a connection.
+    ///
+    /// Returns `false`, and closes the connection, if `new` is banned.
+    pub fn follow_rotation(&self, addr: SocketAddr, new: PeerId) -> bool {
+        match self.peers.get_mut(&addr) {
+            Some(mut peer) if peer.info.peer_id.is_some() => {
+                peer.info.peer_id = Some(new);
+            }
+            _ => return true,
+        }
+        self.proven.insert(new);
+        if self.bans.is_banned(BanTarget::Peer(new), Instant::now()) {
+            debug!("Closing connection {addr} of banned peer {new}");
+            close(&self.peers, &self.connections, addr);
+            return false;
+        }
+        true
+    }
+
+    /// Add `misbehaviour` to the score of the peer at `addr`.
+    ///
+    /// Returns whether that got the peer banned, in which case every
+    /// connection from its address, or signed for by its key, is closed.
+    pub fn penalize(&self, addr: SocketAddr, misbehaviour: Misbehaviour) -> bool {
+        penalize(
+            &self.bans,
+            &self.peers,
+            &self.connections,
+            addr,
+            misbehaviour,
+        )
+    }
+
+    /// Ban `target` for `duration`, closing the connections it covers.
+    pub fn ban(&self, target: BanTarget, duration: Duration) {
+        self.bans.ban(target, duration, Instant::now());
+        close_banned(&self.bans, &self.peers, &self.connections);
+    }
+
+    /// Lift the ban on `target`, returning whether there was one.
+    pub fn unban(&self, target: BanTarget) -> bool {
+        self.bans.unban(target)
+    }
+
+    /// The bans in force.
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.list(Instant::now())
+    }
+
     /// Drop a peer from the registry, returning whether it was present.
     pub fn disconnect(&self, addr: SocketAddr) -> bool {
-        let removed = self.peers.remove(&addr).is_some();
-        if let Some((_, conn)) = self.connections.remove(&addr) {
-            conn.read_abort.abort();
-            conn.write_abort.abort();
-        }
-        removed
+        close(&self.peers, &self.connections, addr)
     }
 
     /// Stop the transport.
@@ -289,62 +703,242 @@ impl Tcp {
         }
     }
 
+    fn shared(&self) -> Shared {
+        Shared {
+            local_addr: Arc::clone(&self.local_addr),
+            identity: self.identity.clone(),
+            proven: Arc::clone(&self.proven),
+            peers: Arc::clone(&self.peers),
+            connections: Arc::clone(&self.connections),
+            message_tx: self.message_tx.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            outbound: self.outbound.clone(),
+            peer_overflow: Arc::clone(&self.peer_overflow),
+            bans: Arc::clone(&self.bans),
+            traffic: Arc::clone(&self.traffic),
+        }
+    }
+
     fn handle_connection(
         stream: TcpStream,
         peer_addr: SocketAddr,
-        peers: Arc<DashMap<SocketAddr, Peer>>,
-        connections: Arc<DashMap<SocketAddr, ConnectionTask>>,
-        message_tx: Sender<(SocketAddr, Message)>,
-        rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
-        max_message_size: usize,
+        inbound: bool,
+        shared: Shared,
+        codec: MessageCodec,
     ) {
+        let Shared {
+            local_addr,
+            identity,
+            proven,
+            peers,
+            connections,
+            message_tx,
+            rate_limiter,
+            outbound,
+            peer_overflow,
+            bans,
+            traffic,
+        } = shared;
+        // The origin a connection proof is authored under: the listening
+        // address, or the socket's own if the transport does not listen.
+        let origin = local_addr
+            .get()
+            .copied()
+            .or_else(|| stream.local_addr().ok())
+            .unwrap_or(peer_addr);
         let (reader, writer) = stream.into_split();
-        let (tx, mut rx) = mpsc::channel::<Message>(WRITE_CHANNEL_CAPACITY);
-
-        peers.insert(peer_addr, Peer::new(peer_addr, tx));
+        let reader = Metered {
+            inner: reader,
+            counters: Arc::clone(&traffic),
+        };
+        let writer = Metered {
+            inner: writer,
+            counters: traffic,
+        };
+        let queue = Arc::new(OutboundQueue::new(&outbound));
+        if let Some(overflow) = peer_overflow.get(&peer_addr) {
+            queue.set_overflow(*overflow);
+        }
 
-        let codec = MessageCodec::with_max_frame_size(max_message_size);
+        let proof_queue = Arc::clone(&queue);
+        let mut peer = Peer::new(peer_addr, Arc::clone(&queue));
+        peer.info.inbound = inbound;
+        peers.insert(peer_addr, peer);
 
         let write_task = {
             let mut sink = FramedWrite::new(writer, codec.clone());
             tokio::spawn(async move {
-                while let Some(message) = rx.recv().await {
+                while let Some(message) = queue.pop().await {
                     if let Err(e) = sink.send(message).await {
                         debug!("Failed to send to {peer_addr}: {e}");
                         break;
                     }
                 }
+                queue.close();
                 let _ = sink.flush().await;
             })
         };
 
         let read_task = {
             let read_peers = Arc::clone(&peers);
+            let read_connections = Arc::clone(&connections);
             let mut stream = FramedRead::new(reader, codec);
+            let limiter = rate_limiter.map(|limiter| limiter.connection());
             tokio::spawn(async move {
+                let forward = |message: Message| {
+                    if let Some(mut peer) = read_peers.get_mut(&peer_addr) {
+                        peer.info.increment_received();
+                    }
+                    message_tx.send((peer_addr, message))
+                };
+                // Whether this side has sent its proof, and the peer's key
+                // once it has proved it.
+                let mut answered = false;
+                let mut bound = None;
+                let mut held = Vec::new();
                 while let Some(result) = stream.next().await {
                     match result {
                         Ok(message) => {
-                            if let Some(mut peer) = read_peers.get_mut(&peer_addr) {
-                                peer.info.increment_received();
+                            if let Some(mut peer) = read_peers.get_mut(&peer_addr)
+                                && peer.info.protocol.is_none()
+                                && let Some(protocol) = stream.decoder().peer_protocol()
+                            {
+                                debug!("Peer {peer_addr} speaks protocol {protocol}");
+                                peer.info.protocol = Some(protocol);
+                            }
+
+                            let class = TrafficClass::of(&message.payload);
+                            let size = stream.decoder().last_frame_size();
+                            let limited = match &limiter {
+                                Some(limiter) if limiter.config().drop_excess => {
+                                    limiter.allow(class, size).err()
+                                }
+                                Some(limiter) => {
+                                    // Not reading while paused leaves the peer's
+                                    // frames in the socket, so TCP flow control
+                                    // slows the sender down to its budget.
+                                    let pause = limiter.reserve(class, size);
+                                    if !pause.is_zero() {
+                                        trace!("Pausing reads from {peer_addr} for {pause:?}");
+                                        tokio::time::sleep(pause).await;
+                                    }
+                                    None
+                                }
+                                None => None,
+                            };
+                            if limited == Some(BudgetExceeded::Global) {
+                                debug!(
+                                    "Global rate limit exceeded, dropping message from {peer_addr}"
+                                );
+                                continue;
+                            }
+                            if let Some(limit) = limited {
+                                warn!(
+                                    "Rate limit exceeded for peer {peer_addr}, dropping message over its {limit}"
+                                );
+                                if penalize(
+                                    &bans,
+                                    &read_peers,
+                                    &read_connections,
+                                    peer_addr,
+                                    Misbehaviour::RateLimited,
+                                ) {
+                                    break;
+                                }
+                                continue;
+                            }
+
+                            let codec = stream.decoder();
+                            if let Some(identity) = &identity
+                                && !answered
+                                && let Some(challenge) = codec.peer_challenge()
+                            {
+                                answered = true;
+                                answer(identity, origin, challenge, &proof_queue, peer_addr);
+                            }
+
+                            if let Payload::ConnectionProof { .. } = message.payload {
+                                match handshake::check(&message, codec.challenge()) {
+                                    Proof::Valid(key) if bound.is_none() => {
+                                        debug!("Peer {peer_addr} proved key {key}");
+                                        bound = Some(key);
+                                        proven.insert(key);
+                                        if let Some(mut peer) = read_peers.get_mut(&peer_addr) {
+                                            peer.info.peer_id = Some(key);
+                                        }
+                                        if bans.is_banned(BanTarget::Peer(key), Instant::now()) {
+                                            debug!(
+                                                "Closing connection {peer_addr} of banned peer {key}"
+                                            );
+                                            break;
+                                        }
+                                        let mut closed = false;
+                                        for message in held.drain(..) {
+                                            closed |= forward(message).await.is_err();
+                                        }
+                                        if closed {
+                                            warn!("Inbound channel closed");
+                                            break;
+                                        }
+                                    }
+                                    Proof::Valid(key) => {
+                                        debug!(
+                                            "Ignoring proof of {key} from {peer_addr}, already bound"
+                                        );
+                                    }
+                                    Proof::Unasked => {
+                                        debug!("Ignoring unasked-for proof from {peer_addr}");
+                                    }
+                                    Proof::Invalid => {
+                                        warn!("Invalid connection proof from {peer_addr}");
+                                        if penalize(
+                                            &bans,
+                                            &read_peers,
+                                            &read_connections,
+                                            peer_addr,
+                                            Misbehaviour::InvalidSignature,
+                                        ) {
+                                            break;
+                                        }
+                                    }
+                                }
+                                continue;
                             }
 
-                            if let Some(ref limiter) = rate_limiter
-                                && !limiter.lock().await.allow_request(peer_addr)
+                            if bound.is_none()
+                                && codec.challenge().is_some()
+                                && codec.peer_challenge().is_some()
                             {
-                                warn!("Rate limit exceeded for peer {peer_addr}, dropping message");
+                                if held.len() >= MAX_HELD {
+                                    debug!(
+                                        "Closing connection from {peer_addr}: too much sent before proving its key"
+                                    );
+                                    break;
+                                }
+                                held.push(message);
                                 continue;
                             }
 
-                            if message_tx.send((peer_addr, message)).await.is_err() {
+                            if forward(message).await.is_err() {
                                 warn!("Inbound channel closed");
                                 break;
                             }
                         }
-                        Err(e) => {
+                        Err(Error::Io(e)) => {
                             debug!("Connection from {peer_addr} closed: {e}");
                             break;
                         }
+                        Err(e) => {
+                            debug!("Closing connection from {peer_addr}: {e}");
+                            penalize(
+                                &bans,
+                                &read_peers,
+                                &read_connections,
+                                peer_addr,
+                                Misbehaviour::MalformedFrame,
+                            );
+                            break;
+                        }
                     }
                 }
             })
@@ -376,6 +970,103 @@ impl Tcp {
     }
 }
 
+/// The codec for a new connection, asking the peer to prove its key if
+/// `handshake` is set.
+fn connection_codec(
+    max_message_size: usize,
+    compression: CompressionConfig,
+    encoding: WireEncoding,
+    handshake: bool,
+) -> MessageCodec {
+    let codec = MessageCodec::with_max_frame_size(max_message_size)
+        .with_compression(compression)
+        .with_encoding(encoding);
+    if handshake {
+        codec.with_handshake()
+    } else {
+        codec
+    }
+}
+
+/// Queue a proof of `identity`'s key, answering the peer's `challenge`.
+fn answer(
+    identity: &Identity,
+    origin: SocketAddr,
+    challenge: u64,
+    queue: &OutboundQueue,
+    peer_addr: SocketAddr,
+) {
+    let proof = match identity.author(origin, 0, Payload::ConnectionProof { challenge }) {
+        Ok(proof) => proof,
+        Err(e) => {
+            warn!("Failed to author connection proof: {e}");
+            return;
+        }
+    };
+    match queue.try_push(proof) {
+        Ok(SendOutcome::Dropped) => debug!("Write queue full for {peer_addr}, dropping proof"),
+        Ok(_) => trace!("Proving key to {peer_addr}"),
+        Err(e) => debug!("Failed to queue proof for {peer_addr}: {e}"),
+    }
+}
+
+/// Drop the connection at `addr` from the registry and stop its tasks,
+/// returning whether it was registered.
+fn close(
+    peers: &DashMap<SocketAddr, Peer>,
+    connections: &DashMap<SocketAddr, ConnectionTask>,
+    addr: SocketAddr,
+) -> bool {
+    let removed = peers.remove(&addr).is_some();
+    if let Some((_, conn)) = connections.remove(&addr) {
+        conn.read_abort.abort();
+        conn.write_abort.abort();
+    }
+    removed
+}
+
+/// Add `misbehaviour` to the score of the peer at `addr`, closing every
+/// connection that is banned as a result. Returns whether the peer was banned.
+fn penalize(
+    bans: &BanList,
+    peers: &DashMap<SocketAddr, Peer>,
+    connections: &DashMap<SocketAddr, ConnectionTask>,
+    addr: SocketAddr,
+    misbehaviour: Misbehaviour,
+) -> bool {
+    let peer_id = peers.get(&addr).and_then(|peer| peer.info.peer_id);
+    let ip = addr.ip().to_canonical();
+    if !bans.penalize(ip, peer_id, misbehaviour, Instant::now()) {
+        return false;
+    }
+    warn!("Banning {ip} for misbehaviour: {misbehaviour}");
+    close_banned(bans, peers, connections);
+    true
+}
+
+/// Close every connection from a banned address or signed for by a banned key.
+fn close_banned(
+    bans: &BanList,
+    peers: &DashMap<SocketAddr, Peer>,
+    connections: &DashMap<SocketAddr, ConnectionTask>,
+) {
+    let now = Instant::now();
+    let banned: Vec<SocketAddr> = peers
+        .iter()
+        .filter(|entry| {
+            let info = &entry.value().info;
+            bans.is_banned(BanTarget::Ip(info.addr.ip().to_canonical()), now)
+                || info
+                    .peer_id
+                    .is_some_and(|id| bans.is_banned(BanTarget::Peer(id), now))
+        })
+        .map(|entry| *entry.key())
+        .collect();
+    for addr in banned {
+        close(peers, connections, addr);
+    }
+}
+
 impl Default for Tcp {
     fn default() -> Self {
         Self::new()
@@ -0,0 +1,199 @@
+//! Verify publish access control: a node drops broadcasts and direct messages
+//! from keys that may not send them, and relays from keys that may not relay
+//! or over connections that proved no key, and picks up grants made while it
+//! runs.
+
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::Bytes;
+use common::{
+    Delivered, connect_proven, init_tracing, stamped_identity, start_recording_node,
+    wait_for_delivery, was_delivered,
+};
+use futures::SinkExt;
+use grapevine::{
+    AclConfig, AclGrant, Identity, Message, MessageCodec, Node, NodeConfigBuilder, Payload,
+    Permissions,
+};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+/// A hand-driven peer: a connection to the hub and the key it signs with.
+struct Peer {
+    framed: Framed<TcpStream, MessageCodec>,
+    addr: SocketAddr,
+    identity: Identity,
+}
+
+impl Peer {
+    /// Connect to `hub_addr` and prove the key, so the hub knows it.
+    async fn connect(hub_addr: SocketAddr) -> Self {
+        let identity = stamped_identity();
+        let (framed, addr) = connect_proven(hub_addr, &identity).await;
+        Self {
+            framed,
+            addr,
+            identity,
+        }
+    }
+
+    fn author(&self, sequence: u64, payload: Payload) -> Message {
+        self.identity
+            .author(self.addr, sequence, payload)
+            .expect("Failed to author")
+    }
+
+    fn broadcast(&self, sequence: u64, data: &'static [u8]) -> Message {
+        self.author(sequence, Payload::Application(Bytes::from_static(data)))
+    }
+
+    async fn send(&mut self, message: Message) {
+        self.framed.send(message).await.expect("Failed to send");
+    }
+}
+
+/// Start a hub enforcing `acl`, recording what it delivers.
+async fn hub(acl: AclConfig) -> (Node, SocketAddr, Delivered) {
+    start_recording_node(NodeConfigBuilder::new().acl(acl)).await
+}
+
+/// A consumer without a grant can neither broadcast nor send direct messages,
+/// until it is granted permission at runtime.
+#[tokio::test(flavor = "multi_thread")]
+async fn consumers_cannot_publish_until_granted() {
+    init_tracing();
+
+    let (hub, hub_addr, delivered) = hub(AclConfig {
+        default: Permissions::NONE,
+        grants: Vec::new(),
+    })
+    .await;
+    let mut producer = Peer::connect(hub_addr).await;
+    let mut consumer = Peer::connect(hub_addr).await;
+    hub.grant(producer.identity.peer_id(), Permissions::ALL);
+
+    let broadcast = consumer.broadcast(1, b"consumer-1");
+    consumer.send(broadcast).await;
+    let direct = consumer.author(
+        0,
+        Payload::DirectMessage {
+            recipient: hub_addr,
+            data: Bytes::from_static(b"consumer-direct"),
+        },
+    );
+    consumer.send(direct).await;
+    let broadcast = producer.broadcast(1, b"producer-1");
+    producer.send(broadcast).await;
+    wait_for_delivery(&delivered, b"producer-1", "the hub delivers").await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!was_delivered(&delivered, b"consumer-1"));
+    assert!(!was_delivered(&delivered, b"consumer-direct"));
+
+    hub.grant(consumer.identity.peer_id(), Permissions::ALL);
+    let broadcast = consumer.broadcast(2, b"consumer-2");
+    consumer.send(broadcast).await;
+    wait_for_delivery(&delivered, b"consumer-2", "the hub delivers").await;
+
+    assert!(hub.remove_grant(consumer.identity.peer_id()));
+    assert_eq!(
+        hub.acl().grants,
+        vec![AclGrant {
+            peer: producer.identity.peer_id(),
+            permissions: Permissions::ALL,
+        }]
+    );
+
+    hub.shutdown().await.ok();
+}
+
+/// A producer's broadcast passed on by a key that may not relay is dropped
+/// without being remembered, so the producer's own copy still gets through.
+#[tokio::test(flavor = "multi_thread")]
+async fn relays_need_permission() {
+    init_tracing();
+
+    let (hub, hub_addr, delivered) = hub(AclConfig {
+        default: Permissions {
+            direct: true,
+            ..Permissions::NONE
+        },
+        grants: Vec::new(),
+    })
+    .await;
+    let mut producer = Peer::connect(hub_addr).await;
+    let mut relay = Peer::connect(hub_addr).await;
+    hub.grant(
+        producer.identity.peer_id(),
+        Permissions {
+            broadcast: true,
+            ..Permissions::NONE
+        },
+    );
+
+    let broadcast = producer.broadcast(1, b"relayed");
+    relay.send(broadcast.clone()).await;
+    let direct = relay.author(
+        0,
+        Payload::DirectMessage {
+            recipient: hub_addr,
+            data: Bytes::from_static(b"barrier"),
+        },
+    );
+    relay.send(direct).await;
+    wait_for_delivery(&delivered, b"barrier", "the hub delivers").await;
+    assert!(!was_delivered(&delivered, b"relayed"));
+
+    producer.send(broadcast).await;
+    wait_for_delivery(&delivered, b"relayed", "the hub delivers").await;
+
+    hub.shutdown().await.ok();
+}
+
+/// A connection that proved no key could be passing on anyone's messages, so
+/// a broadcast relayed over it is held to the default permissions, whatever
+/// key signed it.
+#[tokio::test(flavor = "multi_thread")]
+async fn relays_over_unproven_connections_are_held_to_the_default() {
+    init_tracing();
+
+    let (hub, hub_addr, delivered) = hub(AclConfig {
+        default: Permissions {
+            direct: true,
+            ..Permissions::NONE
+        },
+        grants: Vec::new(),
+    })
+    .await;
+    let mut producer = Peer::connect(hub_addr).await;
+    hub.grant(producer.identity.peer_id(), Permissions::ALL);
+
+    let stream = TcpStream::connect(hub_addr)
+        .await
+        .expect("Failed to connect");
+    let addr = stream.local_addr().expect("No local address");
+    let mut unproven = Peer {
+        framed: Framed::new(stream, MessageCodec::new()),
+        addr,
+        identity: stamped_identity(),
+    };
+    let broadcast = producer.broadcast(1, b"relayed");
+    unproven.send(broadcast.clone()).await;
+    let direct = unproven.author(
+        0,
+        Payload::DirectMessage {
+            recipient: hub_addr,
+            data: Bytes::from_static(b"barrier"),
+        },
+    );
+    unproven.send(direct).await;
+    wait_for_delivery(&delivered, b"barrier", "the hub delivers").await;
+    assert!(!was_delivered(&delivered, b"relayed"));
+
+    producer.send(broadcast).await;
+    wait_for_delivery(&delivered, b"relayed", "the hub delivers").await;
+
+    hub.shutdown().await.ok();
+}
@@ -0,0 +1,90 @@
+//! Verify that adaptive mode sizes fanout to the cluster it discovers and
+//! backs anti-entropy off while there is nothing to repair.
+
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::{READY_TIMEOUT, init_tracing, wait_until};
+use grapevine::{AdaptiveConfig, AntiEntropyConfig, Node, NodeConfigBuilder};
+
+/// Start a node in adaptive mode, bootstrapping from `bootstrap`.
+async fn adaptive_node(bootstrap: Option<SocketAddr>, config: AdaptiveConfig) -> Node {
+    let mut builder = NodeConfigBuilder::new()
+        .anti_entropy(AntiEntropyConfig {
+            interval: Duration::from_secs(1),
+            ..AntiEntropyConfig::default()
+        })
+        .adaptive(config);
+    if let Some(peer) = bootstrap {
+        builder = builder.add_bootstrap_peer(peer);
+    }
+    let node = Node::new(builder.build().expect("Failed to build config"))
+        .await
+        .expect("Failed to create node");
+    node.start().await.expect("Failed to start node");
+    node
+}
+
+fn enabled() -> AdaptiveConfig {
+    AdaptiveConfig {
+        enabled: true,
+        ..AdaptiveConfig::default()
+    }
+}
+
+/// Six nodes each estimate the cluster's size and gossip to `ceil(ln 6 + 2)`
+/// peers.
+#[tokio::test(flavor = "multi_thread")]
+async fn fanout_follows_the_estimated_cluster_size() {
+    init_tracing();
+
+    let first = adaptive_node(None, enabled()).await;
+    assert_eq!(first.adaptive_stats().cluster_size, 1);
+    assert_eq!(first.adaptive_stats().fanout, 2);
+
+    let addr = first.local_addr().await.expect("No local address");
+    let mut nodes = vec![first];
+    for _ in 0..5 {
+        nodes.push(adaptive_node(Some(addr), enabled()).await);
+    }
+
+    wait_until("every node counts the whole cluster", READY_TIMEOUT, || {
+        nodes
+            .iter()
+            .all(|node| node.adaptive_stats().cluster_size == 6)
+    })
+    .await;
+    for node in &nodes {
+        assert_eq!(node.adaptive_stats().fanout, 4);
+    }
+
+    for node in &nodes {
+        node.shutdown().await.ok();
+    }
+}
+
+/// With nothing to repair, each anti-entropy round lengthens the interval up to
+/// the configured maximum.
+#[tokio::test(flavor = "multi_thread")]
+async fn idle_anti_entropy_backs_off_to_its_maximum() {
+    init_tracing();
+
+    let config = AdaptiveConfig {
+        min_anti_entropy_interval: Duration::from_secs(1),
+        max_anti_entropy_interval: Duration::from_secs(2),
+        ..enabled()
+    };
+    let first = adaptive_node(None, config.clone()).await;
+    let addr = first.local_addr().await.expect("No local address");
+    let second = adaptive_node(Some(addr), config).await;
+
+    wait_until("anti-entropy backs off", READY_TIMEOUT, || {
+        first.adaptive_stats().anti_entropy_interval == Duration::from_secs(2)
+    })
+    .await;
+
+    first.shutdown().await.ok();
+    second.shutdown().await.ok();
+}
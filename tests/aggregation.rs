@@ -0,0 +1,86 @@
+//! Verify that gossip aggregation gives every node an estimate of a metric
+//! across the cluster.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{init_tracing, wait_for_peers, wait_until};
+use grapevine::{AggregationConfig, Error, Node, NodeConfigBuilder};
+
+/// Aggregation runs one round per gossip interval, which is at least a second.
+const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Start a node gossiping as often as allowed, bootstrapping from `bootstrap`.
+async fn aggregating_node(bootstrap: Option<std::net::SocketAddr>) -> Node {
+    let mut builder = NodeConfigBuilder::new()
+        .gossip_interval(Duration::from_secs(1))
+        .aggregation(AggregationConfig {
+            rounds: 20,
+            count_samples: 256,
+        });
+    if let Some(peer) = bootstrap {
+        builder = builder.add_bootstrap_peer(peer);
+    }
+    let node = Node::new(builder.build().expect("Failed to build config"))
+        .await
+        .expect("Failed to create node");
+    node.start().await.expect("Failed to start node");
+    node
+}
+
+/// Four nodes contributing 10, 20, 30 and 40 all converge on the aggregates.
+#[tokio::test(flavor = "multi_thread")]
+async fn every_node_estimates_the_cluster_aggregate() {
+    init_tracing();
+
+    let first = aggregating_node(None).await;
+    let addr = first.local_addr().await.expect("No local address");
+    let mut nodes = vec![first];
+    for _ in 0..3 {
+        nodes.push(aggregating_node(Some(addr)).await);
+    }
+    wait_for_peers(&nodes[0], 3, "every node connects").await;
+
+    for (value, node) in [10.0, 20.0, 30.0, 40.0].into_iter().zip(&nodes) {
+        node.contribute("queue_depth", value)
+            .expect("Failed to contribute");
+    }
+
+    wait_until("every node converges", CONVERGENCE_TIMEOUT, || {
+        nodes.iter().all(|node| {
+            node.estimate("queue_depth").is_some_and(|estimate| {
+                (estimate.average - 25.0).abs() < 1.0
+                    && estimate.min == 10.0
+                    && estimate.max == 40.0
+                    && (estimate.count - 4.0).abs() < 1.0
+            })
+        })
+    })
+    .await;
+
+    for node in &nodes {
+        node.shutdown().await.ok();
+    }
+}
+
+/// A NaN or infinite contribution is refused.
+#[tokio::test(flavor = "multi_thread")]
+async fn non_finite_contributions_are_refused() {
+    init_tracing();
+
+    let node = aggregating_node(None).await;
+    assert!(matches!(
+        node.contribute("load", f64::NAN),
+        Err(Error::NonFiniteContribution(metric)) if metric == "load"
+    ));
+    assert!(node.estimate("load").is_none());
+
+    node.contribute("load", 2.5).expect("Failed to contribute");
+    assert_eq!(
+        node.estimate("load").map(|estimate| estimate.average),
+        Some(2.5)
+    );
+
+    node.shutdown().await.ok();
+}
@@ -1,15 +1,23 @@
 //! Verify that a message the epidemic push fails to deliver is
-//! still reconciled through the digest exchange.
+//! still reconciled through the digest exchange, and that a gapped history is
+//! reconciled without resending what the peer already holds.
 
 mod common;
 
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::time::{Duration, Instant};
 
 use bytes::Bytes;
-use common::init_tracing;
-use grapevine::{AntiEntropyConfig, EpidemicConfig, Node, NodeConfigBuilder};
+use common::{READY_TIMEOUT, init_tracing, wait_for_peers, wait_until};
+use futures::{SinkExt, StreamExt};
+use grapevine::{
+    AntiEntropyConfig, EpidemicConfig, EvictionPolicy, Identity, Message, MessageCodec,
+    MessageStoreConfig, Node, NodeConfigBuilder, Payload,
+};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
 
 fn no_forwarding() -> EpidemicConfig {
     EpidemicConfig {
@@ -23,6 +31,7 @@ fn brisk_anti_entropy() -> AntiEntropyConfig {
         interval: Duration::from_millis(500),
         fanout: 3,
         enabled: true,
+        ..AntiEntropyConfig::default()
     }
 }
 
@@ -121,3 +130,210 @@ async fn message_missed_by_epidemic_is_recovered_by_anti_entropy() {
     receiver.shutdown().await.ok();
     hub.shutdown().await.ok();
 }
+
+/// Send `digest` from a hand-driven peer and read the node's replies until its
+/// pull request arrives. Returns how many messages the node pushed first.
+async fn exchange(
+    peer: &mut Framed<TcpStream, MessageCodec>,
+    digest: Message,
+    is_pull: impl Fn(&Payload) -> bool,
+) -> usize {
+    peer.send(digest).await.expect("Failed to send digest");
+    let mut pushed = 0;
+    loop {
+        let message = tokio::time::timeout(READY_TIMEOUT, peer.next())
+            .await
+            .expect("timed out waiting for the node's pull request")
+            .expect("connection closed")
+            .expect("undecodable frame");
+        match message.payload {
+            Payload::MessageResponse { messages } => pushed += messages.len(),
+            ref payload if is_pull(payload) => return pushed,
+            _ => {}
+        }
+    }
+}
+
+/// A peer holding sequences `0` and `2..50` of its own broadcasts, of which
+/// the node holds the same set, is told nothing by a range digest: the node
+/// pushes no messages and sends a few hundred bytes. The same state summarized
+/// as a version vector (contiguous prefix `1`) makes the node resend every
+/// message past the gap.
+#[tokio::test(flavor = "multi_thread")]
+async fn range_digest_reconciles_a_gapped_history_without_resending() {
+    init_tracing();
+
+    const PAYLOAD_SIZE: usize = 1024;
+    let sequences: Vec<u64> = std::iter::once(0).chain(2..50).collect();
+
+    let node = Node::new(
+        NodeConfigBuilder::new()
+            .gossip_interval(Duration::from_secs(1))
+            .anti_entropy(AntiEntropyConfig {
+                enabled: false,
+                ..AntiEntropyConfig::default()
+            })
+            .build()
+            .expect("Failed to build config"),
+    )
+    .await
+    .expect("Failed to create node");
+    let received = Arc::new(AtomicU32::new(0));
+    let counter = Arc::clone(&received);
+    node.on_message(move |_origin, _data| {
+        counter.fetch_add(1, Ordering::Relaxed);
+    })
+    .await;
+    node.start().await.expect("Failed to start node");
+    let node_addr = node.local_addr().await.expect("No local address");
+
+    let stream = TcpStream::connect(node_addr)
+        .await
+        .expect("Failed to connect");
+    let origin: SocketAddr = stream.local_addr().expect("No local address");
+    let mut peer = Framed::new(stream, MessageCodec::new());
+    let identity = Identity::generate();
+
+    for &sequence in &sequences {
+        let message = identity
+            .author(
+                origin,
+                sequence,
+                Payload::Application(Bytes::from(vec![0xab; PAYLOAD_SIZE])),
+            )
+            .expect("Failed to author");
+        peer.send(message).await.expect("Failed to send");
+    }
+    wait_until("the node to hold every broadcast", READY_TIMEOUT, || {
+        received.load(Ordering::Relaxed) == u32::try_from(sequences.len()).unwrap()
+    })
+    .await;
+
+    // Range digests are only sent once the node has advertised its protocol,
+    // which it does with its first frame (a heartbeat).
+    while peer.codec().peer_protocol().is_none() {
+        tokio::time::timeout(READY_TIMEOUT, peer.next())
+            .await
+            .expect("timed out waiting for the node's first frame");
+    }
+
+    let before = node.traffic().bytes_sent;
+    let digest = identity
+        .author(
+            origin,
+            0,
+            Payload::RangeDigest {
+                held: vec![(origin, vec![(0, 1), (2, 50)])],
+            },
+        )
+        .expect("Failed to author");
+    let pushed = exchange(&mut peer, digest, |payload| {
+        matches!(payload, Payload::RangeRequest { .. })
+    })
+    .await;
+    let range_bytes = node.traffic().bytes_sent - before;
+    assert_eq!(pushed, 0, "range digest: nothing is missing");
+    assert!(
+        range_bytes < 1024,
+        "range digest exchange cost {range_bytes} bytes"
+    );
+
+    let before = node.traffic().bytes_sent;
+    let digest = identity
+        .author(
+            origin,
+            0,
+            Payload::AntiEntropyDigest {
+                version_vector: vec![(origin, 1)],
+            },
+        )
+        .expect("Failed to author");
+    let pushed = exchange(&mut peer, digest, |payload| {
+        matches!(payload, Payload::MessageRequest { .. })
+    })
+    .await;
+    let vector_bytes = node.traffic().bytes_sent - before;
+    assert_eq!(
+        pushed,
+        sequences.len() - 1,
+        "version vector: everything past the gap"
+    );
+    assert!(
+        vector_bytes > u64::try_from((sequences.len() - 1) * PAYLOAD_SIZE).unwrap(),
+        "version vector exchange cost only {vector_bytes} bytes"
+    );
+
+    node.shutdown().await.ok();
+}
+
+/// A receiver that retains only four messages still remembers every id it has
+/// seen: anti-entropy rounds with the broadcaster, which holds all twenty,
+/// neither resend the evicted messages nor deliver them a second time.
+#[tokio::test(flavor = "multi_thread")]
+async fn evicted_messages_are_not_redelivered() {
+    init_tracing();
+
+    const BROADCASTS: u32 = 20;
+    const RETAINED: usize = 4;
+
+    let sender = Node::new(
+        NodeConfigBuilder::new()
+            .anti_entropy(brisk_anti_entropy())
+            .build()
+            .expect("Failed to build config"),
+    )
+    .await
+    .expect("Failed to create sender");
+    sender.start().await.expect("Failed to start sender");
+    let sender_addr = sender.local_addr().await.expect("No local address");
+
+    let receiver = Node::new(
+        NodeConfigBuilder::new()
+            .add_bootstrap_peer(sender_addr)
+            .anti_entropy(brisk_anti_entropy())
+            .message_store(MessageStoreConfig {
+                max_messages: RETAINED,
+                eviction: EvictionPolicy::Oldest,
+                ..MessageStoreConfig::default()
+            })
+            .build()
+            .expect("Failed to build config"),
+    )
+    .await
+    .expect("Failed to create receiver");
+    let received = Arc::new(AtomicU32::new(0));
+    let counter = Arc::clone(&received);
+    receiver
+        .on_message(move |_origin, _data| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        })
+        .await;
+    receiver.start().await.expect("Failed to start receiver");
+
+    wait_for_peers(&sender, 1, "the receiver connects").await;
+    for i in 0..BROADCASTS {
+        sender
+            .broadcast(Bytes::from(format!("message {i}")))
+            .await
+            .expect("Failed to broadcast");
+    }
+    wait_until("the receiver to see every broadcast", READY_TIMEOUT, || {
+        received.load(Ordering::Relaxed) >= BROADCASTS
+    })
+    .await;
+
+    // Several reconciliation rounds in both directions.
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    assert_eq!(received.load(Ordering::Relaxed), BROADCASTS);
+    let stats = receiver.message_store_stats();
+    assert_eq!(stats.seen, usize::try_from(BROADCASTS).unwrap());
+    assert_eq!(stats.retained, RETAINED);
+    assert_eq!(
+        stats.evicted,
+        u64::from(BROADCASTS) - u64::try_from(RETAINED).unwrap()
+    );
+
+    sender.shutdown().await.ok();
+    receiver.shutdown().await.ok();
+}
@@ -9,7 +9,7 @@ use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 use common::init_tracing;
-use grapevine::{Identity, Node, NodeConfigBuilder, Payload, Tcp};
+use grapevine::{ClockConfig, HybridClock, Identity, Node, NodeConfigBuilder, Payload, Tcp};
 
 /// Block until the recorded delivery set satisfies `predicate`, or fail.
 async fn wait_for_delivery(
@@ -126,3 +126,119 @@ async fn forged_origin_message_is_rejected() {
         "a message spoofing a pinned origin must be rejected, got {records:?}"
     );
 }
+
+/// With stamping on, every message carries its origin's signed clock stamp to
+/// the application, and the receiver's clock moves past it, so the receiver's
+/// next stamp orders after the message whatever the two wall clocks say.
+#[tokio::test(flavor = "multi_thread")]
+async fn clock_stamps_reach_the_application_and_advance_the_receiver() {
+    init_tracing();
+
+    let stamps = Arc::new(Mutex::new(Vec::new()));
+    let recorder = Arc::clone(&stamps);
+    let receiver = Node::new(NodeConfigBuilder::new().build().expect("receiver config"))
+        .await
+        .expect("create receiver");
+    receiver
+        .on_delivered(move |message| {
+            recorder
+                .lock()
+                .expect("record lock")
+                .push(message.timestamp());
+        })
+        .await;
+    receiver.start().await.expect("start receiver");
+    let receiver_addr = receiver.local_addr().await.expect("receiver address");
+
+    let sender = Node::new(
+        NodeConfigBuilder::new()
+            .add_bootstrap_peer(receiver_addr)
+            .clock(ClockConfig {
+                signed: true,
+                ..ClockConfig::default()
+            })
+            .build()
+            .expect("sender config"),
+    )
+    .await
+    .expect("create sender");
+    sender.start().await.expect("start sender");
+    common::wait_for_peers(&receiver, 1, "sender connects").await;
+
+    let before = sender.now();
+    sender
+        .broadcast(Bytes::from_static(b"stamped"))
+        .await
+        .expect("broadcast");
+    common::wait_until(
+        "the broadcast to be delivered",
+        common::READY_TIMEOUT,
+        || !stamps.lock().expect("record lock").is_empty(),
+    )
+    .await;
+
+    let stamp = stamps.lock().expect("record lock")[0].expect("message is stamped");
+    assert!(stamp > before);
+    assert!(receiver.now() > stamp);
+
+    sender.shutdown().await.ok();
+    receiver.shutdown().await.ok();
+}
+
+/// A relay cannot move a message in time: altering the stamp breaks the
+/// signature, and the message is dropped.
+#[tokio::test(flavor = "multi_thread")]
+async fn tampered_clock_stamp_is_rejected() {
+    init_tracing();
+
+    let delivered: Arc<Mutex<Vec<Bytes>>> = Arc::new(Mutex::new(Vec::new()));
+    let recorder = Arc::clone(&delivered);
+    let victim = Node::new(NodeConfigBuilder::new().build().expect("victim config"))
+        .await
+        .expect("create victim");
+    victim
+        .on_message(move |_origin, data| {
+            recorder.lock().expect("record lock").push(data);
+        })
+        .await;
+    victim.start().await.expect("start victim");
+    let victim_addr = victim.local_addr().await.expect("victim address");
+
+    let clock = Arc::new(HybridClock::new(Duration::from_secs(60)));
+    let author = Identity::generate().with_clock(clock);
+    let origin = "127.0.0.1:1".parse().unwrap();
+    let relay = Tcp::new();
+    relay.connect(victim_addr).await.expect("relay connect");
+
+    let mut tampered = author
+        .author(
+            origin,
+            0,
+            Payload::Application(Bytes::from_static(b"tampered")),
+        )
+        .expect("author");
+    tampered.id.timestamp += 1 << 16;
+    relay
+        .send(victim_addr, tampered)
+        .await
+        .expect("send tampered");
+
+    let honest = author
+        .author(
+            origin,
+            1,
+            Payload::Application(Bytes::from_static(b"honest")),
+        )
+        .expect("author");
+    relay.send(victim_addr, honest).await.expect("send honest");
+
+    wait_for_delivery("the honest message", &delivered, |seen| !seen.is_empty()).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(
+        *delivered.lock().expect("record lock"),
+        [Bytes::from_static(b"honest")]
+    );
+
+    relay.shutdown().await;
+    victim.shutdown().await.ok();
+}
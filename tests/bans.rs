@@ -0,0 +1,204 @@
+//! Verify misbehaviour bans: a peer sending bad signatures or undecodable
+//! frames is disconnected and banned by address and key, and the ban list API
+//! bans and unbans connected peers.
+
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use bytes::Bytes;
+use common::{
+    READY_TIMEOUT, connect_proven, init_tracing, is_closed, stamped_identity, start_node,
+    wait_for_peer_addr, wait_for_peers, wait_until,
+};
+use futures::SinkExt;
+use grapevine::{
+    BanReason, BanTarget, Misbehaviour, MisbehaviourConfig, NodeConfigBuilder, Payload,
+    ReconnectConfig,
+};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+const LOOPBACK: BanTarget = BanTarget::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+/// A peer that has proven its key and then sends messages whose signatures do
+/// not verify is banned by address and by key, and reconnects
+/// only once both bans are lifted.
+#[tokio::test(flavor = "multi_thread")]
+async fn invalid_signatures_ban_the_address_and_key() {
+    init_tracing();
+
+    let (hub, hub_addr) = start_node(NodeConfigBuilder::new().misbehaviour(MisbehaviourConfig {
+        ban_threshold: 40,
+        ..MisbehaviourConfig::default()
+    }))
+    .await;
+    let identity = stamped_identity();
+    let (mut peer, own_addr) = connect_proven(hub_addr, &identity).await;
+    // Scores decay as they go, so it takes a third to pass forty points.
+    for sequence in 1..=3 {
+        let mut forged = identity
+            .author(
+                own_addr,
+                sequence,
+                Payload::Application(Bytes::from_static(b"forged")),
+            )
+            .expect("Failed to author");
+        forged.id.timestamp += 1 << 16;
+        peer.send(forged).await.expect("Failed to send");
+    }
+
+    let mut stream = peer.into_inner();
+    assert!(
+        is_closed(&mut stream, READY_TIMEOUT).await,
+        "misbehaving peer was kept"
+    );
+    let key = BanTarget::Peer(identity.peer_id());
+    let mut bans = hub.bans();
+    bans.sort_by_key(|ban| ban.target != LOOPBACK);
+    assert_eq!(
+        bans.iter().map(|ban| ban.target).collect::<Vec<_>>(),
+        vec![LOOPBACK, key]
+    );
+    let reason = BanReason::Misbehaviour(Misbehaviour::InvalidSignature);
+    assert!(bans.iter().all(|ban| ban.reason == reason));
+
+    let mut refused = TcpStream::connect(hub_addr)
+        .await
+        .expect("Failed to connect");
+    assert!(
+        is_closed(&mut refused, READY_TIMEOUT).await,
+        "banned address was admitted"
+    );
+
+    assert!(hub.unban(LOOPBACK));
+    assert!(hub.unban(key));
+    assert!(hub.bans().is_empty());
+    let admitted = TcpStream::connect(hub_addr)
+        .await
+        .expect("Failed to connect");
+    let admitted_addr = admitted.local_addr().expect("No local address");
+    wait_for_peer_addr(&hub, admitted_addr, "unbanned address is admitted").await;
+
+    hub.shutdown().await.ok();
+}
+
+/// A connection keeps the key its peer proved at handshake: a second proof and
+/// messages signed by another key do not relabel it, so the peer's misbehaviour
+/// bans its own key and never the one it tried to take on.
+#[tokio::test(flavor = "multi_thread")]
+async fn relabel_attempts_are_ignored() {
+    init_tracing();
+
+    let (hub, hub_addr) = start_node(NodeConfigBuilder::new().misbehaviour(MisbehaviourConfig {
+        ban_threshold: 40,
+        ..MisbehaviourConfig::default()
+    }))
+    .await;
+    let identity = stamped_identity();
+    let (mut peer, own_addr) = connect_proven(hub_addr, &identity).await;
+
+    let victim = stamped_identity();
+    let challenge = peer.codec().peer_challenge().expect("No challenge");
+    let proof = victim
+        .author(own_addr, 0, Payload::ConnectionProof { challenge })
+        .expect("Failed to author");
+    peer.send(proof).await.expect("Failed to send");
+    let heartbeat = victim
+        .author(own_addr, 0, Payload::Heartbeat { from: own_addr })
+        .expect("Failed to author");
+    peer.send(heartbeat).await.expect("Failed to send");
+    for sequence in 1..=3 {
+        let mut forged = victim
+            .author(
+                own_addr,
+                sequence,
+                Payload::Application(Bytes::from_static(b"forged")),
+            )
+            .expect("Failed to author");
+        forged.id.timestamp += 1 << 16;
+        peer.send(forged).await.expect("Failed to send");
+    }
+
+    let mut stream = peer.into_inner();
+    assert!(
+        is_closed(&mut stream, READY_TIMEOUT).await,
+        "misbehaving peer was kept"
+    );
+    let mut targets = hub.bans().iter().map(|ban| ban.target).collect::<Vec<_>>();
+    targets.sort_by_key(|target| *target != LOOPBACK);
+    assert_eq!(targets, vec![LOOPBACK, BanTarget::Peer(identity.peer_id())]);
+
+    hub.shutdown().await.ok();
+}
+
+/// Frames that cannot be decoded count against the address that sent them,
+/// across connections.
+#[tokio::test(flavor = "multi_thread")]
+async fn undecodable_frames_ban_the_address() {
+    init_tracing();
+
+    let (hub, hub_addr) = start_node(NodeConfigBuilder::new()).await;
+    // Scores decay as they go, so it takes a third to pass a hundred points.
+    for _ in 0..3 {
+        let mut stream = TcpStream::connect(hub_addr)
+            .await
+            .expect("Failed to connect");
+        stream
+            .write_all(&[0, 0, 0, 8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])
+            .await
+            .expect("Failed to send");
+        assert!(
+            is_closed(&mut stream, READY_TIMEOUT).await,
+            "garbage was not rejected"
+        );
+    }
+
+    wait_until("the address is banned", READY_TIMEOUT, || {
+        !hub.bans().is_empty()
+    })
+    .await;
+    let ban = hub.bans()[0];
+    assert_eq!(ban.target, LOOPBACK);
+    assert_eq!(
+        ban.reason,
+        BanReason::Misbehaviour(Misbehaviour::MalformedFrame)
+    );
+
+    hub.shutdown().await.ok();
+}
+
+/// Banning a connected peer's address disconnects it and keeps it out until
+/// the ban is lifted.
+#[tokio::test(flavor = "multi_thread")]
+async fn banned_peers_are_disconnected_until_unbanned() {
+    init_tracing();
+
+    let (hub, hub_addr) = start_node(NodeConfigBuilder::new()).await;
+    let (peer, _) = start_node(
+        NodeConfigBuilder::new()
+            .add_bootstrap_peer(hub_addr)
+            .reconnect(ReconnectConfig {
+                max_backoff: Duration::from_millis(500),
+                ..ReconnectConfig::default()
+            }),
+    )
+    .await;
+    wait_for_peers(&hub, 1, "peer connects to hub").await;
+
+    hub.ban(LOOPBACK, Duration::from_secs(60 * 60));
+    assert!(hub.peers().await.is_empty());
+    let ban = hub.bans()[0];
+    assert_eq!(ban.reason, BanReason::Manual);
+
+    // The peer keeps redialing, and is refused every time.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(hub.peers().await.is_empty());
+
+    assert!(hub.unban(LOOPBACK));
+    wait_for_peers(&hub, 1, "peer reconnects once unbanned").await;
+
+    peer.shutdown().await.ok();
+    hub.shutdown().await.ok();
+}
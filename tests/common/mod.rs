@@ -2,14 +2,23 @@
 #![allow(dead_code)]
 
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use grapevine::Node;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use grapevine::{HybridClock, Identity, MessageCodec, Node, NodeConfigBuilder, Payload};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
 
 pub const READY_TIMEOUT: Duration = Duration::from_secs(10);
 
 const POLL_INTERVAL: Duration = Duration::from_millis(25);
 
+/// Application payloads a node has delivered, in delivery order.
+pub type Delivered = Arc<Mutex<Vec<Bytes>>>;
+
 /// Initialize test tracing. Idempotent: subsequent calls are ignored.
 pub fn init_tracing() {
     let _ = tracing_subscriber::fmt()
@@ -57,3 +66,97 @@ pub async fn wait_for_peer_addr(node: &Node, addr: SocketAddr, label: &str) {
         tokio::time::sleep(POLL_INTERVAL).await;
     }
 }
+
+/// Build and start a node from `builder`, returning it with its listening
+/// address.
+pub async fn start_node(builder: NodeConfigBuilder) -> (Node, SocketAddr) {
+    let config = builder.build().expect("Failed to build config");
+    let node = Node::new(config).await.expect("Failed to create node");
+    node.start().await.expect("Failed to start node");
+    let addr = node.local_addr().await.expect("No local address");
+    (node, addr)
+}
+
+/// Like [`start_node`], also recording every application payload the node
+/// delivers.
+pub async fn start_recording_node(builder: NodeConfigBuilder) -> (Node, SocketAddr, Delivered) {
+    let config = builder.build().expect("Failed to build config");
+    let node = Node::new(config).await.expect("Failed to create node");
+    let delivered = Delivered::default();
+    let recorder = Arc::clone(&delivered);
+    node.on_message(move |_origin, data| {
+        recorder.lock().expect("record lock").push(data);
+    })
+    .await;
+    node.start().await.expect("Failed to start node");
+    let addr = node.local_addr().await.expect("No local address");
+    (node, addr, delivered)
+}
+
+/// Whether `data` is among what was `delivered`.
+pub fn was_delivered(delivered: &Mutex<Vec<Bytes>>, data: &'static [u8]) -> bool {
+    delivered
+        .lock()
+        .expect("record lock")
+        .iter()
+        .any(|d| d == data)
+}
+
+/// Wait until `data` is among what was `delivered`.
+pub async fn wait_for_delivery(delivered: &Mutex<Vec<Bytes>>, data: &'static [u8], label: &str) {
+    wait_until(label, READY_TIMEOUT, || was_delivered(delivered, data)).await;
+}
+
+/// Whether the node at the other end closes `stream` within `timeout`.
+pub async fn is_closed(stream: &mut TcpStream, timeout: Duration) -> bool {
+    let mut buf = [0; 1024];
+    let closed = async {
+        loop {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+        }
+    };
+    tokio::time::timeout(timeout, closed).await.is_ok()
+}
+
+/// A fresh identity that stamps what it signs with a hybrid clock.
+pub fn stamped_identity() -> Identity {
+    Identity::generate().with_clock(Arc::new(HybridClock::new(Duration::from_secs(60))))
+}
+
+/// Connect to the node at `addr` and prove the connection holds `identity`'s
+/// key, as a node does at handshake, returning the connection and its local
+/// address.
+pub async fn connect_proven(
+    addr: SocketAddr,
+    identity: &Identity,
+) -> (Framed<TcpStream, MessageCodec>, SocketAddr) {
+    let stream = TcpStream::connect(addr).await.expect("Failed to connect");
+    let local_addr = stream.local_addr().expect("No local address");
+    let mut framed = Framed::new(stream, MessageCodec::new().with_handshake());
+    let heartbeat = identity
+        .author(local_addr, 0, Payload::Heartbeat { from: local_addr })
+        .expect("Failed to author");
+    framed.send(heartbeat).await.expect("Failed to send");
+    let challenge = tokio::time::timeout(READY_TIMEOUT, async {
+        loop {
+            if let Some(challenge) = framed.codec().peer_challenge() {
+                return challenge;
+            }
+            framed
+                .next()
+                .await
+                .expect("Connection closed")
+                .expect("Failed to decode");
+        }
+    })
+    .await
+    .expect("timed out waiting for the node's challenge");
+    let proof = identity
+        .author(local_addr, 0, Payload::ConnectionProof { challenge })
+        .expect("Failed to author");
+    framed.send(proof).await.expect("Failed to send");
+    (framed, local_addr)
+}
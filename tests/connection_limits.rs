@@ -0,0 +1,104 @@
+//! Verify inbound connection limits: one address cannot take more than its
+//! share of a node's slots, and a newcomer to a full node evicts the most
+//! recently connected inbound peer while the longest-connected stays.
+
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::{READY_TIMEOUT, init_tracing, is_closed, start_node, wait_for_peer_addr};
+use grapevine::{ConnectionLimitsConfig, Node, NodeConfigBuilder};
+use tokio::net::TcpStream;
+
+/// Start a hub with `max_peers` slots, limiting inbound peers as `limits`
+/// describes.
+async fn hub(max_peers: usize, limits: ConnectionLimitsConfig) -> (Node, SocketAddr) {
+    start_node(
+        NodeConfigBuilder::new()
+            .max_peers(max_peers)
+            .fanout(1)
+            .connection_limits(limits),
+    )
+    .await
+}
+
+/// Connect to `hub` and wait until it registers the connection.
+async fn connect(hub: &Node, hub_addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(hub_addr)
+        .await
+        .expect("Failed to connect");
+    let addr = stream.local_addr().expect("No local address");
+    wait_for_peer_addr(hub, addr, "hub registers the connection").await;
+    stream
+}
+
+/// With the limits applied to loopback, a third connection from 127.0.0.1 is
+/// closed while the first two stay.
+#[tokio::test(flavor = "multi_thread")]
+async fn connections_from_one_address_are_capped() {
+    init_tracing();
+
+    let (hub, hub_addr) = hub(
+        50,
+        ConnectionLimitsConfig {
+            max_inbound_per_ip: 2,
+            limit_local: true,
+            ..ConnectionLimitsConfig::default()
+        },
+    )
+    .await;
+    let first = connect(&hub, hub_addr).await;
+    let second = connect(&hub, hub_addr).await;
+
+    let mut third = TcpStream::connect(hub_addr)
+        .await
+        .expect("Failed to connect");
+    assert!(
+        is_closed(&mut third, READY_TIMEOUT).await,
+        "third connection was kept"
+    );
+
+    let peers = hub.peers().await;
+    assert_eq!(peers.len(), 2);
+    for stream in [&first, &second] {
+        assert!(peers.contains(&stream.local_addr().expect("No local address")));
+    }
+
+    hub.shutdown().await.ok();
+}
+
+/// A full hub admits a newcomer in place of its youngest inbound peer, and
+/// keeps its protected oldest one.
+#[tokio::test(flavor = "multi_thread")]
+async fn newcomers_evict_the_youngest_unprotected_peer() {
+    init_tracing();
+
+    let (hub, hub_addr) = hub(
+        3,
+        ConnectionLimitsConfig {
+            protected_inbound: 1,
+            ..ConnectionLimitsConfig::default()
+        },
+    )
+    .await;
+    let oldest = connect(&hub, hub_addr).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let middle = connect(&hub, hub_addr).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let mut youngest = connect(&hub, hub_addr).await;
+
+    let newcomer = connect(&hub, hub_addr).await;
+    assert!(
+        is_closed(&mut youngest, READY_TIMEOUT).await,
+        "youngest peer was kept"
+    );
+
+    let peers = hub.peers().await;
+    assert_eq!(peers.len(), 3);
+    for stream in [&oldest, &middle, &newcomer] {
+        assert!(peers.contains(&stream.local_addr().expect("No local address")));
+    }
+
+    hub.shutdown().await.ok();
+}
@@ -0,0 +1,178 @@
+//! Verify that ordered delivery modes hand broadcasts to the application in
+//! order, whatever order they arrive in.
+
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use common::{READY_TIMEOUT, init_tracing, wait_for_peers, wait_until};
+use futures::{SinkExt, StreamExt};
+use grapevine::{
+    DeliveryConfig, DeliveryOrder, Identity, MessageCodec, Node, NodeConfigBuilder, Payload,
+};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+type Delivered = Arc<Mutex<Vec<String>>>;
+
+/// Start a node delivering in `order`, recording each delivered broadcast.
+async fn ordered_node(order: DeliveryOrder, bootstrap: &[SocketAddr]) -> (Node, Delivered) {
+    let mut builder = NodeConfigBuilder::new();
+    for &peer in bootstrap {
+        builder = builder.add_bootstrap_peer(peer);
+    }
+    let node = Node::new(
+        builder
+            .delivery(DeliveryConfig {
+                order,
+                gap_timeout: Duration::from_secs(60),
+                ..DeliveryConfig::default()
+            })
+            .build()
+            .expect("Failed to build config"),
+    )
+    .await
+    .expect("Failed to create node");
+    let delivered = Delivered::default();
+    let log = Arc::clone(&delivered);
+    node.on_message(move |_origin, data| {
+        log.lock()
+            .unwrap()
+            .push(String::from_utf8_lossy(&data).into_owned());
+    })
+    .await;
+    node.start().await.expect("Failed to start node");
+    (node, delivered)
+}
+
+/// A hand-driven connection to `node`, and the address its broadcasts claim.
+async fn raw_peer(node: &Node) -> (Framed<TcpStream, MessageCodec>, SocketAddr) {
+    let addr = node.local_addr().await.expect("No local address");
+    let stream = TcpStream::connect(addr).await.expect("Failed to connect");
+    let origin = stream.local_addr().expect("No local address");
+    (Framed::new(stream, MessageCodec::new()), origin)
+}
+
+/// Sequences 2, 1, 0 from one origin reach the application as 0, 1, 2.
+#[tokio::test(flavor = "multi_thread")]
+async fn fifo_delivers_each_origin_in_sequence_order() {
+    init_tracing();
+
+    let (node, delivered) = ordered_node(DeliveryOrder::Fifo, &[]).await;
+    let (mut peer, origin) = raw_peer(&node).await;
+    let identity = Identity::generate();
+
+    for sequence in [2, 1, 0] {
+        let message = identity
+            .author(
+                origin,
+                sequence,
+                Payload::Application(Bytes::from(sequence.to_string())),
+            )
+            .expect("Failed to author");
+        peer.send(message).await.expect("Failed to send");
+    }
+
+    wait_until(
+        "all three broadcasts to be delivered",
+        READY_TIMEOUT,
+        || delivered.lock().unwrap().len() == 3,
+    )
+    .await;
+    assert_eq!(*delivered.lock().unwrap(), ["0", "1", "2"]);
+    assert_eq!(node.delivery_stats().held, 0);
+
+    node.shutdown().await.ok();
+}
+
+/// A reply from origin B that causally follows origin A's question is held
+/// until the question arrives, even though both origins' sequences are in
+/// order on their own.
+#[tokio::test(flavor = "multi_thread")]
+async fn causal_delivery_waits_for_the_broadcasts_a_reply_depends_on() {
+    init_tracing();
+
+    let (node, delivered) = ordered_node(DeliveryOrder::Causal, &[]).await;
+    let (mut peer, a) = raw_peer(&node).await;
+    // Causal broadcasts are v3-only: wait for the node's hello so the codec
+    // knows the peer understands them.
+    while peer.codec().peer_protocol().is_none() {
+        peer.next()
+            .await
+            .expect("Connection closed")
+            .expect("Bad frame");
+    }
+    let b: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let (alice, bob) = (Identity::generate(), Identity::generate());
+
+    let reply = bob
+        .author(
+            b,
+            0,
+            Payload::CausalApplication {
+                data: Bytes::from("reply"),
+                clock: vec![(a, 1)],
+            },
+        )
+        .expect("Failed to author");
+    peer.send(reply).await.expect("Failed to send");
+
+    wait_until("the reply to be held back", READY_TIMEOUT, || {
+        node.delivery_stats().held == 1
+    })
+    .await;
+    assert!(delivered.lock().unwrap().is_empty());
+
+    let question = alice
+        .author(
+            a,
+            0,
+            Payload::CausalApplication {
+                data: Bytes::from("question"),
+                clock: Vec::new(),
+            },
+        )
+        .expect("Failed to author");
+    peer.send(question).await.expect("Failed to send");
+
+    wait_until("both broadcasts to be delivered", READY_TIMEOUT, || {
+        delivered.lock().unwrap().len() == 2
+    })
+    .await;
+    assert_eq!(*delivered.lock().unwrap(), ["question", "reply"]);
+
+    node.shutdown().await.ok();
+}
+
+/// Broadcasts from a causal node carry its vector clock, and a node in the
+/// default mode still delivers them.
+#[tokio::test(flavor = "multi_thread")]
+async fn causal_broadcasts_reach_immediate_nodes() {
+    init_tracing();
+
+    let (causal, _) = ordered_node(DeliveryOrder::Causal, &[]).await;
+    let causal_addr = causal.local_addr().await.expect("No local address");
+
+    let (immediate, delivered) = ordered_node(DeliveryOrder::Immediate, &[causal_addr]).await;
+    wait_for_peers(&causal, 1, "the immediate node to connect").await;
+    let peer = causal.peers().await[0];
+    wait_until("the protocol to be negotiated", READY_TIMEOUT, || {
+        causal.peer_protocol(peer).is_some()
+    })
+    .await;
+
+    causal
+        .broadcast(Bytes::from("causal"))
+        .await
+        .expect("Failed to broadcast");
+    wait_until("the broadcast to be delivered", READY_TIMEOUT, || {
+        delivered.lock().unwrap().len() == 1
+    })
+    .await;
+
+    causal.shutdown().await.ok();
+    immediate.shutdown().await.ok();
+}
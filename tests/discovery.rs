@@ -0,0 +1,249 @@
+//! Verify that nodes find peers through each discovery provider: a seed file
+//! that changes while they run, DNS SRV and A records served by a local stub
+//! resolver, multicast announcements, and a provider the application adds.
+
+mod common;
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use common::{init_tracing, wait_for_peer_addr};
+use futures::future::BoxFuture;
+use grapevine::{
+    Discovery, DiscoveryConfig, DnsConfig, MulticastConfig, Node, NodeConfigBuilder, Result,
+};
+use tokio::net::UdpSocket;
+
+const INTERVAL: Duration = Duration::from_millis(200);
+
+const TYPE_A: u16 = 1;
+const TYPE_SRV: u16 = 33;
+
+/// Start a node discovering peers as `discovery` describes.
+async fn discovering_node(discovery: DiscoveryConfig) -> Node {
+    let config = NodeConfigBuilder::new()
+        .gossip_interval(Duration::from_secs(1))
+        .discovery(DiscoveryConfig {
+            interval: INTERVAL,
+            ..discovery
+        })
+        .build()
+        .expect("Failed to build config");
+    let node = Node::new(config).await.expect("Failed to create node");
+    node.start().await.expect("Failed to start node");
+    node
+}
+
+async fn plain_node() -> (Node, SocketAddr) {
+    let node = discovering_node(DiscoveryConfig::default()).await;
+    let addr = node.local_addr().await.expect("No local address");
+    (node, addr)
+}
+
+/// A seed file path unique to this test run.
+fn seed_file_path(label: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "grapevine-{label}-{}-{}.seeds",
+        std::process::id(),
+        rand::random::<u32>()
+    ))
+}
+
+/// A DNS server answering, from `records`, the queries for (name, type) it
+/// holds, and every other query with NXDOMAIN.
+async fn stub_resolver(records: HashMap<(String, u16), Vec<Vec<u8>>>) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind stub resolver");
+    let addr = socket.local_addr().expect("No local address");
+    tokio::spawn(async move {
+        let mut buf = [0; 512];
+        while let Ok((len, client)) = socket.recv_from(&mut buf).await {
+            if let Some(response) = answer(&buf[..len], &records) {
+                let _ = socket.send_to(&response, client).await;
+            }
+        }
+    });
+    addr
+}
+
+fn answer(query: &[u8], records: &HashMap<(String, u16), Vec<Vec<u8>>>) -> Option<Vec<u8>> {
+    let mut labels = Vec::new();
+    let mut at = 12;
+    while *query.get(at)? != 0 {
+        let len = usize::from(query[at]);
+        labels.push(String::from_utf8_lossy(query.get(at + 1..at + 1 + len)?).to_lowercase());
+        at += 1 + len;
+    }
+    let question_end = at + 5;
+    let qtype = u16::from_be_bytes([*query.get(at + 1)?, *query.get(at + 2)?]);
+    let rdatas = records.get(&(labels.join("."), qtype));
+
+    let (flags, count) = match rdatas {
+        Some(rdatas) => (0x8180u16, u16::try_from(rdatas.len()).ok()?),
+        None => (0x8183, 0),
+    };
+    let mut response = query[..2].to_vec();
+    for field in [flags, 1, count, 0, 0] {
+        response.extend_from_slice(&field.to_be_bytes());
+    }
+    response.extend_from_slice(query.get(12..question_end)?);
+    for rdata in rdatas.into_iter().flatten() {
+        // The owner is the question's name, at offset 12.
+        response.extend_from_slice(&[0xc0, 12]);
+        response.extend_from_slice(&qtype.to_be_bytes());
+        response.extend_from_slice(&1u16.to_be_bytes());
+        response.extend_from_slice(&60u32.to_be_bytes());
+        response.extend_from_slice(&u16::try_from(rdata.len()).ok()?.to_be_bytes());
+        response.extend_from_slice(rdata);
+    }
+    Some(response)
+}
+
+fn srv_rdata(port: u16, target: &str) -> Vec<u8> {
+    let mut rdata = Vec::new();
+    for field in [10u16, 0, port] {
+        rdata.extend_from_slice(&field.to_be_bytes());
+    }
+    for label in target.split('.') {
+        rdata.push(u8::try_from(label.len()).unwrap());
+        rdata.extend_from_slice(label.as_bytes());
+    }
+    rdata.push(0);
+    rdata
+}
+
+/// A node connects to the peers in its seed file, including those added
+/// while it runs.
+#[tokio::test(flavor = "multi_thread")]
+async fn seed_file_is_reloaded_when_it_changes() {
+    init_tracing();
+
+    let (first, first_addr) = plain_node().await;
+    let (second, second_addr) = plain_node().await;
+    let path = seed_file_path("reload");
+    std::fs::write(&path, format!("# seeds\n{first_addr}\n")).expect("Failed to write seeds");
+
+    let node = discovering_node(DiscoveryConfig {
+        seed_file: Some(path.clone()),
+        ..DiscoveryConfig::default()
+    })
+    .await;
+    wait_for_peer_addr(&node, first_addr, "node reaches the first seed").await;
+
+    std::fs::write(
+        &path,
+        format!("{first_addr}\nlocalhost:{}\n", second_addr.port()),
+    )
+    .expect("Failed to rewrite seeds");
+    wait_for_peer_addr(&node, second_addr, "node reaches the added seed").await;
+
+    for node in [&node, &second, &first] {
+        node.shutdown().await.ok();
+    }
+    std::fs::remove_file(&path).ok();
+}
+
+/// A node finds a seed through the SRV record of a service name, with the
+/// target's address in an A record, and another through the A record of a
+/// name without SRV records.
+#[tokio::test(flavor = "multi_thread")]
+async fn dns_srv_and_a_records_are_followed() {
+    init_tracing();
+
+    let (srv_seed, srv_addr) = plain_node().await;
+    let (a_seed, a_addr) = plain_node().await;
+    let loopback = Ipv4Addr::LOCALHOST.octets().to_vec();
+    let resolver = stub_resolver(HashMap::from([
+        (
+            ("_grapevine._tcp.cluster.test".into(), TYPE_SRV),
+            vec![srv_rdata(srv_addr.port(), "seed.cluster.test")],
+        ),
+        (("seed.cluster.test".into(), TYPE_A), vec![loopback.clone()]),
+        (("plain.cluster.test".into(), TYPE_A), vec![loopback]),
+    ]))
+    .await;
+
+    let srv_node = discovering_node(DiscoveryConfig {
+        dns: Some(DnsConfig::new("_grapevine._tcp.cluster.test", 1).with_nameserver(resolver)),
+        ..DiscoveryConfig::default()
+    })
+    .await;
+    let a_node = discovering_node(DiscoveryConfig {
+        dns: Some(DnsConfig::new("plain.cluster.test.", a_addr.port()).with_nameserver(resolver)),
+        ..DiscoveryConfig::default()
+    })
+    .await;
+
+    wait_for_peer_addr(&srv_node, srv_addr, "SRV record followed").await;
+    wait_for_peer_addr(&a_node, a_addr, "A record followed").await;
+
+    for node in [&srv_node, &a_node, &srv_seed, &a_seed] {
+        node.shutdown().await.ok();
+    }
+}
+
+/// Two nodes announcing on one multicast group connect without any seed.
+#[tokio::test(flavor = "multi_thread")]
+async fn multicast_announcements_find_peers() {
+    init_tracing();
+
+    let port = 20_000 + rand::random::<u16>() % 20_000;
+    let multicast = MulticastConfig {
+        group: SocketAddr::from(([239, 255, 42, 99], port)),
+        interface: Ipv4Addr::LOCALHOST,
+        ..MulticastConfig::default()
+    };
+    let config = DiscoveryConfig {
+        multicast: Some(multicast),
+        ..DiscoveryConfig::default()
+    };
+    let first = discovering_node(config.clone()).await;
+    let second = discovering_node(config).await;
+    let first_addr = first.local_addr().await.expect("No local address");
+    let second_addr = second.local_addr().await.expect("No local address");
+
+    wait_for_peer_addr(&first, second_addr, "first finds second").await;
+    wait_for_peer_addr(&second, first_addr, "second finds first").await;
+
+    first.shutdown().await.ok();
+    second.shutdown().await.ok();
+}
+
+/// A provider returning a fixed list.
+struct Fixed(Vec<SocketAddr>);
+
+impl Discovery for Fixed {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn discover(&self, _local_addr: SocketAddr) -> BoxFuture<'_, Result<Vec<SocketAddr>>> {
+        Box::pin(async move { Ok(self.0.clone()) })
+    }
+}
+
+/// Providers the application adds are polled like configured ones.
+#[tokio::test(flavor = "multi_thread")]
+async fn application_providers_are_polled() {
+    init_tracing();
+
+    let (seed, seed_addr) = plain_node().await;
+    let config = NodeConfigBuilder::new()
+        .discovery(DiscoveryConfig {
+            interval: INTERVAL,
+            ..DiscoveryConfig::default()
+        })
+        .build()
+        .expect("Failed to build config");
+    let node = Node::new(config).await.expect("Failed to create node");
+    node.add_discovery(Fixed(vec![seed_addr]));
+    node.start().await.expect("Failed to start node");
+
+    wait_for_peer_addr(&node, seed_addr, "node reaches the fixed seed").await;
+
+    node.shutdown().await.ok();
+    seed.shutdown().await.ok();
+}
@@ -0,0 +1,158 @@
+//! Verify that expired and retracted broadcasts are not repaired to nodes that
+//! missed them, and that retractions reach the application.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use common::{READY_TIMEOUT, init_tracing, wait_for_peers, wait_until};
+use grapevine::{AntiEntropyConfig, Error, MessageId, Node, NodeConfigBuilder};
+
+/// Frequent reconciliation so a late joiner is repaired quickly.
+fn brisk_anti_entropy() -> AntiEntropyConfig {
+    AntiEntropyConfig {
+        interval: Duration::from_millis(300),
+        ..AntiEntropyConfig::default()
+    }
+}
+
+/// A started node bootstrapping from `bootstrap`, recording the data it
+/// delivers and the ids it is told were retracted.
+async fn recording_node(
+    bootstrap: Option<std::net::SocketAddr>,
+) -> (Node, Arc<Mutex<Vec<Bytes>>>, Arc<Mutex<Vec<MessageId>>>) {
+    let mut builder = NodeConfigBuilder::new().anti_entropy(brisk_anti_entropy());
+    if let Some(peer) = bootstrap {
+        builder = builder.add_bootstrap_peer(peer);
+    }
+    let node = Node::new(builder.build().expect("Failed to build config"))
+        .await
+        .expect("Failed to create node");
+
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&delivered);
+    node.on_message(move |_origin, data| log.lock().unwrap().push(data))
+        .await;
+    let retracted = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&retracted);
+    node.on_retracted(move |retraction| log.lock().unwrap().push(retraction.target))
+        .await;
+
+    node.start().await.expect("Failed to start node");
+    (node, delivered, retracted)
+}
+
+/// A connected node is told of the retraction; a node that joins afterwards
+/// receives the tombstone through anti-entropy but never the retracted message.
+#[tokio::test(flavor = "multi_thread")]
+async fn retracted_broadcast_is_withdrawn_cluster_wide() {
+    init_tracing();
+
+    let (origin, _, _) = recording_node(None).await;
+    let origin_addr = origin.local_addr().await.expect("No local address");
+    let (early, early_delivered, early_retracted) = recording_node(Some(origin_addr)).await;
+    wait_for_peers(&origin, 1, "the early node connects").await;
+
+    let id = origin
+        .broadcast_id(Bytes::from_static(b"regrettable"))
+        .await
+        .expect("Failed to broadcast");
+    wait_until("the early node delivers", READY_TIMEOUT, || {
+        !early_delivered.lock().unwrap().is_empty()
+    })
+    .await;
+
+    let tombstone = origin.retract(id).await.expect("Failed to retract");
+    // Only application broadcasts can be retracted, not the tombstone itself.
+    assert!(matches!(
+        origin.retract(tombstone).await,
+        Err(Error::NotOwnBroadcast(_))
+    ));
+    wait_until("the early node is told", READY_TIMEOUT, || {
+        !early_retracted.lock().unwrap().is_empty()
+    })
+    .await;
+    assert_eq!(early_retracted.lock().unwrap()[0], id);
+
+    let (late, late_delivered, late_retracted) = recording_node(Some(origin_addr)).await;
+    wait_until(
+        "the late node learns of the retraction",
+        READY_TIMEOUT,
+        || !late_retracted.lock().unwrap().is_empty(),
+    )
+    .await;
+    tokio::time::sleep(Duration::from_millis(700)).await;
+    assert!(late_delivered.lock().unwrap().is_empty());
+
+    late.shutdown().await.ok();
+    early.shutdown().await.ok();
+    origin.shutdown().await.ok();
+}
+
+/// Only a node's own broadcasts can be retracted.
+#[tokio::test(flavor = "multi_thread")]
+async fn retracting_another_origins_broadcast_is_refused() {
+    init_tracing();
+
+    let (node, _, _) = recording_node(None).await;
+    let elsewhere = MessageId::new("127.0.0.1:1".parse().unwrap(), 0);
+    assert!(matches!(
+        node.retract(elsewhere).await,
+        Err(Error::NotOwnBroadcast(id)) if id == elsewhere
+    ));
+
+    let local = node.local_addr().await.expect("No local address");
+    let unsent = MessageId::new(local, 0);
+    assert!(matches!(
+        node.retract(unsent).await,
+        Err(Error::NotOwnBroadcast(_))
+    ));
+
+    node.shutdown().await.ok();
+}
+
+/// A node that joins after a broadcast's deadline is repaired with the
+/// broadcasts that are still live, but not the expired one.
+#[tokio::test(flavor = "multi_thread")]
+async fn expired_broadcast_is_not_repaired() {
+    init_tracing();
+
+    let (origin, _, _) = recording_node(None).await;
+    let origin_addr = origin.local_addr().await.expect("No local address");
+    let (early, early_delivered, _) = recording_node(Some(origin_addr)).await;
+    wait_for_peers(&origin, 1, "the early node connects").await;
+
+    origin
+        .broadcast_until(
+            Bytes::from_static(b"fleeting"),
+            SystemTime::now() + Duration::from_millis(500),
+        )
+        .await
+        .expect("Failed to broadcast");
+    origin
+        .broadcast(Bytes::from_static(b"lasting"))
+        .await
+        .expect("Failed to broadcast");
+    wait_until("the early node delivers both", READY_TIMEOUT, || {
+        early_delivered.lock().unwrap().len() == 2
+    })
+    .await;
+
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    let (late, late_delivered, _) = recording_node(Some(origin_addr)).await;
+    wait_until("the late node is repaired", READY_TIMEOUT, || {
+        !late_delivered.lock().unwrap().is_empty()
+    })
+    .await;
+    tokio::time::sleep(Duration::from_millis(700)).await;
+    assert_eq!(
+        *late_delivered.lock().unwrap(),
+        [Bytes::from_static(b"lasting")]
+    );
+
+    late.shutdown().await.ok();
+    early.shutdown().await.ok();
+    origin.shutdown().await.ok();
+}
@@ -0,0 +1,141 @@
+//! Verify key rotation and revocation: a node that rotates its key keeps being
+//! heard across the cluster, and a revoked key is rejected by every node the
+//! revocation reaches.
+
+mod common;
+
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use common::{
+    Delivered, READY_TIMEOUT, init_tracing, stamped_identity, start_recording_node,
+    wait_for_delivery, wait_for_peers, was_delivered,
+};
+use futures::{SinkExt, StreamExt};
+use grapevine::{EpidemicConfig, Identity, MessageCodec, Node, NodeConfigBuilder, Payload};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+/// Start a node bootstrapping from `bootstrap`, recording what it delivers.
+/// The hub always passes rumors on, so what reaches the edge does not depend
+/// on the roll of the epidemic gate.
+async fn node(bootstrap: Option<SocketAddr>) -> (Node, SocketAddr, Delivered) {
+    let mut builder = NodeConfigBuilder::new().epidemic(EpidemicConfig {
+        forward_probability: 1.0,
+    });
+    if let Some(addr) = bootstrap {
+        builder = builder.add_bootstrap_peer(addr);
+    }
+    start_recording_node(builder).await
+}
+
+/// A node that rotates its key is still heard by nodes that pinned the old
+/// one, without being treated as an impostor.
+#[tokio::test(flavor = "multi_thread")]
+async fn rotated_keys_keep_being_heard() {
+    init_tracing();
+
+    let (hub, hub_addr, _) = node(None).await;
+    let (rotating, _, _) = node(Some(hub_addr)).await;
+    let (listener, _, delivered) = node(Some(hub_addr)).await;
+    wait_for_peers(&hub, 2, "both nodes join the hub").await;
+
+    rotating
+        .broadcast("before")
+        .await
+        .expect("Failed to broadcast");
+    wait_for_delivery(&delivered, b"before", "the old key is heard").await;
+
+    let old = rotating.peer_id();
+    let rotation = rotating.rotate_key().await;
+    assert_eq!(rotation.old, old);
+    assert_eq!(rotating.peer_id(), rotation.new);
+
+    rotating
+        .broadcast("after")
+        .await
+        .expect("Failed to broadcast");
+    wait_for_delivery(&delivered, b"after", "the new key is heard").await;
+    assert_eq!(hub.peers().await.len(), 2);
+
+    listener.shutdown().await.ok();
+    rotating.shutdown().await.ok();
+    hub.shutdown().await.ok();
+}
+
+/// A revocation sent to one node is flooded to the others, and every node
+/// that has it drops messages signed with the revoked key.
+#[tokio::test(flavor = "multi_thread")]
+async fn revoked_keys_are_rejected_across_the_cluster() {
+    init_tracing();
+
+    let (hub, hub_addr, hub_delivered) = node(None).await;
+    let (edge, edge_addr, edge_delivered) = node(Some(hub_addr)).await;
+    wait_for_peers(&edge, 1, "edge joins the hub").await;
+
+    let stream = TcpStream::connect(hub_addr)
+        .await
+        .expect("Failed to connect");
+    let own_addr = stream.local_addr().expect("No local address");
+    let mut peer = Framed::new(stream, MessageCodec::new());
+    let stolen = stamped_identity();
+    let bystander = stamped_identity();
+    let bystander_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let author = |identity: &Identity, origin, sequence, payload| {
+        identity
+            .author(origin, sequence, payload)
+            .expect("Failed to author")
+    };
+    let application = |data: &'static [u8]| Payload::Application(Bytes::from_static(data));
+
+    let before = author(&stolen, own_addr, 1, application(b"before"));
+    peer.send(before).await.expect("Failed to send");
+    wait_for_delivery(&hub_delivered, b"before", "the key is heard").await;
+    // Key statements are withheld until the hub's first frame says it can
+    // decode them.
+    tokio::time::timeout(READY_TIMEOUT, peer.next())
+        .await
+        .expect("The hub sent nothing")
+        .expect("Connection closed")
+        .expect("Failed to decode");
+
+    // The bystander's broadcast, behind the revocation on the same connection,
+    // marks the point the hub has applied it and passed it on.
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Clock before epoch");
+    let now = u64::try_from(now.as_millis()).expect("Clock overflow");
+    let statements = Payload::KeyStatements {
+        rotations: Vec::new(),
+        revocations: vec![stolen.revocation(now)],
+    };
+    let revocation = author(&stolen, own_addr, 0, statements);
+    peer.send(revocation).await.expect("Failed to send");
+    let after = author(&stolen, own_addr, 2, application(b"after"));
+    peer.send(after.clone()).await.expect("Failed to send");
+    let marker = author(&bystander, bystander_addr, 1, application(b"marker"));
+    peer.send(marker).await.expect("Failed to send");
+    wait_for_delivery(&edge_delivered, b"marker", "the edge hears the marker").await;
+    assert!(!was_delivered(&hub_delivered, b"after"));
+
+    // The edge heard of the revocation from the hub, so it too drops the key's
+    // messages sent to it directly.
+    let stream = TcpStream::connect(edge_addr)
+        .await
+        .expect("Failed to connect");
+    let mut direct = Framed::new(stream, MessageCodec::new());
+    direct.send(after).await.expect("Failed to send");
+    let marker = author(&bystander, bystander_addr, 2, application(b"marker-2"));
+    direct.send(marker).await.expect("Failed to send");
+    wait_for_delivery(
+        &edge_delivered,
+        b"marker-2",
+        "the edge hears the second marker",
+    )
+    .await;
+    assert!(!was_delivered(&edge_delivered, b"after"));
+
+    edge.shutdown().await.ok();
+    hub.shutdown().await.ok();
+}
@@ -0,0 +1,152 @@
+//! Verify that replicas of the key-value store converge: writes made on both
+//! sides of a partition meet once it heals, and subscribers hear of changes
+//! made elsewhere.
+
+mod common;
+
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::Bytes;
+use common::{READY_TIMEOUT, init_tracing, wait_for_peers, wait_until};
+use grapevine::kv::{Change, Value};
+use grapevine::{AntiEntropyConfig, Error, KvConfig, Node, NodeConfigBuilder};
+
+/// Start a node bootstrapping from `bootstrap`, syncing its store often.
+/// Message anti-entropy is disabled so that only the store's own full-state
+/// sync can carry writes a node missed.
+async fn kv_node(bootstrap: &[SocketAddr]) -> Node {
+    let mut builder = NodeConfigBuilder::new()
+        .anti_entropy(AntiEntropyConfig {
+            enabled: false,
+            ..AntiEntropyConfig::default()
+        })
+        .kv(KvConfig {
+            sync_interval: Duration::from_millis(200),
+            ..KvConfig::default()
+        });
+    for &peer in bootstrap {
+        builder = builder.add_bootstrap_peer(peer);
+    }
+    let node = Node::new(builder.build().expect("Failed to build config"))
+        .await
+        .expect("Failed to create node");
+    node.start().await.expect("Failed to start node");
+    node
+}
+
+/// Two connected nodes: the second bootstraps from the first.
+async fn pair() -> (Node, Node) {
+    let first = kv_node(&[]).await;
+    let addr = first.local_addr().await.expect("No local address");
+    let second = kv_node(&[addr]).await;
+    wait_for_peers(&first, 1, "the pair connects").await;
+    (first, second)
+}
+
+/// Writes made independently in two partitions converge on every node once a
+/// bridging node joins both.
+#[tokio::test(flavor = "multi_thread")]
+async fn partitioned_writes_converge_once_healed() {
+    init_tracing();
+
+    let (a1, a2) = pair().await;
+    let (b1, b2) = pair().await;
+
+    a1.kv()
+        .put("leader", Bytes::from_static(b"a1"))
+        .await
+        .expect("Failed to put");
+    a1.kv()
+        .add("members", Bytes::from_static(b"a1"))
+        .await
+        .unwrap();
+    a2.kv()
+        .add("members", Bytes::from_static(b"a2"))
+        .await
+        .unwrap();
+    a1.kv().increment("hits", 3).await.unwrap();
+    a2.kv().increment("hits", 2).await.unwrap();
+
+    // Written later, so it wins the register.
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    b1.kv()
+        .put("leader", Bytes::from_static(b"b1"))
+        .await
+        .expect("Failed to put");
+    b2.kv()
+        .add("members", Bytes::from_static(b"b2"))
+        .await
+        .unwrap();
+    b2.kv().remove("members", b"b2").await.unwrap();
+    b1.kv().increment("hits", -1).await.unwrap();
+
+    let a_addr = a1.local_addr().await.expect("No local address");
+    let b_addr = b1.local_addr().await.expect("No local address");
+    let bridge = kv_node(&[a_addr, b_addr]).await;
+
+    let nodes = [&a1, &a2, &b1, &b2, &bridge];
+    let members = BTreeSet::from([Bytes::from_static(b"a1"), Bytes::from_static(b"a2")]);
+    wait_until("every replica converges", READY_TIMEOUT, || {
+        nodes.iter().all(|node| {
+            let kv = node.kv();
+            kv.get("leader") == Some(Value::Register(Bytes::from_static(b"b1")))
+                && kv.get("members") == Some(Value::Set(members.clone()))
+                && kv.get("hits") == Some(Value::Counter(4))
+        })
+    })
+    .await;
+
+    for node in nodes {
+        node.shutdown().await.ok();
+    }
+}
+
+/// A subscriber is told of a change written on another node.
+#[tokio::test(flavor = "multi_thread")]
+async fn subscribers_hear_of_remote_writes() {
+    init_tracing();
+
+    let (writer, reader) = pair().await;
+    let mut changes = reader.kv().subscribe();
+
+    writer
+        .kv()
+        .put("greeting", Bytes::from_static(b"hello"))
+        .await
+        .expect("Failed to put");
+
+    let change = tokio::time::timeout(READY_TIMEOUT, changes.recv())
+        .await
+        .expect("timed out waiting for the change")
+        .expect("Subscription closed");
+    assert_eq!(
+        change,
+        Change {
+            key: "greeting".to_string(),
+            value: Value::Register(Bytes::from_static(b"hello")),
+        }
+    );
+
+    writer.shutdown().await.ok();
+    reader.shutdown().await.ok();
+}
+
+/// A key keeps the type of its first write.
+#[tokio::test(flavor = "multi_thread")]
+async fn writes_of_another_type_are_refused() {
+    init_tracing();
+
+    let node = kv_node(&[]).await;
+    let kv = node.kv();
+    kv.increment("hits", 1).await.expect("Failed to increment");
+
+    assert!(matches!(
+        kv.put("hits", Bytes::from_static(b"x")).await,
+        Err(Error::KvTypeMismatch(key)) if key == "hits"
+    ));
+    assert_eq!(kv.get("hits"), Some(Value::Counter(1)));
+
+    node.shutdown().await.ok();
+}
@@ -6,8 +6,11 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
 
 use bytes::Bytes;
-use common::{READY_TIMEOUT, init_tracing, wait_for_peers, wait_until};
-use grapevine::{Node, NodeConfig, NodeConfigBuilder};
+use common::{READY_TIMEOUT, init_tracing, wait_for_peer_addr, wait_for_peers, wait_until};
+use grapevine::{
+    Capabilities, CompressionConfig, Node, NodeConfig, NodeConfigBuilder, PeerProtocol,
+    WireEncoding,
+};
 
 /// Test message broadcast and reception between two nodes.
 #[tokio::test(flavor = "multi_thread")]
@@ -120,3 +123,187 @@ async fn three_node_broadcast_propagation() {
     node2.shutdown().await.ok();
     node3.shutdown().await.ok();
 }
+
+/// A compressing node and a node that leaves compression off still exchange
+/// large broadcasts in both directions: compression is negotiated per
+/// connection and every node can decompress.
+#[tokio::test(flavor = "multi_thread")]
+async fn compressed_broadcast_round_trips() {
+    init_tracing();
+
+    let payload = Bytes::from("{\"queue\":\"orders\",\"depth\":42},".repeat(4096));
+
+    let compressing = Node::new(
+        NodeConfigBuilder::new()
+            .compression(CompressionConfig {
+                enabled: true,
+                min_size: 256,
+            })
+            .build()
+            .expect("Failed to build config"),
+    )
+    .await
+    .expect("Failed to create compressing node");
+    let compressing_received = Arc::new(AtomicU32::new(0));
+    let counter = Arc::clone(&compressing_received);
+    let expected = payload.clone();
+    compressing
+        .on_message(move |_origin, data| {
+            if data == expected {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .await;
+    compressing.start().await.expect("Failed to start node");
+    let addr = compressing.local_addr().await.expect("No local address");
+
+    let plain = Node::new(
+        NodeConfigBuilder::new()
+            .add_bootstrap_peer(addr)
+            .build()
+            .expect("Failed to build config"),
+    )
+    .await
+    .expect("Failed to create plain node");
+    let plain_received = Arc::new(AtomicU32::new(0));
+    let counter = Arc::clone(&plain_received);
+    let expected = payload.clone();
+    plain
+        .on_message(move |_origin, data| {
+            if data == expected {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .await;
+    plain.start().await.expect("Failed to start node");
+
+    wait_for_peers(&compressing, 1, "the plain node connects").await;
+
+    compressing
+        .broadcast(payload.clone())
+        .await
+        .expect("Failed to broadcast");
+    plain
+        .broadcast(payload.clone())
+        .await
+        .expect("Failed to broadcast");
+
+    wait_until(
+        "both nodes to receive the other's broadcast",
+        READY_TIMEOUT,
+        || {
+            compressing_received.load(Ordering::Relaxed) >= 1
+                && plain_received.load(Ordering::Relaxed) >= 1
+        },
+    )
+    .await;
+
+    compressing.shutdown().await.ok();
+    plain.shutdown().await.ok();
+}
+
+/// Two current nodes advertise their protocol when they connect and each
+/// records the other's version and capabilities.
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_negotiate_the_wire_protocol() {
+    init_tracing();
+
+    let node1 = Node::new(NodeConfig::default())
+        .await
+        .expect("Failed to create node1");
+    node1.start().await.expect("Failed to start node1");
+    let addr1 = node1.local_addr().await.expect("No local address");
+
+    let node2 = Node::new(
+        NodeConfigBuilder::new()
+            .add_bootstrap_peer(addr1)
+            .build()
+            .expect("Failed to build config"),
+    )
+    .await
+    .expect("Failed to create node2");
+    node2.start().await.expect("Failed to start node2");
+    let addr2 = node2.local_addr().await.expect("No local address");
+
+    wait_for_peer_addr(&node1, addr2, "node1 learns node2's listening address").await;
+    // Nodes sign with a key of their own, so they also offer to prove it.
+    let advertised = PeerProtocol {
+        capabilities: PeerProtocol::LOCAL
+            .capabilities
+            .union(Capabilities::HANDSHAKE),
+        ..PeerProtocol::LOCAL
+    };
+    wait_until("both sides to negotiate", READY_TIMEOUT, || {
+        node1.peer_protocol(addr2) == Some(advertised)
+            && node2.peer_protocol(addr1) == Some(advertised)
+    })
+    .await;
+
+    node1.shutdown().await.ok();
+    node2.shutdown().await.ok();
+}
+
+/// Nodes preferring different wire encodings exchange broadcasts: a postcard
+/// node and a bincode node settle on bincode between them, and two postcard
+/// nodes use postcard.
+#[tokio::test(flavor = "multi_thread")]
+async fn mixed_encodings_interoperate() {
+    init_tracing();
+
+    let postcard = || {
+        NodeConfigBuilder::new()
+            .encoding(WireEncoding::Postcard)
+            .build()
+            .expect("Failed to build config")
+    };
+
+    let hub = Node::new(postcard()).await.expect("Failed to create hub");
+    hub.start().await.expect("Failed to start hub");
+    let hub_addr = hub.local_addr().await.expect("No local address");
+
+    let mut leaves = Vec::new();
+    let mut counters = Vec::new();
+    for encoding in [WireEncoding::Postcard, WireEncoding::Bincode] {
+        let leaf = Node::new(
+            NodeConfigBuilder::new()
+                .encoding(encoding)
+                .add_bootstrap_peer(hub_addr)
+                .build()
+                .expect("Failed to build config"),
+        )
+        .await
+        .expect("Failed to create leaf");
+        let received = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&received);
+        leaf.on_message(move |_origin, data| {
+            if data == "encoded" {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .await;
+        leaf.start().await.expect("Failed to start leaf");
+        leaves.push(leaf);
+        counters.push(received);
+    }
+
+    wait_for_peers(&hub, 2, "both leaves connect").await;
+    hub.broadcast(Bytes::from("encoded"))
+        .await
+        .expect("Failed to broadcast");
+
+    wait_until(
+        "both leaves to receive the broadcast",
+        READY_TIMEOUT,
+        || {
+            counters
+                .iter()
+                .all(|received| received.load(Ordering::Relaxed) >= 1)
+        },
+    )
+    .await;
+
+    hub.shutdown().await.ok();
+    for leaf in leaves {
+        leaf.shutdown().await.ok();
+    }
+}
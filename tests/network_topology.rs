@@ -19,6 +19,7 @@ fn brisk_anti_entropy() -> AntiEntropyConfig {
         interval: Duration::from_millis(500),
         fanout: 3,
         enabled: true,
+        ..AntiEntropyConfig::default()
     }
 }
 
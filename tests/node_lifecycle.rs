@@ -238,6 +238,7 @@ async fn validate_config_params() {
         enabled: true,
         capacity: 0,
         refill_rate: 10,
+        ..RateLimitConfig::default()
     };
 
     let maybe_config = NodeConfigBuilder::new().rate_limit(rate_limit).build();
@@ -0,0 +1,230 @@
+//! Verify outbound backpressure: broadcast reports say which peers a
+//! broadcast was queued for, a peer that stops reading has its full queue
+//! handled by its overflow policy, and only the application's own messages
+//! wait for room.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use common::{
+    READY_TIMEOUT, connect_proven, init_tracing, stamped_identity, start_node,
+    start_recording_node, wait_for_delivery, wait_for_peers,
+};
+use futures::SinkExt;
+use grapevine::{
+    BroadcastReport, DeliveryConfig, DeliveryOrder, Node, NodeConfigBuilder, OutboundConfig,
+    OverflowPolicy, Payload,
+};
+use tokio::net::TcpStream;
+
+/// Large enough that a few fill the socket buffers of a peer that never reads.
+const PAYLOAD_SIZE: usize = 1024 * 1024;
+
+/// Most broadcasts sent waiting for a full queue.
+const MAX_BROADCASTS: usize = 64;
+
+/// Start a node whose only peer never reads what it is sent.
+async fn stalled(outbound: OutboundConfig) -> (Node, TcpStream) {
+    let (node, addr) = start_node(NodeConfigBuilder::new().outbound(outbound)).await;
+    let peer = TcpStream::connect(addr).await.expect("Failed to connect");
+    wait_for_peers(&node, 1, "the stalled peer connects").await;
+    (node, peer)
+}
+
+/// Broadcast large payloads until one is not queued for the stalled peer,
+/// returning that broadcast's report and how long it took.
+async fn broadcast_until_full(node: &Node) -> (BroadcastReport, Duration) {
+    let payload = Bytes::from(vec![0x5a; PAYLOAD_SIZE]);
+    for _ in 0..MAX_BROADCASTS {
+        let start = Instant::now();
+        let report = node
+            .broadcast_with_report(payload.clone())
+            .await
+            .expect("Failed to broadcast");
+        if !report.dropped.is_empty() || report.displaced > 0 {
+            return (report, start.elapsed());
+        }
+        assert!(report.is_complete());
+    }
+    panic!("the stalled peer's queue never filled");
+}
+
+fn outbound(queue_capacity: usize, overflow: OverflowPolicy) -> OutboundConfig {
+    OutboundConfig {
+        queue_capacity,
+        overflow,
+        ..OutboundConfig::default()
+    }
+}
+
+/// A broadcast to healthy peers is reported as queued for each of them.
+#[tokio::test(flavor = "multi_thread")]
+async fn healthy_peers_are_reported_enqueued() {
+    init_tracing();
+
+    let (hub, hub_addr) = start_node(NodeConfigBuilder::new()).await;
+    let (edge, _) = start_node(NodeConfigBuilder::new().add_bootstrap_peer(hub_addr)).await;
+    wait_for_peers(&hub, 1, "the edge joins the hub").await;
+
+    let report = hub
+        .broadcast_with_report("hello")
+        .await
+        .expect("Failed to broadcast");
+    assert_eq!(report.enqueued.len(), 1);
+    assert!(report.is_complete());
+    assert_eq!(report.displaced, 0);
+
+    edge.shutdown().await.ok();
+    hub.shutdown().await.ok();
+}
+
+/// Under drop-newest, a broadcast to a peer whose queue is full is reported
+/// as dropped for it.
+#[tokio::test(flavor = "multi_thread")]
+async fn full_queues_drop_newest_and_say_so() {
+    init_tracing();
+
+    let (node, _peer) = stalled(outbound(2, OverflowPolicy::DropNewest)).await;
+    let (report, _) = broadcast_until_full(&node).await;
+    assert_eq!(report.dropped.len(), 1);
+    assert!(report.enqueued.is_empty());
+    assert!(!report.is_complete());
+
+    node.shutdown().await.ok();
+}
+
+/// Under drop-oldest, the broadcast is queued and an older message makes
+/// room for it.
+#[tokio::test(flavor = "multi_thread")]
+async fn full_queues_drop_oldest_to_make_room() {
+    init_tracing();
+
+    let (node, _peer) = stalled(outbound(2, OverflowPolicy::DropOldest)).await;
+    let (report, _) = broadcast_until_full(&node).await;
+    assert_eq!(report.enqueued.len(), 1);
+    assert_eq!(report.displaced, 1);
+    assert!(report.is_complete());
+
+    node.shutdown().await.ok();
+}
+
+/// Under block, a broadcast waits out the timeout for room before the full
+/// queue drops it.
+#[tokio::test(flavor = "multi_thread")]
+async fn full_queues_block_the_producer_until_the_timeout() {
+    init_tracing();
+
+    const TIMEOUT: Duration = Duration::from_millis(300);
+    let (node, _peer) = stalled(outbound(1, OverflowPolicy::Block { timeout: TIMEOUT })).await;
+    let (report, elapsed) = broadcast_until_full(&node).await;
+    assert_eq!(report.dropped.len(), 1);
+    assert!(
+        elapsed >= TIMEOUT,
+        "dropped after {elapsed:?}, before the timeout"
+    );
+
+    node.shutdown().await.ok();
+}
+
+/// A peer's own policy applies in place of the default: of two stalled peers,
+/// the one set to drop-oldest makes room while the other drops the newest.
+#[tokio::test(flavor = "multi_thread")]
+async fn each_peer_can_have_its_own_policy() {
+    init_tracing();
+
+    let (node, addr) =
+        start_node(NodeConfigBuilder::new().outbound(outbound(2, OverflowPolicy::DropNewest)))
+            .await;
+    let newest = TcpStream::connect(addr).await.expect("Failed to connect");
+    let oldest = TcpStream::connect(addr).await.expect("Failed to connect");
+    let oldest_addr = oldest.local_addr().expect("No local address");
+    wait_for_peers(&node, 2, "the stalled peers connect").await;
+    node.set_peer_overflow(oldest_addr, OverflowPolicy::DropOldest);
+
+    // Fill both queues, however much each socket takes first.
+    let payload = Bytes::from(vec![0x5a; PAYLOAD_SIZE]);
+    let mut full = None;
+    for _ in 0..MAX_BROADCASTS {
+        let report = node
+            .broadcast_with_report(payload.clone())
+            .await
+            .expect("Failed to broadcast");
+        if !report.dropped.is_empty() && report.displaced > 0 {
+            full = Some(report);
+            break;
+        }
+    }
+    let report = full.expect("the stalled peers' queues never filled");
+    assert_eq!(report.dropped, vec![newest.local_addr().unwrap()]);
+    assert_eq!(report.enqueued, vec![oldest_addr]);
+    assert_eq!(report.displaced, 1);
+
+    node.shutdown().await.ok();
+}
+
+/// A full queue under block holds up the application's broadcasts, but not
+/// the receiver: what it forwards to the stalled peer is dropped at once, so
+/// it keeps taking messages from other peers.
+#[tokio::test(flavor = "multi_thread")]
+async fn forwarding_never_blocks_the_receiver() {
+    init_tracing();
+
+    const TIMEOUT: Duration = Duration::from_secs(3);
+    let (node, addr, delivered) = start_recording_node(
+        NodeConfigBuilder::new().outbound(outbound(1, OverflowPolicy::Block { timeout: TIMEOUT })),
+    )
+    .await;
+    let _stalled = TcpStream::connect(addr).await.expect("Failed to connect");
+    wait_for_peers(&node, 1, "the stalled peer connects").await;
+    broadcast_until_full(&node).await;
+
+    let identity = stamped_identity();
+    let (mut producer, producer_addr) = connect_proven(addr, &identity).await;
+    let start = Instant::now();
+    for (sequence, data) in [(1, &b"first"[..]), (2, &b"second"[..])] {
+        let broadcast = identity
+            .author(
+                producer_addr,
+                sequence,
+                Payload::Application(Bytes::from_static(data)),
+            )
+            .expect("Failed to author");
+        producer.send(broadcast).await.expect("Failed to send");
+    }
+    wait_for_delivery(&delivered, b"second", "the node delivers").await;
+    assert!(
+        start.elapsed() < TIMEOUT,
+        "the receiver waited {:?} on the stalled peer",
+        start.elapsed()
+    );
+
+    node.shutdown().await.ok();
+}
+
+/// A peer that has not said it can decode a broadcast's kind is skipped, not
+/// reported as reached.
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_that_cannot_decode_a_broadcast_are_skipped() {
+    init_tracing();
+
+    let (node, addr) = start_node(NodeConfigBuilder::new().delivery(DeliveryConfig {
+        order: DeliveryOrder::Causal,
+        ..DeliveryConfig::default()
+    }))
+    .await;
+    // Never sending a hello, it is taken for a 1.1.0 node.
+    let legacy = TcpStream::connect(addr).await.expect("Failed to connect");
+    wait_for_peers(&node, 1, "the legacy peer connects").await;
+
+    let report = tokio::time::timeout(READY_TIMEOUT, node.broadcast_with_report("causal"))
+        .await
+        .expect("timed out broadcasting")
+        .expect("Failed to broadcast");
+    assert_eq!(report.skipped, vec![legacy.local_addr().unwrap()]);
+    assert!(report.enqueued.is_empty());
+    assert!(!report.is_complete());
+
+    node.shutdown().await.ok();
+}
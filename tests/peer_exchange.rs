@@ -0,0 +1,137 @@
+//! Verify peer exchange: nodes find each other through signed advertisements,
+//! and a peer list is capped and taken only in answer to a request.
+
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::{READY_TIMEOUT, init_tracing, wait_for_peer_addr, wait_until};
+use futures::{SinkExt, StreamExt};
+use grapevine::{
+    Identity, MessageCodec, Node, NodeConfigBuilder, Payload, PeerExchangeConfig,
+    PeerExchangeStats, ReconnectConfig,
+};
+use tokio::net::TcpListener;
+use tokio_util::codec::Framed;
+
+/// Start a node bootstrapping from `bootstrap`, if given.
+async fn exchanging_node(bootstrap: Option<SocketAddr>) -> (Node, SocketAddr) {
+    let mut builder = NodeConfigBuilder::new().gossip_interval(Duration::from_secs(1));
+    if let Some(peer) = bootstrap {
+        builder = builder.add_bootstrap_peer(peer);
+    }
+    let node = Node::new(builder.build().expect("Failed to build config"))
+        .await
+        .expect("Failed to create node");
+    node.start().await.expect("Failed to start node");
+    let addr = node.local_addr().await.expect("No local address");
+    (node, addr)
+}
+
+/// A node that knows only the seed connects to the seed's other peers, which
+/// it learns from their signed advertisements.
+#[tokio::test(flavor = "multi_thread")]
+async fn advertised_peers_are_connected_to() {
+    init_tracing();
+
+    let (seed, seed_addr) = exchanging_node(None).await;
+    let (first, first_addr) = exchanging_node(Some(seed_addr)).await;
+    let (second, second_addr) = exchanging_node(Some(seed_addr)).await;
+    wait_until("the seed holds both advertisements", READY_TIMEOUT, || {
+        seed.peer_exchange_stats().new == 2
+    })
+    .await;
+
+    let (node, _) = exchanging_node(Some(seed_addr)).await;
+    wait_for_peer_addr(&node, first_addr, "node reaches the first peer").await;
+    wait_for_peer_addr(&node, second_addr, "node reaches the second peer").await;
+    wait_until("both are tried", READY_TIMEOUT, || {
+        node.peer_exchange_stats().tried == 2
+    })
+    .await;
+
+    for node in [&node, &second, &first, &seed] {
+        node.shutdown().await.ok();
+    }
+}
+
+/// A node takes at most `max_addresses` of a peer list it asked for, ignores
+/// a second list it did not ask for, and from an unsolicited advertisement
+/// takes only the sender's own address.
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_lists_are_capped_and_taken_only_when_asked() {
+    init_tracing();
+
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind seed");
+    let seed_addr = listener.local_addr().expect("No local address");
+    let config = NodeConfigBuilder::new()
+        .add_bootstrap_peer(seed_addr)
+        .peer_exchange(PeerExchangeConfig {
+            max_addresses: 8,
+            ..PeerExchangeConfig::default()
+        })
+        // At its target with the seed alone, the node dials nothing it learns.
+        .reconnect(ReconnectConfig {
+            target_peers: 1,
+            ..ReconnectConfig::default()
+        })
+        .build()
+        .expect("Failed to build config");
+    let node = Node::new(config).await.expect("Failed to create node");
+    let (stream, _) = tokio::join!(
+        async { listener.accept().await.expect("Failed to accept").0 },
+        async { node.start().await.expect("Failed to start node") },
+    );
+    let mut seed = Framed::new(stream, MessageCodec::new());
+
+    loop {
+        let message = seed.next().await.expect("Node hung up").expect("Bad frame");
+        if matches!(message.payload, Payload::PeerListRequest) {
+            break;
+        }
+    }
+
+    let seed_identity = Identity::generate();
+    let list = |net: u8| Payload::PeerListResponse {
+        peers: (1..=100)
+            .map(|host| SocketAddr::from(([10, net, 0, host], 7946)))
+            .collect(),
+    };
+    let relayed = Identity::generate().advertise(SocketAddr::from(([10, 9, 0, 1], 7946)), now_ms());
+    let own = seed_identity.advertise(seed_addr, now_ms());
+    for payload in [
+        list(1),
+        list(2),
+        Payload::PeerAdvertisements {
+            ads: vec![relayed, own],
+        },
+    ] {
+        let message = seed_identity
+            .author(seed_addr, 0, payload)
+            .expect("Failed to author");
+        seed.send(message).await.expect("Failed to send");
+    }
+
+    wait_until(
+        "the seed's own advertisement is taken",
+        READY_TIMEOUT,
+        || node.peer_exchange_stats().new == 9,
+    )
+    .await;
+    assert_eq!(
+        node.peer_exchange_stats(),
+        PeerExchangeStats { new: 9, tried: 0 }
+    );
+
+    node.shutdown().await.ok();
+}
+
+fn now_ms() -> u64 {
+    let elapsed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Clock before the epoch");
+    u64::try_from(elapsed.as_millis()).expect("Time out of range")
+}
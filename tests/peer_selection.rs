@@ -0,0 +1,103 @@
+//! Verify that nodes time their peers' round trips over ping and pong, and
+//! that broadcasts still reach the cluster under each peer selection strategy.
+
+mod common;
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{READY_TIMEOUT, init_tracing, wait_for_peers, wait_until};
+use grapevine::{Node, NodeConfigBuilder, PeerSelection};
+
+/// Start a node choosing peers by `selection`, bootstrapping from
+/// `bootstrap`, that records the data of every broadcast delivered to it.
+async fn selecting_node(
+    bootstrap: Option<SocketAddr>,
+    selection: PeerSelection,
+) -> (Node, Arc<Mutex<HashSet<Vec<u8>>>>) {
+    let mut builder = NodeConfigBuilder::new()
+        .gossip_interval(Duration::from_secs(1))
+        .peer_selection(selection);
+    if let Some(peer) = bootstrap {
+        builder = builder.add_bootstrap_peer(peer);
+    }
+    let node = Node::new(builder.build().expect("Failed to build config"))
+        .await
+        .expect("Failed to create node");
+
+    let delivered = Arc::new(Mutex::new(HashSet::new()));
+    let record = Arc::clone(&delivered);
+    node.on_message(move |_, data| {
+        record.lock().unwrap().insert(data.to_vec());
+    })
+    .await;
+    node.start().await.expect("Failed to start node");
+    (node, delivered)
+}
+
+/// Each side of a connection measures its round trip to the other.
+#[tokio::test(flavor = "multi_thread")]
+async fn heartbeat_pings_measure_round_trips() {
+    init_tracing();
+
+    let (first, _) = selecting_node(None, PeerSelection::RttWeighted).await;
+    let first_addr = first.local_addr().await.expect("No local address");
+    let (second, _) = selecting_node(Some(first_addr), PeerSelection::RttWeighted).await;
+    let second_addr = second.local_addr().await.expect("No local address");
+
+    wait_for_peers(&second, 1, "second connects to first").await;
+    wait_until("both sides measure the round trip", READY_TIMEOUT, || {
+        first.peer_rtt(second_addr).is_some() && second.peer_rtt(first_addr).is_some()
+    })
+    .await;
+    assert!(second.peer_rtt(first_addr).unwrap() < Duration::from_secs(1));
+
+    second.shutdown().await.ok();
+    first.shutdown().await.ok();
+}
+
+/// A broadcast reaches every peer of its origin whichever strategy chooses the
+/// fanout.
+#[tokio::test(flavor = "multi_thread")]
+async fn broadcasts_reach_the_cluster_under_every_strategy() {
+    init_tracing();
+
+    for selection in [
+        PeerSelection::Uniform,
+        PeerSelection::HealthWeighted,
+        PeerSelection::RttWeighted,
+    ] {
+        let (seed, seed_delivered) = selecting_node(None, selection).await;
+        let seed_addr = seed.local_addr().await.expect("No local address");
+        let mut nodes = vec![(seed, seed_delivered)];
+        for _ in 0..3 {
+            nodes.push(selecting_node(Some(seed_addr), selection).await);
+        }
+        wait_for_peers(&nodes[0].0, 3, "seed connects to every node").await;
+
+        let data = format!("{selection:?}").into_bytes();
+        nodes[0]
+            .0
+            .broadcast(data.clone())
+            .await
+            .expect("Failed to broadcast");
+
+        wait_until(
+            &format!("{selection:?} broadcast delivered"),
+            READY_TIMEOUT,
+            || {
+                nodes
+                    .iter()
+                    .skip(1)
+                    .all(|(_, delivered)| delivered.lock().unwrap().contains(&data))
+            },
+        )
+        .await;
+
+        for (node, _) in &nodes {
+            node.shutdown().await.ok();
+        }
+    }
+}
@@ -7,7 +7,7 @@ use std::sync::atomic::{AtomicU32, Ordering};
 use std::time::{Duration, Instant};
 
 use bytes::Bytes;
-use common::{READY_TIMEOUT, init_tracing, wait_for_peers};
+use common::{READY_TIMEOUT, init_tracing, wait_for_peers, wait_until};
 use grapevine::{Node, NodeConfigBuilder, RateLimitConfig};
 
 /// Test rate limiting is enabled and the node starts with a limiter configured.
@@ -19,6 +19,7 @@ async fn rate_limiting_enabled() {
         enabled: true,
         capacity: 5,
         refill_rate: 1,
+        ..RateLimitConfig::default()
     };
 
     let config = NodeConfigBuilder::new()
@@ -43,6 +44,7 @@ async fn rate_limiting_disabled() {
         enabled: false,
         capacity: 0,
         refill_rate: 0,
+        ..RateLimitConfig::default()
     };
 
     let config = NodeConfigBuilder::new()
@@ -58,20 +60,12 @@ async fn rate_limiting_disabled() {
     node.shutdown().await.ok();
 }
 
-#[tokio::test(flavor = "multi_thread")]
-async fn rate_limited_peer_drops_excess_inbound() {
-    init_tracing();
-
-    const BURST: u32 = 60;
-
-    // Receiver B: a tight limiter and a counter of delivered application messages.
+/// Start a receiver limited by `rate_limit`, counting the application
+/// messages it delivers, and a sender connected to it.
+async fn receiver_and_sender(rate_limit: RateLimitConfig) -> (Node, Node, Arc<AtomicU32>) {
     let receiver = Node::new(
         NodeConfigBuilder::new()
-            .rate_limit(RateLimitConfig {
-                enabled: true,
-                capacity: 5,
-                refill_rate: 1,
-            })
+            .rate_limit(rate_limit)
             .build()
             .expect("Failed to build receiver config"),
     )
@@ -88,7 +82,8 @@ async fn rate_limited_peer_drops_excess_inbound() {
     receiver.start().await.expect("Failed to start receiver");
     let receiver_addr = receiver.local_addr().await.expect("No receiver address");
 
-    // Sender A: bootstraps from B so its frames arrive on B's rate-limited path.
+    // The sender bootstraps from the receiver so its frames arrive on the
+    // receiver's rate-limited path.
     let sender = Node::new(
         NodeConfigBuilder::new()
             .add_bootstrap_peer(receiver_addr)
@@ -100,14 +95,12 @@ async fn rate_limited_peer_drops_excess_inbound() {
     sender.start().await.expect("Failed to start sender");
 
     wait_for_peers(&sender, 1, "sender connects to receiver").await;
+    (receiver, sender, delivered)
+}
 
-    for i in 0..BURST {
-        sender
-            .broadcast(Bytes::from(format!("burst {i}")))
-            .await
-            .expect("Failed to broadcast");
-    }
-
+/// Wait until at least one message is delivered and deliveries stop, and
+/// return how many were.
+async fn settled_count(delivered: &AtomicU32) -> u32 {
     let mut last = 0;
     let mut stable_since = Instant::now();
     let deadline = Instant::now() + READY_TIMEOUT;
@@ -118,7 +111,7 @@ async fn rate_limited_peer_drops_excess_inbound() {
             stable_since = Instant::now();
         }
         if count >= 1 && stable_since.elapsed() >= Duration::from_millis(500) {
-            break;
+            return last;
         }
         assert!(
             Instant::now() < deadline,
@@ -126,6 +119,31 @@ async fn rate_limited_peer_drops_excess_inbound() {
         );
         tokio::time::sleep(Duration::from_millis(50)).await;
     }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rate_limited_peer_drops_excess_inbound() {
+    init_tracing();
+
+    const BURST: u32 = 60;
+
+    // A tight limiter on the receiver, dropping what exceeds it.
+    let (receiver, sender, delivered) = receiver_and_sender(RateLimitConfig {
+        enabled: true,
+        capacity: 5,
+        refill_rate: 1,
+        ..RateLimitConfig::default()
+    })
+    .await;
+
+    for i in 0..BURST {
+        sender
+            .broadcast(Bytes::from(format!("burst {i}")))
+            .await
+            .expect("Failed to broadcast");
+    }
+
+    let last = settled_count(&delivered).await;
 
     assert!(
         last >= 1,
@@ -139,3 +157,81 @@ async fn rate_limited_peer_drops_excess_inbound() {
     sender.shutdown().await.ok();
     receiver.shutdown().await.ok();
 }
+
+/// A byte budget drops large broadcasts that fit the message budget many
+/// times over.
+#[tokio::test(flavor = "multi_thread")]
+async fn byte_limited_peer_drops_large_inbound() {
+    init_tracing();
+
+    const BURST: u32 = 30;
+
+    let (receiver, sender, delivered) = receiver_and_sender(RateLimitConfig {
+        byte_capacity: 16 * 1024,
+        byte_refill_rate: 1024,
+        ..RateLimitConfig::default()
+    })
+    .await;
+
+    let payload = vec![7u8; 4 * 1024];
+    for i in 0..BURST {
+        let mut data = payload.clone();
+        data.extend_from_slice(format!("burst {i}").as_bytes());
+        sender
+            .broadcast(Bytes::from(data))
+            .await
+            .expect("Failed to broadcast");
+    }
+
+    let last = settled_count(&delivered).await;
+    assert!(
+        last < BURST / 2,
+        "the byte budget must drop most of the burst, delivered {last} of {BURST}"
+    );
+
+    sender.shutdown().await.ok();
+    receiver.shutdown().await.ok();
+}
+
+/// By default a peer over budget is paced by pausing reads from it: every
+/// message arrives, no faster than the budget allows.
+#[tokio::test(flavor = "multi_thread")]
+async fn rate_limited_peer_is_paced_not_dropped() {
+    init_tracing();
+
+    const BURST: u32 = 60;
+    const CAPACITY: u32 = 5;
+    const REFILL_RATE: u32 = 20;
+
+    let (receiver, sender, delivered) = receiver_and_sender(RateLimitConfig {
+        capacity: CAPACITY,
+        refill_rate: REFILL_RATE,
+        drop_excess: false,
+        ..RateLimitConfig::default()
+    })
+    .await;
+
+    let start = Instant::now();
+    for i in 0..BURST {
+        sender
+            .broadcast(Bytes::from(format!("burst {i}")))
+            .await
+            .expect("Failed to broadcast");
+    }
+    wait_until("every message is delivered", READY_TIMEOUT, || {
+        delivered.load(Ordering::Relaxed) == BURST
+    })
+    .await;
+
+    // The bucket may have refilled a little before the burst, so allow a
+    // few messages' slack.
+    let paced = Duration::from_secs_f64(f64::from(BURST - CAPACITY - 5) / f64::from(REFILL_RATE));
+    assert!(
+        start.elapsed() >= paced,
+        "the burst was delivered in {:?}, faster than the budget allows",
+        start.elapsed()
+    );
+
+    sender.shutdown().await.ok();
+    receiver.shutdown().await.ok();
+}
@@ -0,0 +1,94 @@
+//! Verify that nodes keep dialing bootstrap peers that are not up yet, and
+//! redial them after they restart.
+
+mod common;
+
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+
+use common::{READY_TIMEOUT, init_tracing, wait_for_peers, wait_until};
+use grapevine::{Node, NodeConfigBuilder, ReconnectConfig};
+
+/// A loopback address with a port no one is listening on, for now.
+fn unused_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to reserve a port");
+    listener.local_addr().expect("No local address")
+}
+
+/// Start a node listening on `bind`, bootstrapping from `bootstrap`, that
+/// redials quickly.
+async fn reconnecting_node(bind: SocketAddr, bootstrap: Option<SocketAddr>) -> Node {
+    let mut builder = NodeConfigBuilder::new()
+        .bind_addr(bind)
+        .gossip_interval(Duration::from_secs(1))
+        .reconnect(ReconnectConfig {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(400),
+            ..ReconnectConfig::default()
+        });
+    if let Some(peer) = bootstrap {
+        builder = builder.add_bootstrap_peer(peer);
+    }
+    let node = Node::new(builder.build().expect("Failed to build config"))
+        .await
+        .expect("Failed to create node");
+    node.start().await.expect("Failed to start node");
+    node
+}
+
+/// A node that starts before its seed connects once the seed is up.
+#[tokio::test(flavor = "multi_thread")]
+async fn bootstrap_is_retried_until_the_seed_is_up() {
+    init_tracing();
+
+    let seed_addr = unused_addr();
+    let node = reconnecting_node(unused_addr(), Some(seed_addr)).await;
+
+    wait_until("the first retries fail", READY_TIMEOUT, || {
+        let status = node.connection_status();
+        status.candidates.len() == 1 && status.candidates[0].failures >= 2
+    })
+    .await;
+    let status = node.connection_status();
+    assert_eq!(status.connected, 0);
+    assert!(status.candidates[0].bootstrap);
+    assert!(status.candidates[0].retry_in.is_some());
+
+    let seed = reconnecting_node(seed_addr, None).await;
+    wait_for_peers(&node, 1, "node reaches the late seed").await;
+    wait_until("the seed is marked connected", READY_TIMEOUT, || {
+        let status = node.connection_status();
+        status.candidates[0].connected && status.candidates[0].failures == 0
+    })
+    .await;
+
+    node.shutdown().await.ok();
+    seed.shutdown().await.ok();
+}
+
+/// A bootstrap peer that shuts down and comes back is reconnected to.
+#[tokio::test(flavor = "multi_thread")]
+async fn restarted_seed_is_reconnected() {
+    init_tracing();
+
+    let seed_addr = unused_addr();
+    let seed = reconnecting_node(seed_addr, None).await;
+    let node = reconnecting_node(unused_addr(), Some(seed_addr)).await;
+    wait_for_peers(&node, 1, "node connects to the seed").await;
+
+    seed.shutdown().await.ok();
+    wait_until("node notices the seed left", READY_TIMEOUT, || {
+        node.connection_status().connected == 0
+    })
+    .await;
+    let status = node.connection_status();
+    assert_eq!(status.candidates.len(), 1, "the seed is still a candidate");
+    assert!(!status.candidates[0].connected);
+
+    let seed = reconnecting_node(seed_addr, None).await;
+    wait_for_peers(&node, 1, "node reconnects to the restarted seed").await;
+    wait_for_peers(&seed, 1, "the restarted seed sees the node").await;
+
+    node.shutdown().await.ok();
+    seed.shutdown().await.ok();
+}
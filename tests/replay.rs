@@ -0,0 +1,127 @@
+//! Verify replay protection: a captured `Goodbye`, sent again on a new
+//! connection, no longer makes the hub drop its sender, whether or not it
+//! carries a clock stamp.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{
+    READY_TIMEOUT, connect_proven, init_tracing, is_closed, stamped_identity, start_node,
+    wait_for_peer_addr,
+};
+use futures::SinkExt;
+use grapevine::{Identity, MessageCodec, NodeConfigBuilder, Payload};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+/// A `Goodbye` disconnects its sender once; the same frame replayed on a new
+/// connection is dropped and the connection stays.
+#[tokio::test(flavor = "multi_thread")]
+async fn replayed_goodbye_is_ignored() {
+    init_tracing();
+
+    let (hub, hub_addr) = start_node(NodeConfigBuilder::new()).await;
+    let identity = stamped_identity();
+
+    let stream = TcpStream::connect(hub_addr)
+        .await
+        .expect("Failed to connect");
+    let first_addr = stream.local_addr().expect("No local address");
+    wait_for_peer_addr(&hub, first_addr, "hub registers the first connection").await;
+    let mut peer = Framed::new(stream, MessageCodec::new());
+    let goodbye = identity
+        .author(
+            first_addr,
+            0,
+            Payload::Goodbye {
+                reason: "leaving".to_string(),
+            },
+        )
+        .expect("Failed to author");
+    peer.send(goodbye.clone()).await.expect("Failed to send");
+    let mut stream = peer.into_inner();
+    assert!(
+        is_closed(&mut stream, READY_TIMEOUT).await,
+        "goodbye was not honored"
+    );
+
+    let stream = TcpStream::connect(hub_addr)
+        .await
+        .expect("Failed to connect");
+    let second_addr = stream.local_addr().expect("No local address");
+    wait_for_peer_addr(&hub, second_addr, "hub registers the second connection").await;
+    let mut peer = Framed::new(stream, MessageCodec::new());
+    peer.send(goodbye).await.expect("Failed to send");
+    let heartbeat = identity
+        .author(first_addr, 0, Payload::Heartbeat { from: first_addr })
+        .expect("Failed to author");
+    peer.send(heartbeat).await.expect("Failed to send");
+
+    let mut stream = peer.into_inner();
+    assert!(
+        !is_closed(&mut stream, Duration::from_secs(1)).await,
+        "replayed goodbye was honored"
+    );
+    // The heartbeat registers the connection under its canonical address.
+    assert!(hub.peers().await.contains(&first_addr));
+
+    hub.shutdown().await.ok();
+}
+
+/// With the default configuration nothing is stamped, yet an unstamped
+/// `Goodbye` captured from a peer that proved its key is honored only on that
+/// peer's connection: replayed over a connection that proved no key, or one
+/// that proved another, it is dropped.
+#[tokio::test(flavor = "multi_thread")]
+async fn replayed_unstamped_goodbye_is_ignored_by_default() {
+    init_tracing();
+
+    let (hub, hub_addr) = start_node(NodeConfigBuilder::new()).await;
+    let identity = Identity::generate();
+
+    let (mut peer, own_addr) = connect_proven(hub_addr, &identity).await;
+    let goodbye = identity
+        .author(
+            own_addr,
+            0,
+            Payload::Goodbye {
+                reason: "leaving".to_string(),
+            },
+        )
+        .expect("Failed to author");
+    assert!(goodbye.id.hlc().is_none());
+    peer.send(goodbye.clone()).await.expect("Failed to send");
+    let mut stream = peer.into_inner();
+    assert!(
+        is_closed(&mut stream, READY_TIMEOUT).await,
+        "goodbye was not honored"
+    );
+
+    let stream = TcpStream::connect(hub_addr)
+        .await
+        .expect("Failed to connect");
+    let unproven_addr = stream.local_addr().expect("No local address");
+    wait_for_peer_addr(&hub, unproven_addr, "hub registers the unproven connection").await;
+    let mut unproven = Framed::new(stream, MessageCodec::new());
+    unproven
+        .send(goodbye.clone())
+        .await
+        .expect("Failed to send");
+
+    let (mut other, _) = connect_proven(hub_addr, &Identity::generate()).await;
+    other.send(goodbye).await.expect("Failed to send");
+
+    let mut unproven = unproven.into_inner();
+    let mut other = other.into_inner();
+    assert!(
+        !is_closed(&mut unproven, Duration::from_secs(1)).await,
+        "goodbye replayed over an unproven connection was honored"
+    );
+    assert!(
+        !is_closed(&mut other, Duration::from_secs(1)).await,
+        "goodbye replayed over another key's connection was honored"
+    );
+
+    hub.shutdown().await.ok();
+}
@@ -0,0 +1,143 @@
+//! Verify that a node joining through a bootstrap peer fetches the cluster's
+//! history as a snapshot from that one peer, before anti-entropy takes over.
+
+mod common;
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use common::{READY_TIMEOUT, init_tracing, wait_until};
+use grapevine::{
+    AntiEntropyConfig, Node, NodeConfigBuilder, SnapshotConfig, SnapshotProgress, SnapshotState,
+};
+
+/// Broadcasts made before the joiner starts.
+const HISTORY: usize = 200;
+
+/// Small enough that the history spans many snapshot chunks.
+const MAX_MESSAGE_SIZE: usize = 8 * 1024;
+
+/// Start a node with small frames, bootstrapping from `bootstrap`, that
+/// records the `(origin, sequence)` of every broadcast delivered to it.
+async fn recording_node(
+    bootstrap: Option<SocketAddr>,
+    snapshot: SnapshotConfig,
+    anti_entropy: AntiEntropyConfig,
+) -> (Node, Arc<Mutex<HashSet<(SocketAddr, u64)>>>) {
+    let mut builder = NodeConfigBuilder::new()
+        .max_message_size(MAX_MESSAGE_SIZE)
+        .snapshot(snapshot)
+        .anti_entropy(anti_entropy);
+    if let Some(peer) = bootstrap {
+        builder = builder.add_bootstrap_peer(peer);
+    }
+    let node = Node::new(builder.build().expect("Failed to build config"))
+        .await
+        .expect("Failed to create node");
+
+    let delivered = Arc::new(Mutex::new(HashSet::new()));
+    let record = Arc::clone(&delivered);
+    node.on_delivered(move |message| {
+        record
+            .lock()
+            .unwrap()
+            .insert((message.id.origin, message.id.sequence));
+    })
+    .await;
+    node.start().await.expect("Failed to start node");
+    (node, delivered)
+}
+
+/// A seed node holding `HISTORY` broadcasts of 200 bytes each.
+async fn seed_with_history() -> Node {
+    let (seed, _) = recording_node(
+        None,
+        SnapshotConfig::default(),
+        AntiEntropyConfig::default(),
+    )
+    .await;
+    for i in 0..HISTORY {
+        seed.broadcast(Bytes::from(vec![u8::try_from(i % 256).unwrap(); 200]))
+            .await
+            .expect("Failed to broadcast");
+    }
+    seed
+}
+
+/// The joiner receives every retained message in a multi-chunk snapshot, and
+/// its transfer completes.
+#[tokio::test(flavor = "multi_thread")]
+async fn joiner_fetches_the_history_as_a_snapshot() {
+    init_tracing();
+
+    let seed = seed_with_history().await;
+    let seed_addr = seed.local_addr().await.expect("No local address");
+    assert_eq!(seed.snapshot_progress().state, SnapshotState::Skipped);
+
+    let snapshot = SnapshotConfig {
+        window: 2,
+        ..SnapshotConfig::default()
+    };
+    let (joiner, delivered) =
+        recording_node(Some(seed_addr), snapshot, AntiEntropyConfig::default()).await;
+
+    wait_until("the snapshot transfer completes", READY_TIMEOUT, || {
+        joiner.snapshot_progress().state == SnapshotState::Complete
+    })
+    .await;
+    let SnapshotProgress {
+        peer,
+        received,
+        checkpoint,
+        ..
+    } = joiner.snapshot_progress();
+    assert_eq!(peer, Some(seed_addr));
+    assert_eq!(received, u64::try_from(HISTORY).unwrap());
+    assert_eq!(
+        checkpoint,
+        Some((seed_addr, u64::try_from(HISTORY - 1).unwrap()))
+    );
+
+    let expected: HashSet<(SocketAddr, u64)> = (0..HISTORY)
+        .map(|sequence| (seed_addr, u64::try_from(sequence).unwrap()))
+        .collect();
+    wait_until("every broadcast is delivered", READY_TIMEOUT, || {
+        *delivered.lock().unwrap() == expected
+    })
+    .await;
+
+    joiner.shutdown().await.ok();
+    seed.shutdown().await.ok();
+}
+
+/// With snapshots disabled, a joiner still catches up by anti-entropy.
+#[tokio::test(flavor = "multi_thread")]
+async fn joiner_without_snapshots_catches_up_by_anti_entropy() {
+    init_tracing();
+
+    let seed = seed_with_history().await;
+    let seed_addr = seed.local_addr().await.expect("No local address");
+
+    let snapshot = SnapshotConfig {
+        enabled: false,
+        ..SnapshotConfig::default()
+    };
+    let anti_entropy = AntiEntropyConfig {
+        interval: Duration::from_millis(500),
+        ..AntiEntropyConfig::default()
+    };
+    let (joiner, delivered) = recording_node(Some(seed_addr), snapshot, anti_entropy).await;
+
+    wait_until("every broadcast is delivered", READY_TIMEOUT, || {
+        delivered.lock().unwrap().len() == HISTORY
+    })
+    .await;
+    assert_eq!(joiner.snapshot_progress().state, SnapshotState::Skipped);
+    assert_eq!(joiner.snapshot_progress().received, 0);
+
+    joiner.shutdown().await.ok();
+    seed.shutdown().await.ok();
+}
@@ -0,0 +1,140 @@
+//! Verify that zone labels spread between peers, and that fanout keeps to the
+//! local zone apart from its guaranteed cross-zone links.
+
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use common::{READY_TIMEOUT, init_tracing, wait_for_peers, wait_until};
+use grapevine::{
+    AntiEntropyConfig, EpidemicConfig, Node, NodeConfigBuilder, SnapshotConfig, ZoneConfig,
+};
+
+/// Start a node in `zone`, bootstrapping from `bootstrap`, that counts the
+/// broadcasts delivered to it. Nodes never forward or repair, so a broadcast
+/// reaches exactly the peers its origin's fanout chose.
+async fn zoned_node(
+    bootstrap: Option<SocketAddr>,
+    zone: Option<&str>,
+    fanout: usize,
+) -> (Node, Arc<AtomicUsize>) {
+    let mut builder = NodeConfigBuilder::new()
+        .gossip_interval(Duration::from_secs(1))
+        .fanout(fanout)
+        .zone(ZoneConfig {
+            label: zone.map(str::to_string),
+            ..ZoneConfig::default()
+        })
+        .epidemic(EpidemicConfig {
+            forward_probability: 0.0,
+        })
+        .anti_entropy(AntiEntropyConfig {
+            enabled: false,
+            ..AntiEntropyConfig::default()
+        })
+        .snapshot(SnapshotConfig {
+            enabled: false,
+            ..SnapshotConfig::default()
+        });
+    if let Some(peer) = bootstrap {
+        builder = builder.add_bootstrap_peer(peer);
+    }
+    let node = Node::new(builder.build().expect("Failed to build config"))
+        .await
+        .expect("Failed to create node");
+
+    let delivered = Arc::new(AtomicUsize::new(0));
+    let count = Arc::clone(&delivered);
+    node.on_message(move |_, _| {
+        count.fetch_add(1, Ordering::Relaxed);
+    })
+    .await;
+    node.start().await.expect("Failed to start node");
+    (node, delivered)
+}
+
+/// Labels reach directly connected peers, whichever side opened the
+/// connection, and unlabelled nodes stay without one.
+#[tokio::test(flavor = "multi_thread")]
+async fn zone_labels_spread_between_peers() {
+    init_tracing();
+
+    let (seed, _) = zoned_node(None, Some("eu-west-1a"), 3).await;
+    let seed_addr = seed.local_addr().await.expect("No local address");
+    let (near, _) = zoned_node(Some(seed_addr), Some("eu-west-1a"), 3).await;
+    let near_addr = near.local_addr().await.expect("No local address");
+    let (far, _) = zoned_node(Some(seed_addr), Some("us-east-1b"), 3).await;
+    let far_addr = far.local_addr().await.expect("No local address");
+    let (unlabelled, _) = zoned_node(Some(seed_addr), None, 3).await;
+    let unlabelled_addr = unlabelled.local_addr().await.expect("No local address");
+
+    wait_until("every label reaches every peer", READY_TIMEOUT, || {
+        seed.peer_zone(near_addr).as_deref() == Some("eu-west-1a")
+            && seed.peer_zone(far_addr).as_deref() == Some("us-east-1b")
+            && near.peer_zone(seed_addr).as_deref() == Some("eu-west-1a")
+            && far.peer_zone(near_addr).as_deref() == Some("eu-west-1a")
+            && near.peer_zone(far_addr).as_deref() == Some("us-east-1b")
+            && unlabelled.peer_zone(far_addr).as_deref() == Some("us-east-1b")
+    })
+    .await;
+    assert_eq!(seed.peer_zone(unlabelled_addr), None);
+    assert_eq!(far.peer_zone(unlabelled_addr), None);
+
+    for node in [&unlabelled, &far, &near, &seed] {
+        node.shutdown().await.ok();
+    }
+}
+
+/// With a fanout of two and one cross-zone link, every broadcast goes to one
+/// peer in the origin's zone and one outside it.
+#[tokio::test(flavor = "multi_thread")]
+async fn fanout_prefers_the_local_zone() {
+    init_tracing();
+
+    const BROADCASTS: usize = 20;
+
+    let (origin, _) = zoned_node(None, Some("a"), 2).await;
+    let origin_addr = origin.local_addr().await.expect("No local address");
+    let mut peers = Vec::new();
+    for zone in ["a", "a", "b", "b"] {
+        let (node, delivered) = zoned_node(Some(origin_addr), Some(zone), 2).await;
+        let addr = node.local_addr().await.expect("No local address");
+        peers.push((node, addr, zone, delivered));
+    }
+    wait_for_peers(&origin, 4, "origin connects to every peer").await;
+    wait_until("the origin knows every peer's zone", READY_TIMEOUT, || {
+        peers
+            .iter()
+            .all(|(_, addr, zone, _)| origin.peer_zone(*addr).as_deref() == Some(*zone))
+    })
+    .await;
+
+    for i in 0..BROADCASTS {
+        origin
+            .broadcast(format!("m{i}"))
+            .await
+            .expect("Failed to broadcast");
+    }
+
+    let delivered_in = |wanted: &str| -> usize {
+        peers
+            .iter()
+            .filter(|(_, _, zone, _)| *zone == wanted)
+            .map(|(_, _, _, delivered)| delivered.load(Ordering::Relaxed))
+            .sum()
+    };
+    wait_until("every broadcast reaches two peers", READY_TIMEOUT, || {
+        delivered_in("a") + delivered_in("b") == 2 * BROADCASTS
+    })
+    .await;
+    assert_eq!(delivered_in("a"), BROADCASTS);
+    assert_eq!(delivered_in("b"), BROADCASTS);
+
+    for (node, ..) in &peers {
+        node.shutdown().await.ok();
+    }
+    origin.shutdown().await.ok();
+}